@@ -0,0 +1,73 @@
+//! Request parsing and response serialization:
+//! `cargo bench --bench protocol`
+
+use bytes::BytesMut;
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use http::protocol::{
+    codec::{self, Event, RequestDecoder},
+    response::HttpResponse,
+};
+use std::hint::black_box;
+
+const REQUEST: &[u8] = b"GET /assets/app.js?v=3 HTTP/1.1\r\n\
+    Host: example.com\r\n\
+    User-Agent: Mozilla/5.0 (X11; Linux x86_64) Gecko/20100101 Firefox/128.0\r\n\
+    Accept: */*\r\n\
+    Accept-Language: en-US,en;q=0.5\r\n\
+    Accept-Encoding: gzip, deflate\r\n\
+    Referer: https://example.com/\r\n\
+    Connection: keep-alive\r\n\r\n";
+
+const POST: &[u8] = b"POST /api/items HTTP/1.1\r\n\
+    Host: example.com\r\n\
+    Content-Type: application/json\r\n\
+    Transfer-Encoding: chunked\r\n\r\n\
+    10\r\n{\"name\": \"item\"}\r\n0\r\n\r\n";
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(REQUEST.len() as u64));
+    group.bench_function("head", |b| {
+        b.iter(|| codec::parse_head(black_box(REQUEST)).unwrap())
+    });
+
+    // A keep-alive connection's decoder, fed one request at a time
+    for (name, request) in [("decode_get", REQUEST), ("decode_chunked_post", POST)] {
+        group.throughput(Throughput::Bytes(request.len() as u64));
+        group.bench_function(name, |b| {
+            let mut decoder = RequestDecoder::new();
+            b.iter(|| {
+                decoder.feed(black_box(request));
+                while let Some(event) = decoder.decode().unwrap() {
+                    if matches!(event, Event::End) {
+                        break;
+                    }
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn serialize(c: &mut Criterion) {
+    let response = HttpResponse::ok()
+        .with_header("cache-control", "no-store")
+        .with_header("x-request-id", "0000abcd00000001")
+        .with_text("hello");
+
+    let mut group = c.benchmark_group("serialize");
+    group.bench_function("head", |b| {
+        let mut buffer = BytesMut::with_capacity(1024);
+        b.iter(|| {
+            buffer.clear();
+            codec::encode_response_head_into(black_box(&response), &mut buffer);
+        })
+    });
+    group.bench_function("response", |b| {
+        b.iter(|| codec::encode_response(black_box(&response)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, serialize);
+criterion_main!(benches);
@@ -0,0 +1,215 @@
+//! Per-request access logging, separate from the `tracing` diagnostics.
+
+use crate::protocol::request::HttpRequest;
+use chrono::{DateTime, Local};
+use std::{fs::OpenOptions, io, net::SocketAddr, path::Path, time::Duration};
+use tokio::{fs::File, io::AsyncWriteExt, sync::mpsc};
+use tracing::error;
+
+/// Line format of the access log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    /// NCSA Common Log Format.
+    Common,
+    /// Combined Log Format: Common plus referrer and user agent.
+    Combined,
+    /// One JSON object per line, for log pipelines.
+    Json,
+}
+
+/// What is recorded about a completed request.
+#[derive(Debug)]
+pub struct AccessRecord<'a> {
+    pub request_id: &'a str,
+    pub peer_addr: Option<SocketAddr>,
+    pub request: &'a HttpRequest,
+    pub status: u16,
+    pub bytes: usize,
+    pub duration: Duration,
+    pub time: DateTime<Local>,
+}
+
+/// Handle to an append-only access log file. Lines are written by a
+/// background task so logging never blocks request handling. Cloning shares
+/// the same file.
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl AccessLog {
+    /// Open (or create) `path` for appending. Must be called from within the
+    /// Tokio runtime, which hosts the writer task.
+    pub fn open(path: impl AsRef<Path>, format: AccessLogFormat) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut file = File::from_std(file);
+        let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+
+        tokio::spawn(async move {
+            while let Some(line) = receiver.recv().await {
+                if let Err(e) = file.write_all(line.as_bytes()).await {
+                    error!(error = ?e, "Failed to write access log");
+                }
+            }
+        });
+
+        Ok(Self { format, sender })
+    }
+
+    pub fn log(&self, record: &AccessRecord<'_>) {
+        let mut line = format_record(record, self.format);
+        line.push('\n');
+        // The writer task only stops with the runtime
+        let _ = self.sender.send(line);
+    }
+}
+
+/// Render one log line (without the trailing newline).
+pub fn format_record(record: &AccessRecord<'_>, format: AccessLogFormat) -> String {
+    match format {
+        AccessLogFormat::Common | AccessLogFormat::Combined => format_clf(record, format),
+        AccessLogFormat::Json => format_json(record),
+    }
+}
+
+/// Common/Combined Log Format, with the request duration in microseconds
+/// appended as a final field.
+fn format_clf(record: &AccessRecord<'_>, format: AccessLogFormat) -> String {
+    let request = record.request;
+    let host = record
+        .peer_addr
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let bytes = if record.bytes == 0 {
+        "-".to_string()
+    } else {
+        record.bytes.to_string()
+    };
+
+    let mut line = format!(
+        "{} - - [{}] \"{}\" {} {}",
+        host,
+        record.time.format("%d/%b/%Y:%H:%M:%S %z"),
+        escape(&format!(
+            "{} {} {}",
+            request.method, request.path, request.version
+        )),
+        record.status,
+        bytes
+    );
+
+    if format == AccessLogFormat::Combined {
+        let header = |name: &str| {
+            request
+                .get_header(name)
+                .map(|value| escape(value))
+                .unwrap_or_else(|| "-".to_string())
+        };
+        line.push_str(&format!(
+            " \"{}\" \"{}\"",
+            header("referer"),
+            header("user-agent")
+        ));
+    }
+
+    line.push_str(&format!(" {}", record.duration.as_micros()));
+    line
+}
+
+fn format_json(record: &AccessRecord<'_>) -> String {
+    let request = record.request;
+    let string = |value: Option<&str>| match value {
+        Some(value) => format!("\"{}\"", json_escape(value)),
+        None => "null".to_string(),
+    };
+    let remote_addr = record.peer_addr.map(|addr| addr.ip().to_string());
+
+    format!(
+        concat!(
+            r#"{{"time":{},"request_id":{},"remote_addr":{},"method":{},"path":{},"#,
+            r#""version":{},"status":{},"bytes":{},"duration_ms":{:.3},"#,
+            r#""referrer":{},"user_agent":{}}}"#
+        ),
+        string(Some(&record.time.to_rfc3339())),
+        string(Some(record.request_id)),
+        string(remote_addr.as_deref()),
+        string(Some(&request.method.to_string())),
+        string(Some(&request.path)),
+        string(Some(&request.version)),
+        record.status,
+        record.bytes,
+        record.duration.as_secs_f64() * 1000.0,
+        string(request.get_header("referer").map(String::as_str)),
+        string(request.get_header("user-agent").map(String::as_str)),
+    )
+}
+
+/// Escape a value for use inside a JSON string literal.
+fn json_escape(value: &str) -> String {
+    value.chars().fold(String::new(), |mut out, c| {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+        out
+    })
+}
+
+/// Escape quotes, backslashes and control characters inside quoted fields.
+fn escape(value: &str) -> String {
+    value.chars().fold(String::new(), |mut out, c| {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\x{:02x}", c as u32)),
+            c => out.push(c),
+        }
+        out
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_combined_format() {
+        let request = HttpRequest::from_buffer_sync(
+            b"GET /a\"b HTTP/1.1\r\nReferer: http://x/\r\nUser-Agent: curl/8.0\r\n\r\n",
+        )
+        .unwrap();
+        let record = AccessRecord {
+            request_id: "abc",
+            peer_addr: Some("10.0.0.1:5000".parse().unwrap()),
+            request: &request,
+            status: 200,
+            bytes: 1234,
+            duration: Duration::from_micros(1500),
+            time: Local.with_ymd_and_hms(2000, 10, 10, 13, 55, 36).unwrap(),
+        };
+
+        let line = format_record(&record, AccessLogFormat::Combined);
+        assert!(line.starts_with("10.0.0.1 - - [10/Oct/2000:13:55:36 "));
+        assert!(
+            line.ends_with("] \"GET /a\\\"b HTTP/1.1\" 200 1234 \"http://x/\" \"curl/8.0\" 1500")
+        );
+
+        let line = format_record(&record, AccessLogFormat::Common);
+        assert!(line.ends_with("] \"GET /a\\\"b HTTP/1.1\" 200 1234 1500"));
+
+        let line = format_record(&record, AccessLogFormat::Json);
+        assert!(line.starts_with(r#"{"time":"2000-10-10T13:55:36"#));
+        assert!(line.contains(
+            r#""request_id":"abc","remote_addr":"10.0.0.1","method":"GET","path":"/a\"b""#
+        ));
+        assert!(line.contains(r#""status":200,"bytes":1234,"duration_ms":1.500,"#));
+        assert!(line.ends_with(r#""referrer":"http://x/","user_agent":"curl/8.0"}"#));
+    }
+}
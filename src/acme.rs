@@ -0,0 +1,351 @@
+//! Automatic certificates from an ACME CA such as Let's Encrypt (RFC 8555),
+//! validated with HTTP-01 challenges answered by the regular HTTP listener.
+//!
+//! The account key, certificate and private key are kept in `cache_dir`, so
+//! restarts reuse them instead of hitting the CA's rate limits.
+
+use crate::{
+    error::{Result, ServerError},
+    protocol::response::HttpResponse,
+    tls::TlsConfig,
+};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, OrderStatus, RetryPolicy,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{fs, io::AsyncWriteExt, task::JoinHandle, time::sleep};
+use tracing::{info, warn};
+
+/// Path under which the CA fetches HTTP-01 challenge responses.
+pub const CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// Certificates are renewed once they are this old (Let's Encrypt issues
+/// them for 90 days).
+const RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+
+/// How often the certificate's age is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Wait before retrying after a failed order.
+const RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+const ACCOUNT_FILE: &str = "account.json";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// Which domains to obtain a certificate for, and from where.
+/// Clones share the pending challenge responses.
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    /// Contact URLs for the account, e.g. `mailto:admin@example.com`.
+    pub contact: Vec<String>,
+    pub directory_url: String,
+    pub cache_dir: PathBuf,
+    /// Challenge token → key authorization, while an order is in progress.
+    challenges: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl AcmeConfig {
+    /// One certificate covering `domains` from Let's Encrypt's production CA.
+    pub fn new(domains: &[&str], cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            domains: domains.iter().map(|domain| domain.to_string()).collect(),
+            contact: Vec::new(),
+            directory_url: LetsEncrypt::Production.url().to_string(),
+            cache_dir: cache_dir.into(),
+            challenges: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_contact(mut self, contact: &str) -> Self {
+        self.contact.push(contact.to_string());
+        self
+    }
+
+    /// Use another CA, e.g. Let's Encrypt staging while testing.
+    pub fn with_directory(mut self, directory_url: &str) -> Self {
+        self.directory_url = directory_url.to_string();
+        self
+    }
+
+    /// Answer the CA's validation request for a pending challenge.
+    pub fn challenge_response(&self, path: &str) -> Option<HttpResponse> {
+        let token = path.strip_prefix(CHALLENGE_PREFIX)?;
+        let key_authorization = self.challenges.read().unwrap().get(token).cloned()?;
+        Some(
+            HttpResponse::ok()
+                .with_header("content-type", "application/octet-stream")
+                .with_body(key_authorization.into_bytes()),
+        )
+    }
+
+    /// The cached certificate chain and key, if present.
+    pub fn cached_certificate(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        let cert = std::fs::read(self.cache_dir.join(CERT_FILE)).ok()?;
+        let key = std::fs::read(self.cache_dir.join(KEY_FILE)).ok()?;
+        Some((cert, key))
+    }
+
+    /// Whether the cached certificate is missing or old enough to renew.
+    async fn renewal_due(&self) -> bool {
+        let modified = fs::metadata(self.cache_dir.join(CERT_FILE))
+            .await
+            .and_then(|metadata| metadata.modified());
+        match modified {
+            Ok(modified) => is_due(modified, SystemTime::now()),
+            Err(_) => true,
+        }
+    }
+}
+
+fn is_due(issued: SystemTime, now: SystemTime) -> bool {
+    now.duration_since(issued)
+        .map(|age| age >= RENEW_AFTER)
+        .unwrap_or(false)
+}
+
+/// Keep the certificate for `acme` current in `tls` for as long as the
+/// server runs.
+pub fn spawn(acme: AcmeConfig, tls: TlsConfig) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let delay = if !acme.renewal_due().await {
+                CHECK_INTERVAL
+            } else {
+                match provision(&acme, &tls).await {
+                    Ok(()) => {
+                        info!(domains = ?acme.domains, "ACME certificate installed");
+                        CHECK_INTERVAL
+                    }
+                    Err(e) => {
+                        warn!(domains = ?acme.domains, error = %e, "ACME order failed");
+                        RETRY_DELAY
+                    }
+                }
+            };
+            sleep(delay).await;
+        }
+    })
+}
+
+/// Run one order to completion, then cache and install the certificate.
+async fn provision(acme: &AcmeConfig, tls: &TlsConfig) -> Result<()> {
+    let account = account(acme).await?;
+
+    let identifiers: Vec<Identifier> = acme
+        .domains
+        .iter()
+        .map(|domain| Identifier::Dns(domain.clone()))
+        .collect();
+    let mut order = account
+        .new_order(&NewOrder::new(&identifiers))
+        .await
+        .map_err(acme_error)?;
+
+    // Challenge responses are withdrawn however the order ends
+    let mut pending = PendingChallenges {
+        challenges: &acme.challenges,
+        tokens: Vec::new(),
+    };
+    let mut authorizations = order.authorizations();
+    while let Some(authorization) = authorizations.next().await {
+        let mut authorization = authorization.map_err(acme_error)?;
+        if authorization.status == AuthorizationStatus::Valid {
+            continue;
+        }
+
+        let mut challenge = authorization
+            .challenge(ChallengeType::Http01)
+            .ok_or_else(|| ServerError::Acme("CA offered no HTTP-01 challenge".to_string()))?;
+        pending.insert(
+            challenge.token.clone(),
+            challenge.key_authorization().as_str().to_string(),
+        );
+        challenge.set_ready().await.map_err(acme_error)?;
+    }
+
+    let result = async {
+        let status = order
+            .poll_ready(&RetryPolicy::default())
+            .await
+            .map_err(acme_error)?;
+        if status != OrderStatus::Ready {
+            return Err(ServerError::Acme(format!("Order ended as {:?}", status)));
+        }
+
+        let key_pem = order.finalize().await.map_err(acme_error)?;
+        let cert_pem = order
+            .poll_certificate(&RetryPolicy::default())
+            .await
+            .map_err(acme_error)?;
+        Ok((cert_pem, key_pem))
+    }
+    .await;
+
+    drop(pending);
+    let (cert_pem, key_pem) = result?;
+
+    let domains: Vec<&str> = acme.domains.iter().map(String::as_str).collect();
+    tls.install_certificate(&domains, cert_pem.as_bytes(), key_pem.as_bytes())?;
+
+    fs::create_dir_all(&acme.cache_dir).await?;
+    write_private(&acme.cache_dir.join(KEY_FILE), key_pem.as_bytes()).await?;
+    fs::write(acme.cache_dir.join(CERT_FILE), cert_pem).await?;
+    Ok(())
+}
+
+/// Load the cached account, or register a new one and cache it.
+async fn account(acme: &AcmeConfig) -> Result<Account> {
+    let path = acme.cache_dir.join(ACCOUNT_FILE);
+    let builder = Account::builder().map_err(acme_error)?;
+
+    if let Ok(cached) = fs::read(&path).await {
+        let credentials: AccountCredentials = serde_json::from_slice(&cached)
+            .map_err(|e| ServerError::Acme(format!("Invalid cached account: {}", e)))?;
+        return builder
+            .from_credentials(credentials)
+            .await
+            .map_err(acme_error);
+    }
+
+    let contact: Vec<&str> = acme.contact.iter().map(String::as_str).collect();
+    let (account, credentials) = builder
+        .create(
+            &NewAccount {
+                contact: &contact,
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            acme.directory_url.clone(),
+            None,
+        )
+        .await
+        .map_err(acme_error)?;
+
+    let serialized = serde_json::to_vec(&credentials)
+        .map_err(|e| ServerError::Acme(format!("Cannot serialize account: {}", e)))?;
+    fs::create_dir_all(&acme.cache_dir).await?;
+    write_private(&path, &serialized).await?;
+    Ok(account)
+}
+
+/// Challenge tokens published for an order, removed again when dropped.
+struct PendingChallenges<'a> {
+    challenges: &'a RwLock<HashMap<String, String>>,
+    tokens: Vec<String>,
+}
+
+impl PendingChallenges<'_> {
+    fn insert(&mut self, token: String, key_authorization: String) {
+        self.challenges
+            .write()
+            .unwrap()
+            .insert(token.clone(), key_authorization);
+        self.tokens.push(token);
+    }
+}
+
+impl Drop for PendingChallenges<'_> {
+    fn drop(&mut self) {
+        let mut challenges = self.challenges.write().unwrap();
+        for token in &self.tokens {
+            challenges.remove(token);
+        }
+    }
+}
+
+/// Write a secret (a private key or account credentials) to a file only its
+/// owner can read, from the moment it is created.
+async fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    // Files left by older versions may still be readable by others
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+    file.write_all(contents).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+fn acme_error(error: instant_acme::Error) -> ServerError {
+    ServerError::Acme(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_response() {
+        let acme = AcmeConfig::new(&["example.com"], "/tmp/acme");
+        acme.challenges
+            .write()
+            .unwrap()
+            .insert("token".to_string(), "token.thumbprint".to_string());
+
+        let response = acme
+            .challenge_response("/.well-known/acme-challenge/token")
+            .unwrap();
+        assert_eq!(response.body, &b"token.thumbprint"[..]);
+        assert!(
+            acme.challenge_response("/.well-known/acme-challenge/other")
+                .is_none()
+        );
+        assert!(acme.challenge_response("/token").is_none());
+    }
+
+    #[test]
+    fn test_pending_challenges_are_withdrawn_on_drop() {
+        let acme = AcmeConfig::new(&["example.com"], "/tmp/acme");
+        let mut pending = PendingChallenges {
+            challenges: &acme.challenges,
+            tokens: Vec::new(),
+        };
+        pending.insert("token".to_string(), "token.thumbprint".to_string());
+        assert!(
+            acme.challenge_response("/.well-known/acme-challenge/token")
+                .is_some()
+        );
+
+        drop(pending);
+        assert!(acme.challenges.read().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_write_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("http-acme-key-{}", std::process::id()));
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_private(&path, b"secret").await.unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read(&path).unwrap(), b"secret");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_is_due() {
+        let now = SystemTime::now();
+        assert!(!is_due(now - Duration::from_secs(24 * 60 * 60), now));
+        assert!(is_due(now - RENEW_AFTER, now));
+    }
+}
@@ -0,0 +1,300 @@
+//! Load generation against a running server, behind `http bench`.
+
+use crate::{
+    error::{Result, ServerError},
+    protocol::codec::{Event, ResponseDecoder},
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinSet,
+    time::timeout_at,
+};
+
+/// GET requests sent to one URL by `concurrency` workers for `duration`.
+#[derive(Debug, Clone)]
+pub struct LoadTest {
+    /// `host:port` to connect to.
+    pub address: String,
+    /// Request target, e.g. `/index.html`.
+    pub path: String,
+    pub concurrency: usize,
+    pub duration: Duration,
+    /// Send each worker's requests over one connection instead of
+    /// connecting for every request.
+    pub keep_alive: bool,
+}
+
+impl LoadTest {
+    /// Load `url`, an `http://` URL, with 32 workers for 10 seconds over
+    /// keep-alive connections.
+    pub fn new(url: &str) -> Result<Self> {
+        let invalid = || {
+            ServerError::InvalidConfig(format!(
+                "Cannot load {:?}: expected http://host:port/path",
+                url
+            ))
+        };
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            return Err(invalid());
+        }
+        let has_port = authority
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| !port.contains(']'));
+        let address = if has_port {
+            authority.to_string()
+        } else {
+            format!("{}:80", authority)
+        };
+        Ok(Self {
+            address,
+            path: path.to_string(),
+            concurrency: 32,
+            duration: Duration::from_secs(10),
+            keep_alive: true,
+        })
+    }
+
+    pub fn with_concurrency(mut self, workers: usize) -> Self {
+        self.concurrency = workers.max(1);
+        self
+    }
+
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn with_keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// Send requests until `duration` has passed and report on them.
+    pub async fn run(&self) -> Result<Report> {
+        let connection = if self.keep_alive {
+            "keep-alive"
+        } else {
+            "close"
+        };
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: {}\r\n\r\n",
+            self.path, self.address, connection
+        );
+
+        // Fail fast when nothing listens, rather than counting errors
+        TcpStream::connect(&self.address).await?;
+
+        let started = Instant::now();
+        let deadline = tokio::time::Instant::now() + self.duration;
+        let mut workers = JoinSet::new();
+        for _ in 0..self.concurrency {
+            let worker = Worker {
+                address: self.address.clone(),
+                request: request.clone().into_bytes(),
+                keep_alive: self.keep_alive,
+            };
+            workers.spawn(worker.run(deadline));
+        }
+
+        let mut report = Report::default();
+        while let Some(worker) = workers.join_next().await {
+            let worker = worker.map_err(|e| ServerError::HandlerPanicked(e.to_string()))?;
+            report.merge(worker);
+        }
+        report.elapsed = started.elapsed();
+        report.latencies.sort_unstable();
+        Ok(report)
+    }
+}
+
+struct Worker {
+    address: String,
+    request: Vec<u8>,
+    keep_alive: bool,
+}
+
+impl Worker {
+    async fn run(self, deadline: tokio::time::Instant) -> Report {
+        let mut report = Report::default();
+        let mut connection: Option<TcpStream> = None;
+        let mut buffer = vec![0u8; 16 * 1024];
+        while tokio::time::Instant::now() < deadline {
+            let sent = Instant::now();
+            let exchange = async {
+                let stream = match connection.take() {
+                    Some(stream) => stream,
+                    None => TcpStream::connect(&self.address).await?,
+                };
+                self.exchange(stream, &mut buffer).await
+            };
+            match timeout_at(deadline, exchange).await {
+                Ok(Ok((status, body_len, reusable))) => {
+                    report.requests += 1;
+                    report.bytes += body_len as u64;
+                    *report.statuses.entry(status).or_default() += 1;
+                    report.latencies.push(sent.elapsed());
+                    connection = reusable;
+                }
+                Ok(Err(_)) => report.errors += 1,
+                // Requests cut off by the end of the run are not counted
+                Err(_) => break,
+            }
+        }
+        report
+    }
+
+    /// Send the request and read the response: its status, body length and
+    /// the connection if it can carry the next request.
+    async fn exchange(
+        &self,
+        mut stream: TcpStream,
+        buffer: &mut [u8],
+    ) -> Result<(u16, usize, Option<TcpStream>)> {
+        stream.write_all(&self.request).await?;
+        let mut decoder = ResponseDecoder::new();
+        let mut status = None;
+        let mut close = !self.keep_alive;
+        let mut body_len = 0;
+        loop {
+            match decoder.decode()? {
+                Some(Event::Head(head)) => {
+                    close |= head
+                        .get_header("connection")
+                        .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+                    status = Some(head.status);
+                }
+                Some(Event::Body(chunk)) => body_len += chunk.len(),
+                Some(Event::End) => {
+                    let status = status.unwrap_or_default();
+                    let reusable = (!close && decoder.buffered().is_empty()).then_some(stream);
+                    return Ok((status, body_len, reusable));
+                }
+                None => {
+                    let n = stream.read(buffer).await?;
+                    if n == 0 {
+                        if decoder.finish() {
+                            close = true;
+                            continue;
+                        }
+                        return Err(ServerError::InvalidHttpRequest("Incomplete response"));
+                    }
+                    decoder.feed(&buffer[..n]);
+                }
+            }
+        }
+    }
+}
+
+/// Outcome of a [`LoadTest`].
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Requests answered, whatever the status.
+    pub requests: u64,
+    /// Requests that failed to connect, send or read a response.
+    pub errors: u64,
+    /// Responses by status code.
+    pub statuses: BTreeMap<u16, u64>,
+    /// Response body bytes received.
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Of the answered requests, sorted.
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.requests += other.requests;
+        self.errors += other.errors;
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        self.bytes += other.bytes;
+        self.latencies.extend(other.latencies);
+    }
+
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// The latency `percentile` (0 to 100) of answered requests were within.
+    pub fn latency(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests in {:.2?}, {} errors",
+            self.requests, self.elapsed, self.errors
+        )?;
+        writeln!(f, "Requests/sec: {:.1}", self.requests_per_second())?;
+        writeln!(
+            f,
+            "Latency: p50 {:.2?}, p90 {:.2?}, p99 {:.2?}, max {:.2?}",
+            self.latency(50.0),
+            self.latency(90.0),
+            self.latency(99.0),
+            self.latency(100.0)
+        )?;
+        let statuses: Vec<String> = self
+            .statuses
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect();
+        write!(
+            f,
+            "Statuses: {}\nBody bytes: {}",
+            statuses.join(", "),
+            self.bytes
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_test_url() {
+        let test = LoadTest::new("http://127.0.0.1:8000/index.html").unwrap();
+        assert_eq!(test.address, "127.0.0.1:8000");
+        assert_eq!(test.path, "/index.html");
+
+        let test = LoadTest::new("http://example.com").unwrap();
+        assert_eq!(test.address, "example.com:80");
+        assert_eq!(test.path, "/");
+        assert_eq!(LoadTest::new("http://[::1]").unwrap().address, "[::1]:80");
+
+        assert!(LoadTest::new("https://example.com/").is_err());
+        assert!(LoadTest::new("http:///path").is_err());
+    }
+
+    #[test]
+    fn test_report_latency_percentiles() {
+        let report = Report {
+            latencies: (1..=100).map(Duration::from_millis).collect(),
+            ..Report::default()
+        };
+        assert_eq!(report.latency(50.0), Duration::from_millis(50));
+        assert_eq!(report.latency(99.0), Duration::from_millis(99));
+        assert_eq!(report.latency(100.0), Duration::from_millis(100));
+        assert_eq!(Report::default().latency(50.0), Duration::ZERO);
+    }
+}
@@ -0,0 +1,338 @@
+//! Command-line interface of the `http` binary.
+
+use crate::{
+    bench::LoadTest,
+    config::{Config, RouteTarget, file::ConfigFile},
+    error::{Result, ServerError},
+    tls::TlsConfig,
+};
+use clap::{Args, Parser, Subcommand};
+use std::{net::ToSocketAddrs, path::PathBuf, time::Duration};
+
+/// HTTP/1.1 and WebSocket server.
+#[derive(Debug, Clone, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML configuration file, read again on SIGHUP; flags override it
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Host or IP address to listen on [default: 127.0.0.1]
+    #[arg(long, global = true)]
+    pub address: Option<String>,
+
+    /// Port to listen on [default: 8000, or the first free port]
+    #[arg(short, long, global = true)]
+    pub port: Option<u16>,
+
+    /// Serve only IPv6 clients on IPv6 addresses such as `::` instead of
+    /// both IPv4 and IPv6
+    #[arg(long, global = true)]
+    pub ipv6_only: bool,
+
+    /// Directory to serve static files from [default: ./static]
+    #[arg(long, global = true)]
+    pub static_dir: Option<String>,
+
+    /// Local development: list directories, disable browser caching, allow
+    /// any origin, log at debug level and reload the `--config` file when it
+    /// changes
+    #[arg(long, global = true)]
+    pub dev: bool,
+
+    /// Log filter such as `debug` or `http=trace`; overrides the file and
+    /// RUST_LOG
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// PEM certificate chain to serve HTTPS with
+    #[arg(long, requires = "tls_key", global = true)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for `--tls-cert`
+    #[arg(long, requires = "tls_cert", global = true)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Address to serve HTTPS on
+    #[arg(long, default_value = "127.0.0.1:8443", global = true)]
+    pub tls_address: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Run the server (the default)
+    Serve,
+    /// Load and validate the configuration, resolve the listen addresses
+    /// and document roots, load the certificates, report and exit; the exit
+    /// status is nonzero on any problem
+    CheckConfig,
+    /// Send GET requests to a running server and report throughput and
+    /// latency
+    Bench(BenchArgs),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Args)]
+pub struct BenchArgs {
+    /// URL to request
+    #[arg(default_value = "http://127.0.0.1:8000/")]
+    pub url: String,
+
+    /// Requests in flight at once
+    #[arg(short = 'n', long, default_value_t = 32)]
+    pub concurrency: usize,
+
+    /// Seconds to run for
+    #[arg(short, long, default_value_t = 10)]
+    pub duration: u64,
+
+    /// Open a new connection for every request
+    #[arg(long)]
+    pub no_keep_alive: bool,
+}
+
+impl BenchArgs {
+    pub fn load_test(&self) -> Result<LoadTest> {
+        Ok(LoadTest::new(&self.url)?
+            .with_concurrency(self.concurrency)
+            .with_duration(Duration::from_secs(self.duration))
+            .with_keep_alive(!self.no_keep_alive))
+    }
+}
+
+impl Cli {
+    pub fn command(&self) -> Command {
+        self.command.clone().unwrap_or(Command::Serve)
+    }
+
+    /// The `--config` file, or an empty one without the flag.
+    pub fn config_file(&self) -> Result<ConfigFile> {
+        match &self.config {
+            Some(path) => ConfigFile::load(path),
+            None => Ok(ConfigFile::default()),
+        }
+    }
+
+    /// The default configuration with `file` and then the flags applied.
+    /// Certificates are loaded here, so unreadable ones are reported before
+    /// anything binds.
+    pub fn config(&self, file: &ConfigFile) -> Result<Config> {
+        let mut config = Config::default();
+        file.apply(&mut config)?;
+
+        if self.address.is_some() || self.port.is_some() {
+            let current = config.addresses.first().and_then(|a| a.rsplit_once(':'));
+            let default_port = current
+                .and_then(|(_, port)| port.parse().ok())
+                .unwrap_or(8000);
+            let host = self
+                .address
+                .as_deref()
+                .or(current.map(|(host, _)| host))
+                .unwrap_or("127.0.0.1");
+            let port = self.port.unwrap_or(default_port);
+            config.addresses = vec![if host.contains(':') && !host.starts_with('[') {
+                format!("[{}]:{}", host, port)
+            } else {
+                format!("{}:{}", host, port)
+            }];
+        }
+
+        if self.ipv6_only {
+            config.ipv6_only = true;
+        }
+
+        if let Some(static_dir) = &self.static_dir {
+            config.static_dir = static_dir.clone();
+        }
+
+        let file_level = file
+            .logging
+            .as_ref()
+            .and_then(|logging| logging.level.as_ref());
+        if let Some(level) = &self.log_level {
+            config.logging.level = level.clone();
+        } else if self.dev {
+            config.logging.level = "http=debug".to_string();
+        } else if file_level.is_none()
+            && let Ok(level) = std::env::var("RUST_LOG")
+        {
+            config.logging.level = level;
+        }
+
+        if self.dev {
+            config.enable_dev_mode();
+        }
+
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            let tls = TlsConfig::new(&[&self.tls_address]).with_certificate(&[], cert, key)?;
+            config.tls = Some(tls);
+        }
+
+        Ok(config)
+    }
+}
+
+/// The checks of `check-config` beyond [`Config::validate`], which should
+/// pass first: listen addresses resolve, document roots can be read, and
+/// TLS has certificates. Returns a report of what was found, or every
+/// problem in one error.
+pub fn dry_run(config: &Config) -> Result<String> {
+    let mut report = Vec::new();
+    let mut problems = Vec::new();
+
+    let tls_addresses = config
+        .tls
+        .iter()
+        .flat_map(|tls| tls.addresses.iter().chain(&tls.redirect_addresses));
+    for address in config.addresses.iter().chain(tls_addresses) {
+        match address.to_socket_addrs() {
+            Ok(resolved) => {
+                let resolved: Vec<String> = resolved.map(|a| a.to_string()).collect();
+                report.push(format!("listen {} ({})", address, resolved.join(", ")));
+            }
+            Err(e) => problems.push(format!("address {:?} does not resolve: {}", address, e)),
+        }
+    }
+
+    let route_roots = config
+        .routes
+        .iter()
+        .filter_map(|route| match &route.target {
+            RouteTarget::Static(root) => Some((format!("route {}", route.prefix), root)),
+            _ => None,
+        });
+    let roots = std::iter::once(("static_dir".to_string(), &config.static_dir))
+        .chain(
+            config
+                .sites
+                .iter()
+                .map(|site| (format!("site {}", site.hostnames.join(", ")), &site.root)),
+        )
+        .chain(route_roots);
+    for (what, root) in roots {
+        match std::fs::canonicalize(root).and_then(|path| std::fs::read_dir(&path).map(|_| path)) {
+            Ok(path) => report.push(format!("{} serves {}", what, path.display())),
+            Err(e) => problems.push(format!("{} {:?} cannot be read: {}", what, root, e)),
+        }
+    }
+
+    if let Some(tls) = &config.tls {
+        if let Some(acme) = &tls.acme {
+            report.push(format!(
+                "TLS certificates from ACME for {}",
+                acme.domains.join(", ")
+            ));
+        } else {
+            let names = tls.certificate_hostnames();
+            report.push(if names.is_empty() {
+                "TLS default certificate loaded".to_string()
+            } else {
+                format!("TLS certificates loaded for {}", names.join(", "))
+            });
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(report.join("\n"));
+    }
+    Err(ServerError::InvalidConfig(format!(
+        "{} problem(s):\n  - {}",
+        problems.len(),
+        problems.join("\n  - ")
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_apply_to_config() {
+        let cli =
+            Cli::try_parse_from(["http", "--port", "9000", "--static-dir", "/srv/www"]).unwrap();
+        assert_eq!(cli.command(), Command::Serve);
+        let config = cli.config(&ConfigFile::default()).unwrap();
+        assert_eq!(config.addresses, ["127.0.0.1:9000"]);
+        assert_eq!(config.static_dir, "/srv/www");
+        assert!(config.tls.is_none());
+
+        let cli =
+            Cli::try_parse_from(["http", "check-config", "--address", "::", "-p", "80"]).unwrap();
+        assert_eq!(cli.command(), Command::CheckConfig);
+        let config = cli.config(&ConfigFile::default()).unwrap();
+        assert_eq!(config.addresses, ["[::]:80"]);
+        assert!(!config.ipv6_only);
+
+        let cli =
+            Cli::try_parse_from(["http", "bench", "http://localhost:9000/a", "-n", "4"]).unwrap();
+        let Command::Bench(args) = cli.command() else {
+            panic!("expected the bench subcommand");
+        };
+        let load_test = args.load_test().unwrap();
+        assert_eq!(load_test.address, "localhost:9000");
+        assert_eq!(load_test.concurrency, 4);
+        assert!(load_test.keep_alive);
+
+        // A certificate needs its key, and a missing one is reported
+        assert!(Cli::try_parse_from(["http", "--tls-cert", "cert.pem"]).is_err());
+        let cli = Cli::try_parse_from([
+            "http",
+            "--tls-cert",
+            "missing.pem",
+            "--tls-key",
+            "missing.key",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.config(&ConfigFile::default()),
+            Err(ServerError::InvalidConfig(_))
+        ));
+
+        // Flags override the file
+        let file = ConfigFile::parse(
+            "addresses = [\"0.0.0.0:8080\"]\nstatic_dir = \"/srv/www\"\n[logging]\nlevel = \"warn\"",
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from(["http", "-c", "http.toml", "-p", "9000"]).unwrap();
+        let config = cli.config(&file).unwrap();
+        assert_eq!(config.addresses, ["0.0.0.0:9000"]);
+        assert_eq!(config.static_dir, "/srv/www");
+        assert_eq!(config.logging.level, "warn");
+
+        let cli = Cli::try_parse_from(["http", "--log-level", "http=[["]).unwrap();
+        let config = cli.config(&ConfigFile::default()).unwrap();
+        assert!(config.logging.filter().is_err());
+
+        let cli = Cli::try_parse_from(["http", "--dev"]).unwrap();
+        let config = cli.config(&ConfigFile::default()).unwrap();
+        assert!(config.directory_listing && config.permissive_cors);
+        assert_eq!(config.logging.level, "http=debug");
+        let policy = config.cache_policy_for("/app.js").unwrap();
+        assert_eq!(policy.cache_control.as_deref(), Some("no-store"));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dir = std::env::temp_dir();
+        let config = Config {
+            static_dir: dir.to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+        let report = dry_run(&config).unwrap();
+        assert!(report.contains(&format!("listen {}", config.addresses[0])));
+        assert!(report.contains("static_dir serves"));
+
+        let config = Config {
+            addresses: vec!["no-such-host.invalid:80".to_string()],
+            static_dir: dir.join("http-missing-root").to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+        let Err(ServerError::InvalidConfig(problems)) = dry_run(&config) else {
+            panic!("expected problems");
+        };
+        assert!(problems.starts_with("2 problem(s)"), "{}", problems);
+    }
+}
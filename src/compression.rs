@@ -0,0 +1,310 @@
+//! Response body compression, negotiated with the client's
+//! `Accept-Encoding` header.
+
+use crate::protocol::{
+    request::{HttpMethod, HttpRequest},
+    response::{HttpResponse, HttpStatusCode},
+};
+use flate2::{
+    Compression,
+    write::{GzEncoder, ZlibEncoder},
+};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Gzip,
+    /// zlib-wrapped deflate, as `Content-Encoding: deflate` is defined.
+    Deflate,
+}
+
+impl Codec {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, body: &[u8], level: u32) -> std::io::Result<Vec<u8>> {
+        let level = Compression::new(level);
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Which responses are compressed, and how.
+///
+/// Content-type patterns are either a full media type (`application/json`)
+/// or a type with a wildcard subtype (`text/*`); parameters such as
+/// `charset` are ignored when matching.
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    /// Codecs offered, most preferred first.
+    pub codecs: Vec<Codec>,
+    /// From 0 (store only) to 9 (smallest output).
+    pub level: u32,
+    /// Bodies smaller than this many bytes are sent as they are.
+    pub min_size: usize,
+    /// Bodies larger than this many bytes are sent as they are too, so
+    /// large files are neither read into memory nor compressed whole.
+    pub max_size: u64,
+    /// Content types that are compressed.
+    pub allow: Vec<String>,
+    /// Content types that are never compressed, even if allowed.
+    pub deny: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            codecs: vec![Codec::Gzip, Codec::Deflate],
+            level: 6,
+            min_size: 1024,
+            max_size: 8 * 1024 * 1024,
+            allow: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .map(String::from)
+            .to_vec(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn with_codecs(mut self, codecs: &[Codec]) -> Self {
+        self.codecs = codecs.to_vec();
+        self
+    }
+
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn with_min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn with_allow(mut self, content_types: &[&str]) -> Self {
+        self.allow = content_types.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    pub fn with_deny(mut self, content_types: &[&str]) -> Self {
+        self.deny = content_types.iter().map(|t| t.to_string()).collect();
+        self
+    }
+
+    /// Whether responses of `content_type` may be compressed.
+    pub fn allows(&self, content_type: &str) -> bool {
+        let media_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+        let matches = |pattern: &String| match pattern.strip_suffix("/*") {
+            Some(kind) => media_type
+                .strip_prefix(kind)
+                .is_some_and(|rest| rest.starts_with('/')),
+            None => pattern.eq_ignore_ascii_case(&media_type),
+        };
+        self.allow.iter().any(matches) && !self.deny.iter().any(matches)
+    }
+
+    /// The first of `codecs` that `accept_encoding` does not refuse.
+    pub fn negotiate(&self, accept_encoding: &str) -> Option<Codec> {
+        let weights: Vec<(&str, bool)> = accept_encoding
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';');
+                let name = parts.next()?.trim();
+                let accepted = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .all(|q| q.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+                (!name.is_empty()).then_some((name, accepted))
+            })
+            .collect();
+        let accepted = |name: &str| {
+            weights
+                .iter()
+                .find(|(coding, _)| coding.eq_ignore_ascii_case(name))
+                .or_else(|| weights.iter().find(|(coding, _)| *coding == "*"))
+                .is_some_and(|(_, accepted)| *accepted)
+        };
+        self.codecs
+            .iter()
+            .copied()
+            .find(|codec| accepted(codec.name()))
+    }
+
+    /// Whether `response` is eligible for compression at all: a not yet
+    /// encoded 200 response of an allowed type, between `min_size` and
+    /// `max_size` bytes long.
+    fn eligible(&self, request: &HttpRequest, response: &HttpResponse) -> bool {
+        request.method != HttpMethod::Head
+            && response.status == HttpStatusCode::Ok
+            && (self.min_size as u64..=self.max_size).contains(&response.body_len())
+            && !response.headers.contains_key("content-encoding")
+            && response
+                .headers
+                .get("content-type")
+                .is_some_and(|content_type| self.allows(content_type))
+    }
+
+    /// Whether [`apply`](Self::apply) would compress `response`, e.g. to
+    /// read a file body into memory first.
+    pub fn would_encode(&self, request: &HttpRequest, response: &HttpResponse) -> bool {
+        self.eligible(request, response)
+            && request
+                .get_header("accept-encoding")
+                .and_then(|accept| self.negotiate(accept))
+                .is_some()
+    }
+
+    /// Compress `response` to `request` in place if its type is allowed,
+    /// its body is large enough and the client accepts one of `codecs`.
+    /// File bodies are left alone; see [`would_encode`](Self::would_encode).
+    /// The encoder runs on the blocking pool.
+    pub async fn apply(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        if !self.eligible(request, response) {
+            return;
+        }
+
+        // Caches must keep the variants apart whatever this client got
+        let vary = match response.headers.get("vary") {
+            Some(vary) => format!("{}, accept-encoding", vary),
+            None => "accept-encoding".to_string(),
+        };
+        response.headers.insert("vary".to_string(), vary);
+
+        let Some(codec) = request
+            .get_header("accept-encoding")
+            .and_then(|accept| self.negotiate(accept))
+        else {
+            return;
+        };
+        if response.file.is_some() {
+            return;
+        }
+        let (body, level) = (response.body.clone(), self.level);
+        let encoded = tokio::task::spawn_blocking(move || codec.encode(&body, level))
+            .await
+            .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+        match encoded {
+            // Not worth it if nothing was saved
+            Ok(body) if body.len() < response.body.len() => {
+                response
+                    .headers
+                    .insert("content-length".to_string(), body.len().to_string());
+                response
+                    .headers
+                    .insert("content-encoding".to_string(), codec.name().to_string());
+                response.body = body.into();
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!(error = %e, "Compression failed, sending as is"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_negotiate_and_allow() {
+        let config = CompressionConfig::default();
+        assert_eq!(config.negotiate("gzip, deflate, br"), Some(Codec::Gzip));
+        assert_eq!(
+            config.negotiate("deflate;q=0.5, gzip;q=0"),
+            Some(Codec::Deflate)
+        );
+        assert_eq!(config.negotiate("*"), Some(Codec::Gzip));
+        assert_eq!(config.negotiate("identity"), None);
+        assert_eq!(
+            config.with_codecs(&[Codec::Deflate]).negotiate("gzip"),
+            None
+        );
+
+        let config = CompressionConfig::default().with_deny(&["text/event-stream"]);
+        assert!(config.allows("text/html; charset=utf-8"));
+        assert!(config.allows("Application/JSON"));
+        assert!(!config.allows("text/event-stream"));
+        assert!(!config.allows("image/png"));
+        assert!(!config.allows("textual/plain"));
+    }
+
+    #[tokio::test]
+    async fn test_apply_compresses_large_allowed_bodies() {
+        let config = CompressionConfig::default();
+        let request =
+            HttpRequest::from_buffer_sync(b"GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n")
+                .unwrap();
+
+        let text = "hello ".repeat(1000);
+        let mut response = HttpResponse::ok().with_text(&text);
+        config.apply(&request, &mut response).await;
+        assert_eq!(response.headers["content-encoding"], "gzip");
+        assert_eq!(response.headers["vary"], "accept-encoding");
+        assert_eq!(
+            response.headers["content-length"],
+            response.body.len().to_string()
+        );
+        let mut decoded = String::new();
+        GzDecoder::new(&response.body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+
+        let mut small = HttpResponse::ok().with_text("hello");
+        config.apply(&request, &mut small).await;
+        assert!(!small.headers.contains_key("content-encoding"));
+
+        let mut large = HttpResponse::ok().with_text(&text);
+        config
+            .clone()
+            .with_max_size(1024)
+            .apply(&request, &mut large)
+            .await;
+        assert!(!large.headers.contains_key("content-encoding"));
+
+        let mut image = HttpResponse::ok()
+            .with_header("content-type", "image/png")
+            .with_body(vec![0; 4096]);
+        config.apply(&request, &mut image).await;
+        assert!(!image.headers.contains_key("content-encoding"));
+    }
+}
@@ -0,0 +1,297 @@
+//! Building a [`Config`] in code, for applications that embed the server.
+//!
+//! ```no_run
+//! # use http::config::Config;
+//! # use std::time::Duration;
+//! let config = Config::builder()
+//!     .address("0.0.0.0:8080")
+//!     .static_dir("/srv/www")
+//!     .header_read_timeout(Duration::from_secs(5))
+//!     .build()?;
+//! # Ok::<(), http::error::ServerError>(())
+//! ```
+
+use super::{BodyLimit, CachePolicy, Config, Limits, Route, Site, TcpKeepalive};
+use crate::{
+    access_log::AccessLog,
+    compression::CompressionConfig,
+    error::ServerError,
+    file_cache::FileCache,
+    gateway::{cgi::CgiRoute, fastcgi::FastCgiRoute},
+    logging::LogConfig,
+    middleware::{
+        basic_auth::BasicAuth,
+        jwt::JwtAuth,
+        rate_limit::{RateLimiter, RouteRateLimit},
+    },
+    protocol::error_handler::ErrorHandler,
+    proxy::ProxyRoute,
+    tls::TlsConfig,
+    websocket::WebSocketConfig,
+};
+use std::time::Duration;
+
+/// Starts from [`Config::default`]; list settings such as `address` or
+/// `route` add to what was set before, the others replace the default.
+#[derive(Debug, Clone)]
+pub struct ConfigBuilder {
+    config: Config,
+    addresses: Option<Vec<String>>,
+}
+
+impl ConfigBuilder {
+    pub(super) fn new() -> Self {
+        Self {
+            config: Config::default(),
+            addresses: None,
+        }
+    }
+
+    /// Listen on `address` (`host:port`). The default address is only used
+    /// if none is given.
+    pub fn address(mut self, address: &str) -> Self {
+        self.addresses
+            .get_or_insert_with(Vec::new)
+            .push(address.to_string());
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    pub fn static_dir(mut self, dir: &str) -> Self {
+        self.config.static_dir = dir.to_string();
+        self
+    }
+
+    pub fn site(mut self, site: Site) -> Self {
+        self.config.sites.push(site);
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = max;
+        self
+    }
+
+    pub fn header_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.header_read_timeout = timeout;
+        self
+    }
+
+    /// In bytes per second; 0 disables the check.
+    pub fn min_body_rate(mut self, rate: u64) -> Self {
+        self.config.min_body_rate = rate;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    pub fn body_limit(mut self, limit: BodyLimit) -> Self {
+        self.config.body_limits.push(limit);
+        self
+    }
+
+    pub fn directory_listing(mut self, listing: bool) -> Self {
+        self.config.directory_listing = listing;
+        self
+    }
+
+    pub fn cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.config.cache_policies.push(policy);
+        self
+    }
+
+    /// `None` disables the endpoint.
+    pub fn health_check_path(mut self, path: Option<&str>) -> Self {
+        self.config.health_check_path = path.map(String::from);
+        self
+    }
+
+    pub fn metrics_path(mut self, path: Option<&str>) -> Self {
+        self.config.metrics_path = path.map(String::from);
+        self
+    }
+
+    pub fn websocket_admin_path(mut self, path: Option<&str>) -> Self {
+        self.config.websocket_admin_path = path.map(String::from);
+        self
+    }
+
+    pub fn websocket_admin_token(mut self, token: Option<&str>) -> Self {
+        self.config.websocket_admin_token = token.map(String::from);
+        self
+    }
+
+    pub fn logging(mut self, logging: LogConfig) -> Self {
+        self.config.logging = logging;
+        self
+    }
+
+    pub fn permissive_cors(mut self, permissive: bool) -> Self {
+        self.config.permissive_cors = permissive;
+        self
+    }
+
+    pub fn error_handler(mut self, handler: ErrorHandler) -> Self {
+        self.config.error_handler = Some(handler);
+        self
+    }
+
+    pub fn compression(mut self, compression: CompressionConfig) -> Self {
+        self.config.compression = Some(compression);
+        self
+    }
+
+    pub fn file_cache(mut self, cache: FileCache) -> Self {
+        self.config.file_cache = Some(cache);
+        self
+    }
+
+    pub fn file_chunk_size(mut self, bytes: usize) -> Self {
+        self.config.file_chunk_size = bytes;
+        self
+    }
+
+    /// Serve files ending in `.extension` as `content_type`, in place of
+    /// the built-in type for it, if any.
+    pub fn content_type(mut self, extension: &str, content_type: &str) -> Self {
+        self.config.content_types.insert(
+            extension.trim_start_matches('.').to_ascii_lowercase(),
+            content_type.to_string(),
+        );
+        self
+    }
+
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.config.access_log = Some(access_log);
+        self
+    }
+
+    pub fn rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.config.rate_limiter = Some(limiter);
+        self
+    }
+
+    pub fn route_rate_limit(mut self, rule: RouteRateLimit) -> Self {
+        self.config.route_rate_limits.push(rule);
+        self
+    }
+
+    pub fn basic_auth(mut self, auth: BasicAuth) -> Self {
+        self.config.basic_auth = Some(auth);
+        self
+    }
+
+    pub fn jwt_auth(mut self, auth: JwtAuth) -> Self {
+        self.config.jwt_auth = Some(auth);
+        self
+    }
+
+    pub fn route(mut self, route: Route) -> Self {
+        self.config.routes.push(route);
+        self
+    }
+
+    pub fn proxy_route(mut self, route: ProxyRoute) -> Self {
+        self.config.proxy_routes.push(route);
+        self
+    }
+
+    pub fn fastcgi_route(mut self, route: FastCgiRoute) -> Self {
+        self.config.fastcgi_routes.push(route);
+        self
+    }
+
+    pub fn cgi_route(mut self, route: CgiRoute) -> Self {
+        self.config.cgi_routes.push(route);
+        self
+    }
+
+    pub fn websocket(mut self, websocket: WebSocketConfig) -> Self {
+        self.config.websocket = websocket;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.config.worker_threads = Some(threads);
+        self
+    }
+
+    pub fn max_blocking_threads(mut self, threads: usize) -> Self {
+        self.config.max_blocking_threads = threads;
+        self
+    }
+
+    pub fn accept_backlog(mut self, backlog: u32) -> Self {
+        self.config.accept_backlog = backlog;
+        self
+    }
+
+    pub fn accept_loops(mut self, loops: usize) -> Self {
+        self.config.accept_loops = Some(loops);
+        self
+    }
+
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.config.reuse_port = reuse_port;
+        self
+    }
+
+    pub fn ipv6_only(mut self, ipv6_only: bool) -> Self {
+        self.config.ipv6_only = ipv6_only;
+        self
+    }
+
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.config.tcp_nodelay = nodelay;
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, keepalive: TcpKeepalive) -> Self {
+        self.config.tcp_keepalive = Some(keepalive);
+        self
+    }
+
+    /// The configuration, if [`Config::validate`] finds no problems with it.
+    pub fn build(self) -> Result<Config, ServerError> {
+        let mut config = self.config;
+        if let Some(addresses) = self.addresses {
+            config.addresses = addresses;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder_validates() {
+        let config = Config::builder()
+            .address("127.0.0.1:0")
+            .address("[::1]:0")
+            .body_limit(BodyLimit::new("/upload", 1024))
+            .tcp_nodelay(false)
+            .build()
+            .unwrap();
+        assert_eq!(config.addresses, ["127.0.0.1:0", "[::1]:0"]);
+        assert_eq!(config.max_body_size_for("/upload/a"), 1024);
+        assert!(!config.tcp_nodelay);
+
+        assert!(Config::builder().address("localhost").build().is_err());
+        assert!(Config::builder().max_connections(0).build().is_err());
+    }
+}
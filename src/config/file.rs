@@ -0,0 +1,773 @@
+//! The TOML configuration file read by the `http` binary with `--config`,
+//! and again on SIGHUP.
+//!
+//! ```toml
+//! addresses = ["[::]:8080"]  # IPv4 and IPv6 clients alike
+//! ipv6_only = false
+//! static_dir = "/srv/www"
+//! header_read_timeout = 10  # seconds
+//! file_chunk_size = 262144   # bytes read at a time for file bodies
+//! worker_threads = 4
+//! accept_loops = 4    # per listener; one per worker thread by default
+//! reuse_port = true   # a SO_REUSEPORT socket for each accept loop
+//!
+//! [limits]
+//! max_body_size = 1048576
+//! max_uri_length = 4096
+//!
+//! [tcp_keepalive]
+//! time = 60  # seconds idle before the first probe
+//!
+//! [rate_limit]  # per client IP, on all paths
+//! rate = 20      # requests per second
+//! burst = 40
+//!
+//! [[rate_limit.routes]]  # per client IP, on top of the above
+//! path = "/login"
+//! rate = 0.2
+//! burst = 5
+//! status = 503   # 429 by default
+//!
+//! [compression]
+//! codecs = ["gzip", "deflate"]  # most preferred first
+//! level = 6                     # 0 to 9
+//! min_size = 1024               # bytes
+//! max_size = 8388608            # larger bodies are sent as they are
+//! allow = ["text/*", "application/json"]
+//! deny = ["text/event-stream"]
+//!
+//! [file_cache]  # small static files kept in memory
+//! capacity = 67108864    # bytes cached at most
+//! max_file_size = 65536  # larger files are not cached
+//!
+//! [content_types]  # by file extension, ahead of the built-in types
+//! md = "text/markdown; charset=utf-8"
+//! wasm = "application/wasm"
+//!
+//! [logging]
+//! level = "http=info"
+//! format = "json"      # or "text"
+//! output = "file"      # "stdout" (the default), "stderr" or "file"
+//! directory = "/var/log/http"
+//! prefix = "http.log"
+//! rotation = "daily"   # "hourly" or "never"
+//! modules = { "http::websocket" = "debug" }
+//!
+//! [[body_limits]]
+//! prefix = "/upload"
+//! max_size = 104857600
+//!
+//! [[cache_policies]]
+//! pattern = "/immutable/*"
+//! cache_control = "public, max-age=31536000, immutable"
+//!
+//! [[cache_policies]]
+//! pattern = "*.css"
+//! cache_control = "public, max-age=3600"
+//! expires = 3600  # seconds from now
+//!
+//! [[sites]]
+//! hostnames = ["example.com", "*.example.com"]
+//! root = "/srv/example"
+//! error_pages = { 404 = "/srv/example/404.html" }
+//!
+//! [[routes]]
+//! path = "/docs"
+//! static_dir = "/srv/docs"
+//!
+//! [[routes]]
+//! path = "/api"
+//! proxy = ["127.0.0.1:9000", "127.0.0.1:9001"]
+//! balance = "least-connections"  # or "round-robin"
+//! max_response_size = 67108864  # bytes, the default
+//!
+//! [[routes]]
+//! path = "/blog"
+//! redirect = "https://blog.example.com"
+//! status = 301  # 308 by default
+//!
+//! [[routes]]
+//! path = "/status"
+//! builtin = "health"  # or "metrics", "echo"
+//!
+//! [tls]
+//! addresses = ["0.0.0.0:8443"]
+//! certificate = "/etc/http/cert.pem"
+//! private_key = "/etc/http/key.pem"
+//! ```
+
+use crate::{
+    compression::{Codec, CompressionConfig},
+    config::{BodyLimit, Builtin, CachePolicy, Config, Route, RouteTarget, Site},
+    error::{Result, ServerError},
+    file_cache::FileCache,
+    logging::{LogConfig, LogFormat, LogOutput, LogRotation},
+    middleware::rate_limit::{RateLimiter, RouteRateLimit},
+    protocol::response::HttpStatusCode,
+    proxy::{Balance, UpstreamPool},
+    tls::{TlsConfig, TlsVersion},
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Settings read from a configuration file. Whatever is left out keeps the
+/// value it already has.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub addresses: Option<Vec<String>>,
+    pub static_dir: Option<String>,
+    pub max_connections: Option<usize>,
+    /// In seconds.
+    pub header_read_timeout: Option<u64>,
+    pub min_body_rate: Option<u64>,
+    pub health_check_path: Option<String>,
+    pub metrics_path: Option<String>,
+    pub worker_threads: Option<usize>,
+    pub max_blocking_threads: Option<usize>,
+    pub accept_backlog: Option<u32>,
+    pub accept_loops: Option<usize>,
+    pub reuse_port: Option<bool>,
+    pub ipv6_only: Option<bool>,
+    pub directory_listing: Option<bool>,
+    pub file_chunk_size: Option<usize>,
+    pub permissive_cors: Option<bool>,
+    pub tcp_nodelay: Option<bool>,
+    pub tcp_keepalive: Option<TcpKeepaliveSection>,
+    pub limits: Option<LimitsSection>,
+    pub logging: Option<LoggingSection>,
+    pub compression: Option<CompressionSection>,
+    pub rate_limit: Option<RateLimitSection>,
+    pub file_cache: Option<FileCacheSection>,
+    /// Content types by file extension, ahead of the built-in ones.
+    #[serde(default)]
+    pub content_types: BTreeMap<String, String>,
+    #[serde(default)]
+    pub body_limits: Vec<BodyLimitSection>,
+    #[serde(default)]
+    pub cache_policies: Vec<CachePolicySection>,
+    #[serde(default)]
+    pub sites: Vec<SiteSection>,
+    #[serde(default)]
+    pub routes: Vec<RouteSection>,
+    pub tls: Option<TlsSection>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BodyLimitSection {
+    pub prefix: String,
+    pub max_size: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CachePolicySection {
+    pub pattern: String,
+    pub cache_control: Option<String>,
+    /// In seconds.
+    pub expires: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsSection {
+    pub max_header_size: Option<usize>,
+    pub max_header_count: Option<usize>,
+    pub max_body_size: Option<usize>,
+    pub max_uri_length: Option<usize>,
+    pub max_chunk_count: Option<usize>,
+}
+
+/// Rate limits per client IP: for all paths if `rate` is set, and for
+/// each of `routes` on its own.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitSection {
+    /// Requests per second.
+    pub rate: Option<f64>,
+    /// Requests allowed at once, 1 by default.
+    pub burst: Option<u32>,
+    /// Rejection status, 429 by default.
+    pub status: Option<u16>,
+    #[serde(default)]
+    pub routes: Vec<RouteRateLimitSection>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteRateLimitSection {
+    pub path: String,
+    pub rate: f64,
+    pub burst: Option<u32>,
+    pub status: Option<u16>,
+}
+
+/// Turns compression on; unset fields keep the defaults of
+/// [`CompressionConfig`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CompressionSection {
+    /// `gzip` and/or `deflate`, most preferred first.
+    pub codecs: Option<Vec<String>>,
+    pub level: Option<u32>,
+    pub min_size: Option<usize>,
+    pub max_size: Option<u64>,
+    /// Content types such as `text/*` or `application/json`.
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+}
+
+/// Turns the file cache on.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileCacheSection {
+    /// Bytes cached at most, over all files.
+    pub capacity: u64,
+    pub max_file_size: Option<u64>,
+}
+
+/// Keepalive probing; times in seconds, unset ones keep their defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TcpKeepaliveSection {
+    pub time: Option<u64>,
+    pub interval: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingSection {
+    /// Filter directives such as `info` or `http=debug`.
+    pub level: Option<String>,
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+    pub format: Option<LogFormatName>,
+    pub output: Option<LogOutputName>,
+    /// Log file directory, for `output = "file"`.
+    pub directory: Option<PathBuf>,
+    pub prefix: Option<String>,
+    pub rotation: Option<LogRotationName>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormatName {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutputName {
+    Stdout,
+    Stderr,
+    File,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotationName {
+    Never,
+    Hourly,
+    Daily,
+}
+
+/// A route under `path`, with exactly one of `static_dir`, `redirect`,
+/// `proxy` or `builtin` saying where requests go.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteSection {
+    pub path: String,
+    pub static_dir: Option<String>,
+    pub redirect: Option<String>,
+    /// Redirect status, 308 by default.
+    pub status: Option<u16>,
+    /// Upstream `host:port` addresses.
+    pub proxy: Option<Vec<String>>,
+    pub balance: Option<BalanceName>,
+    /// Largest upstream response body, in bytes.
+    pub max_response_size: Option<usize>,
+    /// `health`, `metrics` or `echo`.
+    pub builtin: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BalanceName {
+    RoundRobin,
+    LeastConnections,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiteSection {
+    pub hostnames: Vec<String>,
+    pub root: String,
+    pub index_files: Option<Vec<String>>,
+    #[serde(default)]
+    pub error_pages: HashMap<u16, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    pub addresses: Vec<String>,
+    /// PEM certificate chain, read again on every reload.
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    /// `"1.2"` or `"1.3"`.
+    pub min_version: Option<String>,
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+}
+
+impl ConfigFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            ServerError::InvalidConfig(format!("Cannot read {}: {}", path.display(), e))
+        })?;
+        Self::parse(&text)
+            .map_err(|e| ServerError::InvalidConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse(text: &str) -> std::result::Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Apply the settings to `config`, loading any certificates.
+    pub fn apply(&self, config: &mut Config) -> Result<()> {
+        if let Some(addresses) = &self.addresses {
+            config.addresses = addresses.clone();
+        }
+        if let Some(static_dir) = &self.static_dir {
+            config.static_dir = static_dir.clone();
+        }
+        if let Some(max_connections) = self.max_connections {
+            config.max_connections = max_connections;
+        }
+        if let Some(seconds) = self.header_read_timeout {
+            config.header_read_timeout = Duration::from_secs(seconds);
+        }
+        if let Some(min_body_rate) = self.min_body_rate {
+            config.min_body_rate = min_body_rate;
+        }
+        if let Some(section) = &self.limits {
+            let limits = &mut config.limits;
+            for (value, limit) in [
+                (section.max_header_size, &mut limits.max_header_size),
+                (section.max_header_count, &mut limits.max_header_count),
+                (section.max_body_size, &mut limits.max_body_size),
+                (section.max_uri_length, &mut limits.max_uri_length),
+                (section.max_chunk_count, &mut limits.max_chunk_count),
+            ] {
+                if let Some(value) = value {
+                    *limit = value;
+                }
+            }
+        }
+        if let Some(path) = &self.health_check_path {
+            config.health_check_path = Some(path.clone());
+        }
+        if let Some(path) = &self.metrics_path {
+            config.metrics_path = Some(path.clone());
+        }
+        if let Some(worker_threads) = self.worker_threads {
+            config.worker_threads = Some(worker_threads);
+        }
+        if let Some(max_blocking_threads) = self.max_blocking_threads {
+            config.max_blocking_threads = max_blocking_threads;
+        }
+        if let Some(accept_backlog) = self.accept_backlog {
+            config.accept_backlog = accept_backlog;
+        }
+        if let Some(accept_loops) = self.accept_loops {
+            config.accept_loops = Some(accept_loops);
+        }
+        if let Some(reuse_port) = self.reuse_port {
+            config.reuse_port = reuse_port;
+        }
+        if let Some(listing) = self.directory_listing {
+            config.directory_listing = listing;
+        }
+        if let Some(bytes) = self.file_chunk_size {
+            config.file_chunk_size = bytes;
+        }
+        if let Some(permissive) = self.permissive_cors {
+            config.permissive_cors = permissive;
+        }
+        if let Some(ipv6_only) = self.ipv6_only {
+            config.ipv6_only = ipv6_only;
+        }
+        if let Some(tcp_nodelay) = self.tcp_nodelay {
+            config.tcp_nodelay = tcp_nodelay;
+        }
+        if let Some(section) = &self.tcp_keepalive {
+            let mut keepalive = config.tcp_keepalive.unwrap_or_default();
+            if let Some(seconds) = section.time {
+                keepalive.time = Duration::from_secs(seconds);
+            }
+            if let Some(seconds) = section.interval {
+                keepalive.interval = Duration::from_secs(seconds);
+            }
+            if let Some(retries) = section.retries {
+                keepalive.retries = retries;
+            }
+            config.tcp_keepalive = Some(keepalive);
+        }
+
+        if let Some(section) = &self.logging {
+            section.apply(&mut config.logging)?;
+        }
+        if let Some(section) = &self.compression {
+            config.compression = Some(section.compression()?);
+        }
+        if let Some(section) = &self.file_cache {
+            let mut cache = FileCache::new(section.capacity);
+            if let Some(bytes) = section.max_file_size {
+                cache = cache.with_max_file_size(bytes);
+            }
+            config.file_cache = Some(cache);
+        }
+        if let Some(section) = &self.rate_limit {
+            if let Some(rate) = section.rate {
+                config.rate_limiter = Some(rate_limiter(rate, section.burst, section.status)?);
+            }
+            for route in &section.routes {
+                let limiter = rate_limiter(route.rate, route.burst, route.status)?;
+                config
+                    .route_rate_limits
+                    .push(RouteRateLimit::new(&route.path, limiter));
+            }
+        }
+
+        config
+            .content_types
+            .extend(self.content_types.iter().map(|(extension, content_type)| {
+                (
+                    extension.trim_start_matches('.').to_ascii_lowercase(),
+                    content_type.clone(),
+                )
+            }));
+        config.body_limits.extend(
+            self.body_limits
+                .iter()
+                .map(|limit| BodyLimit::new(&limit.prefix, limit.max_size)),
+        );
+        config
+            .cache_policies
+            .extend(self.cache_policies.iter().map(|section| CachePolicy {
+                pattern: section.pattern.clone(),
+                cache_control: section.cache_control.clone(),
+                expires: section.expires.map(Duration::from_secs),
+            }));
+        config.sites.extend(self.sites.iter().map(|section| {
+            let hostnames: Vec<&str> = section.hostnames.iter().map(String::as_str).collect();
+            let mut site = Site::new(&hostnames, &section.root);
+            if let Some(index_files) = &section.index_files {
+                site.index_files = index_files.clone();
+            }
+            site.error_pages = section.error_pages.clone();
+            site
+        }));
+
+        for section in &self.routes {
+            config.routes.push(section.route()?);
+        }
+
+        if let Some(section) = &self.tls {
+            let addresses: Vec<&str> = section.addresses.iter().map(String::as_str).collect();
+            let mut tls = TlsConfig::new(&addresses).with_certificate(
+                &[],
+                &section.certificate,
+                &section.private_key,
+            )?;
+            tls.min_version = match section.min_version.as_deref() {
+                None | Some("1.2") => TlsVersion::Tls12,
+                Some("1.3") => TlsVersion::Tls13,
+                Some(other) => {
+                    return Err(ServerError::InvalidConfig(format!(
+                        "Unknown TLS version {:?}, expected \"1.2\" or \"1.3\"",
+                        other
+                    )));
+                }
+            };
+            tls.cipher_suites = section.cipher_suites.clone();
+            config.tls = Some(tls);
+        }
+        Ok(())
+    }
+}
+
+impl RouteSection {
+    fn route(&self) -> Result<Route> {
+        let invalid = |problem: &str| {
+            ServerError::InvalidConfig(format!("Route {:?}: {}", self.path, problem))
+        };
+        let target = match (&self.static_dir, &self.redirect, &self.proxy, &self.builtin) {
+            (Some(root), None, None, None) => RouteTarget::Static(root.clone()),
+            (None, Some(to), None, None) => {
+                let status = HttpStatusCode::from_code(self.status.unwrap_or(308))
+                    .ok_or_else(|| invalid("unknown redirect status"))?;
+                RouteTarget::Redirect {
+                    to: to.clone(),
+                    status,
+                }
+            }
+            (None, None, Some(upstreams), None) => {
+                let balance = match self.balance {
+                    Some(BalanceName::LeastConnections) => Balance::LeastConnections,
+                    Some(BalanceName::RoundRobin) | None => Balance::RoundRobin,
+                };
+                let mut pool = UpstreamPool::new(upstreams.clone(), balance);
+                if let Some(limit) = self.max_response_size {
+                    pool = pool.with_max_response_size(limit);
+                }
+                RouteTarget::Proxy(pool)
+            }
+            (None, None, None, Some(name)) => RouteTarget::Builtin(
+                Builtin::from_name(name).ok_or_else(|| invalid("unknown builtin handler"))?,
+            ),
+            _ => {
+                return Err(invalid(
+                    "needs exactly one of static_dir, redirect, proxy or builtin",
+                ));
+            }
+        };
+        Ok(Route::new(&self.path, target))
+    }
+}
+
+fn rate_limiter(rate: f64, burst: Option<u32>, status: Option<u16>) -> Result<RateLimiter> {
+    let code = status.unwrap_or(429);
+    let status = HttpStatusCode::from_code(code)
+        .ok_or_else(|| ServerError::InvalidConfig(format!("Unknown rate limit status {}", code)))?;
+    Ok(RateLimiter::new(rate, burst.unwrap_or(1)).with_status(status))
+}
+
+impl CompressionSection {
+    fn compression(&self) -> Result<CompressionConfig> {
+        let mut compression = CompressionConfig::default();
+        if let Some(names) = &self.codecs {
+            compression.codecs = names
+                .iter()
+                .map(|name| {
+                    Codec::from_name(name).ok_or_else(|| {
+                        ServerError::InvalidConfig(format!(
+                            "Unknown compression codec {:?}, expected \"gzip\" or \"deflate\"",
+                            name
+                        ))
+                    })
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(level) = self.level {
+            compression.level = level;
+        }
+        if let Some(min_size) = self.min_size {
+            compression.min_size = min_size;
+        }
+        if let Some(max_size) = self.max_size {
+            compression.max_size = max_size;
+        }
+        if let Some(allow) = &self.allow {
+            compression.allow = allow.clone();
+        }
+        if let Some(deny) = &self.deny {
+            compression.deny = deny.clone();
+        }
+        Ok(compression)
+    }
+}
+
+impl LoggingSection {
+    fn apply(&self, logging: &mut LogConfig) -> Result<()> {
+        if let Some(level) = &self.level {
+            logging.level = level.clone();
+        }
+        logging.modules.extend(self.modules.clone());
+        if let Some(format) = self.format {
+            logging.format = match format {
+                LogFormatName::Text => LogFormat::Text,
+                LogFormatName::Json => LogFormat::Json,
+            };
+        }
+        match self.output {
+            Some(LogOutputName::Stdout) => logging.output = LogOutput::Stdout,
+            Some(LogOutputName::Stderr) => logging.output = LogOutput::Stderr,
+            Some(LogOutputName::File) => {
+                let directory = self.directory.clone().ok_or_else(|| {
+                    ServerError::InvalidConfig(
+                        "logging.directory is required with output = \"file\"".to_string(),
+                    )
+                })?;
+                logging.output = LogOutput::File {
+                    directory,
+                    prefix: self
+                        .prefix
+                        .clone()
+                        .unwrap_or_else(|| "http.log".to_string()),
+                    rotation: match self.rotation {
+                        Some(LogRotationName::Never) => LogRotation::Never,
+                        Some(LogRotationName::Hourly) => LogRotation::Hourly,
+                        Some(LogRotationName::Daily) | None => LogRotation::Daily,
+                    },
+                };
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_applies_to_config() {
+        let file = ConfigFile::parse(
+            r#"
+            addresses = ["0.0.0.0:8080"]
+            header_read_timeout = 3
+            tcp_nodelay = false
+            file_chunk_size = 262144
+
+            [limits]
+            max_uri_length = 100
+
+            [tcp_keepalive]
+            interval = 5
+
+            [compression]
+            codecs = ["deflate"]
+            max_size = 65536
+            deny = ["text/csv"]
+
+            [logging]
+            format = "json"
+            output = "file"
+            directory = "/var/log/http"
+            modules = { "http::proxy" = "trace" }
+
+            [content_types]
+            MD = "text/markdown"
+            [rate_limit]
+            rate = 10
+
+            [file_cache]
+            capacity = 1048576
+
+            [[rate_limit.routes]]
+            path = "/login"
+            rate = 0.5
+            status = 503
+
+            [[cache_policies]]
+            pattern = "*.css"
+            expires = 60
+
+            [[sites]]
+            hostnames = ["example.com"]
+            root = "/srv/example"
+            error_pages = { 404 = "/srv/example/404.html" }
+            "#,
+        )
+        .unwrap();
+
+        let mut config = Config::default();
+        file.apply(&mut config).unwrap();
+        assert_eq!(config.addresses, ["0.0.0.0:8080"]);
+        assert_eq!(config.header_read_timeout, Duration::from_secs(3));
+        assert!(!config.tcp_nodelay);
+        assert_eq!(config.file_chunk_size, 262144);
+        assert_eq!(config.limits.max_uri_length, 100);
+        assert_eq!(config.limits.max_header_count, 100);
+        let keepalive = config.tcp_keepalive.unwrap();
+        assert_eq!(keepalive.interval, Duration::from_secs(5));
+        assert_eq!(keepalive.time, Duration::from_secs(60));
+        assert_eq!(config.static_dir, "./static");
+        assert_eq!(config.rate_limiter.as_ref().unwrap().rate(), 10.0);
+        let rule = config.route_rate_limit_for("/login/").unwrap();
+        assert_eq!(rule.limiter.status(), HttpStatusCode::ServiceUnavailable);
+        assert!(config.route_rate_limit_for("/logout").is_none());
+        let policy = config.cache_policy_for("/css/site.css?v=3").unwrap();
+        assert_eq!(policy.expires, Some(Duration::from_secs(60)));
+        assert!(config.cache_policy_for("/site.js").is_none());
+        assert_eq!(config.sites[0].index_files, ["index.html"]);
+        assert_eq!(config.sites[0].error_pages[&404], "/srv/example/404.html");
+        let file_cache = config.file_cache.unwrap();
+        assert_eq!(file_cache.capacity(), 1048576);
+        assert_eq!(file_cache.max_file_size(), 65536);
+        let compression = config.compression.unwrap();
+        assert_eq!(compression.codecs, [Codec::Deflate]);
+        assert_eq!(compression.min_size, 1024);
+        assert_eq!(compression.max_size, 65536);
+        assert!(!compression.allows("text/csv"));
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(config.logging.modules["http::proxy"], "trace");
+        assert_eq!(config.content_types["md"], "text/markdown");
+        assert_eq!(
+            config.logging.output,
+            LogOutput::File {
+                directory: "/var/log/http".into(),
+                prefix: "http.log".to_string(),
+                rotation: LogRotation::Daily,
+            }
+        );
+
+        assert!(ConfigFile::parse("max_conections = 10").is_err());
+        let file = ConfigFile::parse(
+            r#"
+            [[routes]]
+            path = "/old"
+            redirect = "/new"
+
+            [[routes]]
+            path = "/both"
+            static_dir = "/srv"
+            builtin = "echo"
+            "#,
+        )
+        .unwrap();
+        let mut config = Config::default();
+        assert!(file.apply(&mut config).is_err());
+        assert!(matches!(
+            &config.routes[0].target,
+            RouteTarget::Redirect { to, status: HttpStatusCode::PermanentRedirect } if to == "/new"
+        ));
+
+        let file = ConfigFile::parse(
+            r#"
+            [[routes]]
+            path = "/api"
+            proxy = ["127.0.0.1:9000"]
+            max_response_size = 1024
+            "#,
+        )
+        .unwrap();
+        let mut config = Config::default();
+        file.apply(&mut config).unwrap();
+        assert!(matches!(
+            &config.routes[0].target,
+            RouteTarget::Proxy(pool) if pool.max_response_size() == 1024
+        ));
+
+        let file = ConfigFile::parse(
+            r#"
+            [tls]
+            addresses = ["0.0.0.0:8443"]
+            certificate = "missing.pem"
+            private_key = "missing.key"
+            "#,
+        )
+        .unwrap();
+        assert!(file.apply(&mut Config::default()).is_err());
+    }
+}
@@ -0,0 +1,697 @@
+pub mod builder;
+pub mod file;
+pub mod route;
+
+pub use crate::protocol::codec::Limits;
+pub use builder::ConfigBuilder;
+pub use route::{Builtin, Route, RouteTarget};
+
+use crate::{
+    access_log::AccessLog,
+    compression::CompressionConfig,
+    error::ServerError,
+    file_cache::FileCache,
+    gateway::{self, cgi::CgiRoute, fastcgi::FastCgiRoute},
+    logging::LogConfig,
+    middleware::{
+        self,
+        basic_auth::BasicAuth,
+        jwt::JwtAuth,
+        rate_limit::{RateLimiter, RouteRateLimit},
+    },
+    protocol::error_handler::ErrorHandler,
+    proxy::ProxyRoute,
+    tls::TlsConfig,
+    websocket::WebSocketConfig,
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Overrides the request body limit for paths under `prefix`.
+#[derive(Debug, Clone)]
+pub struct BodyLimit {
+    pub prefix: String,
+    pub max_size: usize,
+}
+
+impl BodyLimit {
+    pub fn new(prefix: &str, max_size: usize) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            max_size,
+        }
+    }
+}
+
+/// Caching headers for static files whose request path matches `pattern`,
+/// where `*` stands for any run of characters (`*.css`, `/immutable/*`).
+#[derive(Debug, Clone)]
+pub struct CachePolicy {
+    pub pattern: String,
+    /// `Cache-Control` value, e.g. `public, max-age=31536000, immutable`.
+    pub cache_control: Option<String>,
+    /// Sets `Expires` to this long after the response is sent.
+    pub expires: Option<Duration>,
+}
+
+impl CachePolicy {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            cache_control: None,
+            expires: None,
+        }
+    }
+
+    pub fn with_cache_control(mut self, value: &str) -> Self {
+        self.cache_control = Some(value.to_string());
+        self
+    }
+
+    pub fn with_expires(mut self, after: Duration) -> Self {
+        self.expires = Some(after);
+        self
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        gateway::matches_pattern(&self.pattern, path)
+    }
+}
+
+/// TCP keepalive probing of idle connections, so peers that vanished
+/// without closing are noticed and their connections freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// Idle time before the first probe.
+    pub time: Duration,
+    /// Time between unanswered probes.
+    pub interval: Duration,
+    /// Unanswered probes before the connection is dropped.
+    pub retries: u32,
+}
+
+impl Default for TcpKeepalive {
+    fn default() -> Self {
+        Self {
+            time: Duration::from_secs(60),
+            interval: Duration::from_secs(10),
+            retries: 6,
+        }
+    }
+}
+
+/// A virtual host: static files from its own root for requests whose
+/// `Host` header names it.
+#[derive(Debug, Clone)]
+pub struct Site {
+    /// Hostnames served, case-insensitively; `*.example.com` matches one
+    /// extra label.
+    pub hostnames: Vec<String>,
+    pub root: String,
+    /// Files tried in order when a directory is requested.
+    pub index_files: Vec<String>,
+    /// Pages under `root` served with error statuses, by status code.
+    pub error_pages: HashMap<u16, String>,
+}
+
+impl Site {
+    pub fn new(hostnames: &[&str], root: &str) -> Self {
+        Self {
+            hostnames: hostnames.iter().map(|name| name.to_lowercase()).collect(),
+            root: root.to_string(),
+            index_files: vec!["index.html".to_string()],
+            error_pages: HashMap::new(),
+        }
+    }
+
+    pub fn with_index_files(mut self, files: &[&str]) -> Self {
+        self.index_files = files.iter().map(|file| file.to_string()).collect();
+        self
+    }
+
+    /// Serve `path` (e.g. `/404.html`) as the body of `status` responses.
+    pub fn with_error_page(mut self, status: u16, path: &str) -> Self {
+        self.error_pages.insert(status, path.to_string());
+        self
+    }
+
+    /// Whether the site serves `host`, a `Host` header value.
+    pub fn matches(&self, host: &str) -> bool {
+        // Drop the port; keep IPv6 literals intact
+        let name = match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => name,
+            _ => host,
+        };
+        let name = name.to_lowercase();
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        self.hostnames
+            .iter()
+            .any(|hostname| *hostname == name || Some(hostname) == wildcard.as_ref())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Addresses to listen on; every listener serves the same routes.
+    pub addresses: Vec<String>,
+    /// HTTPS listeners and their certificates, if any.
+    pub tls: Option<TlsConfig>,
+    /// Static files for requests that match none of `sites`.
+    pub static_dir: String,
+    /// Virtual hosts, picked by the `Host` header; the first match wins.
+    pub sites: Vec<Site>,
+    /// Connections served concurrently; further ones are answered with 503.
+    pub max_connections: usize,
+    /// Time allowed to receive a complete request head, counted from the
+    /// start of the request (or the end of the previous one on keep-alive).
+    pub header_read_timeout: Duration,
+    /// Slowest request body upload tolerated, in bytes per second, once a
+    /// grace period of `header_read_timeout` has passed. 0 disables the check.
+    pub min_body_rate: u64,
+    /// Request size limits: head size, header count, body size, target
+    /// length and chunk count.
+    pub limits: Limits,
+    /// Per-path overrides of `limits.max_body_size`; the first match wins.
+    pub body_limits: Vec<BodyLimit>,
+    /// List the entries of directories that have no index file instead of
+    /// answering 404.
+    pub directory_listing: bool,
+    /// Small static files kept in memory, or `None` to read them on every
+    /// request.
+    pub file_cache: Option<FileCache>,
+    /// Bytes read from disk at a time for file bodies that are not sent
+    /// with `sendfile`; larger chunks suit network filesystems.
+    pub file_chunk_size: usize,
+    /// Caching headers added to static file responses; the first policy
+    /// matching the request path wins.
+    pub cache_policies: Vec<CachePolicy>,
+    /// Content types of static files by lowercase extension (without the
+    /// dot), consulted before the built-in table.
+    pub content_types: HashMap<String, String>,
+    /// Canonical paths of the static roots by root, resolved once by
+    /// [`canonicalize_roots`](Self::canonicalize_roots); other roots are
+    /// resolved per request.
+    pub canonical_roots: HashMap<String, PathBuf>,
+    /// Path answering liveness/readiness probes, or `None` to disable.
+    pub health_check_path: Option<String>,
+    /// Path serving WebSocket metrics in the Prometheus text format, or
+    /// `None` to disable.
+    pub metrics_path: Option<String>,
+    /// Path of the WebSocket connection admin API, or `None` to disable.
+    /// Without `websocket_admin_token` every listen address must be
+    /// loopback.
+    pub websocket_admin_path: Option<String>,
+    /// Bearer token required by the admin API, if set.
+    pub websocket_admin_token: Option<String>,
+    /// Tracing output of the `http` binary.
+    pub logging: LogConfig,
+    /// Allow cross-origin requests from any origin to every path.
+    pub permissive_cors: bool,
+    /// Produces the responses to failed requests in place of the built-in
+    /// plain-text ones, if set.
+    pub error_handler: Option<ErrorHandler>,
+    /// Compression of response bodies, or `None` to send them as they are.
+    pub compression: Option<CompressionConfig>,
+    /// Where completed requests are logged, if anywhere.
+    pub access_log: Option<AccessLog>,
+    /// Per-client-IP request rate limit for all paths, if any.
+    pub rate_limiter: Option<RateLimiter>,
+    /// Per-client-IP limits for path prefixes, checked after
+    /// `rate_limiter`; the first match wins.
+    pub route_rate_limits: Vec<RouteRateLimit>,
+    /// Basic authentication for protected path prefixes, if any.
+    pub basic_auth: Option<BasicAuth>,
+    /// Bearer token authentication for protected path prefixes, if any.
+    pub jwt_auth: Option<JwtAuth>,
+    /// Routes declared in configuration, checked before the proxy and
+    /// gateway routes; the first match wins.
+    pub routes: Vec<Route>,
+    pub proxy_routes: Vec<ProxyRoute>,
+    pub fastcgi_routes: Vec<FastCgiRoute>,
+    pub cgi_routes: Vec<CgiRoute>,
+    pub websocket: WebSocketConfig,
+    /// How long a graceful shutdown waits for open WebSocket connections to
+    /// finish their closing handshakes.
+    pub shutdown_timeout: Duration,
+    /// Runtime worker threads for the `http` binary, or `None` for one per
+    /// CPU core.
+    pub worker_threads: Option<usize>,
+    /// Most threads the runtime starts for blocking work such as file reads.
+    pub max_blocking_threads: usize,
+    /// Connections the kernel queues per listener before they are accepted.
+    pub accept_backlog: u32,
+    /// Accept loops per listener, or `None` for one per worker thread.
+    pub accept_loops: Option<usize>,
+    /// Give each accept loop its own socket bound with `SO_REUSEPORT`, so
+    /// the kernel spreads connections across them (Unix only); otherwise
+    /// the loops share the listener.
+    pub reuse_port: bool,
+    /// Whether IPv6 listeners such as `[::]:8000` refuse IPv4 clients;
+    /// by default they serve both.
+    pub ipv6_only: bool,
+    /// Send small writes right away instead of coalescing them (disables
+    /// Nagle's algorithm) on accepted connections.
+    pub tcp_nodelay: bool,
+    /// Keepalive probing of accepted connections, or `None` for the
+    /// system default (usually off).
+    pub tcp_keepalive: Option<TcpKeepalive>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let port = match find_available_port(8000) {
+            Ok(port) => port,
+            Err(err) => {
+                tracing::warn!(%err, fallback_port = 8000, "Unable to reserve a port, falling back to default");
+                8000
+            }
+        };
+
+        Self {
+            addresses: vec![format!("127.0.0.1:{}", port)],
+            tls: None,
+            static_dir: "./static".to_string(),
+            sites: Vec::new(),
+            max_connections: 1024,
+            header_read_timeout: Duration::from_secs(10),
+            min_body_rate: 512,
+            limits: Limits::default(),
+            body_limits: Vec::new(),
+            directory_listing: false,
+            file_cache: None,
+            file_chunk_size: 64 * 1024,
+            cache_policies: Vec::new(),
+            content_types: HashMap::new(),
+            canonical_roots: HashMap::new(),
+            health_check_path: Some("/healthz".to_string()),
+            metrics_path: None,
+            websocket_admin_path: None,
+            websocket_admin_token: None,
+            logging: LogConfig::default(),
+            permissive_cors: false,
+            error_handler: None,
+            compression: None,
+            access_log: None,
+            rate_limiter: None,
+            route_rate_limits: Vec::new(),
+            basic_auth: None,
+            jwt_auth: None,
+            routes: Vec::new(),
+            proxy_routes: Vec::new(),
+            fastcgi_routes: Vec::new(),
+            cgi_routes: Vec::new(),
+            websocket: WebSocketConfig::default(),
+            shutdown_timeout: Duration::from_secs(10),
+            worker_threads: None,
+            max_blocking_threads: 512,
+            accept_backlog: 1024,
+            accept_loops: None,
+            reuse_port: false,
+            ipv6_only: false,
+            tcp_nodelay: true,
+            tcp_keepalive: None,
+        }
+    }
+}
+
+impl Config {
+    /// Build a configuration in code, validated when done.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    /// The site serving requests for `host`, or one serving `static_dir` if
+    /// none does.
+    pub fn site_for(&self, host: Option<&str>) -> Cow<'_, Site> {
+        host.and_then(|host| self.sites.iter().find(|site| site.matches(host)))
+            .map_or_else(
+                || Cow::Owned(Site::new(&[], &self.static_dir)),
+                Cow::Borrowed,
+            )
+    }
+
+    /// Settings for local development: directories are listed, nothing is
+    /// cached by browsers and any origin may call the server.
+    pub fn enable_dev_mode(&mut self) {
+        self.directory_listing = true;
+        self.permissive_cors = true;
+        self.cache_policies
+            .insert(0, CachePolicy::new("*").with_cache_control("no-store"));
+    }
+
+    /// The caching headers for a static file requested at `path`, if any.
+    /// The query string is ignored.
+    pub fn cache_policy_for(&self, path: &str) -> Option<&CachePolicy> {
+        self.cache_policies
+            .iter()
+            .find(|policy| policy.matches(path))
+    }
+
+    /// `static_dir`, the site roots and the static route directories, with
+    /// what they are.
+    fn static_roots(&self) -> impl Iterator<Item = (&'static str, &String)> {
+        let route_roots = self.routes.iter().filter_map(|route| match &route.target {
+            RouteTarget::Static(root) => Some(("route directory", root)),
+            _ => None,
+        });
+        std::iter::once(("static_dir", &self.static_dir))
+            .chain(self.sites.iter().map(|site| ("site root", &site.root)))
+            .chain(route_roots)
+    }
+
+    /// Resolve the static roots to canonical paths into `canonical_roots`,
+    /// which the server does when it starts and on reload. Roots that do
+    /// not exist are left out.
+    pub fn canonicalize_roots(&mut self) {
+        self.canonical_roots = self
+            .static_roots()
+            .filter_map(|(_, root)| Some((root.clone(), std::fs::canonicalize(root).ok()?)))
+            .collect();
+    }
+
+    /// Check the configuration as a whole, reporting every problem found
+    /// in one error rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), ServerError> {
+        let mut problems = Vec::new();
+
+        if self.addresses.is_empty() && self.tls.is_none() {
+            problems.push("no listen addresses configured".to_string());
+        }
+        let tls_addresses = self
+            .tls
+            .iter()
+            .flat_map(|tls| tls.addresses.iter().chain(&tls.redirect_addresses));
+        for address in self.addresses.iter().chain(tls_addresses) {
+            if !is_listen_address(address) {
+                problems.push(format!(
+                    "address {:?} is not of the form host:port",
+                    address
+                ));
+            }
+        }
+
+        for (what, root) in self.static_roots() {
+            if !Path::new(root).is_dir() {
+                problems.push(format!("{} {:?} is not a directory", what, root));
+            }
+        }
+
+        for (name, value) in [
+            ("max_connections", self.max_connections),
+            ("limits.max_header_size", self.limits.max_header_size),
+            ("limits.max_header_count", self.limits.max_header_count),
+            ("limits.max_uri_length", self.limits.max_uri_length),
+            ("limits.max_chunk_count", self.limits.max_chunk_count),
+            ("file_chunk_size", self.file_chunk_size),
+            ("websocket.max_frame_size", self.websocket.max_frame_size),
+            (
+                "websocket.write_queue_size",
+                self.websocket.write_queue_size,
+            ),
+            ("worker_threads", self.worker_threads.unwrap_or(1)),
+            ("max_blocking_threads", self.max_blocking_threads),
+            ("accept_backlog", self.accept_backlog as usize),
+            ("accept_loops", self.accept_loops.unwrap_or(1)),
+        ] {
+            if value == 0 {
+                problems.push(format!("{} must be greater than zero", name));
+            }
+        }
+        if let Some(keepalive) = self.tcp_keepalive
+            && (keepalive.time.is_zero() || keepalive.interval.is_zero())
+        {
+            problems.push("tcp_keepalive times must be greater than zero".to_string());
+        }
+        if self.header_read_timeout.is_zero() {
+            problems.push("header_read_timeout must be greater than zero".to_string());
+        }
+        if self.websocket.max_frame_size > self.websocket.max_message_size {
+            problems
+                .push("websocket.max_frame_size exceeds websocket.max_message_size".to_string());
+        }
+
+        for path in [
+            &self.health_check_path,
+            &self.metrics_path,
+            &self.websocket_admin_path,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !path.starts_with('/') {
+                problems.push(format!("endpoint path {:?} must start with '/'", path));
+            }
+        }
+        // The admin API can close anyone's connection, so it is either
+        // protected by a token or reachable from this host only
+        if self.websocket_admin_path.is_some() && self.websocket_admin_token.is_none() {
+            let tls_addresses = self
+                .tls
+                .iter()
+                .flat_map(|tls| tls.addresses.iter().chain(&tls.redirect_addresses));
+            if !self
+                .addresses
+                .iter()
+                .chain(tls_addresses)
+                .all(|address| is_loopback_address(address))
+            {
+                problems.push(
+                    "websocket_admin_path needs websocket_admin_token unless every listen address is loopback"
+                        .to_string(),
+                );
+            }
+        }
+        for route in &self.routes {
+            if !route.prefix.starts_with('/') {
+                problems.push(format!("route path {:?} must start with '/'", route.prefix));
+            }
+            if let RouteTarget::Redirect { status, .. } = &route.target
+                && !(300..400).contains(&status.code())
+            {
+                problems.push(format!(
+                    "route {:?} redirects with non-redirect status {}",
+                    route.prefix,
+                    status.code()
+                ));
+            }
+        }
+
+        let rate_limits = self
+            .rate_limiter
+            .iter()
+            .map(|limiter| ("/", limiter))
+            .chain(
+                self.route_rate_limits
+                    .iter()
+                    .map(|rule| (rule.prefix.as_str(), &rule.limiter)),
+            );
+        for (path, limiter) in rate_limits {
+            if !(limiter.rate() > 0.0 && limiter.rate().is_finite()) {
+                problems.push(format!(
+                    "rate limit for {:?} must allow a positive rate",
+                    path
+                ));
+            }
+            if limiter.status().code() < 400 {
+                problems.push(format!(
+                    "rate limit for {:?} rejects with non-error status {}",
+                    path,
+                    limiter.status().code()
+                ));
+            }
+        }
+        for policy in &self.cache_policies {
+            if policy.cache_control.is_none() && policy.expires.is_none() {
+                problems.push(format!(
+                    "cache policy {:?} sets neither cache_control nor expires",
+                    policy.pattern
+                ));
+            }
+        }
+        if let Some(compression) = &self.compression {
+            if compression.codecs.is_empty() {
+                problems.push("compression.codecs is empty".to_string());
+            }
+            if compression.level > 9 {
+                problems.push(format!(
+                    "compression.level {} is not between 0 and 9",
+                    compression.level
+                ));
+            }
+        }
+
+        if let Err(err) = self.logging.filter() {
+            problems.push(format!("logging: {}", err));
+        }
+        if let Some(tls) = &self.tls
+            && let Err(err) = tls.acceptor()
+        {
+            problems.push(format!("TLS: {}", err));
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ServerError::InvalidConfig(format!(
+            "{} problem(s):\n  - {}",
+            problems.len(),
+            problems.join("\n  - ")
+        )))
+    }
+
+    /// The rate limit of the route `path` falls under, if any.
+    pub fn route_rate_limit_for(&self, path: &str) -> Option<&RouteRateLimit> {
+        self.route_rate_limits
+            .iter()
+            .find(|rule| rule.matches(path))
+    }
+
+    /// The body size limit that applies to requests for `path`.
+    pub fn max_body_size_for(&self, path: &str) -> usize {
+        self.body_limits
+            .iter()
+            .find(|limit| middleware::matches_prefix(&limit.prefix, path))
+            .map(|limit| limit.max_size)
+            .unwrap_or(self.limits.max_body_size)
+    }
+}
+
+/// Whether `address` only accepts connections from this host.
+fn is_loopback_address(address: &str) -> bool {
+    match address.parse::<std::net::SocketAddr>() {
+        Ok(address) => address.ip().is_loopback(),
+        Err(_) => address
+            .rsplit_once(':')
+            .is_some_and(|(host, _)| host.eq_ignore_ascii_case("localhost")),
+    }
+}
+
+/// Whether `address` looks like `host:port`, with IPv6 hosts in brackets.
+/// Host names are only resolved when binding.
+fn is_listen_address(address: &str) -> bool {
+    match address.rsplit_once(':') {
+        Some((host, port)) => {
+            let host = host
+                .strip_prefix('[')
+                .and_then(|host| host.strip_suffix(']'))
+                .unwrap_or(host);
+            !host.is_empty()
+                && (host.contains(':') == address.starts_with('['))
+                && port.parse::<u16>().is_ok()
+        }
+        None => false,
+    }
+}
+
+fn try_bind(port: u16) -> std::result::Result<u16, std::io::Error> {
+    use std::net::TcpListener;
+
+    TcpListener::bind(("127.0.0.1", port)).map(|_| port)
+}
+
+fn find_available_port(default: u16) -> Result<u16, ServerError> {
+    if let Ok(port) = try_bind(default) {
+        return Ok(port);
+    }
+
+    (1024..=49151)
+        .find_map(|port| try_bind(port).ok())
+        .ok_or(ServerError::PortUnavailable(default))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_site_picked_by_host() {
+        let config = Config {
+            static_dir: "./static".to_string(),
+            sites: vec![
+                Site::new(&["Example.com", "www.example.com"], "/srv/example"),
+                Site::new(&["*.apps.test"], "/srv/apps"),
+            ],
+            ..Config::default()
+        };
+        let root = |host| config.site_for(host).root.clone();
+
+        assert_eq!(root(Some("example.com")), "/srv/example");
+        assert_eq!(root(Some("WWW.example.com:8080")), "/srv/example");
+        assert_eq!(root(Some("one.apps.test")), "/srv/apps");
+        assert_eq!(root(Some("two.one.apps.test")), "./static");
+        assert_eq!(root(Some("[::1]:8080")), "./static");
+        assert_eq!(root(None), "./static");
+    }
+
+    #[test]
+    fn test_static_roots_canonicalized_once() {
+        let mut config = Config {
+            static_dir: "./static/../static".to_string(),
+            sites: vec![Site::new(&["example.com"], "./does-not-exist")],
+            ..Config::default()
+        };
+        config.canonicalize_roots();
+        assert_eq!(
+            config.canonical_roots["./static/../static"],
+            std::fs::canonicalize("./static").unwrap()
+        );
+        assert!(!config.canonical_roots.contains_key("./does-not-exist"));
+    }
+
+    #[test]
+    fn test_validate_reports_every_problem() {
+        assert!(Config::default().validate().is_ok());
+
+        let config = Config {
+            addresses: vec!["127.0.0.1".to_string(), "[::1]:8080".to_string()],
+            static_dir: "./does-not-exist".to_string(),
+            max_connections: 0,
+            metrics_path: Some("metrics".to_string()),
+            worker_threads: Some(0),
+            route_rate_limits: vec![RouteRateLimit::new("/login", RateLimiter::new(0.0, 1))],
+            ..Config::default()
+        };
+        let Err(ServerError::InvalidConfig(report)) = config.validate() else {
+            panic!("expected an invalid configuration");
+        };
+        assert!(report.starts_with("6 problem(s)"), "{}", report);
+        assert!(report.contains("\"127.0.0.1\""));
+        assert!(!report.contains("[::1]"));
+        assert!(report.contains("./does-not-exist"));
+        assert!(report.contains("max_connections"));
+        assert!(report.contains("\"metrics\""));
+        assert!(report.contains("worker_threads"));
+        assert!(report.contains("rate limit for \"/login\""));
+    }
+
+    #[test]
+    fn test_validate_websocket_admin_exposure() {
+        let loopback = Config {
+            addresses: vec!["127.0.0.1:8080".to_string(), "localhost:8081".to_string()],
+            websocket_admin_path: Some("/admin/websockets".to_string()),
+            ..Config::default()
+        };
+        assert!(loopback.validate().is_ok());
+
+        let public = Config {
+            addresses: vec!["0.0.0.0:8080".to_string()],
+            ..loopback
+        };
+        let Err(ServerError::InvalidConfig(report)) = public.validate() else {
+            panic!("expected an invalid configuration");
+        };
+        assert!(report.contains("websocket_admin_token"));
+
+        let with_token = Config {
+            websocket_admin_token: Some("secret".to_string()),
+            ..public
+        };
+        assert!(with_token.validate().is_ok());
+    }
+}
@@ -0,0 +1,102 @@
+//! Routes declared in configuration rather than code: each maps a path
+//! prefix to static files, a redirect, a proxied upstream pool or one of the
+//! built-in handlers.
+
+use crate::{
+    middleware,
+    protocol::{path, response::HttpStatusCode},
+    proxy::UpstreamPool,
+};
+
+/// Handlers that ship with the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    /// The health check served at `health_check_path`.
+    Health,
+    /// The WebSocket metrics served at `metrics_path`.
+    Metrics,
+    /// Answers with the request body and path as JSON.
+    Echo,
+}
+
+impl Builtin {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "health" => Some(Self::Health),
+            "metrics" => Some(Self::Metrics),
+            "echo" => Some(Self::Echo),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RouteTarget {
+    /// Files from this directory, looked up by the path below the prefix.
+    Static(String),
+    /// Redirect to this URL followed by the path below the prefix, so
+    /// `/old` → `https://example.com/new` sends `/old/a?b` to
+    /// `https://example.com/new/a?b`.
+    Redirect {
+        to: String,
+        status: HttpStatusCode,
+    },
+    /// Forward the request unchanged to one of the pool's upstreams.
+    Proxy(UpstreamPool),
+    Builtin(Builtin),
+}
+
+/// Requests whose path falls under `prefix` are handled by `target`.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub prefix: String,
+    pub target: RouteTarget,
+}
+
+impl Route {
+    pub fn new(prefix: &str, target: RouteTarget) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            target,
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        middleware::matches_prefix(&self.prefix, path)
+    }
+
+    /// The part of a matching `path` below the prefix, normalized as it
+    /// was matched: empty, or starting with `/` or `?`. The query is kept.
+    pub fn rest(&self, path: &str) -> String {
+        let normalized = path::normalize_encoded(path);
+        let mut rest = normalized
+            .get(self.prefix.trim_end_matches('/').len()..)
+            .unwrap_or_default()
+            .to_string();
+        if let Some((_, query)) = path.split_once('?') {
+            rest.push('?');
+            rest.push_str(query);
+        }
+        rest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rest_of_path() {
+        let route = Route::new("/docs", RouteTarget::Static("/srv/docs".to_string()));
+        assert!(route.matches("/docs/guide.html"));
+        assert!(!route.matches("/docsearch"));
+        assert_eq!(route.rest("/docs/guide.html"), "/guide.html");
+        assert_eq!(route.rest("/docs?page=2"), "?page=2");
+        assert_eq!(route.rest("/docs"), "");
+        assert!(route.matches("//docs/../docs/a%20b"));
+        assert_eq!(route.rest("//docs/../docs/a%20b"), "/a%20b");
+
+        let route = Route::new("/docs/", RouteTarget::Builtin(Builtin::Echo));
+        assert_eq!(route.rest("/docs/guide.html"), "/guide.html");
+    }
+}
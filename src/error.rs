@@ -1,5 +1,51 @@
+use crate::{protocol::response::HttpStatusCode, websocket::frame::ParseError};
+use std::{fmt, net::SocketAddr};
 use thiserror::Error;
 
+/// The broad category of a [`ServerError`], for reacting to errors without
+/// matching every variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The request was wrong or too big; retrying it unchanged fails again.
+    Client,
+    /// Something on the server side failed: configuration, a handler, an
+    /// upstream.
+    Server,
+    /// Reading or writing a socket or file failed.
+    Io,
+    /// The peer did not send in time.
+    Timeout,
+    /// The peer broke the WebSocket protocol.
+    Protocol,
+}
+
+/// What was being served when an error happened, as far as it is known.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestInfo {
+    pub peer_addr: Option<SocketAddr>,
+    pub request_id: Option<String>,
+    pub method: Option<String>,
+    pub path: Option<String>,
+}
+
+impl fmt::Display for RequestInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        match (&self.method, &self.path) {
+            (Some(method), Some(path)) => parts.push(format!("{} {}", method, path)),
+            (None, Some(path)) => parts.push(path.clone()),
+            _ => {}
+        }
+        if let Some(id) = &self.request_id {
+            parts.push(format!("request {}", id));
+        }
+        if let Some(peer_addr) = &self.peer_addr {
+            parts.push(format!("from {}", peer_addr));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum ServerError {
     #[error("I/O error: {0}")]
@@ -8,20 +54,247 @@ pub enum ServerError {
     #[error("Invalid HTTP request: {0}")]
     InvalidHttpRequest(&'static str),
 
+    #[error("Request timed out: {0}")]
+    RequestTimeout(&'static str),
+
+    #[error("Message headers exceed the configured size or count limit")]
+    HeadersTooLarge,
+
+    #[error("Message body exceeds the limit of {0} bytes")]
+    BodyTooLarge(usize),
+
+    #[error("Request target exceeds the limit of {0} bytes")]
+    UriTooLong(usize),
+
+    #[error("Unsupported transfer coding: {0}")]
+    UnsupportedTransferCoding(String),
+
     #[error("WebSocket handshake failed: {0}")]
     WebSocketHandshakeFailed(String),
 
     #[error("WebSocket frame error: {0}")]
-    WebSocketFrameError(&'static str),
+    WebSocketFrameError(#[from] ParseError),
 
     #[error("WebSocket error: {0}")]
     WebSocketError(String),
 
+    #[error("Handler panicked: {0}")]
+    HandlerPanicked(String),
+
+    #[error("Gateway error: {0}")]
+    GatewayError(String),
+
+    #[error("ACME error: {0}")]
+    Acme(String),
+
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+
     #[error("Static file not found: {0}")]
     FileNotFound(String),
 
     #[error("No available port found starting from {0}")]
     PortUnavailable(u16),
+
+    /// `source` happened while serving the request described by `info`.
+    #[error("{source} ({info})")]
+    InRequest {
+        info: Box<RequestInfo>,
+        source: Box<ServerError>,
+    },
+}
+
+impl ServerError {
+    /// Attach what is known about the request being served. Fields the
+    /// error already carries are kept, as they were set closer to its cause.
+    pub fn in_request(self, info: RequestInfo) -> Self {
+        match self {
+            ServerError::InRequest {
+                info: mut known,
+                source,
+            } => {
+                known.peer_addr = known.peer_addr.or(info.peer_addr);
+                known.request_id = known.request_id.or(info.request_id);
+                known.method = known.method.or(info.method);
+                known.path = known.path.or(info.path);
+                ServerError::InRequest {
+                    info: known,
+                    source,
+                }
+            }
+            error => ServerError::InRequest {
+                info: Box::new(info),
+                source: Box::new(error),
+            },
+        }
+    }
+
+    /// The request the error happened in, if it was attached.
+    pub fn request_info(&self) -> Option<&RequestInfo> {
+        match self {
+            ServerError::InRequest { info, .. } => Some(info),
+            _ => None,
+        }
+    }
+
+    /// The error itself, without the request it happened in.
+    pub fn root(&self) -> &ServerError {
+        match self {
+            ServerError::InRequest { source, .. } => source.root(),
+            error => error,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ServerError::InRequest { source, .. } => source.kind(),
+            ServerError::Io(_) => ErrorKind::Io,
+            ServerError::RequestTimeout(_) => ErrorKind::Timeout,
+            ServerError::InvalidHttpRequest(_)
+            | ServerError::HeadersTooLarge
+            | ServerError::BodyTooLarge(_)
+            | ServerError::UriTooLong(_)
+            | ServerError::UnsupportedTransferCoding(_)
+            | ServerError::WebSocketHandshakeFailed(_)
+            | ServerError::FileNotFound(_) => ErrorKind::Client,
+            ServerError::WebSocketFrameError(_) | ServerError::WebSocketError(_) => {
+                ErrorKind::Protocol
+            }
+            ServerError::HandlerPanicked(_)
+            | ServerError::GatewayError(_)
+            | ServerError::Acme(_)
+            | ServerError::InvalidConfig(_)
+            | ServerError::PortUnavailable(_) => ErrorKind::Server,
+        }
+    }
+
+    /// The status to answer a request that failed with this error.
+    pub fn status(&self) -> HttpStatusCode {
+        match self {
+            ServerError::InRequest { source, .. } => source.status(),
+            ServerError::InvalidHttpRequest(_)
+            | ServerError::WebSocketHandshakeFailed(_)
+            | ServerError::WebSocketFrameError(_)
+            | ServerError::WebSocketError(_) => HttpStatusCode::BadRequest,
+            ServerError::RequestTimeout(_) => HttpStatusCode::RequestTimeout,
+            ServerError::HeadersTooLarge => HttpStatusCode::RequestHeaderFieldsTooLarge,
+            ServerError::BodyTooLarge(_) => HttpStatusCode::PayloadTooLarge,
+            ServerError::UriTooLong(_) => HttpStatusCode::UriTooLong,
+            ServerError::UnsupportedTransferCoding(_) => HttpStatusCode::NotImplemented,
+            ServerError::FileNotFound(_) => HttpStatusCode::NotFound,
+            ServerError::GatewayError(_) => HttpStatusCode::BadGateway,
+            ServerError::Io(_)
+            | ServerError::HandlerPanicked(_)
+            | ServerError::Acme(_)
+            | ServerError::InvalidConfig(_)
+            | ServerError::PortUnavailable(_) => HttpStatusCode::InternalServerError,
+        }
+    }
+
+    /// The status code to close a WebSocket connection with, for errors
+    /// caused by the peer's frames.
+    pub fn close_code(&self) -> Option<u16> {
+        match self {
+            ServerError::InRequest { source, .. } => source.close_code(),
+            ServerError::WebSocketFrameError(e) => Some(e.close_code()),
+            _ => None,
+        }
+    }
+
+    /// Whether the same request may succeed if tried again later: timeouts,
+    /// dropped connections and failing upstreams, but not bad requests.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ServerError::InRequest { source, .. } => source.is_retryable(),
+            ServerError::RequestTimeout(_) | ServerError::GatewayError(_) => true,
+            ServerError::Io(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::ConnectionRefused
+            ),
+            _ => false,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, ServerError>;
+
+/// The message a panic was raised with, from its payload.
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind_status_and_retry() {
+        let timeout = ServerError::RequestTimeout("Request body too slow");
+        assert_eq!(timeout.kind(), ErrorKind::Timeout);
+        assert_eq!(timeout.status(), HttpStatusCode::RequestTimeout);
+        assert!(timeout.is_retryable());
+
+        let too_large = ServerError::BodyTooLarge(1024);
+        assert_eq!(too_large.kind(), ErrorKind::Client);
+        assert_eq!(too_large.status(), HttpStatusCode::PayloadTooLarge);
+        assert!(!too_large.is_retryable());
+
+        let reset = ServerError::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert_eq!(reset.kind(), ErrorKind::Io);
+        assert!(reset.is_retryable());
+        assert!(
+            !ServerError::from(std::io::Error::from(std::io::ErrorKind::NotFound)).is_retryable()
+        );
+
+        assert_eq!(
+            ServerError::GatewayError("upstream closed".to_string()).status(),
+            HttpStatusCode::BadGateway
+        );
+
+        let frame = ServerError::from(ParseError::InvalidOpcode(0x3));
+        assert_eq!(frame.kind(), ErrorKind::Protocol);
+        assert_eq!(frame.close_code(), Some(1002));
+        assert_eq!(
+            frame.to_string(),
+            "WebSocket frame error: reserved opcode 0x3"
+        );
+        assert!(matches!(
+            frame,
+            ServerError::WebSocketFrameError(ParseError::InvalidOpcode(0x3))
+        ));
+    }
+
+    #[test]
+    fn test_in_request() {
+        let error = ServerError::GatewayError("upstream closed".to_string())
+            .in_request(RequestInfo {
+                request_id: Some("abc".to_string()),
+                method: Some("GET".to_string()),
+                path: Some("/api".to_string()),
+                ..RequestInfo::default()
+            })
+            .in_request(RequestInfo {
+                peer_addr: Some("127.0.0.1:4000".parse().unwrap()),
+                request_id: Some("outer".to_string()),
+                ..RequestInfo::default()
+            });
+
+        assert_eq!(
+            error.to_string(),
+            "Gateway error: upstream closed (GET /api, request abc, from 127.0.0.1:4000)"
+        );
+        assert!(matches!(error.root(), ServerError::GatewayError(_)));
+        assert_eq!(error.status(), HttpStatusCode::BadGateway);
+        assert!(error.is_retryable());
+        assert_eq!(error.request_info().unwrap().path.as_deref(), Some("/api"));
+    }
+}
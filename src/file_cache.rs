@@ -0,0 +1,194 @@
+//! Small static files kept in memory in a least-recently-used cache, so
+//! repeated requests for them are served without reading the file.
+
+use bytes::Bytes;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+#[derive(Debug)]
+struct Entry {
+    body: Bytes,
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<PathBuf, Entry>,
+    /// Paths by when they were last used, least recently used first.
+    order: BTreeMap<u64, PathBuf>,
+    /// Bytes held by all entries.
+    size: u64,
+    /// Bumped on every use, so each use has its own place in `order`.
+    clock: u64,
+}
+
+/// Cache of the contents of files up to `max_file_size` bytes, holding at
+/// most `capacity` bytes in total. Entries are checked against the file's
+/// length and modification time on each use and read again when the file
+/// changed. Cloning shares the same entries.
+///
+/// Contents are copied rather than memory-mapped, so a file truncated in
+/// place cannot fault the server.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    capacity: u64,
+    max_file_size: u64,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl FileCache {
+    /// A cache holding up to `capacity` bytes of files of 64KB or less.
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            max_file_size: 64 * 1024,
+            entries: Arc::new(Mutex::new(Entries::default())),
+        }
+    }
+
+    /// Cache files of up to `bytes` only.
+    pub fn with_max_file_size(mut self, bytes: u64) -> Self {
+        self.max_file_size = bytes;
+        self
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size
+    }
+
+    /// Whether files described by `metadata` are cached at all. Empty files
+    /// are not worth it.
+    pub fn accepts(&self, metadata: &Metadata) -> bool {
+        (1..=self.max_file_size.min(self.capacity)).contains(&metadata.len())
+    }
+
+    /// The cached contents of the file at `path`, if they are current for
+    /// `metadata`.
+    pub fn get(&self, path: &Path, metadata: &Metadata) -> Option<Bytes> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries.map.get_mut(path)?;
+        if entry.body.len() as u64 != metadata.len() || entry.modified != metadata.modified().ok() {
+            return None;
+        }
+        let last_used = std::mem::replace(&mut entry.last_used, clock);
+        let body = entry.body.clone();
+        if let Some(path) = entries.order.remove(&last_used) {
+            entries.order.insert(clock, path);
+        }
+        Some(body)
+    }
+
+    /// Cache `body`, the contents of the file at `path` read after
+    /// `metadata`, in place of any older version, evicting the least
+    /// recently used files to make room.
+    pub fn insert(&self, path: &Path, body: Bytes, metadata: &Metadata) -> Bytes {
+        let size = body.len() as u64;
+
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(old) = entries.map.remove(path) {
+            entries.order.remove(&old.last_used);
+            entries.size -= old.body.len() as u64;
+        }
+        while entries.size + size > self.capacity {
+            let Some((_, oldest)) = entries.order.pop_first() else {
+                break;
+            };
+            if let Some(evicted) = entries.map.remove(&oldest) {
+                entries.size -= evicted.body.len() as u64;
+            }
+        }
+        entries.clock += 1;
+        let last_used = entries.clock;
+        entries.size += size;
+        entries.order.insert(last_used, path.to_path_buf());
+        entries.map.insert(
+            path.to_path_buf(),
+            Entry {
+                body: body.clone(),
+                modified: metadata.modified().ok(),
+                last_used,
+            },
+        );
+        body
+    }
+
+    /// Number of cached files.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(cache: &FileCache, path: &Path) -> Bytes {
+        let metadata = std::fs::metadata(path).unwrap();
+        match cache.get(path, &metadata) {
+            Some(body) => body,
+            None => cache.insert(path, std::fs::read(path).unwrap().into(), &metadata),
+        }
+    }
+
+    #[test]
+    fn test_files_are_cached_and_evicted() {
+        let dir = std::env::temp_dir().join(format!("http-file-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b, c) = (dir.join("a"), dir.join("b"), dir.join("c"));
+        std::fs::write(&a, "aaaa").unwrap();
+        std::fs::write(&b, "bbbb").unwrap();
+        std::fs::write(&c, "cccc").unwrap();
+
+        let cache = FileCache::new(8);
+        assert_eq!(load(&cache, &a), "aaaa");
+        assert_eq!(load(&cache, &b), "bbbb");
+        let metadata = std::fs::metadata(&a).unwrap();
+        assert!(cache.get(&a, &metadata).is_some());
+
+        // `b` is the least recently used now
+        assert_eq!(load(&cache, &c), "cccc");
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&b, &std::fs::metadata(&b).unwrap()).is_none());
+        assert!(cache.get(&a, &metadata).is_some());
+
+        // A changed file is not served from its old contents
+        let replacement = dir.join("a.new");
+        std::fs::write(&replacement, "aaaaa").unwrap();
+        std::fs::rename(&replacement, &a).unwrap();
+        assert!(cache.get(&a, &std::fs::metadata(&a).unwrap()).is_none());
+        assert_eq!(load(&cache, &a), "aaaaa");
+
+        // Truncating a cached file in place leaves the cached copy intact
+        let body = load(&cache, &c);
+        std::fs::write(&c, "").unwrap();
+        assert_eq!(body, "cccc");
+
+        // One large file evicts several small ones, oldest first
+        let d = dir.join("d");
+        std::fs::write(&d, "dddddddd").unwrap();
+        assert_eq!(load(&cache, &d), "dddddddd");
+        assert_eq!(cache.len(), 1);
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(entries.order.len(), entries.map.len());
+        assert_eq!(entries.size, 8);
+        drop(entries);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,213 @@
+//! Classic CGI: one process per request (RFC 3875).
+
+use crate::{
+    error::{Result, ServerError},
+    gateway,
+    protocol::{
+        codec,
+        request::HttpRequest,
+        response::{HttpResponse, HttpStatusCode},
+    },
+};
+use std::{io, path::PathBuf, process::Stdio, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::Command,
+    time::timeout,
+};
+use tracing::{info, warn};
+
+/// Upper bound on a script's run time.
+const CGI_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bytes of a script's stderr kept for the log; the rest is discarded.
+const MAX_STDERR_SIZE: usize = 64 * 1024;
+
+/// Requests under `prefix` run the script named by the next path segment,
+/// looked up in `directory` (e.g. `/cgi-bin/` → `./cgi-bin`).
+#[derive(Debug, Clone)]
+pub struct CgiRoute {
+    pub prefix: String,
+    pub directory: String,
+}
+
+impl CgiRoute {
+    pub fn new(prefix: &str, directory: &str) -> Self {
+        Self {
+            prefix: format!("{}/", prefix.trim_end_matches('/')),
+            directory: directory.to_string(),
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        path.starts_with(&self.prefix)
+    }
+
+    /// Split a request path into the script's URL path and file name.
+    fn script_for<'a>(&self, path: &'a str) -> Option<(&'a str, &'a str)> {
+        let path = path.split('?').next().unwrap_or(path);
+        let rest = path.strip_prefix(&self.prefix)?;
+        let name = rest.split('/').next().unwrap_or("");
+
+        if name.is_empty() || name == "." || name == ".." {
+            return None;
+        }
+
+        Some((&path[..self.prefix.len() + name.len()], name))
+    }
+}
+
+/// Execute the CGI script addressed by `request` and translate its output.
+pub async fn execute(request: &HttpRequest, route: &CgiRoute) -> Result<HttpResponse> {
+    let Some((script_name, file_name)) = route.script_for(&request.path) else {
+        return Ok(HttpResponse::not_found().with_text("File not found"));
+    };
+
+    // Security: the script must be a file inside the CGI directory
+    let Ok(directory) = tokio::fs::canonicalize(&route.directory).await else {
+        return Err(ServerError::FileNotFound(route.directory.clone()));
+    };
+    let script_path = match tokio::fs::canonicalize(directory.join(file_name)).await {
+        Ok(path) if path.starts_with(&directory) => path,
+        _ => return Ok(HttpResponse::not_found().with_text("File not found")),
+    };
+    if !tokio::fs::metadata(&script_path)
+        .await
+        .map(|metadata| metadata.is_file())
+        .unwrap_or(false)
+    {
+        return Ok(HttpResponse::not_found().with_text("File not found"));
+    }
+
+    let document_root = directory.to_string_lossy();
+    let env = gateway::cgi_environment(
+        request,
+        &document_root,
+        script_name,
+        &script_path.to_string_lossy(),
+    );
+
+    match timeout(CGI_TIMEOUT, run(script_path.clone(), env, &request.body)).await {
+        Ok(Ok(output)) => match gateway::parse_cgi_response(&output) {
+            Some(response) => {
+                info!(script = ?script_path, status = response.status.code(), "CGI script completed");
+                Ok(response)
+            }
+            None => {
+                warn!(script = ?script_path, "Invalid CGI response");
+                Ok(bad_gateway())
+            }
+        },
+        Ok(Err(e)) => {
+            warn!(script = ?script_path, error = ?e, "CGI script failed");
+            Ok(bad_gateway())
+        }
+        Err(_) => {
+            warn!(script = ?script_path, "CGI script timed out");
+            Ok(HttpResponse::new(HttpStatusCode::GatewayTimeout).with_text("Gateway timeout"))
+        }
+    }
+}
+
+/// Spawn the script, stream `body` to its stdin and collect its stdout.
+async fn run(script: PathBuf, env: Vec<(String, String)>, body: &[u8]) -> Result<Vec<u8>> {
+    let working_dir = script.parent().map(PathBuf::from).unwrap_or_default();
+
+    let mut child = Command::new(&script)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .envs(env)
+        .current_dir(working_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    // Feed stdin concurrently so a script writing output before reading all
+    // of its input cannot deadlock against us
+    let mut stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::other("CGI stdin unavailable"))?;
+    let body = body.to_vec();
+    let writer = tokio::spawn(async move {
+        // Scripts may legitimately exit without reading their input
+        let _ = stdin.write_all(&body).await;
+    });
+
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| io::Error::other("CGI stdout unavailable"))?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| io::Error::other("CGI stderr unavailable"))?;
+    let stderr = tokio::spawn(read_capped(stderr, MAX_STDERR_SIZE));
+
+    // Output is read up to the body limit only, so a runaway script cannot
+    // exhaust memory
+    let mut output = Vec::new();
+    stdout
+        .take(codec::MAX_BODY_SIZE as u64 + 1)
+        .read_to_end(&mut output)
+        .await?;
+    if output.len() > codec::MAX_BODY_SIZE {
+        let _ = child.kill().await;
+        return Err(ServerError::GatewayError(
+            "CGI response too large".to_string(),
+        ));
+    }
+
+    let status = child.wait().await?;
+    let _ = writer.await;
+    let stderr = stderr.await.unwrap_or_default();
+
+    if !stderr.is_empty() {
+        warn!(script = ?script, stderr = %String::from_utf8_lossy(&stderr), "CGI stderr");
+    }
+
+    if !status.success() && output.is_empty() {
+        return Err(ServerError::GatewayError(format!(
+            "CGI script exited with {}",
+            status
+        )));
+    }
+
+    Ok(output)
+}
+
+/// The first `limit` bytes of `stream`, read to its end so the writer is
+/// never blocked on a full pipe.
+async fn read_capped(mut stream: impl AsyncRead + Unpin, limit: usize) -> Vec<u8> {
+    let mut kept = Vec::new();
+    let _ = (&mut stream)
+        .take(limit as u64)
+        .read_to_end(&mut kept)
+        .await;
+    let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+    kept
+}
+
+fn bad_gateway() -> HttpResponse {
+    HttpResponse::new(HttpStatusCode::BadGateway).with_text("Bad gateway")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_for() {
+        let route = CgiRoute::new("/cgi-bin", "./cgi-bin");
+
+        assert_eq!(
+            route.script_for("/cgi-bin/env.sh/extra?x=1"),
+            Some(("/cgi-bin/env.sh", "env.sh"))
+        );
+        assert_eq!(route.script_for("/cgi-bin/"), None);
+        assert_eq!(route.script_for("/cgi-bin/../etc/passwd"), None);
+        assert!(!route.matches("/cgi-binary"));
+    }
+}
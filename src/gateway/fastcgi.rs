@@ -0,0 +1,326 @@
+//! FastCGI client (responder role), e.g. for forwarding `*.php` to php-fpm.
+
+use crate::{
+    error::{Result, ServerError},
+    gateway,
+    protocol::{
+        codec, path,
+        request::HttpRequest,
+        response::{HttpResponse, HttpStatusCode},
+    },
+};
+use bytes::{Buf, BufMut, BytesMut};
+use std::{io, path::PathBuf, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::{info, warn};
+
+const FCGI_VERSION: u8 = 1;
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+const FCGI_RESPONDER: u16 = 1;
+
+/// Each connection carries a single request, so the id is fixed.
+const REQUEST_ID: u16 = 1;
+
+/// Largest content length a single record can carry.
+const MAX_RECORD_CONTENT: usize = 65535;
+
+/// Upper bound on a full exchange with the application server.
+const FASTCGI_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where the FastCGI application server listens.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FastCgiAddress {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FastCgiAddress {
+    /// Parse `host:port` or `unix:/path/to/socket`.
+    pub fn parse(address: &str) -> Self {
+        match address.strip_prefix("unix:") {
+            Some(path) => FastCgiAddress::Unix(PathBuf::from(path)),
+            None => FastCgiAddress::Tcp(address.to_string()),
+        }
+    }
+}
+
+/// Requests whose path matches `pattern` are executed by the FastCGI server.
+#[derive(Debug, Clone)]
+pub struct FastCgiRoute {
+    pub pattern: String,
+    pub address: FastCgiAddress,
+    /// Document root as seen by the application server, used to build
+    /// `SCRIPT_FILENAME`.
+    pub document_root: String,
+}
+
+impl FastCgiRoute {
+    pub fn new(pattern: &str, address: &str, document_root: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            address: FastCgiAddress::parse(address),
+            document_root: document_root.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        gateway::matches_pattern(&self.pattern, path)
+    }
+}
+
+/// A record received from the application server.
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub record_type: u8,
+    pub request_id: u16,
+    pub content: Vec<u8>,
+}
+
+/// Run `request` through the FastCGI server and translate its output.
+/// Transport or protocol failures are answered with 502 Bad Gateway.
+pub async fn forward(request: &HttpRequest, route: &FastCgiRoute) -> Result<HttpResponse> {
+    // The application server runs whatever file it is given, so the script
+    // must lie below the document root however its path is spelled
+    let Some(script_name) = path::resolve(&request.path) else {
+        warn!(path = %request.path, "FastCGI script path leaves the document root");
+        return Ok(invalid_path());
+    };
+    let script_filename = format!("{}{}", route.document_root, script_name);
+    if !within_document_root(&route.document_root, &script_filename).await {
+        warn!(path = %request.path, "FastCGI script resolves outside the document root");
+        return Ok(invalid_path());
+    }
+    let params = gateway::cgi_environment(
+        request,
+        &route.document_root,
+        &script_name,
+        &script_filename,
+    );
+    let outbound = encode_request(REQUEST_ID, &params, &request.body);
+
+    let result = timeout(FASTCGI_TIMEOUT, async {
+        match &route.address {
+            FastCgiAddress::Tcp(address) => {
+                let mut stream = TcpStream::connect(address).await?;
+                exchange(&mut stream, &outbound).await
+            }
+            #[cfg(unix)]
+            FastCgiAddress::Unix(path) => {
+                let mut stream = tokio::net::UnixStream::connect(path).await?;
+                exchange(&mut stream, &outbound).await
+            }
+            #[cfg(not(unix))]
+            FastCgiAddress::Unix(_) => Err(ServerError::GatewayError(
+                "Unix sockets are not supported on this platform".to_string(),
+            )),
+        }
+    })
+    .await
+    .map_err(|_| ServerError::from(io::Error::from(io::ErrorKind::TimedOut)))
+    .and_then(|result| result);
+
+    let output = match result {
+        Ok(output) => output,
+        Err(e) => {
+            warn!(address = ?route.address, error = ?e, "FastCGI request failed");
+            return Ok(bad_gateway());
+        }
+    };
+
+    match gateway::parse_cgi_response(&output) {
+        Some(response) => {
+            info!(address = ?route.address, status = response.status.code(), "FastCGI request completed");
+            Ok(response)
+        }
+        None => {
+            warn!(address = ?route.address, "Invalid FastCGI response");
+            Ok(bad_gateway())
+        }
+    }
+}
+
+/// Whether `script_filename`, once symlinks are resolved, is still below
+/// `document_root`. Roots that only exist on the application server's host
+/// cannot be checked here and rely on the path being resolved already.
+async fn within_document_root(document_root: &str, script_filename: &str) -> bool {
+    let Ok(root) = tokio::fs::canonicalize(document_root).await else {
+        return true;
+    };
+    match tokio::fs::canonicalize(script_filename).await {
+        Ok(script) => script.starts_with(root),
+        Err(_) => true,
+    }
+}
+
+fn invalid_path() -> HttpResponse {
+    HttpResponse::new(HttpStatusCode::BadRequest).with_text("Invalid path")
+}
+
+/// Send the encoded request and collect STDOUT until END_REQUEST.
+async fn exchange<S>(stream: &mut S, outbound: &[u8]) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(outbound).await?;
+
+    let mut buffer = BytesMut::with_capacity(8192);
+    let mut stdout = Vec::new();
+
+    loop {
+        while let Some(record) = parse_record(&mut buffer) {
+            match record.record_type {
+                FCGI_STDOUT => {
+                    if stdout.len() + record.content.len() > codec::MAX_BODY_SIZE {
+                        return Err(ServerError::GatewayError(
+                            "FastCGI response too large".to_string(),
+                        ));
+                    }
+                    stdout.extend_from_slice(&record.content);
+                }
+                FCGI_STDERR => {
+                    warn!(stderr = %String::from_utf8_lossy(&record.content), "FastCGI stderr");
+                }
+                FCGI_END_REQUEST => return Ok(stdout),
+                _ => {}
+            }
+        }
+
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(ServerError::GatewayError(
+                "FastCGI server closed the connection before END_REQUEST".to_string(),
+            ));
+        }
+    }
+}
+
+/// Serialize BEGIN_REQUEST, PARAMS and STDIN streams for one request.
+pub fn encode_request(request_id: u16, params: &[(String, String)], body: &[u8]) -> Vec<u8> {
+    let mut out = BytesMut::new();
+
+    // Role plus flags (0: close the connection after the request)
+    let mut begin = [0u8; 8];
+    begin[..2].copy_from_slice(&FCGI_RESPONDER.to_be_bytes());
+    encode_record(&mut out, FCGI_BEGIN_REQUEST, request_id, &begin);
+
+    let mut encoded_params = Vec::new();
+    for (name, value) in params {
+        encode_param_length(&mut encoded_params, name.len());
+        encode_param_length(&mut encoded_params, value.len());
+        encoded_params.extend_from_slice(name.as_bytes());
+        encoded_params.extend_from_slice(value.as_bytes());
+    }
+    encode_stream(&mut out, FCGI_PARAMS, request_id, &encoded_params);
+    encode_stream(&mut out, FCGI_STDIN, request_id, body);
+
+    out.to_vec()
+}
+
+/// Write `data` as a stream of records followed by the empty terminator.
+fn encode_stream(out: &mut BytesMut, record_type: u8, request_id: u16, data: &[u8]) {
+    for chunk in data.chunks(MAX_RECORD_CONTENT) {
+        encode_record(out, record_type, request_id, chunk);
+    }
+    encode_record(out, record_type, request_id, &[]);
+}
+
+fn encode_record(out: &mut BytesMut, record_type: u8, request_id: u16, content: &[u8]) {
+    // Pad content to a multiple of 8 bytes, as the spec recommends
+    let padding = (8 - content.len() % 8) % 8;
+
+    out.put_u8(FCGI_VERSION);
+    out.put_u8(record_type);
+    out.put_u16(request_id);
+    out.put_u16(content.len() as u16);
+    out.put_u8(padding as u8);
+    out.put_u8(0);
+    out.extend_from_slice(content);
+    out.put_bytes(0, padding);
+}
+
+/// Name/value lengths use one byte below 128, otherwise four with the high bit set.
+fn encode_param_length(out: &mut Vec<u8>, len: usize) {
+    if len < 128 {
+        out.push(len as u8);
+    } else {
+        out.extend_from_slice(&((len as u32) | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Split one complete record off the front of `buffer`, if available.
+pub fn parse_record(buffer: &mut BytesMut) -> Option<Record> {
+    if buffer.len() < 8 {
+        return None;
+    }
+
+    let content_length = u16::from_be_bytes([buffer[4], buffer[5]]) as usize;
+    let padding_length = buffer[6] as usize;
+    if buffer.len() < 8 + content_length + padding_length {
+        return None;
+    }
+
+    let record_type = buffer[1];
+    let request_id = u16::from_be_bytes([buffer[2], buffer[3]]);
+    buffer.advance(8);
+    let content = buffer.split_to(content_length).to_vec();
+    buffer.advance(padding_length);
+
+    Some(Record {
+        record_type,
+        request_id,
+        content,
+    })
+}
+
+fn bad_gateway() -> HttpResponse {
+    HttpResponse::new(HttpStatusCode::BadGateway).with_text("Bad gateway")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_request_round_trips_through_parse_record() {
+        let params = vec![("SCRIPT_FILENAME".to_string(), "x".repeat(200))];
+        let mut buffer = BytesMut::from(&encode_request(7, &params, b"body")[..]);
+
+        let begin = parse_record(&mut buffer).unwrap();
+        assert_eq!(begin.record_type, FCGI_BEGIN_REQUEST);
+        assert_eq!(begin.request_id, 7);
+
+        let params_record = parse_record(&mut buffer).unwrap();
+        assert_eq!(params_record.record_type, FCGI_PARAMS);
+        // 1-byte name length, 4-byte value length for the 200-byte value
+        assert_eq!(params_record.content[0], 15);
+        assert_eq!(
+            &params_record.content[1..5],
+            &(200u32 | 0x8000_0000).to_be_bytes()
+        );
+
+        assert_eq!(parse_record(&mut buffer).unwrap().content, b"");
+        assert_eq!(parse_record(&mut buffer).unwrap().content, b"body");
+        assert_eq!(parse_record(&mut buffer).unwrap().content, b"");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_address_parse() {
+        assert_eq!(
+            FastCgiAddress::parse("unix:/run/php/php-fpm.sock"),
+            FastCgiAddress::Unix(PathBuf::from("/run/php/php-fpm.sock"))
+        );
+        assert_eq!(
+            FastCgiAddress::parse("127.0.0.1:9000"),
+            FastCgiAddress::Tcp("127.0.0.1:9000".to_string())
+        );
+    }
+}
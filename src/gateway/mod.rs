@@ -0,0 +1,214 @@
+//! Gateways to external application servers speaking CGI-style protocols.
+//!
+//! The request metadata handed to the application and the response it writes
+//! back follow the CGI conventions (RFC 3875) for every transport, so the
+//! translation lives here and the transports only move bytes.
+
+use crate::protocol::{
+    codec,
+    request::HttpRequest,
+    response::{HttpResponse, HttpStatusCode},
+};
+
+pub mod cgi;
+pub mod fastcgi;
+
+/// Match a request path against a pattern where `*` stands for any run of
+/// characters, e.g. `*.php` or `/app/*`. The query string is ignored.
+pub fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let path = path.split('?').next().unwrap_or(path);
+    let (pattern, path) = (pattern.as_bytes(), path.as_bytes());
+
+    // Iterative wildcard match with single-star backtracking
+    let (mut p, mut s) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < path.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, s));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == path[s] {
+            p += 1;
+            s += 1;
+        } else if let Some((star_p, star_s)) = star {
+            p = star_p + 1;
+            s = star_s + 1;
+            star = Some((star_p, star_s + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// Build the CGI meta-variables describing `request`.
+///
+/// `script_name` is the URL path of the script and `script_filename` where it
+/// lives on disk.
+pub fn cgi_environment(
+    request: &HttpRequest,
+    document_root: &str,
+    script_name: &str,
+    script_filename: &str,
+) -> Vec<(String, String)> {
+    let (request_path, query_string) = match request.path.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.path.as_str(), ""),
+    };
+    let path_info = request_path.strip_prefix(script_name).unwrap_or("");
+
+    let host = request.get_header("host").map(String::as_str).unwrap_or("");
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => (name, port),
+        _ => (host, "80"),
+    };
+
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", "http-rs/0.1.0".to_string()),
+        ("SERVER_PROTOCOL", request.version.clone()),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", request.method.to_string()),
+        ("REQUEST_URI", request.path.clone()),
+        ("QUERY_STRING", query_string.to_string()),
+        ("DOCUMENT_ROOT", document_root.to_string()),
+        ("SCRIPT_NAME", script_name.to_string()),
+        ("SCRIPT_FILENAME", script_filename.to_string()),
+        ("PATH_INFO", path_info.to_string()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect::<Vec<_>>();
+
+    if !request.body.is_empty() || request.get_header("content-length").is_some() {
+        env.push(("CONTENT_LENGTH".to_string(), request.body.len().to_string()));
+    }
+    if let Some(content_type) = request.get_header("content-type") {
+        env.push(("CONTENT_TYPE".to_string(), content_type.clone()));
+    }
+
+    // Remaining request headers become HTTP_* variables. `Proxy` is left
+    // out: as HTTP_PROXY, many libraries would take it for their outbound
+    // proxy (httpoxy). So are names with underscores, which could pass for
+    // another header, and any variable already set.
+    for (name, value) in &request.headers {
+        if name.eq_ignore_ascii_case("content-type")
+            || name.eq_ignore_ascii_case("content-length")
+            || name.eq_ignore_ascii_case("proxy")
+            || name.contains('_')
+        {
+            continue;
+        }
+        let var = format!("HTTP_{}", name.to_uppercase().replace('-', "_"));
+        if env.iter().any(|(set, _)| *set == var) {
+            continue;
+        }
+        env.push((var, value.clone()));
+    }
+
+    env
+}
+
+/// Translate a CGI response (header block, blank line, body) into an
+/// `HttpResponse`. Returns `None` if the output is not a valid CGI response.
+pub fn parse_cgi_response(output: &[u8]) -> Option<HttpResponse> {
+    // Scripts commonly terminate lines with a bare \n
+    let (head, body) = match codec::find_header_end(output) {
+        Some(end) => (&output[..end], &output[end..]),
+        None => {
+            let end = output.windows(2).position(|w| w == b"\n\n")? + 2;
+            (&output[..end], &output[end..])
+        }
+    };
+
+    let mut status = None;
+    let mut headers = Vec::new();
+
+    for line in String::from_utf8_lossy(head).lines() {
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        let (name, value) = (name.trim().to_lowercase(), value.trim().to_string());
+
+        if name == "status" {
+            let code = value.split_whitespace().next()?.parse::<u16>().ok()?;
+            status = Some(HttpStatusCode::from_code(code)?);
+        } else {
+            headers.push((name, value));
+        }
+    }
+
+    // A Location header without an explicit status is a redirect
+    let status = status.unwrap_or_else(|| {
+        if headers.iter().any(|(name, _)| name == "location") {
+            HttpStatusCode::Found
+        } else {
+            HttpStatusCode::Ok
+        }
+    });
+
+    let mut response = HttpResponse::new(status);
+    for (name, value) in &headers {
+        response = response.with_header(name, value);
+    }
+
+    Some(response.with_body(body.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("*.php", "/index.php"));
+        assert!(matches_pattern("*.php", "/blog/post.php?id=3"));
+        assert!(matches_pattern("/app/*", "/app/x/y"));
+        assert!(!matches_pattern("*.php", "/index.php.bak"));
+        assert!(!matches_pattern("/app/*", "/apps"));
+    }
+
+    #[test]
+    fn test_parse_cgi_response_with_status_header() {
+        let response =
+            parse_cgi_response(b"Status: 404 Not Found\nContent-Type: text/plain\n\nmissing")
+                .unwrap();
+
+        assert_eq!(response.status, HttpStatusCode::NotFound);
+        assert_eq!(
+            response.headers.get("content-type"),
+            Some(&"text/plain".to_string())
+        );
+        assert_eq!(response.body, &b"missing"[..]);
+    }
+
+    #[test]
+    fn test_parse_cgi_response_location_redirect() {
+        let response = parse_cgi_response(b"Location: /login\r\n\r\n").unwrap();
+        assert_eq!(response.status, HttpStatusCode::Found);
+    }
+
+    #[test]
+    fn test_cgi_environment() {
+        let request = HttpRequest::from_buffer_sync(
+            b"GET /app/run.php/extra?a=1 HTTP/1.1\r\nHost: example.com:8080\r\nX-Token: t\r\n\
+              Proxy: http://evil.example:3128\r\nX_Token: forged\r\n\r\n",
+        )
+        .unwrap();
+
+        let env = cgi_environment(&request, "/srv", "/app/run.php", "/srv/app/run.php");
+        let get = |name: &str| env.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str());
+
+        assert_eq!(get("QUERY_STRING"), Some("a=1"));
+        assert_eq!(get("PATH_INFO"), Some("/extra"));
+        assert_eq!(get("SERVER_NAME"), Some("example.com"));
+        assert_eq!(get("SERVER_PORT"), Some("8080"));
+        assert_eq!(get("HTTP_X_TOKEN"), Some("t"));
+        assert_eq!(env.iter().filter(|(n, _)| n == "HTTP_X_TOKEN").count(), 1);
+        assert_eq!(get("HTTP_PROXY"), None);
+        assert_eq!(get("CONTENT_LENGTH"), None);
+    }
+}
@@ -1,4 +1,18 @@
+pub mod access_log;
+pub mod acme;
+pub mod bench;
+pub mod cli;
+pub mod compression;
 pub mod config;
 pub mod error;
+pub mod file_cache;
+pub mod gateway;
+pub mod logging;
+pub mod middleware;
 pub mod protocol;
+pub mod proxy;
+pub mod server;
+pub mod tls;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub mod uring;
 pub mod websocket;
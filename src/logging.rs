@@ -0,0 +1,204 @@
+//! Where the server's tracing output goes, in what format and how much of
+//! it.
+
+use crate::error::{Result, ServerError};
+use std::{collections::BTreeMap, path::PathBuf};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    EnvFilter, Layer, Registry,
+    fmt::{self, writer::BoxMakeWriter},
+    layer::SubscriberExt,
+    reload,
+    util::SubscriberInitExt,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per event, for log collectors.
+    Json,
+}
+
+/// When a log file is closed and a new one started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum LogOutput {
+    #[default]
+    Stdout,
+    Stderr,
+    /// Files named `prefix` plus the date (and hour) in `directory`,
+    /// written from a background thread.
+    File {
+        directory: PathBuf,
+        prefix: String,
+        rotation: LogRotation,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    /// Filter directives such as `info` or `http=debug,http::proxy=trace`.
+    pub level: String,
+    /// Levels for single modules, e.g. `http::websocket` = `debug`, added
+    /// to `level`.
+    pub modules: BTreeMap<String, String>,
+    pub format: LogFormat,
+    pub output: LogOutput,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "http=info,tower_http=debug".to_string(),
+            modules: BTreeMap::new(),
+            format: LogFormat::default(),
+            output: LogOutput::default(),
+        }
+    }
+}
+
+impl LogConfig {
+    /// The filter made of `level` and `modules`.
+    pub fn filter(&self) -> Result<EnvFilter> {
+        let directives = std::iter::once(self.level.clone())
+            .chain(
+                self.modules
+                    .iter()
+                    .map(|(module, level)| format!("{}={}", module, level)),
+            )
+            .filter(|directive| !directive.is_empty())
+            .collect::<Vec<_>>()
+            .join(",");
+        EnvFilter::try_new(&directives).map_err(|e| {
+            ServerError::InvalidConfig(format!("Invalid log level {:?}: {}", directives, e))
+        })
+    }
+
+    /// Install this configuration as the global tracing subscriber. Logs
+    /// written to a file are flushed when the returned value is dropped.
+    pub fn init(&self) -> Result<Logging> {
+        let (filter, filter_handle) = reload::Layer::new(self.filter()?);
+
+        let (writer, guard) = match &self.output {
+            LogOutput::Stdout => (BoxMakeWriter::new(std::io::stdout), None),
+            LogOutput::Stderr => (BoxMakeWriter::new(std::io::stderr), None),
+            LogOutput::File {
+                directory,
+                prefix,
+                rotation,
+            } => {
+                let appender = RollingFileAppender::builder()
+                    .rotation(match rotation {
+                        LogRotation::Never => Rotation::NEVER,
+                        LogRotation::Hourly => Rotation::HOURLY,
+                        LogRotation::Daily => Rotation::DAILY,
+                    })
+                    .filename_prefix(prefix)
+                    .build(directory)
+                    .map_err(|e| {
+                        ServerError::InvalidConfig(format!(
+                            "Cannot log to {}: {}",
+                            directory.display(),
+                            e
+                        ))
+                    })?;
+                let (writer, guard) = tracing_appender::non_blocking(appender);
+                (BoxMakeWriter::new(writer), Some(guard))
+            }
+        };
+        let ansi = matches!(self.output, LogOutput::Stdout | LogOutput::Stderr);
+        let layer = match self.format {
+            LogFormat::Text => fmt::layer().with_writer(writer).with_ansi(ansi).boxed(),
+            LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+        };
+
+        tracing_subscriber::registry()
+            .with(filter)
+            .with(layer)
+            .try_init()
+            .map_err(|e| ServerError::InvalidConfig(format!("Cannot set up logging: {}", e)))?;
+
+        Ok(Logging {
+            reloader: LogReloader {
+                filter_handle,
+                format: self.format,
+                output: self.output.clone(),
+            },
+            _guard: guard,
+        })
+    }
+}
+
+/// The installed logging setup.
+pub struct Logging {
+    reloader: LogReloader,
+    _guard: Option<WorkerGuard>,
+}
+
+impl Logging {
+    pub fn reloader(&self) -> LogReloader {
+        self.reloader.clone()
+    }
+}
+
+/// Applies the levels of a reloaded [`LogConfig`]. The format and output
+/// stay as they were at startup.
+#[derive(Clone)]
+pub struct LogReloader {
+    filter_handle: reload::Handle<EnvFilter, Registry>,
+    format: LogFormat,
+    output: LogOutput,
+}
+
+impl LogReloader {
+    pub fn reload(&self, config: &LogConfig) -> Result<()> {
+        if config.format != self.format || config.output != self.output {
+            tracing::warn!("Log format or output changed; restart to apply");
+        }
+        self.filter_handle
+            .reload(config.filter()?)
+            .map_err(|e| ServerError::InvalidConfig(format!("Cannot change log level: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_combines_level_and_modules() {
+        let config = LogConfig {
+            level: "warn".to_string(),
+            modules: BTreeMap::from([
+                ("http::proxy".to_string(), "trace".to_string()),
+                ("http::websocket".to_string(), "debug".to_string()),
+            ]),
+            ..LogConfig::default()
+        };
+        let filter = config.filter().unwrap().to_string();
+        for directive in ["warn", "http::proxy=trace", "http::websocket=debug"] {
+            assert!(filter.split(',').any(|d| d == directive), "{}", filter);
+        }
+
+        let config = LogConfig {
+            modules: BTreeMap::from([("http".to_string(), "loud".to_string())]),
+            ..LogConfig::default()
+        };
+        assert!(matches!(
+            config.filter(),
+            Err(ServerError::InvalidConfig(message)) if message.contains("http=loud")
+        ));
+    }
+}
@@ -1,30 +1,135 @@
-use http::{config::Config, error::Result, protocol::handle_connection};
-use tokio::net::TcpListener;
-use tracing::{error, info};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "http=info,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    let config = Config::default();
-    let listener = TcpListener::bind(&config.address).await?;
-    info!("Server running on http://{}", config.address);
-
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        let config = config.clone();
+use clap::Parser;
+use http::{
+    cli::{self, Cli, Command},
+    config::Config,
+    error::Result,
+    logging::LogReloader,
+    server,
+};
+use std::time::Duration;
+use tokio::sync::mpsc;
 
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if let Command::Bench(args) = cli.command() {
+        let load_test = args.load_test()?;
+        let report = tokio::runtime::Runtime::new()?.block_on(load_test.run())?;
+        println!("{}", report);
+        return Ok(());
+    }
+    let config = cli.config(&cli.config_file()?)?;
+    if let Err(err) = config.validate() {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+    if cli.command() == Command::CheckConfig {
+        match cli::dry_run(&config) {
+            Ok(report) => println!("{}\nConfiguration OK", report),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    let logging = config.logging.init()?;
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    match http::uring::Runtime::new() {
+        Ok(runtime) => {
+            if config.worker_threads.is_some() {
+                tracing::warn!("worker_threads does not apply to the io_uring runtime");
+            }
+            return runtime.block_on(run(cli, config, logging.reloader()));
+        }
+        Err(e) => tracing::warn!(
+            error = %e,
+            "io_uring is unavailable, falling back to the multi-threaded runtime"
+        ),
+    }
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime
+        .enable_all()
+        .max_blocking_threads(config.max_blocking_threads);
+    if let Some(worker_threads) = config.worker_threads {
+        runtime.worker_threads(worker_threads);
+    }
+    runtime
+        .build()?
+        .block_on(run(cli, config, logging.reloader()))
+}
+
+async fn run(cli: Cli, config: Config, log_reloader: LogReloader) -> Result<()> {
+    let (reload_tx, reloads) = mpsc::channel(1);
+    let (trigger, mut triggers) = mpsc::channel::<()>(1);
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::hangup()) {
+            Ok(mut hangups) => {
+                let trigger = trigger.clone();
+                tokio::spawn(async move {
+                    while hangups.recv().await.is_some() {
+                        let _ = trigger.try_send(());
+                    }
+                });
+            }
+            Err(_) => tracing::warn!("Cannot listen for SIGHUP, reloading is disabled"),
+        }
+    }
+
+    // Dev mode picks up edits to the configuration file without a signal
+    if cli.dev
+        && let Some(path) = cli.config.clone()
+    {
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(socket, &config).await {
-                error!(?addr, error = ?e, "Connection error");
+            let modified = || std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            let mut last = modified();
+            let mut ticks = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                ticks.tick().await;
+                let current = modified();
+                if current != last {
+                    last = current;
+                    tracing::info!(path = %path.display(), "Configuration file changed");
+                    if trigger.send(()).await.is_err() {
+                        return;
+                    }
+                }
             }
         });
+    } else {
+        drop(trigger);
     }
+
+    tokio::spawn(async move {
+        while triggers.recv().await.is_some() {
+            let reloaded = cli.config_file().and_then(|file| {
+                let config = cli.config(&file)?;
+                config.validate()?;
+                Ok(config)
+            });
+            match reloaded {
+                Ok(config) => {
+                    if let Err(err) = log_reloader.reload(&config.logging) {
+                        tracing::warn!("{}", err);
+                    }
+                    if reload_tx.send(config).await.is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("Reload failed, keeping the current configuration: {}", err)
+                }
+            }
+        }
+    });
+
+    let listeners = server::bind(&config).await?;
+    server::serve_with_reload(listeners, config, reloads, async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+    .await
 }
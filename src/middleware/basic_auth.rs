@@ -0,0 +1,236 @@
+use crate::{
+    middleware,
+    protocol::{
+        request::HttpRequest,
+        response::{HttpResponse, HttpStatusCode},
+    },
+};
+use base64::{Engine as _, engine::general_purpose};
+use sha1::{Digest, Sha1};
+use std::{collections::HashMap, io, path::Path, sync::Arc};
+use tracing::warn;
+
+/// A stored password in one of the htpasswd hash formats we understand.
+#[derive(Debug, Clone, PartialEq)]
+enum PasswordHash {
+    /// `$2y$…` / `$2b$…` / `$2a$…` (`htpasswd -B`)
+    Bcrypt(String),
+    /// `{SHA}` + base64 SHA-1 digest (`htpasswd -s`)
+    Sha1(Vec<u8>),
+    /// Plain text (`htpasswd -p`)
+    Plain(String),
+}
+
+impl PasswordHash {
+    fn parse(entry: &str) -> Option<Self> {
+        if entry.starts_with("$2y$") || entry.starts_with("$2b$") || entry.starts_with("$2a$") {
+            Some(PasswordHash::Bcrypt(entry.to_string()))
+        } else if let Some(digest) = entry.strip_prefix("{SHA}") {
+            general_purpose::STANDARD
+                .decode(digest)
+                .ok()
+                .map(PasswordHash::Sha1)
+        } else if entry.starts_with('$') {
+            // apr1 / crypt variants are not supported
+            None
+        } else {
+            Some(PasswordHash::Plain(entry.to_string()))
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            PasswordHash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            PasswordHash::Sha1(digest) => {
+                constant_time_eq(&Sha1::digest(password.as_bytes()), digest)
+            }
+            PasswordHash::Plain(expected) => {
+                constant_time_eq(password.as_bytes(), expected.as_bytes())
+            }
+        }
+    }
+}
+
+/// HTTP Basic authentication (RFC 7617) for a set of path prefixes.
+/// Cloning shares the same credential store.
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    realm: String,
+    prefixes: Vec<String>,
+    credentials: Arc<HashMap<String, PasswordHash>>,
+}
+
+impl BasicAuth {
+    /// Protect every path starting with one of `prefixes`.
+    pub fn new(realm: &str, prefixes: &[&str]) -> Self {
+        Self {
+            realm: realm.to_string(),
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            credentials: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Add a user whose password is given in htpasswd hash syntax.
+    /// Entries in an unsupported format are skipped with a warning.
+    pub fn with_user(mut self, user: &str, password_hash: &str) -> Self {
+        match PasswordHash::parse(password_hash) {
+            Some(hash) => {
+                Arc::make_mut(&mut self.credentials).insert(user.to_string(), hash);
+            }
+            None => warn!(user, "Unsupported password hash format, user ignored"),
+        }
+        self
+    }
+
+    /// Add the `user:hash` lines of htpasswd-formatted `contents`.
+    pub fn with_htpasswd(self, contents: &str) -> Self {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .fold(self, |auth, (user, hash)| auth.with_user(user, hash))
+    }
+
+    /// Add the users from an htpasswd file.
+    pub fn with_htpasswd_file(self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(self.with_htpasswd(&contents))
+    }
+
+    fn protects(&self, path: &str) -> bool {
+        self.prefixes
+            .iter()
+            .any(|prefix| middleware::matches_prefix(prefix, path))
+    }
+
+    /// Let the request through if its path is unprotected or it carries valid
+    /// credentials; otherwise answer with a 401 challenge.
+    pub async fn check(&self, request: &HttpRequest) -> Option<HttpResponse> {
+        if !self.protects(&request.path) {
+            return None;
+        }
+
+        let credentials = request
+            .get_header("authorization")
+            .and_then(|value| decode_credentials(value));
+
+        if let Some((user, password)) = credentials
+            && let Some(hash) = self.credentials.get(&user).cloned()
+        {
+            // bcrypt is deliberately slow; keep it off the async workers
+            let verified = tokio::task::spawn_blocking(move || hash.verify(&password))
+                .await
+                .unwrap_or(false);
+            if verified {
+                return None;
+            }
+            warn!(user, path = %request.path, "Basic auth failed");
+        }
+
+        Some(
+            HttpResponse::new(HttpStatusCode::Unauthorized)
+                .with_header(
+                    "www-authenticate",
+                    &format!(r#"Basic realm="{}", charset="UTF-8""#, self.realm),
+                )
+                .with_text("Unauthorized"),
+        )
+    }
+}
+
+/// Decode `Basic base64(user:password)`.
+fn decode_credentials(header: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Compare without short-circuiting, so timing does not reveal the prefix
+/// that matched.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, authorization: Option<&str>) -> HttpRequest {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        if let Some(value) = authorization {
+            raw.push_str(&format!("Authorization: {}\r\n", value));
+        }
+        raw.push_str("\r\n");
+        HttpRequest::from_buffer_sync(raw.as_bytes()).unwrap()
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            general_purpose::STANDARD.encode(format!("{}:{}", user, password))
+        )
+    }
+
+    #[tokio::test]
+    async fn test_htpasswd_formats() {
+        let bcrypt_hash = bcrypt::hash("s3cret", 4).unwrap();
+        let auth = BasicAuth::new("admin", &["/admin"]).with_htpasswd(&format!(
+            "# users\nalice:{}\nbob:{{SHA}}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\ncarol:plain\n",
+            bcrypt_hash
+        ));
+
+        assert!(
+            auth.check(&request("/admin", Some(&basic("alice", "s3cret"))))
+                .await
+                .is_none()
+        );
+        // {SHA} of "password"
+        assert!(
+            auth.check(&request("/admin/x", Some(&basic("bob", "password"))))
+                .await
+                .is_none()
+        );
+        assert!(
+            auth.check(&request("/admin", Some(&basic("carol", "plain"))))
+                .await
+                .is_none()
+        );
+        assert!(
+            auth.check(&request("/admin", Some(&basic("carol", "wrong"))))
+                .await
+                .is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_challenge_and_unprotected_paths() {
+        let auth = BasicAuth::new("admin", &["/admin"]).with_user("carol", "plain");
+
+        assert!(auth.check(&request("/administrator", None)).await.is_none());
+        // Paths that reach the protected files in another spelling
+        for path in [
+            "//admin/secret",
+            "/public/../admin/secret",
+            "/./admin",
+            "/%61dmin/secret",
+            "/public/%2e%2e/admin",
+        ] {
+            let response = auth.check(&request(path, None)).await;
+            assert!(response.is_some(), "{} got through", path);
+        }
+
+        let response = auth.check(&request("/admin", None)).await.unwrap();
+        assert_eq!(response.status, HttpStatusCode::Unauthorized);
+        assert_eq!(
+            response.headers.get("www-authenticate"),
+            Some(&r#"Basic realm="admin", charset="UTF-8""#.to_string())
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use crate::{
+    error::{Result, ServerError},
+    middleware,
+    protocol::{
+        request::HttpRequest,
+        response::{HttpResponse, HttpStatusCode},
+    },
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, errors::ErrorKind};
+use serde_json::Value;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Claims of a verified token, attached to the request's extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JwtClaims(pub Value);
+
+impl JwtClaims {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+
+    /// The `sub` claim, if present and a string.
+    pub fn subject(&self) -> Option<&str> {
+        self.get("sub").and_then(Value::as_str)
+    }
+}
+
+/// Bearer token authentication (RFC 6750) for a set of path prefixes.
+/// Tokens must be signed with the configured key and not be expired; if an
+/// audience is set, their `aud` claim must contain it.
+#[derive(Clone)]
+pub struct JwtAuth {
+    realm: String,
+    prefixes: Vec<String>,
+    key: Arc<DecodingKey>,
+    validation: Validation,
+}
+
+impl std::fmt::Debug for JwtAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtAuth")
+            .field("realm", &self.realm)
+            .field("prefixes", &self.prefixes)
+            .field("algorithms", &self.validation.algorithms)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtAuth {
+    /// Verify HS256 tokens against a shared secret.
+    pub fn hs256(secret: &[u8], realm: &str, prefixes: &[&str]) -> Self {
+        Self::with_key(
+            DecodingKey::from_secret(secret),
+            Algorithm::HS256,
+            realm,
+            prefixes,
+        )
+    }
+
+    /// Verify RS256 tokens against a PEM-encoded RSA public key.
+    pub fn rs256(public_key_pem: &[u8], realm: &str, prefixes: &[&str]) -> Result<Self> {
+        let key = DecodingKey::from_rsa_pem(public_key_pem)
+            .map_err(|e| ServerError::InvalidConfig(format!("Invalid RSA public key: {}", e)))?;
+        Ok(Self::with_key(key, Algorithm::RS256, realm, prefixes))
+    }
+
+    fn with_key(key: DecodingKey, algorithm: Algorithm, realm: &str, prefixes: &[&str]) -> Self {
+        let mut validation = Validation::new(algorithm);
+        validation.validate_aud = false;
+
+        Self {
+            realm: realm.to_string(),
+            prefixes: prefixes.iter().map(|prefix| prefix.to_string()).collect(),
+            key: Arc::new(key),
+            validation,
+        }
+    }
+
+    /// Require the token's `aud` claim to contain `audience`.
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.validation.set_audience(&[audience]);
+        self.validation.validate_aud = true;
+        self
+    }
+
+    fn protects(&self, path: &str) -> bool {
+        self.prefixes
+            .iter()
+            .any(|prefix| middleware::matches_prefix(prefix, path))
+    }
+
+    /// Let the request through if its path is unprotected or it carries a
+    /// valid token, in which case the claims are attached as [`JwtClaims`].
+    /// Otherwise answer with a 401 challenge.
+    pub fn check(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        if !self.protects(&request.path) {
+            return None;
+        }
+
+        let Some(token) = request
+            .get_header("authorization")
+            .and_then(|value| bearer_token(value))
+        else {
+            return Some(self.challenge(None));
+        };
+
+        match jsonwebtoken::decode::<Value>(token, &self.key, &self.validation) {
+            Ok(data) => {
+                request.extensions.insert(JwtClaims(data.claims));
+                None
+            }
+            Err(e) => {
+                warn!(path = %request.path, error = %e, "Rejected bearer token");
+                let description = match e.kind() {
+                    ErrorKind::ExpiredSignature => "The token has expired",
+                    ErrorKind::InvalidAudience => "The token audience is not accepted",
+                    _ => "The token is invalid",
+                };
+                Some(self.challenge(Some(description)))
+            }
+        }
+    }
+
+    fn challenge(&self, error_description: Option<&str>) -> HttpResponse {
+        let challenge = match error_description {
+            Some(description) => format!(
+                r#"Bearer realm="{}", error="invalid_token", error_description="{}""#,
+                self.realm, description
+            ),
+            None => format!(r#"Bearer realm="{}""#, self.realm),
+        };
+
+        HttpResponse::new(HttpStatusCode::Unauthorized)
+            .with_header("www-authenticate", &challenge)
+            .with_text("Unauthorized")
+    }
+}
+
+fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    Some(token.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &[u8] = b"test-secret";
+
+    fn token(claims: Value) -> String {
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET),
+        )
+        .unwrap()
+    }
+
+    fn request(path: &str, token: Option<&str>) -> HttpRequest {
+        let mut raw = format!("GET {} HTTP/1.1\r\n", path);
+        if let Some(token) = token {
+            raw.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        raw.push_str("\r\n");
+        HttpRequest::from_buffer_sync(raw.as_bytes()).unwrap()
+    }
+
+    fn now() -> u64 {
+        jsonwebtoken::get_current_timestamp()
+    }
+
+    #[test]
+    fn test_valid_token_exposes_claims() {
+        let auth = JwtAuth::hs256(SECRET, "api", &["/api"]).with_audience("web");
+        let token = token(json!({"sub": "alice", "aud": "web", "exp": now() + 60}));
+        let mut request = request("/api/me", Some(&token));
+
+        assert!(auth.check(&mut request).is_none());
+        let claims = request.extensions.get::<JwtClaims>().unwrap();
+        assert_eq!(claims.subject(), Some("alice"));
+    }
+
+    #[test]
+    fn test_other_spellings_of_protected_paths_need_a_token() {
+        let auth = JwtAuth::hs256(SECRET, "api", &["/api"]);
+
+        for path in ["//api/me", "/public/../api/me", "/%61pi", "/x/%2e%2e/api"] {
+            assert!(auth.check(&mut request(path, None)).is_some(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_rejections() {
+        let auth = JwtAuth::hs256(SECRET, "api", &["/api"]).with_audience("web");
+
+        let missing = auth.check(&mut request("/api", None)).unwrap();
+        assert_eq!(missing.status, HttpStatusCode::Unauthorized);
+        assert_eq!(
+            missing.headers.get("www-authenticate"),
+            Some(&r#"Bearer realm="api""#.to_string())
+        );
+
+        let expired = token(json!({"aud": "web", "exp": now() - 3600}));
+        let response = auth.check(&mut request("/api", Some(&expired))).unwrap();
+        assert!(response.headers["www-authenticate"].contains("has expired"));
+
+        let wrong_audience = token(json!({"aud": "admin", "exp": now() + 60}));
+        assert!(
+            auth.check(&mut request("/api", Some(&wrong_audience)))
+                .is_some()
+        );
+
+        let forged = jsonwebtoken::encode(
+            &Header::default(),
+            &json!({"aud": "web", "exp": now() + 60}),
+            &EncodingKey::from_secret(b"other-secret"),
+        )
+        .unwrap();
+        assert!(auth.check(&mut request("/api", Some(&forged))).is_some());
+
+        assert!(auth.check(&mut request("/public", None)).is_none());
+    }
+}
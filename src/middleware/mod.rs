@@ -0,0 +1,25 @@
+//! Checks applied to every request before it is routed. Each one either lets
+//! the request through or answers it with a rejection response.
+
+pub mod basic_auth;
+pub mod jwt;
+pub mod rate_limit;
+
+use crate::protocol::path;
+
+/// Whether `path` falls under `prefix`, on a path segment boundary
+/// (`/admin` covers `/admin/x` but not `/administrator`). The path is
+/// [normalized](path::normalize) first, so no other spelling of a path
+/// under the prefix escapes it.
+pub fn matches_prefix(prefix: &str, path: &str) -> bool {
+    let path = path::normalize(path);
+    match path.strip_prefix(prefix) {
+        Some(rest) => {
+            prefix.ends_with('/')
+                || rest.is_empty()
+                || rest.starts_with('/')
+                || rest.starts_with('?')
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{
+    middleware,
+    protocol::response::{HttpResponse, HttpStatusCode},
+};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Number of tracked clients above which idle buckets are swept.
+const SWEEP_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token-bucket rate limiter keyed by client IP. Each client may burst up to
+/// `burst` requests, refilled at `rate` requests per second. Cloning shares
+/// the same buckets.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    status: HttpStatusCode,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            status: HttpStatusCode::TooManyRequests,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reject with `status` instead of 429.
+    pub fn with_status(mut self, status: HttpStatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Requests per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn status(&self) -> HttpStatusCode {
+        self.status
+    }
+
+    /// Take a token for `ip`, or return a rejection (429 unless set
+    /// otherwise) if it has none left.
+    pub fn check(&self, ip: IpAddr) -> Option<HttpResponse> {
+        let retry_after = self.acquire(ip, Instant::now()).err()?;
+
+        Some(
+            HttpResponse::new(self.status)
+                .with_header("retry-after", &retry_after.to_string())
+                .with_text("Too many requests"),
+        )
+    }
+
+    /// Take a token at `now`, or return the whole seconds until one is available.
+    fn acquire(&self, ip: IpAddr, now: Instant) -> Result<(), u64> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > SWEEP_THRESHOLD {
+            // Buckets that have refilled completely carry no state worth keeping
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / self.rate).ceil().max(1.0) as u64)
+        }
+    }
+}
+
+/// A rate limit of its own for paths under `prefix`, applied on top of
+/// any limit for all paths.
+#[derive(Debug, Clone)]
+pub struct RouteRateLimit {
+    pub prefix: String,
+    pub limiter: RateLimiter,
+}
+
+impl RouteRateLimit {
+    pub fn new(prefix: &str, limiter: RateLimiter) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            limiter,
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        middleware::matches_prefix(&self.prefix, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = RateLimiter::new(0.5, 2);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+
+        assert!(limiter.acquire(ip, start).is_ok());
+        assert!(limiter.acquire(ip, start).is_ok());
+        assert_eq!(limiter.acquire(ip, start), Err(2));
+
+        // Other clients have their own bucket
+        assert!(limiter.acquire("10.0.0.2".parse().unwrap(), start).is_ok());
+
+        assert!(limiter.acquire(ip, start + Duration::from_secs(2)).is_ok());
+        assert!(limiter.acquire(ip, start + Duration::from_secs(2)).is_err());
+    }
+
+    #[test]
+    fn test_check_sets_retry_after() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let ip: IpAddr = "::1".parse().unwrap();
+
+        assert!(limiter.check(ip).is_none());
+        let response = limiter.check(ip).unwrap();
+        assert_eq!(response.status, HttpStatusCode::TooManyRequests);
+        assert_eq!(response.headers.get("retry-after"), Some(&"1".to_string()));
+
+        let limiter = RateLimiter::new(1.0, 1).with_status(HttpStatusCode::ServiceUnavailable);
+        assert!(limiter.check(ip).is_none());
+        assert_eq!(
+            limiter.check(ip).unwrap().status,
+            HttpStatusCode::ServiceUnavailable
+        );
+    }
+}
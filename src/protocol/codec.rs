@@ -0,0 +1,1009 @@
+//! Sans-IO HTTP/1.1 codec.
+//!
+//! The decoder is fed raw bytes and yields parse events; the encoders turn
+//! messages into wire bytes. Requests are decoded on the server side and
+//! responses on the proxy side, with the same body framing rules. Nothing in
+//! here knows about sockets or the async runtime, so the connection layer,
+//! tests and fuzzers all share it.
+
+use crate::{
+    error::{Result, ServerError},
+    protocol::{
+        headers::Headers,
+        request::{Extensions, HttpMethod, HttpRequest},
+        response::HttpResponse,
+    },
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::Utc;
+use std::{cell::RefCell, marker::PhantomData};
+
+/// Default maximum size of the start line plus headers.
+pub const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+/// Default maximum number of header fields in a message.
+pub const MAX_HEADER_COUNT: usize = 100;
+
+/// Default maximum size of a message body, however it is framed.
+pub const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+/// Default maximum length of a request target (path and query).
+pub const MAX_URI_LENGTH: usize = 8 * 1024;
+
+/// Default maximum number of chunks in a chunked body.
+pub const MAX_CHUNK_COUNT: usize = 64 * 1024;
+
+/// Maximum length of a chunk-size line (hex size plus extensions).
+const MAX_CHUNK_SIZE_LINE: usize = 20;
+
+/// Room made for the first read, and the least made for any.
+const MIN_READ_SIZE: usize = 2048;
+
+/// Size limits enforced on every decoded message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Largest start line plus headers, in bytes; larger heads get 431.
+    pub max_header_size: usize,
+    /// Most header fields in one message; more get 431.
+    pub max_header_count: usize,
+    /// Largest body, however it is framed; larger ones get 413.
+    pub max_body_size: usize,
+    /// Longest request target (path and query); longer ones get 414.
+    pub max_uri_length: usize,
+    /// Most chunks in a chunked body, so it cannot be sent a few bytes at a
+    /// time.
+    pub max_chunk_count: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_header_size: MAX_HEADER_SIZE,
+            max_header_count: MAX_HEADER_COUNT,
+            max_body_size: MAX_BODY_SIZE,
+            max_uri_length: MAX_URI_LENGTH,
+            max_chunk_count: MAX_CHUNK_COUNT,
+        }
+    }
+}
+
+/// Something the decoder recognised in the byte stream.
+#[derive(Debug)]
+pub enum Event<H> {
+    /// A message head was parsed. The payload follows as `Body` events.
+    Head(H),
+    /// A piece of the message body, already de-chunked.
+    Body(Bytes),
+    /// The current message is complete and the decoder is ready for the next.
+    End,
+}
+
+/// How the body following a message head is delimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    None,
+    Length(usize),
+    Chunked,
+    /// The body runs until the peer closes the connection (responses only).
+    UntilClose,
+}
+
+/// A start line plus headers that the decoder knows how to parse.
+pub trait MessageHead: Sized {
+    fn parse(buffer: &[u8]) -> Result<Self>;
+    fn framing(&self) -> Result<Framing>;
+
+    /// Length of the request target, checked against `max_uri_length`.
+    fn target_len(&self) -> usize {
+        0
+    }
+}
+
+impl MessageHead for HttpRequest {
+    fn parse(buffer: &[u8]) -> Result<Self> {
+        parse_head(buffer)
+    }
+
+    fn framing(&self) -> Result<Framing> {
+        header_framing(&self.headers, Framing::None)
+    }
+
+    fn target_len(&self) -> usize {
+        self.path.len()
+    }
+}
+
+/// Status line and headers of a response received from a peer.
+#[derive(Debug, Clone)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    pub fn get_header(&self, name: &str) -> Option<&String> {
+        self.headers.get(name)
+    }
+}
+
+impl MessageHead for ResponseHead {
+    fn parse(buffer: &[u8]) -> Result<Self> {
+        parse_response_head(buffer)
+    }
+
+    fn framing(&self) -> Result<Framing> {
+        // 1xx, 204 and 304 responses never carry a body
+        if (100..200).contains(&self.status) || self.status == 204 || self.status == 304 {
+            return Ok(Framing::None);
+        }
+        header_framing(&self.headers, Framing::UntilClose)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Head,
+    Length(usize),
+    UntilClose,
+    ChunkSize,
+    ChunkData(usize),
+    ChunkDataEnd,
+    Trailers,
+    Done,
+}
+
+/// Incremental message decoder.
+///
+/// Bytes read from the transport go in through [`feed`](Self::feed); each
+/// call to [`decode`](Self::decode) returns the next event, or `None` when
+/// more input is needed. Bytes past the end of one message stay buffered for
+/// the next, so keep-alive and pipelined messages decode naturally.
+#[derive(Debug)]
+pub struct Decoder<H> {
+    buffer: BytesMut,
+    state: State,
+    body_len: usize,
+    chunk_count: usize,
+    /// How much of `buffer` is known not to contain the end of the head, so
+    /// each read only scans the new bytes.
+    head_scanned: usize,
+    /// Room made for the next read, adapted to what recent reads brought.
+    read_size: usize,
+    limits: Limits,
+    _head: PhantomData<H>,
+}
+
+pub type RequestDecoder = Decoder<HttpRequest>;
+pub type ResponseDecoder = Decoder<ResponseHead>;
+
+impl<H: MessageHead> Default for Decoder<H> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H: MessageHead> Decoder<H> {
+    pub fn new() -> Self {
+        Self {
+            buffer: BytesMut::with_capacity(MIN_READ_SIZE),
+            state: State::Head,
+            body_len: 0,
+            chunk_count: 0,
+            head_scanned: 0,
+            read_size: MIN_READ_SIZE,
+            limits: Limits::default(),
+            _head: PhantomData,
+        }
+    }
+
+    /// Enforce `limits` from the next message on. Heads over the size or
+    /// field count fail decoding with [`ServerError::HeadersTooLarge`], long
+    /// targets with [`ServerError::UriTooLong`].
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Limit the body of the current message (and of later ones) to `limit`
+    /// bytes. Call right after `Event::Head` to apply a per-route limit;
+    /// exceeding it fails decoding with [`ServerError::BodyTooLarge`].
+    pub fn set_max_body_size(&mut self, limit: usize) {
+        self.limits.max_body_size = limit;
+    }
+
+    /// Append bytes received from the peer.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// True between the head of a message and the end of its body.
+    pub fn in_body(&self) -> bool {
+        !matches!(self.state, State::Head | State::Done)
+    }
+
+    /// The input buffer with room for the next read from the transport,
+    /// e.g. with `AsyncReadExt::read_buf`; report how much it brought with
+    /// [`record_read`](Self::record_read). The room starts at 2KB and
+    /// adapts to the peer's messages, up to `max_header_size`.
+    pub fn read_buffer(&mut self) -> &mut BytesMut {
+        // Let go of a buffer grown for an earlier, larger message
+        if self.buffer.is_empty() && self.buffer.capacity() > 4 * self.read_size {
+            self.buffer = BytesMut::with_capacity(self.read_size);
+        }
+        self.buffer.reserve(self.read_size);
+        &mut self.buffer
+    }
+
+    /// Adapt the room made for reads to a read of `n` bytes: double it
+    /// after a read that filled it, halve it after one that used a quarter.
+    pub fn record_read(&mut self, n: usize) {
+        let max_read_size = self.limits.max_header_size.max(MIN_READ_SIZE);
+        if n >= self.read_size {
+            self.read_size = (self.read_size * 2).min(max_read_size);
+        } else if n <= self.read_size / 4 {
+            self.read_size = (self.read_size / 2).max(MIN_READ_SIZE);
+        }
+    }
+
+    /// True when no message is in progress and nothing is buffered.
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Head && self.buffer.is_empty()
+    }
+
+    /// Bytes received but not yet consumed by the decoder.
+    pub fn buffered(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// Treat the current message as having no body, whatever its headers say
+    /// (e.g. the response to a HEAD request). Call right after `Event::Head`.
+    pub fn skip_body(&mut self) {
+        self.state = State::Done;
+    }
+
+    /// Signal that the peer closed the connection. Returns true if this
+    /// completed a close-delimited body, in which case `decode` yields `End`.
+    pub fn finish(&mut self) -> bool {
+        if self.state == State::UntilClose {
+            self.state = State::Done;
+            return true;
+        }
+        false
+    }
+
+    /// Produce the next event, or `None` if more bytes are needed.
+    pub fn decode(&mut self) -> Result<Option<Event<H>>> {
+        loop {
+            match self.state {
+                State::Head => {
+                    let Some(header_end) = self.find_head_end() else {
+                        // Prevent header bombs
+                        if self.buffer.len() > self.limits.max_header_size {
+                            return Err(ServerError::HeadersTooLarge);
+                        }
+                        return Ok(None);
+                    };
+                    if header_end > self.limits.max_header_size {
+                        return Err(ServerError::HeadersTooLarge);
+                    }
+
+                    let head = self.buffer.split_to(header_end);
+                    // Count the lines the parser will see, whatever ends them
+                    let field_count = head_lines(&head)
+                        .skip(1)
+                        .take_while(|line| !line.is_empty())
+                        .count();
+                    if field_count > self.limits.max_header_count {
+                        return Err(ServerError::HeadersTooLarge);
+                    }
+                    let head = H::parse(&head)?;
+                    if head.target_len() > self.limits.max_uri_length {
+                        return Err(ServerError::UriTooLong(self.limits.max_uri_length));
+                    }
+                    self.state = match head.framing()? {
+                        Framing::None | Framing::Length(0) => State::Done,
+                        Framing::Length(length) => State::Length(length),
+                        Framing::Chunked => State::ChunkSize,
+                        Framing::UntilClose => State::UntilClose,
+                    };
+                    self.body_len = 0;
+                    self.chunk_count = 0;
+                    return Ok(Some(Event::Head(head)));
+                }
+                State::Length(remaining) => {
+                    // The declared length is known up front, so reject it
+                    // before any of the body is consumed
+                    if self.body_len + remaining > self.limits.max_body_size {
+                        return Err(ServerError::BodyTooLarge(self.limits.max_body_size));
+                    }
+                    let Some(chunk) = self.take_body(remaining) else {
+                        return Ok(None);
+                    };
+                    self.body_len += chunk.len();
+                    let remaining = remaining - chunk.len();
+                    self.state = if remaining == 0 {
+                        State::Done
+                    } else {
+                        State::Length(remaining)
+                    };
+                    return Ok(Some(Event::Body(chunk)));
+                }
+                State::UntilClose => {
+                    let Some(chunk) = self.take_body(self.buffer.len()) else {
+                        return Ok(None);
+                    };
+                    if self.body_len + chunk.len() > self.limits.max_body_size {
+                        return Err(ServerError::BodyTooLarge(self.limits.max_body_size));
+                    }
+                    self.body_len += chunk.len();
+                    return Ok(Some(Event::Body(chunk)));
+                }
+                State::ChunkSize => {
+                    let Some(line) = self.take_line(MAX_CHUNK_SIZE_LINE, "Invalid chunk size")?
+                    else {
+                        return Ok(None);
+                    };
+
+                    // Parse chunk size (ignore chunk extensions)
+                    let size_str = String::from_utf8_lossy(&line);
+                    let size_hex = size_str.split(';').next().unwrap_or("").trim();
+                    let chunk_size = usize::from_str_radix(size_hex, 16)
+                        .map_err(|_| ServerError::InvalidHttpRequest("Invalid chunk size"))?;
+
+                    if chunk_size == 0 {
+                        self.state = State::Trailers;
+                        continue;
+                    }
+
+                    self.chunk_count += 1;
+                    if self.chunk_count > self.limits.max_chunk_count {
+                        return Err(ServerError::InvalidHttpRequest("Too many chunks"));
+                    }
+
+                    if self.body_len + chunk_size > self.limits.max_body_size {
+                        return Err(ServerError::BodyTooLarge(self.limits.max_body_size));
+                    }
+
+                    self.body_len += chunk_size;
+                    self.state = State::ChunkData(chunk_size);
+                }
+                State::ChunkData(remaining) => {
+                    let Some(chunk) = self.take_body(remaining) else {
+                        return Ok(None);
+                    };
+                    let remaining = remaining - chunk.len();
+                    self.state = if remaining == 0 {
+                        State::ChunkDataEnd
+                    } else {
+                        State::ChunkData(remaining)
+                    };
+                    return Ok(Some(Event::Body(chunk)));
+                }
+                State::ChunkDataEnd => {
+                    // Trailing CRLF after chunk data
+                    if self.buffer.len() < 2 {
+                        return Ok(None);
+                    }
+                    if &self.buffer[..2] != b"\r\n" {
+                        return Err(ServerError::InvalidHttpRequest("Invalid chunk terminator"));
+                    }
+                    self.buffer.advance(2);
+                    self.state = State::ChunkSize;
+                }
+                State::Trailers => {
+                    // Trailer fields are read and discarded up to the empty line
+                    let Some(line) =
+                        self.take_line(self.limits.max_header_size, "Trailers too large")?
+                    else {
+                        return Ok(None);
+                    };
+                    if line.is_empty() {
+                        self.state = State::Done;
+                    }
+                }
+                State::Done => {
+                    self.state = State::Head;
+                    return Ok(Some(Event::End));
+                }
+            }
+        }
+    }
+
+    /// Split off up to `remaining` body bytes, or `None` if nothing is buffered.
+    fn take_body(&mut self, remaining: usize) -> Option<Bytes> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let n = remaining.min(self.buffer.len());
+        Some(self.buffer.split_to(n).freeze())
+    }
+
+    /// Continue the search for the end of the head where the last one
+    /// stopped; the terminator may straddle the old and new bytes.
+    fn find_head_end(&mut self) -> Option<usize> {
+        let start = self.head_scanned.saturating_sub(3);
+        match find_header_end(&self.buffer[start..]) {
+            Some(end) => {
+                self.head_scanned = 0;
+                Some(start + end)
+            }
+            None => {
+                self.head_scanned = self.buffer.len();
+                None
+            }
+        }
+    }
+
+    /// Split off a CRLF-terminated line (without the CRLF).
+    fn take_line(&mut self, max_len: usize, error: &'static str) -> Result<Option<BytesMut>> {
+        match self.buffer.windows(2).position(|w| w == b"\r\n") {
+            Some(pos) if pos > max_len => Err(ServerError::InvalidHttpRequest(error)),
+            Some(pos) => {
+                let line = self.buffer.split_to(pos);
+                self.buffer.advance(2);
+                Ok(Some(line))
+            }
+            None if self.buffer.len() > max_len => Err(ServerError::InvalidHttpRequest(error)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Derive body framing from Content-Length / Transfer-Encoding, falling back
+/// to `default` when neither is present. Anything that two parsers could
+/// read differently (RFC 9112 section 6.3) is rejected: both headers at
+/// once, Content-Length values that disagree or are not plain digits, and
+/// transfer codings not ending in a single `chunked`.
+fn header_framing(headers: &Headers, default: Framing) -> Result<Framing> {
+    let length = content_length(headers)?;
+    if !headers.contains_key("transfer-encoding") {
+        return Ok(length.map_or(default, Framing::Length));
+    }
+    if length.is_some() {
+        return Err(ServerError::InvalidHttpRequest(
+            "Both Content-Length and Transfer-Encoding",
+        ));
+    }
+
+    let codings = list_elements(headers.get_all("transfer-encoding"));
+    let mut codings = codings.iter().rev();
+    if !codings
+        .next()
+        .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+    {
+        return Err(ServerError::InvalidHttpRequest(
+            "Chunked is not the final transfer coding",
+        ));
+    }
+    match codings.next() {
+        None => Ok(Framing::Chunked),
+        Some(coding) if coding.eq_ignore_ascii_case("chunked") => Err(
+            ServerError::InvalidHttpRequest("Chunked transfer coding applied twice"),
+        ),
+        Some(coding) => Err(ServerError::UnsupportedTransferCoding(coding.to_string())),
+    }
+}
+
+/// The body length given by Content-Length, which may be repeated (or a
+/// list) as long as every value is the same digits-only number.
+fn content_length(headers: &Headers) -> Result<Option<usize>> {
+    let mut length = None;
+    let values = headers
+        .get_all("content-length")
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+    for value in values {
+        // `parse` alone would also take a leading `+`
+        if !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ServerError::InvalidHttpRequest("Invalid Content-Length"));
+        }
+        let parsed: usize = value
+            .parse()
+            .map_err(|_| ServerError::InvalidHttpRequest("Invalid Content-Length"))?;
+        if length.is_some_and(|length| length != parsed) {
+            return Err(ServerError::InvalidHttpRequest(
+                "Conflicting Content-Length values",
+            ));
+        }
+        length = Some(parsed);
+    }
+    Ok(length)
+}
+
+/// The elements of comma-separated list fields, trimmed, with empty ones
+/// skipped as RFC 9110 section 5.6.1 asks of recipients.
+fn list_elements<'a>(values: impl Iterator<Item = &'a String>) -> Vec<&'a str> {
+    values
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|element| !element.is_empty())
+        .collect()
+}
+
+/// Find the position after \r\n\r\n in the buffer
+pub fn find_header_end(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+/// The lines of a message head, without their line endings. Bare LF is
+/// accepted as well as CRLF.
+fn head_lines(buffer: &[u8]) -> impl Iterator<Item = &[u8]> {
+    buffer
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+/// Collect header fields up to the empty line ending the head. Values are
+/// trimmed and repeated fields kept; lines without a colon are ignored.
+fn parse_fields<'a>(lines: impl Iterator<Item = &'a [u8]>) -> Headers {
+    let mut headers = Headers::new();
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        if let Some(colon) = line.iter().position(|&b| b == b':') {
+            let name = String::from_utf8_lossy(line[..colon].trim_ascii()).into_owned();
+            let value = String::from_utf8_lossy(line[colon + 1..].trim_ascii()).into_owned();
+            headers.append(name, value);
+        }
+    }
+    headers
+}
+
+/// Parse a request line and headers in one pass over the head. The returned
+/// request has an empty body.
+pub fn parse_head(buffer: &[u8]) -> Result<HttpRequest> {
+    if buffer.is_empty() {
+        return Err(ServerError::InvalidHttpRequest("Empty request"));
+    }
+    let mut lines = head_lines(buffer);
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|part| !part.is_empty())
+        .map(String::from_utf8_lossy);
+    let (Some(method), Some(path), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ServerError::InvalidHttpRequest("Invalid request line"));
+    };
+
+    Ok(HttpRequest {
+        method: method.parse::<HttpMethod>()?,
+        path: path.into_owned(),
+        version: version.into_owned(),
+        headers: parse_fields(lines),
+        body: Bytes::new(),
+        extensions: Extensions::default(),
+    })
+}
+
+/// Parse a status line and headers.
+pub fn parse_response_head(buffer: &[u8]) -> Result<ResponseHead> {
+    if buffer.is_empty() {
+        return Err(ServerError::InvalidHttpRequest("Empty response"));
+    }
+    let mut lines = head_lines(buffer);
+
+    // Parse status line; the reason phrase may contain spaces or be empty
+    let status_line = lines.next().unwrap_or_default();
+    let mut parts = status_line.splitn(3, |&b| b == b' ');
+    let version = String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+    let status = parts
+        .next()
+        .and_then(|code| std::str::from_utf8(code).ok()?.parse::<u16>().ok())
+        .ok_or(ServerError::InvalidHttpRequest("Invalid status line"))?;
+    let reason = String::from_utf8_lossy(parts.next().unwrap_or_default()).into_owned();
+
+    if !version.starts_with("HTTP/") {
+        return Err(ServerError::InvalidHttpRequest("Invalid status line"));
+    }
+
+    Ok(ResponseHead {
+        version,
+        status,
+        reason,
+        headers: parse_fields(lines),
+    })
+}
+
+/// Serialize a request as-is. The caller is responsible for framing headers
+/// (`content-length`) matching `request.body`.
+pub fn encode_request(request: &HttpRequest) -> Vec<u8> {
+    let mut head = format!(
+        "{} {} {}\r\n",
+        request.method, request.path, request.version
+    );
+
+    for (name, value) in &request.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    head.push_str("\r\n");
+
+    let mut bytes = head.into_bytes();
+    bytes.extend(&request.body);
+    bytes
+}
+
+/// Serialize a response, adding the standard headers it does not set itself.
+pub fn encode_response(response: &HttpResponse) -> Vec<u8> {
+    let mut bytes = encode_response_head(response);
+    bytes.extend(&response.body);
+    bytes
+}
+
+/// Serialize the status line and headers of a response, as
+/// [`encode_response`] does, without the body.
+pub fn encode_response_head(response: &HttpResponse) -> Vec<u8> {
+    let mut head = Vec::with_capacity(256);
+    encode_response_head_into(response, &mut head);
+    head
+}
+
+/// Append the status line and headers of a response to `dst`, adding the
+/// standard headers it does not set itself. Nothing is allocated besides
+/// what `dst` needs to grow.
+pub fn encode_response_head_into(response: &HttpResponse, dst: &mut impl BufMut) {
+    let code = response.status.code();
+    dst.put_slice(b"HTTP/1.1 ");
+    dst.put_slice(&[
+        b'0' + (code / 100) as u8,
+        b'0' + (code / 10 % 10) as u8,
+        b'0' + (code % 10) as u8,
+        b' ',
+    ]);
+    dst.put_slice(response.status.reason_phrase().as_bytes());
+    dst.put_slice(b"\r\n");
+
+    for (name, value) in &response.headers {
+        put_field(dst, name.as_bytes(), value.as_bytes());
+    }
+
+    // Add standard headers if not already present
+    let headers = &response.headers;
+    if !headers.contains_key("date") {
+        HTTP_DATE.with_borrow_mut(|(second, date)| {
+            let now = Utc::now();
+            if now.timestamp() != *second {
+                *second = now.timestamp();
+                *date = now.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+            }
+            put_field(dst, b"date", date.as_bytes());
+        });
+    }
+    if !headers.contains_key("server") {
+        put_field(dst, b"server", b"http-rs/0.1.0");
+    }
+    // Add Connection header for keep-alive
+    if !headers.contains_key("connection") {
+        if response.keep_alive && response.status.is_success() {
+            put_field(dst, b"connection", b"keep-alive");
+            if !headers.contains_key("keep-alive") {
+                put_field(dst, b"keep-alive", b"timeout=5, max=100");
+            }
+        } else {
+            put_field(dst, b"connection", b"close");
+        }
+    }
+
+    dst.put_slice(b"\r\n");
+}
+
+thread_local! {
+    /// The `Date` value for the current second, formatted once per second
+    /// per thread.
+    static HTTP_DATE: RefCell<(i64, String)> = const { RefCell::new((i64::MIN, String::new())) };
+}
+
+fn put_field(dst: &mut impl BufMut, name: &[u8], value: &[u8]) {
+    dst.put_slice(name);
+    dst.put_slice(b": ");
+    dst.put_slice(value);
+    dst.put_slice(b"\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::response::HttpStatusCode;
+
+    fn decode_all<H: MessageHead>(decoder: &mut Decoder<H>) -> Vec<Event<H>> {
+        let mut events = Vec::new();
+        while let Some(event) = decoder.decode().unwrap() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn test_decode_content_length_body_split_across_feeds() {
+        let mut decoder = RequestDecoder::new();
+        decoder.feed(b"POST /echo HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello");
+
+        let events = decode_all(&mut decoder);
+        assert!(matches!(&events[0], Event::Head(r) if r.path == "/echo"));
+        assert!(matches!(&events[1], Event::Body(b) if *b == b"hello"[..]));
+        assert_eq!(events.len(), 2);
+
+        decoder.feed(b" world");
+        let events = decode_all(&mut decoder);
+        assert!(matches!(&events[0], Event::Body(b) if *b == b" world"[..]));
+        assert!(matches!(events[1], Event::End));
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn test_decode_chunked_body_with_trailers() {
+        let mut decoder = RequestDecoder::new();
+        decoder.feed(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: yes\r\n\r\n",
+        );
+
+        let body: Vec<u8> = decode_all(&mut decoder)
+            .into_iter()
+            .filter_map(|event| match event {
+                Event::Body(chunk) => Some(chunk),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+
+        assert_eq!(body, b"hello world");
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn test_decode_framing_is_unambiguous() {
+        let framing = |fields: &str| {
+            parse_head(format!("POST / HTTP/1.1\r\n{}\r\n", fields).as_bytes())
+                .unwrap()
+                .framing()
+        };
+
+        assert_eq!(
+            framing("Content-Length: 5\r\n").unwrap(),
+            Framing::Length(5)
+        );
+        assert_eq!(
+            framing("Content-Length: 5\r\nContent-Length: 5\r\n").unwrap(),
+            Framing::Length(5)
+        );
+        assert_eq!(
+            framing("Transfer-Encoding: Chunked\r\n").unwrap(),
+            Framing::Chunked
+        );
+        assert_eq!(
+            framing("Transfer-Encoding: ,chunked\r\n").unwrap(),
+            Framing::Chunked
+        );
+
+        for fields in [
+            // Smuggling: two parsers picking different headers
+            "Content-Length: 5\r\nTransfer-Encoding: chunked\r\n",
+            "Transfer-Encoding: chunked\r\nContent-Length: 5\r\n",
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "Content-Length: 5, 6\r\n",
+            "Content-Length: +5\r\n",
+            "Content-Length: -5\r\n",
+            "Content-Length: 0x5\r\n",
+            "Content-Length: \r\n",
+            "Content-Length: 99999999999999999999999\r\n",
+            // Chunked must be the final coding, matched as a whole token
+            "Transfer-Encoding: chunkedx\r\n",
+            "Transfer-Encoding: xchunked\r\n",
+            "Transfer-Encoding: chunked, gzip\r\n",
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n",
+            "Transfer-Encoding: gzip\r\n",
+            "Transfer-Encoding: \r\n",
+            "Transfer-Encoding: chunked, chunked\r\n",
+        ] {
+            let error = framing(fields).unwrap_err();
+            assert_eq!(error.status(), HttpStatusCode::BadRequest, "{:?}", fields);
+        }
+
+        // Codings we cannot undo are not implemented, rather than bad
+        let error = framing("Transfer-Encoding: gzip, chunked\r\n").unwrap_err();
+        assert!(matches!(
+            &error,
+            ServerError::UnsupportedTransferCoding(coding) if coding == "gzip"
+        ));
+        assert_eq!(error.status(), HttpStatusCode::NotImplemented);
+    }
+
+    #[test]
+    fn test_decode_keeps_bytes_of_next_request() {
+        let mut decoder = RequestDecoder::new();
+        decoder.feed(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n");
+
+        let events = decode_all(&mut decoder);
+        assert_eq!(events.len(), 4);
+        assert!(matches!(&events[2], Event::Head(r) if r.path == "/b"));
+    }
+
+    #[test]
+    fn test_decode_head_fed_byte_by_byte() {
+        let mut decoder = RequestDecoder::new();
+        let raw = b"GET /a HTTP/1.1\r\nHost: x\r\nX-Empty:\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let mut heads = Vec::new();
+        for byte in raw {
+            decoder.feed(&[*byte]);
+            while let Some(event) = decoder.decode().unwrap() {
+                if let Event::Head(head) = event {
+                    heads.push(head);
+                }
+            }
+        }
+
+        assert_eq!(heads.len(), 2);
+        assert_eq!(heads[0].get_header("host"), Some(&"x".to_string()));
+        assert_eq!(heads[0].get_header("x-empty"), Some(&String::new()));
+        assert_eq!(heads[1].path, "/b");
+    }
+
+    #[test]
+    fn test_parse_head_rejects_bad_request_lines() {
+        assert!(parse_head(b"GET /\r\n\r\n").is_err());
+        assert!(parse_head(b"GET / HTTP/1.1 extra\r\n\r\n").is_err());
+        assert!(parse_head(b"").is_err());
+
+        let head = parse_head(b"GET  /spaced\tHTTP/1.1\nHost: y\n\n").unwrap();
+        assert_eq!(head.path, "/spaced");
+        assert_eq!(head.get_header("host"), Some(&"y".to_string()));
+    }
+
+    #[test]
+    fn test_read_buffer_adapts_to_reads() {
+        let mut decoder = RequestDecoder::new();
+        assert!(decoder.read_buffer().capacity() >= MIN_READ_SIZE);
+
+        // Reads that fill the room make more, up to the header limit
+        for _ in 0..10 {
+            let room = decoder.read_size;
+            decoder.record_read(room);
+        }
+        assert_eq!(decoder.read_size, MAX_HEADER_SIZE);
+
+        // Small reads give it back, and an idle buffer shrinks with it
+        for _ in 0..10 {
+            decoder.record_read(100);
+        }
+        assert_eq!(decoder.read_size, MIN_READ_SIZE);
+        decoder.buffer = BytesMut::with_capacity(MAX_HEADER_SIZE * 2);
+        assert!(decoder.read_buffer().capacity() < MAX_HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_headers() {
+        let mut decoder = RequestDecoder::new();
+        decoder.feed(&vec![b'a'; MAX_HEADER_SIZE + 1]);
+        assert!(decoder.decode().is_err());
+    }
+
+    #[test]
+    fn test_decode_enforces_body_limit_set_after_head() {
+        let mut decoder = RequestDecoder::new();
+        decoder.feed(b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello");
+        assert!(matches!(decoder.decode().unwrap(), Some(Event::Head(_))));
+
+        decoder.set_max_body_size(4);
+        assert!(matches!(
+            decoder.decode(),
+            Err(ServerError::BodyTooLarge(4))
+        ));
+
+        let mut decoder = RequestDecoder::new();
+        decoder.set_max_body_size(4);
+        decoder
+            .feed(b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n");
+        let result = std::iter::from_fn(|| decoder.decode().transpose()).find(Result::is_err);
+        assert!(matches!(result, Some(Err(ServerError::BodyTooLarge(4)))));
+    }
+
+    #[test]
+    fn test_decode_enforces_header_count() {
+        let mut decoder = RequestDecoder::new();
+        decoder.set_limits(Limits {
+            max_header_count: 2,
+            ..Limits::default()
+        });
+        decoder.feed(
+            b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\nGET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n",
+        );
+
+        assert!(matches!(decoder.decode().unwrap(), Some(Event::Head(_))));
+        assert!(matches!(decoder.decode().unwrap(), Some(Event::End)));
+        assert!(matches!(
+            decoder.decode(),
+            Err(ServerError::HeadersTooLarge)
+        ));
+
+        // Fields ended by a bare LF count just the same
+        let mut decoder = RequestDecoder::new();
+        decoder.set_limits(Limits {
+            max_header_count: 2,
+            ..Limits::default()
+        });
+        decoder.feed(b"GET / HTTP/1.1\nA: 1\nB: 2\nC: 3\r\n\r\n");
+        assert!(matches!(
+            decoder.decode(),
+            Err(ServerError::HeadersTooLarge)
+        ));
+    }
+
+    #[test]
+    fn test_decode_enforces_uri_length_and_chunk_count() {
+        let mut decoder = RequestDecoder::new();
+        decoder.set_limits(Limits {
+            max_uri_length: 8,
+            ..Limits::default()
+        });
+        decoder.feed(b"GET /ninechars HTTP/1.1\r\n\r\n");
+        assert!(matches!(decoder.decode(), Err(ServerError::UriTooLong(8))));
+
+        let mut decoder = RequestDecoder::new();
+        decoder.set_limits(Limits {
+            max_chunk_count: 2,
+            ..Limits::default()
+        });
+        decoder.feed(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n");
+        decoder.feed(b"1\r\na\r\n1\r\nb\r\n1\r\nc\r\n0\r\n\r\n");
+        let result = std::iter::from_fn(|| decoder.decode().transpose()).find(Result::is_err);
+        assert!(matches!(
+            result,
+            Some(Err(ServerError::InvalidHttpRequest("Too many chunks")))
+        ));
+    }
+
+    #[test]
+    fn test_decode_close_delimited_response() {
+        let mut decoder = ResponseDecoder::new();
+        decoder.feed(b"HTTP/1.1 418 I'm a teapot\r\nServer: pot\r\n\r\nshort and stout");
+
+        let events = decode_all(&mut decoder);
+        assert!(
+            matches!(&events[0], Event::Head(h) if h.status == 418 && h.reason == "I'm a teapot")
+        );
+        assert!(matches!(&events[1], Event::Body(b) if *b == b"short and stout"[..]));
+        assert_eq!(events.len(), 2);
+
+        assert!(decoder.finish());
+        assert!(matches!(decoder.decode().unwrap(), Some(Event::End)));
+    }
+
+    #[test]
+    fn test_encode_response_head_into_reused_buffer() {
+        let mut buffer = BytesMut::new();
+        let response = HttpResponse::new(HttpStatusCode::NotFound)
+            .with_header("connection", "close")
+            .with_header("date", "Thu, 01 Jan 2026 00:00:00 GMT")
+            .with_text("gone");
+        encode_response_head_into(&response, &mut buffer);
+        let first = buffer.split().freeze();
+        encode_response_head_into(&response, &mut buffer);
+
+        assert_eq!(first, buffer);
+        let text = String::from_utf8_lossy(&first);
+        assert!(text.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert_eq!(text.matches("connection:").count(), 1);
+        assert!(text.contains("content-length: 4\r\n"));
+        assert!(text.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_encode_response_adds_standard_headers() {
+        let bytes = encode_response(&HttpResponse::ok().with_text("hi"));
+        let text = String::from_utf8_lossy(&bytes);
+
+        assert!(text.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(text.contains("server: http-rs/0.1.0\r\n"));
+        assert!(text.contains("connection: keep-alive\r\n"));
+        assert!(text.ends_with("\r\n\r\nhi"));
+    }
+}
@@ -0,0 +1,75 @@
+//! Application hook producing the responses to failed requests, e.g. for
+//! branded error pages.
+
+use crate::{
+    error::ServerError,
+    protocol::{request::HttpRequest, response::HttpResponse},
+};
+use std::{fmt, future::Future, net::SocketAddr, pin::Pin, sync::Arc};
+
+/// What is known about the request that failed.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorContext {
+    pub peer_addr: Option<SocketAddr>,
+    /// The request, if it was received completely.
+    pub request: Option<HttpRequest>,
+}
+
+type ErrorFuture = Pin<Box<dyn Future<Output = HttpResponse> + Send>>;
+type ErrorFn = dyn Fn(&ServerError, ErrorContext) -> ErrorFuture + Send + Sync;
+
+/// Turns errors into responses in place of the built-in plain-text ones.
+/// The connection is closed after the response whatever it says.
+#[derive(Clone)]
+pub struct ErrorHandler {
+    handler: Arc<ErrorFn>,
+}
+
+impl ErrorHandler {
+    /// Answer failed requests with the response `handler` produces. The
+    /// error is only borrowed for the call, so take what the response needs
+    /// from it before the returned future:
+    ///
+    /// ```
+    /// use http::protocol::{
+    ///     error_handler::{self, ErrorHandler},
+    ///     response::HttpResponse,
+    /// };
+    ///
+    /// let handler = ErrorHandler::new(|error, context| {
+    ///     let status = error_handler::default_response(error).status;
+    ///     async move {
+    ///         let path = context.request.map(|request| request.path);
+    ///         HttpResponse::new(status)
+    ///             .with_header("x-error", "1")
+    ///             .with_html(&format!("<h1>Sorry</h1><p>{:?}</p>", path))
+    ///     }
+    /// });
+    /// ```
+    pub fn new<F, Fut>(handler: F) -> Self
+    where
+        F: Fn(&ServerError, ErrorContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = HttpResponse> + Send + 'static,
+    {
+        Self {
+            handler: Arc::new(move |error, context| Box::pin(handler(error, context))),
+        }
+    }
+
+    pub async fn handle(&self, error: &ServerError, context: ErrorContext) -> HttpResponse {
+        (self.handler)(error, context).await
+    }
+}
+
+impl fmt::Debug for ErrorHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ErrorHandler").finish_non_exhaustive()
+    }
+}
+
+/// The built-in response for a request that failed with `error`: its
+/// suggested status, with the reason phrase as the text.
+pub fn default_response(error: &ServerError) -> HttpResponse {
+    let status = error.status();
+    HttpResponse::new(status).with_text(status.reason_phrase())
+}
@@ -1,60 +1,523 @@
 use crate::{
-    config::Config,
+    config::{Builtin, Config, Route, RouteTarget, Site},
     error::{Result, ServerError},
+    gateway::{cgi, fastcgi},
+    middleware::basic_auth,
     protocol::{
+        path, readahead,
         request::{HttpMethod, HttpRequest},
         response::{HttpResponse, HttpStatusCode},
     },
+    proxy,
+    websocket::Message,
 };
-use std::path::Path;
-use tokio::{fs, io::AsyncWriteExt, net::TcpStream};
+use chrono::Utc;
+use std::{borrow::Cow, net::SocketAddr, path::Path};
+use tokio::fs;
 
+/// Static files from this size on are sent from the open file rather than
+/// read into memory first.
+const MIN_FILE_BODY_SIZE: u64 = 64 * 1024;
+
+/// Produce the response for a request: admission checks first, then the
+/// configured, proxy and gateway routes, then the built-in handlers by method.
 pub async fn handle_http_request(
-    socket: &mut TcpStream,
-    request: HttpRequest,
+    request: &mut HttpRequest,
+    peer_addr: Option<SocketAddr>,
     config: &Config,
-) -> Result<()> {
-    let response = match request.method {
-        HttpMethod::Get => handle_get_request(&request, config).await,
-        HttpMethod::Post => handle_post_request(&request).await,
-        HttpMethod::Options => handle_options_request(&request).await,
+) -> Result<HttpResponse> {
+    match admit(request, peer_addr, config).await {
+        Some(response) => Ok(response),
+        None => dispatch(request, config).await,
+    }
+}
+
+/// Run the admission checks (rate limits, then authentication) on a
+/// request, upgrades included. Returns the answer to a request they turn
+/// away, or to an ACME challenge, or `None` to go on.
+pub async fn admit(
+    request: &mut HttpRequest,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+) -> Option<HttpResponse> {
+    if let (Some(limiter), Some(peer_addr)) = (&config.rate_limiter, peer_addr)
+        && let Some(rejection) = limiter.check(peer_addr.ip())
+    {
+        return Some(rejection);
+    }
+    if let (Some(rule), Some(peer_addr)) = (config.route_rate_limit_for(&request.path), peer_addr)
+        && let Some(rejection) = rule.limiter.check(peer_addr.ip())
+    {
+        return Some(rejection);
+    }
+
+    // CA validation requests must get through authentication
+    if let Some(acme) = config.tls.as_ref().and_then(|tls| tls.acme.as_ref())
+        && let Some(response) = acme.challenge_response(&request.path)
+    {
+        return Some(response);
+    }
+
+    if let Some(auth) = &config.basic_auth
+        && let Some(rejection) = auth.check(request).await
+    {
+        return Some(rejection);
+    }
+
+    if let Some(auth) = &config.jwt_auth
+        && let Some(rejection) = auth.check(request)
+    {
+        return Some(rejection);
+    }
+    None
+}
+
+/// Produce the response for a request that passed [`admit`]: the
+/// configured, proxy and gateway routes, then the built-in handlers by
+/// method.
+pub async fn dispatch(request: &mut HttpRequest, config: &Config) -> Result<HttpResponse> {
+    // Built-in endpoints ignore the query, e.g. a probe's cache buster
+    let target = request.path.split('?').next().unwrap_or_default();
+
+    if request.method == HttpMethod::Get && config.health_check_path.as_deref() == Some(target) {
+        return handle_health_check(config).await;
+    }
+
+    if request.method == HttpMethod::Get && config.metrics_path.as_deref() == Some(target) {
+        return Ok(handle_metrics(config));
+    }
+
+    if let Some(admin_path) = config.websocket_admin_path.as_deref()
+        && let Some(rest) = request.path.strip_prefix(admin_path)
+        && (rest.is_empty() || rest.starts_with('/'))
+    {
+        return Ok(handle_websocket_admin(request, rest, config));
+    }
+
+    if let Some(route) = config
+        .routes
+        .iter()
+        .find(|route| route.matches(&request.path))
+    {
+        return handle_route(request, route, config).await;
+    }
+
+    if let Some(route) = config
+        .proxy_routes
+        .iter()
+        .find(|route| route.matches(&request.path))
+    {
+        return proxy::forward(request, &route.pool).await;
+    }
+
+    if let Some(route) = config
+        .fastcgi_routes
+        .iter()
+        .find(|route| route.matches(&request.path))
+    {
+        return fastcgi::forward(request, route).await;
+    }
+
+    if let Some(route) = config
+        .cgi_routes
+        .iter()
+        .find(|route| route.matches(&request.path))
+    {
+        return cgi::execute(request, route).await;
+    }
+
+    match request.method {
+        HttpMethod::Get => handle_get_request(request, config).await,
+        HttpMethod::Post => handle_post_request(request).await,
+        HttpMethod::Options => handle_options_request(request).await,
+        HttpMethod::Other(_) => {
+            Ok(HttpResponse::new(HttpStatusCode::NotImplemented).with_text("Not implemented"))
+        }
         _ => {
             Ok(HttpResponse::new(HttpStatusCode::MethodNotAllowed).with_text("Method not allowed"))
         }
-    }?;
+    }
+}
 
-    socket.write_all(&response.to_bytes()).await?;
-    Ok(())
+/// Whether answering `request` involves its body, following the dispatch of
+/// [`handle_http_request`]. Other bodies need not be read before the
+/// response.
+pub(crate) fn reads_body(request: &HttpRequest, config: &Config) -> bool {
+    // Upgrades hand the connection over, body and all
+    if request.get_header("upgrade").is_some() {
+        return true;
+    }
+    if let Some(admin_path) = config.websocket_admin_path.as_deref()
+        && let Some(rest) = request.path.strip_prefix(admin_path)
+        && (rest.is_empty() || rest.starts_with('/'))
+    {
+        return request.method == HttpMethod::Post;
+    }
+    if let Some(route) = config
+        .routes
+        .iter()
+        .find(|route| route.matches(&request.path))
+    {
+        return matches!(
+            route.target,
+            RouteTarget::Proxy(_) | RouteTarget::Builtin(Builtin::Echo)
+        );
+    }
+    let gateway = config
+        .proxy_routes
+        .iter()
+        .any(|route| route.matches(&request.path))
+        || config
+            .fastcgi_routes
+            .iter()
+            .any(|route| route.matches(&request.path))
+        || config
+            .cgi_routes
+            .iter()
+            .any(|route| route.matches(&request.path));
+    gateway || request.method == HttpMethod::Post
 }
 
-async fn handle_get_request(request: &HttpRequest, config: &Config) -> Result<HttpResponse> {
-    // Handle root path
-    let file_path = if request.path == "/" {
-        format!("{}/index.html", config.static_dir)
+/// Permanently redirect `request` to the same URL on HTTPS, except for ACME
+/// challenges, which the CA may only fetch over plain HTTP.
+pub fn https_redirect(request: &HttpRequest, https_port: u16, config: &Config) -> HttpResponse {
+    if let Some(acme) = config.tls.as_ref().and_then(|tls| tls.acme.as_ref())
+        && let Some(response) = acme.challenge_response(&request.path)
+    {
+        return response;
+    }
+
+    let Some(host) = request.get_header("host") else {
+        return HttpResponse::bad_request().with_text("Missing Host header");
+    };
+
+    // Drop the plain HTTP port; keep IPv6 literals intact
+    let hostname = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host.as_str(),
+    };
+    let authority = if https_port == 443 {
+        hostname.to_string()
     } else {
-        format!("{}{}", config.static_dir, request.path)
+        format!("{}:{}", hostname, https_port)
     };
 
+    HttpResponse::new(HttpStatusCode::MovedPermanently)
+        .with_header(
+            "location",
+            &format!("https://{}{}", authority, request.path),
+        )
+        .with_body(Vec::new())
+}
+
+async fn handle_get_request(request: &HttpRequest, config: &Config) -> Result<HttpResponse> {
+    let site = config.site_for(request.get_header("host").map(String::as_str));
+    let listing = config.directory_listing.then_some(request.path.as_str());
+    let response = serve_static(&site, &request.path, listing, config).await?;
+    Ok(with_cache_policy(response, &request.path, config))
+}
+
+async fn handle_route(
+    request: &HttpRequest,
+    route: &Route,
+    config: &Config,
+) -> Result<HttpResponse> {
+    let rest = route.rest(&request.path);
+    match &route.target {
+        RouteTarget::Static(root) => {
+            if request.method != HttpMethod::Get {
+                return Ok(HttpResponse::new(HttpStatusCode::MethodNotAllowed)
+                    .with_header("allow", "GET")
+                    .with_text("Method not allowed"));
+            }
+            let path = rest.split('?').next().unwrap_or_default();
+            let path = if path.is_empty() { "/" } else { path };
+            let site = Site::new(&[], root);
+            let listing = config.directory_listing.then_some(request.path.as_str());
+            let response = serve_static(&site, path, listing, config).await?;
+            Ok(with_cache_policy(response, &request.path, config))
+        }
+        RouteTarget::Redirect { to, status } => Ok(HttpResponse::new(*status)
+            .with_header("location", &format!("{}{}", to.trim_end_matches('/'), rest))
+            .with_body(Vec::new())),
+        RouteTarget::Proxy(pool) => proxy::forward(request, pool).await,
+        RouteTarget::Builtin(Builtin::Health) => handle_health_check(config).await,
+        RouteTarget::Builtin(Builtin::Metrics) => Ok(handle_metrics(config)),
+        RouteTarget::Builtin(Builtin::Echo) => handle_post_request(request).await,
+    }
+}
+
+/// Serve the file at `path` below the site's root. Directories without an
+/// index file are listed if `listing` gives the URL they were requested at.
+async fn serve_static(
+    site: &Site,
+    path: &str,
+    listing: Option<&str>,
+    config: &Config,
+) -> Result<HttpResponse> {
     // Security: prevent directory traversal
-    let canonical_static_dir = std::fs::canonicalize(&config.static_dir)
-        .map_err(|_| ServerError::FileNotFound(config.static_dir.clone()))?;
+    let canonical_root = match config.canonical_roots.get(&site.root) {
+        Some(root) => Cow::Borrowed(root.as_path()),
+        None => Cow::Owned(
+            fs::canonicalize(&site.root)
+                .await
+                .map_err(|_| ServerError::FileNotFound(site.root.clone()))?,
+        ),
+    };
 
-    let canonical_file_path = match std::fs::canonicalize(&file_path) {
+    let canonical_file_path = match fs::canonicalize(format!("{}{}", site.root, path)).await {
         Ok(path) => path,
-        Err(_) => return Ok(HttpResponse::not_found().with_text("File not found")),
+        Err(_) => {
+            return Ok(error_page(site, config, HttpStatusCode::NotFound, "File not found").await);
+        }
+    };
+
+    if !canonical_file_path.starts_with(&canonical_root) {
+        return Ok(error_page(site, config, HttpStatusCode::BadRequest, "Invalid path").await);
+    }
+
+    // Directories are served by their first existing index file
+    let is_dir = fs::metadata(&canonical_file_path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir());
+    let file_path = if is_dir {
+        let mut index = None;
+        for name in &site.index_files {
+            let path = canonical_file_path.join(name);
+            if fs::metadata(&path)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                index = Some(path);
+                break;
+            }
+        }
+        match (index, listing) {
+            (Some(path), _) => path,
+            (None, Some(url)) => return Ok(list_directory(&canonical_file_path, url).await),
+            (None, None) => {
+                return Ok(
+                    error_page(site, config, HttpStatusCode::NotFound, "File not found").await,
+                );
+            }
+        }
+    } else {
+        canonical_file_path
+    };
+
+    // Serve file if it exists; large ones are sent from the open file,
+    // cached ones from memory
+    let response =
+        HttpResponse::ok().with_header("content-type", get_content_type(&file_path, config));
+    let opened = match fs::File::open(&file_path).await {
+        Ok(file) => file.metadata().await.map(|metadata| (file, metadata)),
+        Err(e) => Err(e),
+    };
+    let (file, metadata) = match opened {
+        Ok(opened) => opened,
+        Err(_) => {
+            return Ok(error_page(site, config, HttpStatusCode::NotFound, "File not found").await);
+        }
+    };
+    let cache = config
+        .file_cache
+        .as_ref()
+        .filter(|cache| cache.accepts(&metadata));
+    if let Some(body) = cache.and_then(|cache| cache.get(&file_path, &metadata)) {
+        return Ok(response.with_body(body));
+    }
+    let len = metadata.len();
+    if cache.is_none() && len >= MIN_FILE_BODY_SIZE {
+        return Ok(response.with_file(file.into_std().await, len));
+    }
+    match readahead::read_file(file.into_std().await, len).await {
+        Ok(contents) => match cache {
+            Some(cache) => {
+                Ok(response.with_body(cache.insert(&file_path, contents.into(), &metadata)))
+            }
+            None => Ok(response.with_body(contents)),
+        },
+        Err(_) => Ok(error_page(site, config, HttpStatusCode::NotFound, "File not found").await),
+    }
+}
+
+/// An HTML page linking to the entries of `dir`, requested at `url`.
+async fn list_directory(dir: &Path, url: &str) -> HttpResponse {
+    let path = url.split('?').next().unwrap_or(url);
+    let mut names = Vec::new();
+    if let Ok(mut entries) = fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_dir = entry.file_type().await.is_ok_and(|kind| kind.is_dir());
+            names.push((name, is_dir));
+        }
+    }
+    names.sort();
+
+    // Links are relative, so without a trailing slash they need the last
+    // segment of the path in front. Segments are escaped so names with `#`,
+    // `?` or `%` link to themselves.
+    let base = match path.rsplit_once('/') {
+        Some((_, "")) | None => String::new(),
+        Some((_, last)) => format!("{}/", path::encode_segment(&path::percent_decode(last))),
+    };
+    let title = html_escape(path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><title>Index of {}</title></head><body>\n<h1>Index of {}</h1>\n<ul>\n",
+        title, title
+    );
+    if !path.trim_end_matches('/').is_empty() {
+        html.push_str(&format!("<li><a href=\"{}../\">../</a></li>\n", base));
+    }
+    for (name, is_dir) in names {
+        let slash = if is_dir { "/" } else { "" };
+        html.push_str(&format!(
+            "<li><a href=\"{}{}{}\">{}{}</a></li>\n",
+            base,
+            path::encode_segment(&name),
+            slash,
+            html_escape(&name),
+            slash
+        ));
+    }
+    html.push_str("</ul>\n</body></html>\n");
+    HttpResponse::ok().with_html(&html)
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Add the headers of the cache policy for `path` to a successful static
+/// response. Headers the response already has are kept.
+fn with_cache_policy(mut response: HttpResponse, path: &str, config: &Config) -> HttpResponse {
+    let Some(policy) = config.cache_policy_for(path) else {
+        return response;
     };
+    if response.status != HttpStatusCode::Ok {
+        return response;
+    }
+    if let Some(value) = &policy.cache_control {
+        response
+            .headers
+            .get_or_insert_with("cache-control", || value.clone());
+    }
+    if let Some(after) = policy.expires
+        && let Ok(after) = chrono::Duration::from_std(after)
+    {
+        let expires = Utc::now() + after;
+        response.headers.get_or_insert_with("expires", || {
+            expires.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+        });
+    }
+    response
+}
 
-    if !canonical_file_path.starts_with(&canonical_static_dir) {
-        return Ok(HttpResponse::bad_request().with_text("Invalid path"));
+/// A `status` response with the site's error page for it, or `text` if it
+/// has none.
+async fn error_page(
+    site: &Site,
+    config: &Config,
+    status: HttpStatusCode,
+    text: &str,
+) -> HttpResponse {
+    if let Some(page) = site.error_pages.get(&status.code())
+        && let Ok(contents) = fs::read(format!("{}{}", site.root, page)).await
+    {
+        return HttpResponse::new(status)
+            .with_header("content-type", get_content_type(Path::new(page), config))
+            .with_body(contents);
     }
+    HttpResponse::new(status).with_text(text)
+}
+
+/// Report liveness (we are answering) and readiness (static dir is usable)
+/// for load balancers. Not ready is reported as 503.
+async fn handle_health_check(config: &Config) -> Result<HttpResponse> {
+    let static_dir_ok = match fs::metadata(&config.static_dir).await {
+        Ok(metadata) if metadata.is_dir() => fs::read_dir(&config.static_dir).await.is_ok(),
+        _ => false,
+    };
+
+    let (status, label) = if static_dir_ok {
+        (HttpStatusCode::Ok, "ok")
+    } else {
+        (HttpStatusCode::ServiceUnavailable, "unavailable")
+    };
 
-    // Serve file if it exists
-    match fs::read(&file_path).await {
-        Ok(contents) => Ok(HttpResponse::ok()
-            .with_header("content-type", &get_content_type(&file_path))
-            .with_body(contents)),
-        Err(_) => Ok(HttpResponse::not_found().with_text("File not found")),
+    Ok(HttpResponse::new(status)
+        .with_header("cache-control", "no-store")
+        .with_json(&format!(
+            r#"{{"status": "{}", "live": true, "ready": {}, "checks": {{"static_dir": "{}"}}}}"#,
+            label, static_dir_ok, label
+        )))
+}
+
+fn handle_metrics(config: &Config) -> HttpResponse {
+    HttpResponse::ok()
+        .with_header("content-type", "text/plain; version=0.0.4; charset=utf-8")
+        .with_header("cache-control", "no-store")
+        .with_body(config.websocket.metrics.render().into_bytes())
+}
+
+/// The WebSocket admin API, with `rest` being the path below its root:
+/// `GET /` lists connections, `GET /{id}` shows one, `DELETE /{id}` closes
+/// it and `POST /broadcast` sends the body as a text message to all of them.
+/// With `websocket_admin_token` set, requests must carry it as a bearer token.
+fn handle_websocket_admin(request: &HttpRequest, rest: &str, config: &Config) -> HttpResponse {
+    if let Some(token) = &config.websocket_admin_token {
+        let presented = request
+            .get_header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if !basic_auth::constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+            return HttpResponse::new(HttpStatusCode::Unauthorized)
+                .with_header("www-authenticate", "Bearer")
+                .with_header("cache-control", "no-store")
+                .with_text("Unauthorized");
+        }
     }
+    let hub = &config.websocket.hub;
+    let method_not_allowed = |allow: &str| {
+        HttpResponse::new(HttpStatusCode::MethodNotAllowed)
+            .with_header("allow", allow)
+            .with_text("Method not allowed")
+    };
+    let response = match rest.trim_end_matches('/') {
+        "" => match request.method {
+            HttpMethod::Get => HttpResponse::ok().with_json(&hub.render()),
+            _ => method_not_allowed("GET"),
+        },
+        "/broadcast" => match (&request.method, std::str::from_utf8(&request.body)) {
+            (HttpMethod::Post, Ok(text)) => {
+                let sent = hub.broadcast(Message::text(text));
+                HttpResponse::ok().with_json(&format!(r#"{{"sent": {}}}"#, sent))
+            }
+            (HttpMethod::Post, Err(_)) => {
+                HttpResponse::bad_request().with_text("Message must be UTF-8")
+            }
+            _ => method_not_allowed("POST"),
+        },
+        id => match (&request.method, id[1..].parse::<u64>()) {
+            (_, Err(_)) => HttpResponse::not_found().with_text("Not Found"),
+            (HttpMethod::Get, Ok(id)) => match hub.render_one(id) {
+                Some(json) => HttpResponse::ok().with_json(&json),
+                None => HttpResponse::not_found().with_text("No such connection"),
+            },
+            (HttpMethod::Delete, Ok(id)) => {
+                if hub.close(id, 1008, "Closed by administrator") {
+                    HttpResponse::new(HttpStatusCode::Accepted).with_body(Vec::new())
+                } else {
+                    HttpResponse::not_found().with_text("No such connection")
+                }
+            }
+            _ => method_not_allowed("GET, DELETE"),
+        },
+    };
+    response.with_header("cache-control", "no-store")
 }
 
 async fn handle_post_request(request: &HttpRequest) -> Result<HttpResponse> {
@@ -80,20 +543,38 @@ async fn handle_options_request(_request: &HttpRequest) -> Result<HttpResponse>
         .with_body(Vec::new()))
 }
 
-fn get_content_type(file_path: &str) -> String {
-    let path = Path::new(file_path);
-    match path.extension().and_then(|ext| ext.to_str()) {
-        Some("html") | Some("htm") => "text/html; charset=utf-8".to_string(),
-        Some("css") => "text/css; charset=utf-8".to_string(),
-        Some("js") => "application/javascript; charset=utf-8".to_string(),
-        Some("json") => "application/json; charset=utf-8".to_string(),
-        Some("png") => "image/png".to_string(),
-        Some("jpg") | Some("jpeg") => "image/jpeg".to_string(),
-        Some("gif") => "image/gif".to_string(),
-        Some("svg") => "image/svg+xml".to_string(),
-        Some("ico") => "image/x-icon".to_string(),
-        Some("txt") => "text/plain; charset=utf-8".to_string(),
-        Some("pdf") => "application/pdf".to_string(),
-        _ => "application/octet-stream".to_string(),
+/// Content types of static files by extension, matched in any case.
+const CONTENT_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "application/javascript; charset=utf-8"),
+    ("json", "application/json; charset=utf-8"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("pdf", "application/pdf"),
+];
+
+/// The content type of the file at `path`, from `config.content_types` or
+/// else the built-in table.
+fn get_content_type<'a>(path: &Path, config: &'a Config) -> &'a str {
+    let Some(extension) = path.extension().and_then(|ext| ext.to_str()) else {
+        return "application/octet-stream";
+    };
+    if !config.content_types.is_empty()
+        && let Some(content_type) = config
+            .content_types
+            .get(extension.to_ascii_lowercase().as_str())
+    {
+        return content_type;
     }
+    CONTENT_TYPES
+        .iter()
+        .find(|(known, _)| extension.eq_ignore_ascii_case(known))
+        .map_or("application/octet-stream", |(_, content_type)| content_type)
 }
@@ -0,0 +1,223 @@
+//! Header fields of a message, kept inline for the common case.
+
+use smallvec::SmallVec;
+use std::{fmt, ops::Index};
+
+/// Fields kept inline before spilling to the heap; requests rarely carry
+/// more.
+const INLINE_FIELDS: usize = 32;
+
+/// Header fields in the order received. Names keep their case and are
+/// looked up case-insensitively with a linear scan, which beats hashing at
+/// these sizes.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: SmallVec<[(String, String); INLINE_FIELDS]>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.fields
+            .iter()
+            .position(|(field, _)| field.eq_ignore_ascii_case(name))
+    }
+
+    /// The value of the field `name`, in any case.
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.position(name).map(|i| &self.fields[i].1)
+    }
+
+    /// The values of every field `name`, in the order received.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a String> {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    /// Set the field `name`, replacing and returning an earlier value.
+    /// Any further fields of that name are removed.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        let name = name.into();
+        let value = value.into();
+        match self.position(&name) {
+            Some(i) => {
+                let previous = std::mem::replace(&mut self.fields[i].1, value);
+                let mut index = 0;
+                self.fields.retain(|(field, _)| {
+                    index += 1;
+                    index - 1 <= i || !field.eq_ignore_ascii_case(&name)
+                });
+                Some(previous)
+            }
+            None => {
+                self.fields.push((name, value));
+                None
+            }
+        }
+    }
+
+    /// Add a field `name`, keeping earlier ones of the same name, as
+    /// repeated fields such as `Set-Cookie` need.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.fields.push((name.into(), value.into()));
+    }
+
+    /// The value of the field `name`, set to `default()` first if missing.
+    pub fn get_or_insert_with(
+        &mut self,
+        name: &str,
+        default: impl FnOnce() -> String,
+    ) -> &mut String {
+        let i = match self.position(name) {
+            Some(i) => i,
+            None => {
+                self.fields.push((name.to_string(), default()));
+                self.fields.len() - 1
+            }
+        };
+        &mut self.fields[i].1
+    }
+
+    /// Remove every field `name`, returning the first value.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let first = self.position(name).map(|i| self.fields.remove(i).1);
+        if first.is_some() {
+            self.fields
+                .retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+        }
+        first
+    }
+
+    /// Keep only the fields for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&str, &str) -> bool) {
+        self.fields.retain(|(name, value)| keep(name, value));
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Names and values in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.fields.iter().map(|(name, value)| (name, value))
+    }
+}
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl Index<&str> for Headers {
+    type Output = String;
+
+    /// The value of the field `name`; panics if there is none.
+    fn index(&self, name: &str) -> &String {
+        self.get(name)
+            .unwrap_or_else(|| panic!("no header field {:?}", name))
+    }
+}
+
+impl<'a> IntoIterator for &'a Headers {
+    type Item = (&'a String, &'a String);
+    type IntoIter = std::iter::Map<
+        std::slice::Iter<'a, (String, String)>,
+        fn(&'a (String, String)) -> (&'a String, &'a String),
+    >;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.fields.iter().map(|(name, value)| (name, value))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut headers = Self::new();
+        headers.extend(iter);
+        headers
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> Extend<(K, V)> for Headers {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (name, value) in iter {
+            self.insert(name, value);
+        }
+    }
+}
+
+impl<K: Into<String>, V: Into<String>, const N: usize> From<[(K, V); N]> for Headers {
+    fn from(fields: [(K, V); N]) -> Self {
+        fields.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_ignores_case() {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", "text/plain");
+        assert_eq!(
+            headers.get("content-type").map(String::as_str),
+            Some("text/plain")
+        );
+        assert!(headers.contains_key("CONTENT-TYPE"));
+
+        assert_eq!(
+            headers.insert("content-type", "text/html").as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(headers.len(), 1);
+        // The name keeps the case it was first added with
+        assert_eq!(
+            headers.iter().next(),
+            Some((&"Content-Type".to_string(), &"text/html".to_string()))
+        );
+
+        assert_eq!(headers["CONTENT-TYPE"], "text/html");
+
+        assert_eq!(headers.remove("Content-type").as_deref(), Some("text/html"));
+        assert!(headers.is_empty());
+        headers.get_or_insert_with("Vary", || "origin".to_string());
+        headers.get_or_insert_with("vary", || "accept-encoding".to_string());
+        assert_eq!(headers["vary"], "origin");
+    }
+
+    #[test]
+    fn test_repeated_fields() {
+        let mut headers = Headers::new();
+        headers.append("Set-Cookie", "a=1");
+        headers.append("Content-Type", "text/plain");
+        headers.append("set-cookie", "b=2");
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers["set-cookie"], "a=1");
+        assert_eq!(
+            headers.get_all("SET-COOKIE").collect::<Vec<_>>(),
+            ["a=1", "b=2"]
+        );
+
+        assert_eq!(headers.insert("set-cookie", "c=3").as_deref(), Some("a=1"));
+        assert_eq!(headers.get_all("set-cookie").collect::<Vec<_>>(), ["c=3"]);
+
+        headers.append("Set-Cookie", "d=4");
+        assert_eq!(headers.remove("set-cookie").as_deref(), Some("c=3"));
+        assert_eq!(headers.len(), 1);
+    }
+}
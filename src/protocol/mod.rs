@@ -1,99 +1,498 @@
-use crate::{config::Config, error::ServerError, websocket};
+use crate::{
+    access_log::AccessRecord,
+    config::Config,
+    error::{RequestInfo, ServerError, panic_message},
+    protocol::{
+        codec::{Event, Framing, MessageHead, RequestDecoder},
+        error_handler::ErrorContext,
+        request::HttpRequest,
+        response::HttpResponse,
+    },
+    proxy, websocket,
+};
 use bytes::BytesMut;
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use chrono::Local;
+use futures_util::FutureExt;
+use std::{
+    net::SocketAddr,
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::TcpStream,
+    time::{self, timeout_at},
+};
 use tracing::{error, info};
 
+pub mod codec;
+pub mod error_handler;
 pub mod handler;
+pub mod headers;
+pub mod path;
+mod readahead;
 pub mod request;
 pub mod response;
+#[cfg(target_os = "linux")]
+mod sendfile;
 
 /// Entry point for HTTP connections.
 /// Detects WebSocket upgrades or delegates to HTTP handler with keep-alive support.
-pub async fn handle_connection(mut socket: TcpStream, config: &Config) -> Result<(), ServerError> {
+pub async fn handle_connection(socket: TcpStream, config: &Config) -> Result<(), ServerError> {
     let peer_addr = socket.peer_addr().ok();
+    serve_connection(socket, peer_addr, config).await
+}
+
+/// Serve HTTP on an established stream, e.g. one wrapped in TLS.
+pub async fn serve_connection<S>(
+    socket: S,
+    peer_addr: Option<SocketAddr>,
+    config: &Config,
+) -> Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
     info!(?peer_addr, "New connection");
 
+    // The decoder keeps any bytes past the current request for the next one
+    let mut decoder = RequestDecoder::new();
+    decoder.set_limits(config.limits);
+    // Response heads are serialized into the same buffer all along
+    let mut head_buffer = BytesMut::with_capacity(1024);
+    // Everything written for a response goes out together when it is
+    // flushed; bodies larger than the buffer are written directly
+    let mut socket = BufWriter::with_capacity(WRITE_BUFFER_SIZE, socket);
+
     loop {
-        // Read until we find the end of headers (\r\n\r\n)
-        let mut buffer = BytesMut::with_capacity(8192);
-
-        loop {
-            let mut temp_buf = [0u8; 1024];
-            match socket.read(&mut temp_buf).await {
-                Ok(0) => {
-                    if buffer.is_empty() {
-                        info!(?peer_addr, "Connection closed by client");
-                        return Ok(());
-                    } else {
-                        error!(?peer_addr, "Connection closed unexpectedly during request");
-                        return Err(ServerError::InvalidHttpRequest("Incomplete request"));
-                    }
-                }
-                Ok(n) => {
-                    buffer.extend_from_slice(&temp_buf[..n]);
-
-                    // Look for \r\n\r\n in the accumulated buffer
-                    if let Some(header_end) = find_header_end(&buffer) {
-                        let request =
-                            request::HttpRequest::from_buffer(&buffer[..header_end], &mut socket)
-                                .await?;
-
-                        // Check if this is a WebSocket upgrade
-                        if let Some(websocket_key) =
-                            websocket::handshake::is_websocket_request(&request)
-                        {
-                            info!(?peer_addr, "Upgrading to WebSocket");
-                            return websocket::handle_websocket(socket, websocket_key).await;
-                        }
+        let mut request = match read_request(&mut socket, &mut decoder, config).await {
+            Ok(Some(request)) => request,
+            Ok(None) => {
+                info!(?peer_addr, "Connection closed by client");
+                return Ok(());
+            }
+            Err(e @ ServerError::RequestTimeout(_)) => {
+                // A 408 tells the client it may retry on a new connection
+                info!(?peer_addr, error = %e, "Request timed out, closing");
+                let context = ErrorContext {
+                    peer_addr,
+                    request: None,
+                };
+                let _ = respond_to_error(&mut socket, &e, context, config).await;
+                return Ok(());
+            }
+            Err(
+                e @ (ServerError::HeadersTooLarge
+                | ServerError::BodyTooLarge(_)
+                | ServerError::UriTooLong(_)),
+            ) => {
+                // The rest of the request is still in flight, so the
+                // connection cannot be reused
+                info!(?peer_addr, error = %e, "Request exceeds limits");
+                let context = ErrorContext {
+                    peer_addr,
+                    request: None,
+                };
+                respond_to_error(&mut socket, &e, context, config).await?;
+                return Ok(());
+            }
+            Err(
+                e
+                @ (ServerError::InvalidHttpRequest(_) | ServerError::UnsupportedTransferCoding(_)),
+            ) => {
+                // Nothing after a malformed request can be trusted to start
+                // the next one. A client that hung up mid-request will not
+                // read the answer, so failing to write it is no error.
+                info!(?peer_addr, error = %e, "Malformed request");
+                let context = ErrorContext {
+                    peer_addr,
+                    request: None,
+                };
+                let _ = respond_to_error(&mut socket, &e, context, config).await;
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(e.in_request(RequestInfo {
+                    peer_addr,
+                    ..RequestInfo::default()
+                }));
+            }
+        };
+        let request_id = request.request_id();
 
-                        // Handle HTTP request
-                        let should_close = request
-                            .get_header("connection")
-                            .map(|v| v.to_lowercase() == "close")
-                            .unwrap_or(false);
-
-                        if let Err(e) =
-                            handler::handle_http_request(&mut socket, request, config).await
-                        {
-                            error!(?peer_addr, error = ?e, "Error handling HTTP request");
-                            return Err(e);
-                        }
+        // Rate limits and authentication apply to upgrades as well
+        let started = Instant::now();
+        let rejection = handler::admit(&mut request, peer_addr, config).await;
 
-                        if should_close {
-                            info!(?peer_addr, "Connection: close requested, closing");
-                            return Ok(());
-                        }
+        // Upgrades on proxied paths are tunnelled to the upstream, whatever
+        // the protocol
+        if rejection.is_none()
+            && request.get_header("upgrade").is_some()
+            && let Some(route) = config
+                .proxy_routes
+                .iter()
+                .find(|route| route.matches(&request.path))
+        {
+            info!(?peer_addr, "Tunnelling upgrade to upstream");
+            socket.flush().await?;
+            let early_data = decoder.buffered().to_vec();
+            return proxy::tunnel(socket.get_mut(), &request, &early_data, &route.pool)
+                .await
+                .map_err(|e| e.in_request(request_info(peer_addr, &request, &request_id)));
+        }
 
-                        // Continue reading next request on the same connection
-                        info!(?peer_addr, "Keeping connection alive for next request");
-                        break;
-                    }
+        // Check if this is a WebSocket upgrade
+        // Refused upgrades are answered by the WebSocket layer too
+        if rejection.is_none()
+            && !matches!(
+                websocket::handshake::is_websocket_request(&request, &config.websocket),
+                Ok(None)
+            )
+        {
+            info!(?peer_addr, "Upgrading to WebSocket");
+            socket.flush().await?;
+            return websocket::handle_websocket(
+                socket.into_inner(),
+                peer_addr,
+                &request,
+                &config.websocket,
+            )
+            .await
+            .map_err(|e| e.in_request(request_info(peer_addr, &request, &request_id)));
+        }
+
+        // Handle HTTP request
+        let should_close = request
+            .get_header("connection")
+            .map(|v| v.to_lowercase() == "close")
+            .unwrap_or(false);
+
+        // A panicking handler fails only its own request
+        let handled = match rejection {
+            Some(response) => Ok(response),
+            None => AssertUnwindSafe(handler::dispatch(&mut request, config))
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|panic| {
+                    Err(ServerError::HandlerPanicked(
+                        panic_message(&*panic).to_string(),
+                    ))
+                }),
+        };
+        let mut response = match handled {
+            Ok(response) => response,
+            Err(e) => {
+                // The client gets an answer even though the connection ends
+                let context = ErrorContext {
+                    peer_addr,
+                    request: Some(request.clone()),
+                };
+                let _ = respond_to_error(&mut socket, &e, context, config).await;
+                return Err(e.in_request(request_info(peer_addr, &request, &request_id)));
+            }
+        };
+
+        if config.permissive_cors {
+            response
+                .headers
+                .get_or_insert_with("access-control-allow-origin", || "*".to_string());
+        }
+        if let Some(compression) = &config.compression {
+            if response.file.is_some() && compression.would_encode(&request, &response) {
+                response.read_file().await?;
+            }
+            compression.apply(&request, &mut response).await;
+        }
+
+        // Responses to requests the client pipelined are held back until
+        // the last one, and go out together
+        let pipelined = !should_close && !decoder.in_body() && !decoder.buffered().is_empty();
+        write_response(&mut socket, &response, &mut head_buffer, config, !pipelined)
+            .await
+            .map_err(|e| {
+                ServerError::from(e).in_request(request_info(peer_addr, &request, &request_id))
+            })?;
+
+        if let Some(access_log) = &config.access_log {
+            access_log.log(&AccessRecord {
+                request_id: &request_id,
+                peer_addr,
+                request: &request,
+                status: response.status.code(),
+                bytes: response.body_len() as usize,
+                duration: started.elapsed(),
+                time: Local::now(),
+            });
+        }
+
+        if should_close {
+            info!(?peer_addr, "Connection: close requested, closing");
+            return Ok(());
+        }
+        if decoder.in_body() && !drain_body(&mut socket, &mut decoder, &request, config).await {
+            info!(?peer_addr, "Unread request body not drained, closing");
+            return Ok(());
+        }
+
+        // Continue reading next request on the same connection
+        info!(?peer_addr, "Keeping connection alive for next request");
+    }
+}
+
+/// Serve a plain HTTP connection that only redirects to HTTPS on `https_port`.
+pub async fn serve_https_redirect<S>(
+    mut socket: S,
+    peer_addr: Option<SocketAddr>,
+    https_port: u16,
+    config: &Config,
+) -> Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut decoder = RequestDecoder::new();
+    decoder.set_limits(config.limits);
 
-                    // Prevent header bombs
-                    if buffer.len() > 16384 {
-                        error!(?peer_addr, "Request headers too large");
-                        return Err(ServerError::InvalidHttpRequest("Headers too large"));
+    loop {
+        let request = match read_request(&mut socket, &mut decoder, config).await {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                info!(?peer_addr, error = %e, "Closing redirect connection");
+                return Ok(());
+            }
+        };
+
+        let response = handler::https_redirect(&request, https_port, config);
+        response.write_to(&mut socket).await?;
+
+        let should_close = request
+            .get_header("connection")
+            .map(|v| v.to_lowercase() == "close")
+            .unwrap_or(false);
+        if should_close {
+            return Ok(());
+        }
+    }
+}
+
+/// Write `response` to `socket`, and flush it if `flush` is set. File
+/// bodies on plain TCP connections are sent by the kernel where it supports
+/// that.
+async fn write_response<S: AsyncWrite + Unpin + 'static>(
+    socket: &mut BufWriter<S>,
+    response: &HttpResponse,
+    head_buffer: &mut BytesMut,
+    config: &Config,
+    flush: bool,
+) -> std::io::Result<()> {
+    response.write_head(socket, head_buffer).await?;
+    #[cfg(target_os = "linux")]
+    if let Some(file) = &response.file {
+        socket.flush().await?;
+        if let Some(tcp) = (socket.get_mut() as &mut dyn std::any::Any).downcast_mut::<TcpStream>()
+        {
+            return sendfile::send_file(tcp, &file.file, file.len).await;
+        }
+        #[cfg(feature = "uring")]
+        if let Some(stream) =
+            (socket.get_mut() as &mut dyn std::any::Any).downcast_mut::<crate::uring::UringStream>()
+        {
+            return stream
+                .send_file(&file.file, file.len, config.file_chunk_size)
+                .await;
+        }
+    }
+    response
+        .write_file_body(socket, config.file_chunk_size)
+        .await?;
+    if flush {
+        socket.flush().await?;
+    }
+    Ok(())
+}
+
+/// What errors from serving `request` are logged with.
+fn request_info(
+    peer_addr: Option<SocketAddr>,
+    request: &HttpRequest,
+    request_id: &str,
+) -> RequestInfo {
+    RequestInfo {
+        peer_addr,
+        request_id: Some(request_id.to_string()),
+        method: Some(request.method.to_string()),
+        path: Some(request.path.clone()),
+    }
+}
+
+/// Answer a request that failed with `error`, through the application's
+/// error handler if it has one (and it does not panic), and mark the
+/// connection for closing.
+async fn respond_to_error<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    error: &ServerError,
+    context: ErrorContext,
+    config: &Config,
+) -> std::io::Result<()> {
+    let handled = match &config.error_handler {
+        Some(handler) => AssertUnwindSafe(handler.handle(error, context))
+            .catch_unwind()
+            .await
+            .map_err(|panic| error!(panic = panic_message(&*panic), "Error handler panicked"))
+            .ok(),
+        None => None,
+    };
+    let response = handled.unwrap_or_else(|| error_handler::default_response(error));
+    response.close_connection().write_to(socket).await?;
+    socket.flush().await
+}
+
+/// Capacity of the buffer collecting a response's writes.
+const WRITE_BUFFER_SIZE: usize = 8192;
+
+/// Drive the decoder until a full request (head and body) has been received.
+/// Returns `None` if the client closes the connection between requests, or
+/// stays silent past the header deadline.
+///
+/// Clients that trickle data are cut off: the head must arrive within
+/// `header_read_timeout` and the body at no less than `min_body_rate`.
+/// Clients sending `Expect: 100-continue` are told to go on once the head
+/// shows the body within its limit.
+async fn read_request<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    decoder: &mut RequestDecoder,
+    config: &Config,
+) -> Result<Option<HttpRequest>, ServerError> {
+    let mut request: Option<HttpRequest> = None;
+    let header_deadline = time::Instant::now() + config.header_read_timeout;
+    let mut body_started = None;
+    let mut body = BytesMut::new();
+
+    loop {
+        match decoder.decode()? {
+            Some(Event::Head(head)) => {
+                let max_body_size = config.max_body_size_for(&head.path);
+                decoder.set_max_body_size(max_body_size);
+                // A body the handler never looks at is drained after the
+                // response rather than buffered before it
+                let lazy = decoder.in_body() && !handler::reads_body(&head, config);
+                if expects_continue(&head) {
+                    // The client holds the body back until told to send it,
+                    // so a body over the limit is refused before it is sent
+                    match head.framing()? {
+                        Framing::Length(length) if length > max_body_size => {
+                            return Err(ServerError::BodyTooLarge(max_body_size));
+                        }
+                        Framing::Length(0) | Framing::None => {}
+                        _ if !lazy && decoder.buffered().is_empty() => {
+                            socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+                            socket.flush().await?;
+                        }
+                        _ => {}
                     }
                 }
-                Err(e) => {
-                    error!(?peer_addr, error = ?e, "Failed to read from socket");
-                    return Err(e.into());
+                if lazy {
+                    return Ok(Some(head));
+                }
+                request = Some(head);
+                body_started = Some(time::Instant::now());
+            }
+            Some(Event::Body(chunk)) => body.extend_from_slice(&chunk),
+            Some(Event::End) => {
+                return Ok(request.map(|mut request| {
+                    request.body = body.freeze();
+                    request
+                }));
+            }
+            None => {
+                // Responses held back for pipelined requests go out before
+                // waiting for more
+                socket.flush().await?;
+                let deadline = match (&request, body_started) {
+                    (Some(_), Some(body_started)) => {
+                        body_deadline(body_started, body.len(), config)
+                    }
+                    _ => Some(header_deadline),
+                };
+
+                // Reads land in the decoder's buffer, behind whatever is
+                // left of earlier reads
+                let buffer = decoder.read_buffer();
+                let n = match deadline {
+                    Some(deadline) => match timeout_at(deadline, socket.read_buf(buffer)).await {
+                        Ok(read) => read?,
+                        Err(_) if request.is_none() && decoder.is_idle() => return Ok(None),
+                        Err(_) if request.is_none() => {
+                            return Err(ServerError::RequestTimeout(
+                                "Request head not received in time",
+                            ));
+                        }
+                        Err(_) => {
+                            return Err(ServerError::RequestTimeout("Request body too slow"));
+                        }
+                    },
+                    None => socket.read_buf(buffer).await?,
+                };
+                if n == 0 {
+                    if request.is_none() && decoder.is_idle() {
+                        return Ok(None);
+                    }
+                    return Err(ServerError::InvalidHttpRequest("Incomplete request"));
                 }
+                decoder.record_read(n);
             }
         }
     }
 }
 
-/// Find the position after \r\n\r\n in the buffer
-fn find_header_end(buffer: &[u8]) -> Option<usize> {
-    for i in 0..buffer.len().saturating_sub(3) {
-        if buffer[i] == b'\r'
-            && buffer[i + 1] == b'\n'
-            && buffer[i + 2] == b'\r'
-            && buffer[i + 3] == b'\n'
-        {
-            return Some(i + 4);
+/// Whether the client waits for `100 Continue` before sending the body.
+fn expects_continue(request: &HttpRequest) -> bool {
+    request.version == "HTTP/1.1"
+        && request
+            .get_header("expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+}
+
+/// Read and discard the rest of the body of `request`, which its handler
+/// did not need, so the next request starts where it should. Returns false
+/// if the connection cannot be reused: the client is holding the body back
+/// for a `100 Continue` it never got, or the body is over its limit or does
+/// not arrive within `header_read_timeout`.
+async fn drain_body<S: AsyncRead + Unpin>(
+    socket: &mut S,
+    decoder: &mut RequestDecoder,
+    request: &HttpRequest,
+    config: &Config,
+) -> bool {
+    if expects_continue(request) {
+        return false;
+    }
+    let deadline = time::Instant::now() + config.header_read_timeout;
+    loop {
+        match decoder.decode() {
+            Ok(Some(Event::End)) => return true,
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                let buffer = decoder.read_buffer();
+                match timeout_at(deadline, socket.read_buf(buffer)).await {
+                    Ok(Ok(n)) if n > 0 => decoder.record_read(n),
+                    _ => return false,
+                }
+            }
+            Err(_) => return false,
         }
     }
-    None
+}
+
+/// When the next body bytes must have arrived for the upload to keep up with
+/// `min_body_rate`, after a grace period of `header_read_timeout`.
+fn body_deadline(
+    started: time::Instant,
+    received: usize,
+    config: &Config,
+) -> Option<time::Instant> {
+    if config.min_body_rate == 0 {
+        return None;
+    }
+    let expected = Duration::from_secs_f64(received as f64 / config.min_body_rate as f64);
+    Some(started + config.header_read_timeout + expected)
 }
@@ -0,0 +1,111 @@
+//! Request target paths in the form the filesystem and upstreams see them.
+
+/// The path of a request target without its query, percent-decoded, with
+/// repeated slashes collapsed and `.` and `..` segments resolved. `..`
+/// never climbs above the root. Different spellings of one resource, such
+/// as `//admin`, `/public/../admin` and `/%61dmin`, normalize alike.
+pub fn normalize(path: &str) -> String {
+    resolve_segments(path).0
+}
+
+/// As [`normalize`], but `None` if a `..` segment would climb above the
+/// root, for paths that must stay inside a directory.
+pub fn resolve(path: &str) -> Option<String> {
+    match resolve_segments(path) {
+        (normalized, false) => Some(normalized),
+        (_, true) => None,
+    }
+}
+
+/// The normalized path, and whether `..` tried to climb above the root.
+fn resolve_segments(path: &str) -> (String, bool) {
+    let path = path.split('?').next().unwrap_or_default();
+    let decoded = percent_decode(path);
+    let mut segments: Vec<&str> = Vec::new();
+    let mut climbed = false;
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => climbed |= segments.pop().is_none(),
+            segment => segments.push(segment),
+        }
+    }
+    let mut normalized = format!("/{}", segments.join("/"));
+    // A trailing slash names a directory and is kept
+    if !segments.is_empty() && (decoded.ends_with('/') || decoded.ends_with("/.")) {
+        normalized.push('/');
+    }
+    (normalized, climbed)
+}
+
+/// Decode `%XX` escapes; malformed ones are kept as they are.
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Escape everything in a path segment but unreserved characters
+/// (RFC 3986 §2.3), so `#`, `?`, `%`, `/` or spaces in it cannot change
+/// the meaning of a URL.
+pub fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// [`normalize`]d `path` with its segments escaped again, fit for a URL.
+pub fn normalize_encoded(path: &str) -> String {
+    normalize(path)
+        .split('/')
+        .map(encode_segment)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("/a/b?x=/c"), "/a/b");
+        assert_eq!(normalize("//admin///secret"), "/admin/secret");
+        assert_eq!(normalize("/public/../admin/./x"), "/admin/x");
+        assert_eq!(normalize("/public/%2e%2e/admin"), "/admin");
+        assert_eq!(normalize("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize("/%61dmin%2Fx%zz"), "/admin/x%zz");
+        assert_eq!(normalize("/docs/"), "/docs/");
+        assert_eq!(normalize(""), "/");
+        assert_eq!(resolve("/a/../b.php").as_deref(), Some("/b.php"));
+        assert_eq!(resolve("/a/../../etc/passwd.php"), None);
+        assert_eq!(resolve("/%2e%2e/etc/passwd.php"), None);
+
+        assert_eq!(encode_segment("a b#1?%.txt"), "a%20b%231%3F%25.txt");
+        assert_eq!(normalize_encoded("/docs//a%20b/../c d/"), "/docs/c%20d/");
+    }
+}
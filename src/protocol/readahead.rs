@@ -0,0 +1,143 @@
+//! Copying file bodies through userspace with the reads done on the blocking
+//! pool, a chunk ahead of the socket, so a slow disk holds up a blocking
+//! thread rather than the runtime's workers. Each read is its own blocking
+//! task, so no thread is held while the socket applies backpressure. On the
+//! io_uring runtime the reads go through io_uring instead.
+
+use bytes::{Bytes, BytesMut};
+use std::{
+    fs::File,
+    io::{self, Read, Take},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    task::{self, JoinHandle},
+};
+
+/// Copy the first `len` bytes of `file` to `writer`, reading `chunk_size`
+/// bytes at a time.
+pub async fn copy_file<W: AsyncWrite + Unpin>(
+    file: &File,
+    len: u64,
+    chunk_size: usize,
+    writer: &mut W,
+) -> io::Result<()> {
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    if crate::uring::active() {
+        return crate::uring::copy_file(file, len, chunk_size, writer).await;
+    }
+    let mut next = read_chunk(file.try_clone()?.take(len), chunk_size);
+    let mut copied = 0;
+    loop {
+        let (file, chunk) = next.await.map_err(io::Error::other)??;
+        if chunk.is_empty() {
+            break;
+        }
+        // The next read runs while this chunk is written; if the copy is
+        // abandoned it simply finishes unobserved
+        next = read_chunk(file, chunk_size);
+        writer.write_all(&chunk).await?;
+        copied += chunk.len() as u64;
+    }
+    if copied < len {
+        // The file shrank since its length was taken
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Read the first `len` bytes of `file` into memory, or up to its end if it
+/// is shorter.
+pub async fn read_file(file: File, len: u64) -> io::Result<Vec<u8>> {
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    if crate::uring::active() {
+        return crate::uring::read_file(&file, len).await;
+    }
+    let mut contents = Vec::with_capacity(len as usize);
+    tokio::fs::File::from_std(file)
+        .take(len)
+        .read_to_end(&mut contents)
+        .await?;
+    Ok(contents)
+}
+
+/// Read the next chunk of `file` on the blocking pool, handing the file
+/// back with it. An empty chunk marks the end.
+fn read_chunk(
+    mut file: Take<File>,
+    chunk_size: usize,
+) -> JoinHandle<io::Result<(Take<File>, Bytes)>> {
+    task::spawn_blocking(move || {
+        let mut chunk = BytesMut::zeroed(chunk_size.min(file.limit() as usize));
+        loop {
+            match file.read(&mut chunk) {
+                Ok(n) => {
+                    chunk.truncate(n);
+                    return Ok((file, chunk.freeze()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_copies_file_in_chunks() {
+        let path = std::env::temp_dir().join(format!("http-readahead-{}", std::process::id()));
+        let contents: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+        let file = File::open(&path).unwrap();
+
+        let mut copied = Vec::new();
+        copy_file(&file, contents.len() as u64 - 7, 1024, &mut copied)
+            .await
+            .unwrap();
+        assert_eq!(copied, contents[..contents.len() - 7]);
+
+        // A file shorter than promised is an error
+        let file = File::open(&path).unwrap();
+        let mut copied = Vec::new();
+        assert!(
+            copy_file(&file, contents.len() as u64 + 1, 1024, &mut copied)
+                .await
+                .is_err()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_stalled_socket_holds_no_blocking_thread() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .max_blocking_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let path =
+            std::env::temp_dir().join(format!("http-readahead-stall-{}", std::process::id()));
+        std::fs::write(&path, vec![0u8; 64 * 1024]).unwrap();
+        let file = File::open(&path).unwrap();
+
+        runtime.block_on(async {
+            // Nobody reads the other end, so the copy stalls after 1KB
+            let (mut writer, _reader) = tokio::io::duplex(1024);
+            let copy = tokio::spawn(async move {
+                let _ = copy_file(&file, 64 * 1024, 1024, &mut writer).await;
+            });
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+            let other = task::spawn_blocking(|| 42);
+            let result = tokio::time::timeout(std::time::Duration::from_secs(5), other).await;
+            assert_eq!(result.unwrap().unwrap(), 42);
+            copy.abort();
+        });
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,19 @@
-use crate::error::{Result, ServerError};
-use std::{collections::HashMap, fmt};
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use crate::{
+    error::{Result, ServerError},
+    protocol::{codec, headers::Headers},
+};
+use bytes::Bytes;
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum HttpMethod {
@@ -13,6 +26,9 @@ pub enum HttpMethod {
     Patch,
     Trace,
     Connect,
+    /// A method this server does not know, e.g. WebDAV's `PROPFIND`. Routes
+    /// that forward requests pass it on; the server itself answers 501.
+    Other(String),
 }
 
 impl fmt::Display for HttpMethod {
@@ -27,6 +43,7 @@ impl fmt::Display for HttpMethod {
             HttpMethod::Patch => write!(f, "PATCH"),
             HttpMethod::Trace => write!(f, "TRACE"),
             HttpMethod::Connect => write!(f, "CONNECT"),
+            HttpMethod::Other(method) => f.write_str(method),
         }
     }
 }
@@ -45,183 +62,99 @@ impl std::str::FromStr for HttpMethod {
             "PATCH" => Ok(HttpMethod::Patch),
             "TRACE" => Ok(HttpMethod::Trace),
             "CONNECT" => Ok(HttpMethod::Connect),
-            _ => Err(ServerError::InvalidHttpRequest("Unsupported HTTP method")),
+            _ if !s.is_empty() && s.bytes().all(is_token_char) => {
+                Ok(HttpMethod::Other(s.to_string()))
+            }
+            _ => Err(ServerError::InvalidHttpRequest("Invalid HTTP method")),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct HttpRequest {
-    pub method: HttpMethod,
-    pub path: String,
-    pub version: String,
-    pub headers: HashMap<String, String>,
-    pub body: Vec<u8>,
+impl HttpMethod {
+    /// Whether sending the request twice has the same effect as sending it
+    /// once (RFC 9110 §9.2.2), so it may be retried.
+    pub fn is_idempotent(&self) -> bool {
+        matches!(
+            self,
+            HttpMethod::Get
+                | HttpMethod::Head
+                | HttpMethod::Put
+                | HttpMethod::Delete
+                | HttpMethod::Options
+                | HttpMethod::Trace
+        )
+    }
 }
 
-impl HttpRequest {
-    pub async fn from_buffer(buffer: &[u8], socket: &mut TcpStream) -> Result<Self> {
-        let request_str = String::from_utf8_lossy(buffer);
-        let lines: Vec<&str> = request_str.lines().collect();
-        if lines.is_empty() {
-            return Err(ServerError::InvalidHttpRequest("Empty request"));
-        }
-
-        // Parse request line
-        let request_line_parts: Vec<&str> = lines[0].split_whitespace().collect();
-        if request_line_parts.len() != 3 {
-            return Err(ServerError::InvalidHttpRequest("Invalid request line"));
-        }
-
-        let method = request_line_parts[0].parse::<HttpMethod>()?;
-        let path = request_line_parts[1].to_string();
-        let version = request_line_parts[2].to_string();
-
-        // Parse headers
-        let mut headers = HashMap::new();
-
-        for line in lines.iter().skip(1) {
-            if line.is_empty() {
-                break;
-            }
-
-            if let Some(colon_pos) = line.find(':') {
-                let key = line[..colon_pos].trim().to_lowercase();
-                let value = line[colon_pos + 1..].trim().to_string();
-                headers.insert(key, value);
-            }
-        }
+/// Whether `byte` may appear in a method name (RFC 9110 §5.6.2 `tchar`).
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
 
-        // Parse body based on Content-Length or Transfer-Encoding
-        let body = if let Some(content_length) = headers.get("content-length") {
-            // Read body based on Content-Length
-            let length: usize = content_length
-                .parse()
-                .map_err(|_| ServerError::InvalidHttpRequest("Invalid Content-Length"))?;
+/// Typed values attached to a request by middleware (e.g. verified token
+/// claims) for the stages that run after it. One value per type.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
 
-            if length > 10 * 1024 * 1024 {
-                return Err(ServerError::InvalidHttpRequest("Body too large"));
-            }
+impl Extensions {
+    /// Attach `value`, replacing any previous value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
 
-            let mut body = vec![0u8; length];
-            socket.read_exact(&mut body).await?;
-            body
-        } else if let Some(transfer_encoding) = headers.get("transfer-encoding") {
-            if transfer_encoding.to_lowercase().contains("chunked") {
-                // Decode chunked transfer encoding
-                read_chunked_body(socket).await?
-            } else {
-                Vec::new()
-            }
-        } else {
-            Vec::new()
-        };
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+}
 
-        Ok(Self {
-            method,
-            path,
-            version,
-            headers,
-            body,
-        })
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
     }
+}
 
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: HttpMethod,
+    pub path: String,
+    pub version: String,
+    pub headers: Headers,
+    pub body: Bytes,
+    pub extensions: Extensions,
+}
+
+impl HttpRequest {
     /// Parse a complete HTTP request (headers only, no body) - for testing
     pub fn from_buffer_sync(buffer: &[u8]) -> Result<Self> {
-        let request_str = String::from_utf8_lossy(buffer);
-        let lines: Vec<&str> = request_str.lines().collect();
-        if lines.is_empty() {
-            return Err(ServerError::InvalidHttpRequest("Empty request"));
-        }
-
-        // Parse request line
-        let request_line_parts: Vec<&str> = lines[0].split_whitespace().collect();
-        if request_line_parts.len() != 3 {
-            return Err(ServerError::InvalidHttpRequest("Invalid request line"));
-        }
-
-        let method = request_line_parts[0].parse::<HttpMethod>()?;
-        let path = request_line_parts[1].to_string();
-        let version = request_line_parts[2].to_string();
-
-        // Parse headers
-        let mut headers = HashMap::new();
-
-        for line in lines.iter().skip(1) {
-            if line.is_empty() {
-                break;
-            }
-
-            if let Some(colon_pos) = line.find(':') {
-                let key = line[..colon_pos].trim().to_lowercase();
-                let value = line[colon_pos + 1..].trim().to_string();
-                headers.insert(key, value);
-            }
-        }
-
-        Ok(Self {
-            method,
-            path,
-            version,
-            headers,
-            body: Vec::new(),
-        })
+        codec::parse_head(buffer)
     }
 
     pub fn get_header(&self, name: &str) -> Option<&String> {
-        self.headers.get(&name.to_lowercase())
+        self.headers.get(name)
     }
-}
-
-/// Read chunked transfer-encoded body
-async fn read_chunked_body(socket: &mut TcpStream) -> Result<Vec<u8>> {
-    let mut body = Vec::new();
-
-    loop {
-        // Read chunk size line
-        let mut size_line = Vec::new();
-        let mut byte_buf = [0u8; 1];
 
-        loop {
-            socket.read_exact(&mut byte_buf).await?;
-            size_line.push(byte_buf[0]);
-
-            if size_line.len() >= 2
-                && size_line[size_line.len() - 2] == b'\r'
-                && size_line[size_line.len() - 1] == b'\n'
+    /// Identifier correlating this request across logs: the client's
+    /// `X-Request-Id` if it sent a reasonable one, else a fresh id unique
+    /// within this process.
+    pub fn request_id(&self) -> String {
+        match self.get_header("x-request-id") {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= 128
+                    && id.bytes().all(|b| b.is_ascii_graphic()) =>
             {
-                break;
+                id.clone()
             }
-
-            if size_line.len() > 20 {
-                return Err(ServerError::InvalidHttpRequest("Invalid chunk size"));
+            _ => {
+                let sequence = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+                format!("{:08x}{:08x}", std::process::id(), sequence)
             }
         }
-
-        // Parse chunk size (ignore chunk extensions)
-        let size_str = String::from_utf8_lossy(&size_line[..size_line.len() - 2]);
-        let size_hex = size_str.split(';').next().unwrap_or("").trim();
-        let chunk_size = usize::from_str_radix(size_hex, 16)
-            .map_err(|_| ServerError::InvalidHttpRequest("Invalid chunk size"))?;
-
-        if chunk_size == 0 {
-            // Read trailing CRLF after last chunk
-            socket.read_exact(&mut [0u8; 2]).await?;
-            break;
-        }
-
-        if body.len() + chunk_size > 10 * 1024 * 1024 {
-            return Err(ServerError::InvalidHttpRequest("Chunked body too large"));
-        }
-
-        // Read chunk data
-        let mut chunk = vec![0u8; chunk_size];
-        socket.read_exact(&mut chunk).await?;
-        body.extend_from_slice(&chunk);
-
-        // Read trailing CRLF after chunk data
-        socket.read_exact(&mut [0u8; 2]).await?;
     }
-
-    Ok(body)
 }
@@ -1,5 +1,14 @@
-use chrono::Utc;
-use std::{collections::HashMap, fmt};
+use crate::protocol::{codec, headers::Headers, readahead};
+use bytes::{Bytes, BytesMut};
+use std::{
+    fmt,
+    io::{self, IoSlice},
+};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Bytes of a file body read at a time, as `Config::file_chunk_size`
+/// defaults to.
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
 
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub enum HttpStatusCode {
@@ -11,50 +20,59 @@ pub enum HttpStatusCode {
     Ok = 200,
     Created = 201,
     Accepted = 202,
+    NonAuthoritativeInformation = 203,
     NoContent = 204,
+    ResetContent = 205,
+    PartialContent = 206,
 
     // 3xx Redirection
+    MultipleChoices = 300,
     MovedPermanently = 301,
     Found = 302,
+    SeeOther = 303,
     NotModified = 304,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
 
     // 4xx Client Error
     BadRequest = 400,
     Unauthorized = 401,
+    PaymentRequired = 402,
     Forbidden = 403,
     NotFound = 404,
     MethodNotAllowed = 405,
+    NotAcceptable = 406,
+    ProxyAuthenticationRequired = 407,
+    RequestTimeout = 408,
+    Conflict = 409,
+    Gone = 410,
+    LengthRequired = 411,
+    PreconditionFailed = 412,
+    PayloadTooLarge = 413,
+    UriTooLong = 414,
+    UnsupportedMediaType = 415,
+    RangeNotSatisfiable = 416,
+    ExpectationFailed = 417,
+    MisdirectedRequest = 421,
+    UnprocessableEntity = 422,
+    UpgradeRequired = 426,
+    PreconditionRequired = 428,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
+    UnavailableForLegalReasons = 451,
 
     // 5xx Server Error
     InternalServerError = 500,
     NotImplemented = 501,
     BadGateway = 502,
     ServiceUnavailable = 503,
+    GatewayTimeout = 504,
+    HttpVersionNotSupported = 505,
 }
 
 impl fmt::Display for HttpStatusCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let (code, text) = match self {
-            HttpStatusCode::Continue => (100, "Continue"),
-            HttpStatusCode::SwitchingProtocols => (101, "Switching Protocols"),
-            HttpStatusCode::Ok => (200, "OK"),
-            HttpStatusCode::Created => (201, "Created"),
-            HttpStatusCode::Accepted => (202, "Accepted"),
-            HttpStatusCode::NoContent => (204, "No Content"),
-            HttpStatusCode::MovedPermanently => (301, "Moved Permanently"),
-            HttpStatusCode::Found => (302, "Found"),
-            HttpStatusCode::NotModified => (304, "Not Modified"),
-            HttpStatusCode::BadRequest => (400, "Bad Request"),
-            HttpStatusCode::Unauthorized => (401, "Unauthorized"),
-            HttpStatusCode::Forbidden => (403, "Forbidden"),
-            HttpStatusCode::NotFound => (404, "Not Found"),
-            HttpStatusCode::MethodNotAllowed => (405, "Method Not Allowed"),
-            HttpStatusCode::InternalServerError => (500, "Internal Server Error"),
-            HttpStatusCode::NotImplemented => (501, "Not Implemented"),
-            HttpStatusCode::BadGateway => (502, "Bad Gateway"),
-            HttpStatusCode::ServiceUnavailable => (503, "Service Unavailable"),
-        };
-        write!(f, "{} {}", code, text)
+        write!(f, "{} {}", self.code(), self.reason_phrase())
     }
 }
 
@@ -63,6 +81,60 @@ impl HttpStatusCode {
         *self as u16
     }
 
+    /// Look up the status for a numeric code, if it is one we know.
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            100 => Some(HttpStatusCode::Continue),
+            101 => Some(HttpStatusCode::SwitchingProtocols),
+            200 => Some(HttpStatusCode::Ok),
+            201 => Some(HttpStatusCode::Created),
+            202 => Some(HttpStatusCode::Accepted),
+            203 => Some(HttpStatusCode::NonAuthoritativeInformation),
+            204 => Some(HttpStatusCode::NoContent),
+            205 => Some(HttpStatusCode::ResetContent),
+            206 => Some(HttpStatusCode::PartialContent),
+            300 => Some(HttpStatusCode::MultipleChoices),
+            301 => Some(HttpStatusCode::MovedPermanently),
+            302 => Some(HttpStatusCode::Found),
+            303 => Some(HttpStatusCode::SeeOther),
+            304 => Some(HttpStatusCode::NotModified),
+            307 => Some(HttpStatusCode::TemporaryRedirect),
+            308 => Some(HttpStatusCode::PermanentRedirect),
+            400 => Some(HttpStatusCode::BadRequest),
+            401 => Some(HttpStatusCode::Unauthorized),
+            402 => Some(HttpStatusCode::PaymentRequired),
+            403 => Some(HttpStatusCode::Forbidden),
+            404 => Some(HttpStatusCode::NotFound),
+            405 => Some(HttpStatusCode::MethodNotAllowed),
+            406 => Some(HttpStatusCode::NotAcceptable),
+            407 => Some(HttpStatusCode::ProxyAuthenticationRequired),
+            408 => Some(HttpStatusCode::RequestTimeout),
+            409 => Some(HttpStatusCode::Conflict),
+            410 => Some(HttpStatusCode::Gone),
+            411 => Some(HttpStatusCode::LengthRequired),
+            412 => Some(HttpStatusCode::PreconditionFailed),
+            413 => Some(HttpStatusCode::PayloadTooLarge),
+            414 => Some(HttpStatusCode::UriTooLong),
+            415 => Some(HttpStatusCode::UnsupportedMediaType),
+            416 => Some(HttpStatusCode::RangeNotSatisfiable),
+            417 => Some(HttpStatusCode::ExpectationFailed),
+            421 => Some(HttpStatusCode::MisdirectedRequest),
+            422 => Some(HttpStatusCode::UnprocessableEntity),
+            426 => Some(HttpStatusCode::UpgradeRequired),
+            428 => Some(HttpStatusCode::PreconditionRequired),
+            429 => Some(HttpStatusCode::TooManyRequests),
+            431 => Some(HttpStatusCode::RequestHeaderFieldsTooLarge),
+            451 => Some(HttpStatusCode::UnavailableForLegalReasons),
+            500 => Some(HttpStatusCode::InternalServerError),
+            501 => Some(HttpStatusCode::NotImplemented),
+            502 => Some(HttpStatusCode::BadGateway),
+            503 => Some(HttpStatusCode::ServiceUnavailable),
+            504 => Some(HttpStatusCode::GatewayTimeout),
+            505 => Some(HttpStatusCode::HttpVersionNotSupported),
+            _ => None,
+        }
+    }
+
     pub fn reason_phrase(&self) -> &'static str {
         match self {
             HttpStatusCode::Continue => "Continue",
@@ -70,19 +142,48 @@ impl HttpStatusCode {
             HttpStatusCode::Ok => "OK",
             HttpStatusCode::Created => "Created",
             HttpStatusCode::Accepted => "Accepted",
+            HttpStatusCode::NonAuthoritativeInformation => "Non-Authoritative Information",
             HttpStatusCode::NoContent => "No Content",
+            HttpStatusCode::ResetContent => "Reset Content",
+            HttpStatusCode::PartialContent => "Partial Content",
+            HttpStatusCode::MultipleChoices => "Multiple Choices",
             HttpStatusCode::MovedPermanently => "Moved Permanently",
             HttpStatusCode::Found => "Found",
+            HttpStatusCode::SeeOther => "See Other",
             HttpStatusCode::NotModified => "Not Modified",
+            HttpStatusCode::TemporaryRedirect => "Temporary Redirect",
+            HttpStatusCode::PermanentRedirect => "Permanent Redirect",
             HttpStatusCode::BadRequest => "Bad Request",
             HttpStatusCode::Unauthorized => "Unauthorized",
+            HttpStatusCode::PaymentRequired => "Payment Required",
             HttpStatusCode::Forbidden => "Forbidden",
             HttpStatusCode::NotFound => "Not Found",
             HttpStatusCode::MethodNotAllowed => "Method Not Allowed",
+            HttpStatusCode::NotAcceptable => "Not Acceptable",
+            HttpStatusCode::ProxyAuthenticationRequired => "Proxy Authentication Required",
+            HttpStatusCode::RequestTimeout => "Request Timeout",
+            HttpStatusCode::Conflict => "Conflict",
+            HttpStatusCode::Gone => "Gone",
+            HttpStatusCode::LengthRequired => "Length Required",
+            HttpStatusCode::PreconditionFailed => "Precondition Failed",
+            HttpStatusCode::PayloadTooLarge => "Payload Too Large",
+            HttpStatusCode::UriTooLong => "URI Too Long",
+            HttpStatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            HttpStatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            HttpStatusCode::ExpectationFailed => "Expectation Failed",
+            HttpStatusCode::MisdirectedRequest => "Misdirected Request",
+            HttpStatusCode::UnprocessableEntity => "Unprocessable Entity",
+            HttpStatusCode::UpgradeRequired => "Upgrade Required",
+            HttpStatusCode::PreconditionRequired => "Precondition Required",
+            HttpStatusCode::TooManyRequests => "Too Many Requests",
+            HttpStatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            HttpStatusCode::UnavailableForLegalReasons => "Unavailable For Legal Reasons",
             HttpStatusCode::InternalServerError => "Internal Server Error",
             HttpStatusCode::NotImplemented => "Not Implemented",
             HttpStatusCode::BadGateway => "Bad Gateway",
             HttpStatusCode::ServiceUnavailable => "Service Unavailable",
+            HttpStatusCode::GatewayTimeout => "Gateway Timeout",
+            HttpStatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
         }
     }
 
@@ -92,11 +193,22 @@ impl HttpStatusCode {
     }
 }
 
+/// A body sent from an open file instead of from memory.
+#[derive(Debug)]
+pub struct FileBody {
+    pub file: std::fs::File,
+    /// Bytes to send from the start of the file.
+    pub len: u64,
+}
+
 #[derive(Debug)]
 pub struct HttpResponse {
     pub status: HttpStatusCode,
-    pub headers: HashMap<String, String>,
-    pub body: Vec<u8>,
+    /// Written in the order added, with names in the case given.
+    pub headers: Headers,
+    pub body: Bytes,
+    /// Sent after `body` if set, see [`with_file`](Self::with_file).
+    pub file: Option<FileBody>,
     pub keep_alive: bool,
 }
 
@@ -104,8 +216,9 @@ impl HttpResponse {
     pub fn new(status: HttpStatusCode) -> Self {
         Self {
             status,
-            headers: HashMap::new(),
-            body: Vec::new(),
+            headers: Headers::new(),
+            body: Bytes::new(),
+            file: None,
             keep_alive: true,
         }
     }
@@ -131,15 +244,16 @@ impl HttpResponse {
     }
 
     pub fn with_header(mut self, name: &str, value: &str) -> Self {
-        self.headers.insert(name.to_string(), value.to_string());
+        self.headers.insert(name, value);
         self
     }
 
-    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+    pub fn with_body(mut self, body: impl Into<Bytes>) -> Self {
+        let body = body.into();
         // Auto-set Content-Length if not already set
         if !self.headers.contains_key("content-length") {
             self.headers
-                .insert("content-length".to_string(), body.len().to_string());
+                .insert("content-length", body.len().to_string());
         }
         self.body = body;
         self
@@ -160,51 +274,91 @@ impl HttpResponse {
             .with_body(json.as_bytes().to_vec())
     }
 
+    /// Send the first `len` bytes of `file` as the body. Plain TCP
+    /// connections on Linux hand them to the kernel with `sendfile`, others
+    /// copy them through a buffer.
+    pub fn with_file(mut self, file: std::fs::File, len: u64) -> Self {
+        self.headers.insert("content-length", len.to_string());
+        self.body = Bytes::new();
+        self.file = Some(FileBody { file, len });
+        self
+    }
+
+    /// The length of the body, whether it is in memory or in a file.
+    pub fn body_len(&self) -> u64 {
+        self.body.len() as u64 + self.file.as_ref().map_or(0, |file| file.len)
+    }
+
+    /// Move a file body into memory, e.g. to compress it.
+    pub async fn read_file(&mut self) -> io::Result<()> {
+        let Some(FileBody { file, len }) = self.file.take() else {
+            return Ok(());
+        };
+        let mut contents = readahead::read_file(file, len).await?;
+        contents.splice(..0, self.body.iter().copied());
+        self.body = contents.into();
+        Ok(())
+    }
+
     pub fn close_connection(mut self) -> Self {
         self.keep_alive = false;
         self
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut response = format!("HTTP/1.1 {}\r\n", self.status);
-
-        // Add standard headers if not already present
-        let mut headers = self.headers.clone();
-
-        // Add Date header
-        if !headers.contains_key("date") {
-            let now = Utc::now();
-            headers.insert(
-                "date".to_string(),
-                now.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
-            );
-        }
+        codec::encode_response(self)
+    }
 
-        // Add Server header
-        if !headers.contains_key("server") {
-            headers.insert("server".to_string(), "http-rs/0.1.0".to_string());
-        }
+    /// Write the response to `writer`, the head and the body as separate
+    /// slices of one vectored write so the body is not copied.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        self.write_with_buffer(writer, &mut BytesMut::with_capacity(256))
+            .await
+    }
 
-        // Add Connection header for keep-alive
-        if !headers.contains_key("connection") {
-            if self.keep_alive && self.status.is_success() {
-                headers.insert("connection".to_string(), "keep-alive".to_string());
-                if !headers.contains_key("keep-alive") {
-                    headers.insert("keep-alive".to_string(), "timeout=5, max=100".to_string());
-                }
-            } else {
-                headers.insert("connection".to_string(), "close".to_string());
+    /// As [`write_to`](Self::write_to), serializing the head into `buffer`,
+    /// which keeps its capacity for the next response on the connection.
+    pub async fn write_with_buffer<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        buffer: &mut BytesMut,
+    ) -> io::Result<()> {
+        self.write_head(writer, buffer).await?;
+        self.write_file_body(writer, FILE_CHUNK_SIZE).await
+    }
+
+    /// Copy the file body, if there is one, after the head, reading
+    /// `chunk_size` bytes of it at a time.
+    pub(crate) async fn write_file_body<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        chunk_size: usize,
+    ) -> io::Result<()> {
+        match &self.file {
+            Some(FileBody { file, len }) => {
+                readahead::copy_file(file, *len, chunk_size, writer).await
             }
+            None => Ok(()),
         }
+    }
 
-        for (name, value) in &headers {
-            response.push_str(&format!("{}: {}\r\n", name, value));
+    /// Write the head and the in-memory body, leaving out a file body.
+    pub(crate) async fn write_head<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+        buffer: &mut BytesMut,
+    ) -> io::Result<()> {
+        buffer.clear();
+        codec::encode_response_head_into(self, buffer);
+        let mut slices = [IoSlice::new(buffer), IoSlice::new(&self.body)];
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            let written = writer.write_vectored(slices).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            IoSlice::advance_slices(&mut slices, written);
         }
-
-        response.push_str("\r\n");
-
-        let mut bytes = response.into_bytes();
-        bytes.extend(&self.body);
-        bytes
+        Ok(())
     }
 }
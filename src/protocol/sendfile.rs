@@ -0,0 +1,95 @@
+//! Sending file bodies with `sendfile(2)`, so their bytes go from the page
+//! cache to the socket without passing through userspace.
+
+use std::{
+    fs::File,
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd},
+};
+use tokio::{io::Interest, net::TcpStream, task};
+
+/// Largest count a single `sendfile` call transfers on Linux.
+const MAX_SENDFILE_COUNT: u64 = 0x7fff_f000;
+
+/// Files from this size on are sent from the blocking pool: their pages are
+/// less likely to be cached, and waiting for the disk there does not hold
+/// up the runtime's workers.
+const BLOCKING_SEND_SIZE: u64 = 1024 * 1024;
+
+/// Send the first `len` bytes of `file` to `socket`.
+pub async fn send_file(socket: &TcpStream, file: &File, len: u64) -> io::Result<()> {
+    if len >= BLOCKING_SEND_SIZE {
+        return send_file_blocking(socket, file, len).await;
+    }
+    let mut offset: libc::off_t = 0;
+    let mut remaining = len;
+    while remaining > 0 {
+        let sent = socket
+            .async_io(Interest::WRITABLE, || {
+                send(socket.as_fd(), file, &mut offset, remaining)
+            })
+            .await?;
+        remaining -= sent;
+    }
+    Ok(())
+}
+
+/// As [`send_file`], with the calls made on the blocking pool. They are
+/// handed duplicates of the descriptors, which stay open if the send is
+/// abandoned while a call is running.
+async fn send_file_blocking(socket: &TcpStream, file: &File, len: u64) -> io::Result<()> {
+    let mut descriptors = (socket.as_fd().try_clone_to_owned()?, file.try_clone()?);
+    let mut offset: libc::off_t = 0;
+    let mut remaining = len;
+    while remaining > 0 {
+        socket.writable().await?;
+        let (returned, sent, result) = task::spawn_blocking(move || {
+            let (out, file) = &descriptors;
+            let mut sent = 0;
+            // Send until the socket's buffer is full
+            let result = loop {
+                if sent == remaining {
+                    break Ok(());
+                }
+                match send(out.as_fd(), file, &mut offset, remaining - sent) {
+                    Ok(n) => sent += n,
+                    Err(e) => break Err(e),
+                }
+            };
+            (descriptors, sent, result)
+        })
+        .await?;
+        descriptors = returned;
+        remaining -= sent;
+        match result {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                // Wait for the socket to drain before trying again
+                let _ = socket.try_io(Interest::WRITABLE, || Err::<(), _>(e));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// One `sendfile` call of up to `remaining` bytes from `offset`.
+fn send(
+    out: BorrowedFd<'_>,
+    file: &File,
+    offset: &mut libc::off_t,
+    remaining: u64,
+) -> io::Result<u64> {
+    let count = remaining.min(MAX_SENDFILE_COUNT) as usize;
+    // SAFETY: both descriptors stay open for the call and `offset` is a
+    // valid, exclusively borrowed off_t
+    let sent = unsafe { libc::sendfile(out.as_raw_fd(), file.as_raw_fd(), offset, count) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if sent == 0 {
+        // The file shrank since its length was taken
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(sent as u64)
+}
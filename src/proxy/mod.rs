@@ -0,0 +1,425 @@
+use crate::{
+    error::{Result, ServerError},
+    protocol::{
+        codec::{self, Event, ResponseDecoder, ResponseHead},
+        request::{HttpMethod, HttpRequest},
+        response::{HttpResponse, HttpStatusCode},
+    },
+};
+use std::{io, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, copy_bidirectional},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::{info, warn};
+
+pub mod upstream;
+
+pub use upstream::{Balance, Upstream, UpstreamPool};
+
+/// Upper bound on a full request/response exchange with an upstream.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers scoped to a single connection, never forwarded (RFC 7230 §6.1).
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .any(|header| name.eq_ignore_ascii_case(header))
+}
+
+/// Requests whose path falls under `prefix` are forwarded to `pool`.
+#[derive(Debug, Clone)]
+pub struct ProxyRoute {
+    pub prefix: String,
+    pub pool: UpstreamPool,
+}
+
+impl ProxyRoute {
+    pub fn new(prefix: &str, pool: UpstreamPool) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+            pool,
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => {
+                self.prefix.ends_with('/')
+                    || rest.is_empty()
+                    || rest.starts_with('/')
+                    || rest.starts_with('?')
+            }
+            None => false,
+        }
+    }
+}
+
+/// Forward a request to one of the pool's upstreams and relay its response.
+///
+/// Upstreams that refuse connections are marked dead and the next one is
+/// tried; once a request has been sent it is not retried elsewhere. A
+/// failed pooled connection is only replaced by a fresh one if the request
+/// could not be written or is idempotent, so non-idempotent requests are
+/// never duplicated.
+pub async fn forward(request: &HttpRequest, pool: &UpstreamPool) -> Result<HttpResponse> {
+    let outbound = codec::encode_request(&upstream_request(request));
+    let head_request = request.method == HttpMethod::Head;
+    let max_response_size = pool.max_response_size();
+    let mut tried = Vec::new();
+
+    while let Some(upstream) = pool.select(&tried) {
+        let _active = upstream.acquire();
+
+        // Reuse a pooled connection when possible. The upstream may close it
+        // just as the request arrives, which cannot be told apart from a
+        // failure while processing the request
+        if let Some(stream) = upstream.take_idle() {
+            match exchange(
+                &upstream,
+                stream,
+                &outbound,
+                head_request,
+                max_response_size,
+            )
+            .await
+            {
+                Ok(response) => return Ok(response),
+                Err(e) if e.unsent || request.method.is_idempotent() => {
+                    info!(upstream = %upstream.address, error = ?e.error, "Pooled upstream connection failed, retrying");
+                }
+                Err(e) => {
+                    warn!(upstream = %upstream.address, error = ?e.error, "Upstream exchange failed");
+                    return Ok(bad_gateway());
+                }
+            }
+        }
+
+        let stream = match upstream.connect().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(upstream = %upstream.address, error = ?e, "Upstream unreachable, marking dead");
+                upstream.mark_dead();
+                tried.push(upstream.address.clone());
+                continue;
+            }
+        };
+
+        return match exchange(
+            &upstream,
+            stream,
+            &outbound,
+            head_request,
+            max_response_size,
+        )
+        .await
+        {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                warn!(upstream = %upstream.address, error = ?e.error, "Upstream exchange failed");
+                Ok(bad_gateway())
+            }
+        };
+    }
+
+    warn!(path = %request.path, "No live upstream available");
+    Ok(bad_gateway())
+}
+
+/// A failed exchange with an upstream.
+#[derive(Debug)]
+struct ExchangeError {
+    error: ServerError,
+    /// The request could not be written, so the upstream cannot have acted
+    /// on it.
+    unsent: bool,
+}
+
+impl From<ServerError> for ExchangeError {
+    fn from(error: ServerError) -> Self {
+        Self {
+            error,
+            unsent: false,
+        }
+    }
+}
+
+impl From<io::Error> for ExchangeError {
+    fn from(error: io::Error) -> Self {
+        ServerError::from(error).into()
+    }
+}
+
+/// Forward an upgrade request (e.g. a WebSocket handshake) to one of the
+/// pool's upstreams. If the upstream switches protocols, its 101 is relayed
+/// and the two connections are spliced together until either side closes;
+/// any other answer is relayed as a normal response. `early_data` holds bytes
+/// the client sent after the request, which belong to the new protocol.
+pub async fn tunnel<S>(
+    client: &mut S,
+    request: &HttpRequest,
+    early_data: &[u8],
+    pool: &UpstreamPool,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let outbound = codec::encode_request(&upgrade_request(request));
+    let mut tried = Vec::new();
+
+    while let Some(upstream) = pool.select(&tried) {
+        let _active = upstream.acquire();
+
+        // An upgraded connection can never go back to the pool, so always
+        // open a fresh one
+        let mut stream = match upstream.connect().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(upstream = %upstream.address, error = ?e, "Upstream unreachable, marking dead");
+                upstream.mark_dead();
+                tried.push(upstream.address.clone());
+                continue;
+            }
+        };
+
+        let handshake =
+            match timeout(UPSTREAM_TIMEOUT, upgrade_round_trip(&mut stream, &outbound)).await {
+                Ok(result) => result,
+                Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+            };
+        let (head, body, leftover) = match handshake {
+            Ok(exchange) => exchange,
+            Err(e) => {
+                warn!(upstream = %upstream.address, error = ?e, "Upstream upgrade failed");
+                return send(client, bad_gateway()).await;
+            }
+        };
+
+        if head.status != 101 {
+            info!(upstream = %upstream.address, status = head.status, "Upstream refused upgrade");
+            let response = relay_response(head, body).unwrap_or_else(bad_gateway);
+            return send(client, response).await;
+        }
+
+        let protocol = head.get_header("upgrade").cloned().unwrap_or_default();
+        let response = relay_headers(HttpResponse::switching_protocols(), &head)
+            .with_header("upgrade", &protocol)
+            .with_header("connection", "Upgrade");
+        response.write_to(client).await?;
+        client.write_all(&leftover).await?;
+        stream.write_all(early_data).await?;
+
+        info!(upstream = %upstream.address, %protocol, "Tunnelling upgraded connection");
+        let (sent, received) = copy_bidirectional(client, &mut stream).await?;
+        info!(upstream = %upstream.address, sent, received, "Tunnel closed");
+        return Ok(());
+    }
+
+    warn!(path = %request.path, "No live upstream available");
+    send(client, bad_gateway()).await
+}
+
+/// Send the upgrade request and read the upstream's answer: its head, the
+/// body of a refusal, and for a 101 any bytes of the new protocol that came
+/// with it.
+async fn upgrade_round_trip(
+    stream: &mut TcpStream,
+    outbound: &[u8],
+) -> Result<(ResponseHead, Vec<u8>, Vec<u8>)> {
+    stream.write_all(outbound).await?;
+
+    let mut decoder = ResponseDecoder::new();
+    let mut head = None;
+    let mut body = Vec::new();
+    let mut temp_buf = [0u8; 8192];
+
+    loop {
+        match decoder.decode()? {
+            Some(Event::Head(response_head)) => head = Some(response_head),
+            Some(Event::Body(chunk)) => body.extend_from_slice(&chunk),
+            Some(Event::End) => {
+                let Some(response_head) = head.take() else {
+                    continue;
+                };
+                // A 100 Continue may come first
+                if response_head.status != 101 && (100..200).contains(&response_head.status) {
+                    continue;
+                }
+                return Ok((response_head, body, decoder.buffered().to_vec()));
+            }
+            None => {
+                let n = stream.read(&mut temp_buf).await?;
+                if n == 0 {
+                    if !decoder.finish() {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    continue;
+                }
+                decoder.feed(&temp_buf[..n]);
+            }
+        }
+    }
+}
+
+async fn send<S: AsyncWrite + Unpin>(client: &mut S, response: HttpResponse) -> Result<()> {
+    response.close_connection().write_to(client).await?;
+    Ok(())
+}
+
+/// Send a serialized request and read back the complete response, returning
+/// the connection to the pool if it can carry another request.
+async fn exchange(
+    upstream: &Arc<Upstream>,
+    mut stream: TcpStream,
+    outbound: &[u8],
+    head_request: bool,
+    max_response_size: usize,
+) -> std::result::Result<HttpResponse, ExchangeError> {
+    let (head, body, reusable) = timeout(
+        UPSTREAM_TIMEOUT,
+        round_trip(&mut stream, outbound, head_request, max_response_size),
+    )
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    if reusable {
+        upstream.release(stream);
+    }
+
+    info!(upstream = %upstream.address, status = head.status, "Proxied request");
+    Ok(relay_response(head, body).unwrap_or_else(bad_gateway))
+}
+
+async fn round_trip(
+    stream: &mut TcpStream,
+    outbound: &[u8],
+    head_request: bool,
+    max_response_size: usize,
+) -> std::result::Result<(ResponseHead, Vec<u8>, bool), ExchangeError> {
+    stream
+        .write_all(outbound)
+        .await
+        .map_err(|e| ExchangeError {
+            error: e.into(),
+            unsent: true,
+        })?;
+
+    let mut decoder = ResponseDecoder::new();
+    decoder.set_max_body_size(max_response_size);
+    let mut head = None;
+    let mut body = Vec::new();
+    let mut closed = false;
+    let mut temp_buf = [0u8; 8192];
+
+    loop {
+        match decoder.decode()? {
+            Some(Event::Head(response_head)) => {
+                if head_request {
+                    decoder.skip_body();
+                }
+                head = Some(response_head);
+            }
+            Some(Event::Body(chunk)) => body.extend_from_slice(&chunk),
+            Some(Event::End) => {
+                let Some(response_head) = head.take() else {
+                    continue;
+                };
+
+                // Interim 1xx responses precede the final one
+                if (100..200).contains(&response_head.status) {
+                    continue;
+                }
+
+                let reusable = !closed
+                    && decoder.is_idle()
+                    && !response_head
+                        .get_header("connection")
+                        .map(|v| v.to_lowercase().contains("close"))
+                        .unwrap_or(false);
+
+                return Ok((response_head, body, reusable));
+            }
+            None => {
+                let n = stream.read(&mut temp_buf).await?;
+                if n == 0 {
+                    closed = true;
+                    if !decoder.finish() {
+                        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                    }
+                    continue;
+                }
+                decoder.feed(&temp_buf[..n]);
+            }
+        }
+    }
+}
+
+/// Build the request sent upstream: hop-by-hop headers removed and the
+/// (already de-chunked) body framed by Content-Length.
+fn upstream_request(request: &HttpRequest) -> HttpRequest {
+    let mut outbound = request.clone();
+    outbound.version = "HTTP/1.1".to_string();
+    outbound.headers.retain(|name, _| !is_hop_by_hop(name));
+
+    if !outbound.body.is_empty() || outbound.headers.contains_key("content-length") {
+        outbound.headers.insert(
+            "content-length".to_string(),
+            outbound.body.len().to_string(),
+        );
+    }
+    outbound
+        .headers
+        .insert("connection".to_string(), "keep-alive".to_string());
+
+    outbound
+}
+
+/// Like [`upstream_request`], but keeping the request an upgrade.
+fn upgrade_request(request: &HttpRequest) -> HttpRequest {
+    let mut outbound = upstream_request(request);
+    if let Some(protocol) = request.get_header("upgrade") {
+        outbound
+            .headers
+            .insert("upgrade".to_string(), protocol.clone());
+    }
+    outbound
+        .headers
+        .insert("connection".to_string(), "Upgrade".to_string());
+    outbound
+}
+
+/// Turn an upstream response into one we can send to the client, or `None`
+/// if its status code is not one we can represent.
+fn relay_response(head: ResponseHead, body: Vec<u8>) -> Option<HttpResponse> {
+    let status = HttpStatusCode::from_code(head.status)?;
+    Some(relay_headers(HttpResponse::new(status), &head).with_body(body))
+}
+
+/// Copy the end-to-end headers of `head`, keeping repeated fields such as
+/// `Set-Cookie` apart.
+fn relay_headers(mut response: HttpResponse, head: &ResponseHead) -> HttpResponse {
+    for (name, value) in &head.headers {
+        if !is_hop_by_hop(name) {
+            response
+                .headers
+                .append(name.to_ascii_lowercase(), value.clone());
+        }
+    }
+    response
+}
+
+fn bad_gateway() -> HttpResponse {
+    HttpResponse::new(HttpStatusCode::BadGateway).with_text("Bad gateway")
+}
@@ -0,0 +1,225 @@
+use std::{
+    io,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, time::timeout};
+
+/// How long an upstream that refused a connection is left out of rotation.
+const DEAD_UPSTREAM_COOLDOWN: Duration = Duration::from_secs(10);
+
+/// Upper bound on establishing a new upstream connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Idle keep-alive connections kept per upstream.
+const MAX_IDLE_CONNECTIONS: usize = 32;
+
+/// Default limit on a response body read from an upstream.
+pub const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Strategy used to pick an upstream for each request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+}
+
+/// A single backend server and its pooled connections.
+#[derive(Debug)]
+pub struct Upstream {
+    pub address: String,
+    active: AtomicUsize,
+    dead_until: Mutex<Option<Instant>>,
+    idle: Mutex<Vec<TcpStream>>,
+}
+
+impl Upstream {
+    fn new(address: String) -> Self {
+        Self {
+            address,
+            active: AtomicUsize::new(0),
+            dead_until: Mutex::new(None),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// False while the upstream is cooling down after a failed connect.
+    pub fn is_alive(&self) -> bool {
+        match *self.dead_until.lock().unwrap() {
+            Some(until) => Instant::now() >= until,
+            None => true,
+        }
+    }
+
+    /// Number of requests currently in flight to this upstream.
+    pub fn active_requests(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Take the upstream out of rotation for a while.
+    pub fn mark_dead(&self) {
+        *self.dead_until.lock().unwrap() = Some(Instant::now() + DEAD_UPSTREAM_COOLDOWN);
+        // Pooled connections to a dead server are useless
+        self.idle.lock().unwrap().clear();
+    }
+
+    /// Count a request against this upstream until the guard is dropped.
+    pub fn acquire(self: &Arc<Self>) -> ActiveGuard {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveGuard {
+            upstream: Arc::clone(self),
+        }
+    }
+
+    /// Take a pooled keep-alive connection, if there is one the upstream
+    /// has not closed while it sat idle.
+    pub fn take_idle(&self) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(stream) = idle.pop() {
+            // An idle connection has nothing to read; EOF or stray bytes
+            // mean the upstream is done with it
+            let mut probe = [0u8; 1];
+            if let Err(e) = stream.try_read(&mut probe)
+                && e.kind() == io::ErrorKind::WouldBlock
+            {
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    /// Return a connection to the pool after a complete exchange.
+    pub fn release(&self, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+    }
+
+    /// Open a fresh connection to the upstream.
+    pub async fn connect(&self) -> io::Result<TcpStream> {
+        timeout(CONNECT_TIMEOUT, TcpStream::connect(&self.address))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+}
+
+/// Decrements the upstream's in-flight counter on drop.
+#[derive(Debug)]
+pub struct ActiveGuard {
+    upstream: Arc<Upstream>,
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A set of interchangeable upstreams. Cloning shares the same pool.
+#[derive(Debug, Clone)]
+pub struct UpstreamPool {
+    upstreams: Arc<Vec<Arc<Upstream>>>,
+    balance: Balance,
+    next: Arc<AtomicUsize>,
+    max_response_size: usize,
+}
+
+impl UpstreamPool {
+    pub fn new<I, S>(addresses: I, balance: Balance) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            upstreams: Arc::new(
+                addresses
+                    .into_iter()
+                    .map(|address| Arc::new(Upstream::new(address.into())))
+                    .collect(),
+            ),
+            balance,
+            next: Arc::new(AtomicUsize::new(0)),
+            max_response_size: MAX_RESPONSE_SIZE,
+        }
+    }
+
+    /// Limit the body of responses read from these upstreams to `limit`
+    /// bytes; larger ones are answered with 502. Independent of the limit
+    /// on request bodies.
+    pub fn with_max_response_size(mut self, limit: usize) -> Self {
+        self.max_response_size = limit;
+        self
+    }
+
+    pub fn max_response_size(&self) -> usize {
+        self.max_response_size
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    /// Pick a live upstream not listed in `exclude`, or `None` if there is
+    /// nothing left to try.
+    pub fn select(&self, exclude: &[String]) -> Option<Arc<Upstream>> {
+        let candidates: Vec<&Arc<Upstream>> = self
+            .upstreams
+            .iter()
+            .filter(|upstream| upstream.is_alive() && !exclude.contains(&upstream.address))
+            .collect();
+
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let upstream = match self.balance {
+            Balance::RoundRobin => {
+                let index = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+                candidates[index]
+            }
+            Balance::LeastConnections => candidates
+                .into_iter()
+                .min_by_key(|upstream| upstream.active_requests())?,
+        };
+
+        Some(Arc::clone(upstream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_robin_rotates() {
+        let pool = UpstreamPool::new(["a:1", "b:1"], Balance::RoundRobin);
+        let picks: Vec<String> = (0..4)
+            .map(|_| pool.select(&[]).unwrap().address.clone())
+            .collect();
+        assert_eq!(picks, ["a:1", "b:1", "a:1", "b:1"]);
+    }
+
+    #[test]
+    fn test_least_connections_prefers_idle_upstream() {
+        let pool = UpstreamPool::new(["a:1", "b:1"], Balance::LeastConnections);
+        let busy = pool.select(&[]).unwrap();
+        let _guard = busy.acquire();
+
+        assert_eq!(pool.select(&[]).unwrap().address, "b:1");
+    }
+
+    #[test]
+    fn test_dead_upstream_is_skipped() {
+        let pool = UpstreamPool::new(["a:1", "b:1"], Balance::RoundRobin);
+        pool.upstreams()[0].mark_dead();
+
+        for _ in 0..3 {
+            assert_eq!(pool.select(&[]).unwrap().address, "b:1");
+        }
+        assert!(pool.select(&["b:1".to_string()]).is_none());
+    }
+}
@@ -0,0 +1,727 @@
+//! Listeners and the accept loop feeding connections to the HTTP pipeline.
+
+use crate::{
+    acme,
+    config::Config,
+    error::{Result, ServerError, panic_message},
+    protocol::{
+        response::{HttpResponse, HttpStatusCode},
+        serve_connection, serve_https_redirect,
+    },
+};
+use futures_util::FutureExt;
+use std::{
+    future::Future, net::SocketAddr, panic::AssertUnwindSafe, pin::pin, sync::Arc, time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpSocket, TcpStream, lookup_host},
+    sync::{OwnedSemaphorePermit, Semaphore, mpsc, oneshot, watch},
+    task::{JoinHandle, JoinSet},
+    time::{Instant, timeout},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+/// What a listener's connections speak.
+#[derive(Clone)]
+pub enum Protocol {
+    Http,
+    Https(TlsAcceptor),
+    /// Plain HTTP answered only with redirects to HTTPS on `port`.
+    RedirectToHttps {
+        port: u16,
+    },
+}
+
+/// A bound socket and the protocol served on it.
+pub struct Listener {
+    pub listener: TcpListener,
+    pub protocol: Protocol,
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self {
+            listener,
+            protocol: Protocol::Http,
+        }
+    }
+}
+
+/// First file descriptor passed by the service manager (`SD_LISTEN_FDS_START`).
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// How often rejections at the connection limit are logged.
+const SHED_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Bounds of the pause after a failed `accept`, doubling while it keeps failing.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Listeners for the server: the sockets passed by systemd when socket
+/// activated, otherwise every address in `config.addresses` plus the HTTPS
+/// and redirect ones in `config.tls`. The configuration is validated first.
+pub async fn bind(config: &Config) -> Result<Vec<Listener>> {
+    config.validate()?;
+    if let Some(listeners) = inherited_listeners()? {
+        info!(count = listeners.len(), "Using sockets passed by systemd");
+        let ports = listeners
+            .iter()
+            .map(|listener| Ok(listener.local_addr()?.port()))
+            .collect::<Result<Vec<_>>>()?;
+        let protocols = inherited_protocols(&ports, config)?;
+        return Ok(listeners
+            .into_iter()
+            .zip(protocols)
+            .map(|(listener, protocol)| Listener { listener, protocol })
+            .collect());
+    }
+
+    let mut listeners = Vec::new();
+    for address in &config.addresses {
+        listeners.push(listen(address, config).await?.into());
+    }
+
+    if let Some(tls) = &config.tls {
+        let acceptor = tls.acceptor()?;
+        let mut https_port = None;
+        for address in &tls.addresses {
+            let listener = listen(address, config).await?;
+            https_port.get_or_insert(listener.local_addr()?.port());
+            listeners.push(Listener {
+                listener,
+                protocol: Protocol::Https(acceptor.clone()),
+            });
+        }
+
+        if !tls.redirect_addresses.is_empty() {
+            let port = https_port.ok_or_else(|| {
+                ServerError::InvalidConfig(
+                    "HTTPS redirect configured without an HTTPS address".to_string(),
+                )
+            })?;
+            for address in &tls.redirect_addresses {
+                listeners.push(Listener {
+                    listener: listen(address, config).await?,
+                    protocol: Protocol::RedirectToHttps { port },
+                });
+            }
+        }
+    }
+
+    if listeners.is_empty() {
+        return Err(ServerError::InvalidConfig(
+            "No listen addresses configured".to_string(),
+        ));
+    }
+    Ok(listeners)
+}
+
+/// Bind a listener on the first of the addresses `address` resolves to that
+/// works, with room for `config.accept_backlog` pending connections. IPv6
+/// sockets also accept IPv4 clients unless `config.ipv6_only` is set.
+async fn listen(address: &str, config: &Config) -> Result<TcpListener> {
+    let mut last_error = None;
+    for address in lookup_host(address).await? {
+        match bind_socket(address, config) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} resolved to no addresses", address),
+            )
+        })
+        .into())
+}
+
+/// A listening socket on `address` with the options from `config`.
+fn bind_socket(address: SocketAddr, config: &Config) -> std::io::Result<TcpListener> {
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        let socket = TcpSocket::new_v6()?;
+        socket2::SockRef::from(&socket).set_only_v6(config.ipv6_only)?;
+        socket
+    };
+    #[cfg(unix)]
+    {
+        socket.set_reuseaddr(true)?;
+        if config.reuse_port {
+            socket.set_reuseport(true)?;
+        }
+    }
+    socket.bind(address)?;
+    socket.listen(config.accept_backlog)
+}
+
+/// Take over the sockets a service manager passed via `LISTEN_FDS`, if they
+/// were meant for this process. Keeping the sockets in systemd across
+/// restarts means no connection is refused while the server is down.
+#[cfg(unix)]
+fn inherited_listeners() -> Result<Option<Vec<TcpListener>>> {
+    use std::os::fd::FromRawFd;
+
+    let Some(count) = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    ) else {
+        return Ok(None);
+    };
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count as i32)
+        .map(|fd| {
+            // SAFETY: systemd hands these descriptors to this process and
+            // nothing else in it claims them
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(TcpListener::from_std(listener)?)
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+}
+
+#[cfg(not(unix))]
+fn inherited_listeners() -> Result<Option<Vec<TcpListener>>> {
+    Ok(None)
+}
+
+/// The protocols for sockets passed by systemd, by the port each is bound
+/// to: ports of `config.tls` addresses serve HTTPS or the redirect, the
+/// rest plain HTTP. With TLS configured, a socket on none of the configured
+/// ports is refused rather than served unencrypted.
+fn inherited_protocols(ports: &[u16], config: &Config) -> Result<Vec<Protocol>> {
+    let Some(tls) = &config.tls else {
+        return Ok(vec![Protocol::Http; ports.len()]);
+    };
+    let acceptor = tls.acceptor()?;
+    let on = |addresses: &[String], port: u16| {
+        addresses.iter().any(|address| {
+            address
+                .rsplit_once(':')
+                .and_then(|(_, configured)| configured.parse::<u16>().ok())
+                == Some(port)
+        })
+    };
+    let https_port = ports.iter().copied().find(|&port| on(&tls.addresses, port));
+
+    ports
+        .iter()
+        .map(|&port| {
+            if on(&tls.addresses, port) {
+                Ok(Protocol::Https(acceptor.clone()))
+            } else if on(&tls.redirect_addresses, port) {
+                let port = https_port.ok_or_else(|| {
+                    ServerError::InvalidConfig(
+                        "HTTPS redirect configured without an HTTPS socket".to_string(),
+                    )
+                })?;
+                Ok(Protocol::RedirectToHttps { port })
+            } else if on(&config.addresses, port) {
+                Ok(Protocol::Http)
+            } else {
+                Err(ServerError::InvalidConfig(format!(
+                    "Socket passed by systemd on port {} matches no configured address",
+                    port
+                )))
+            }
+        })
+        .collect()
+}
+
+/// Number of passed sockets, if `LISTEN_PID` names this process and
+/// `LISTEN_FDS` is a positive count.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Option<usize> {
+    if listen_pid?.parse::<u32>().ok()? != pid {
+        return None;
+    }
+    listen_fds?.parse::<usize>().ok().filter(|&count| count > 0)
+}
+
+/// Accept connections on all `listeners` until one of them fails.
+/// The connection limit is shared across listeners.
+pub async fn serve(listeners: Vec<Listener>, config: Config) -> Result<()> {
+    serve_with_shutdown(listeners, config, std::future::pending()).await
+}
+
+/// Like [`serve`], but stop accepting once `signal` resolves, then close
+/// open WebSocket connections with 1001 and wait up to
+/// `config.shutdown_timeout` for their close replies.
+pub async fn serve_with_shutdown(
+    listeners: Vec<Listener>,
+    config: Config,
+    signal: impl Future<Output = ()>,
+) -> Result<()> {
+    let (_, reloads) = mpsc::channel(1);
+    serve_with_reload(listeners, config, reloads, signal).await
+}
+
+/// Like [`serve_with_shutdown`], but switch to each configuration received
+/// on `reloads`. Connections accepted from then on use it; open ones keep
+/// the one they started with. The WebSocket hub and metrics carry over, and
+/// TLS listeners serve the new certificates. Listen addresses, TLS protocol
+/// settings, `max_connections` and the runtime settings only change on
+/// restart.
+pub async fn serve_with_reload(
+    listeners: Vec<Listener>,
+    mut config: Config,
+    mut reloads: mpsc::Receiver<Config>,
+    signal: impl Future<Output = ()>,
+) -> Result<()> {
+    config.canonicalize_roots();
+    let connection_slots = Arc::new(Semaphore::new(config.max_connections));
+
+    if let Some(tls) = &config.tls
+        && let Some(acme_config) = &tls.acme
+    {
+        acme::spawn(acme_config.clone(), tls.clone());
+    }
+    let (current, configs) = watch::channel(Arc::new(config));
+
+    let mut accept_loops = JoinSet::new();
+    for listener in listeners {
+        if let Ok(address) = listener.listener.local_addr() {
+            match listener.protocol {
+                Protocol::Http => info!("Server running on http://{}", address),
+                Protocol::Https(_) => info!("Server running on https://{}", address),
+                Protocol::RedirectToHttps { .. } => {
+                    info!("Redirecting http://{} to HTTPS", address)
+                }
+            }
+        }
+        let Listener { listener, protocol } = listener;
+        for listener in shards(listener, &current.borrow()) {
+            let accept_loop = accept_loop(
+                listener,
+                protocol.clone(),
+                configs.clone(),
+                Arc::clone(&connection_slots),
+            );
+            // Connections on io_uring are local to the runtime's thread, and
+            // so must be the loop spawning them
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            if crate::uring::active() {
+                accept_loops.spawn_local(accept_loop);
+                continue;
+            }
+            accept_loops.spawn(accept_loop);
+        }
+    }
+
+    let mut signal = pin!(signal);
+    loop {
+        tokio::select! {
+            result = accept_loops.join_next() => match result {
+                Some(Ok(())) => {}
+                Some(Err(e)) => return Err(ServerError::Io(e.into())),
+                None => return Ok(()),
+            },
+            Some(config) = reloads.recv() => {
+                let mut config = carry_over(&current.borrow(), config);
+                config.canonicalize_roots();
+                current.send_replace(Arc::new(config));
+                info!("Configuration reloaded");
+            }
+            _ = &mut signal => break,
+        }
+    }
+
+    info!("Shutting down, no longer accepting connections");
+    accept_loops.shutdown().await;
+    let config = Arc::clone(&current.borrow());
+    let remaining = config.websocket.hub.shutdown(config.shutdown_timeout).await;
+    if remaining > 0 {
+        warn!(remaining, "WebSocket connections did not close in time");
+    }
+    Ok(())
+}
+
+/// A server started with [`start`], running in the background.
+pub struct ServerHandle {
+    addresses: Vec<SocketAddr>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    /// The addresses listened on, in the order of `config.addresses` then
+    /// the TLS ones, with the ports the system picked for any given as 0.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    /// The first address listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addresses[0]
+    }
+
+    /// Shut down gracefully, as [`serve_with_shutdown`] does, and wait for
+    /// the server to stop.
+    pub async fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown.send(());
+        self.task.await.map_err(|e| ServerError::Io(e.into()))?
+    }
+}
+
+/// Bind the listeners for `config` and serve them on a background task.
+/// Listening on port 0 (e.g. `127.0.0.1:0`) picks a free port, which the
+/// returned handle reports.
+pub async fn start(config: Config) -> Result<ServerHandle> {
+    let listeners = bind(&config).await?;
+    let addresses = listeners
+        .iter()
+        .map(|listener| listener.listener.local_addr())
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let (shutdown, signal) = oneshot::channel();
+    let task = tokio::spawn(serve_with_shutdown(listeners, config, async {
+        let _ = signal.await;
+    }));
+    Ok(ServerHandle {
+        addresses,
+        shutdown,
+        task,
+    })
+}
+
+/// Prepare a reloaded `config` to replace the `running` one: keep the state
+/// shared with open connections, and install new certificates in the TLS
+/// settings the listeners were built from.
+fn carry_over(running: &Config, mut config: Config) -> Config {
+    config.websocket.hub = running.websocket.hub.clone();
+    config.websocket.metrics = running.websocket.metrics.clone();
+
+    let tls_addresses = |config: &Config| config.tls.as_ref().map(|tls| tls.addresses.clone());
+    if config.addresses != running.addresses
+        || tls_addresses(&config) != tls_addresses(running)
+        || config.ipv6_only != running.ipv6_only
+    {
+        warn!("Listen addresses changed; restart to apply");
+    }
+    if config.max_connections != running.max_connections
+        || config.accept_backlog != running.accept_backlog
+        || config.accept_loops != running.accept_loops
+        || config.reuse_port != running.reuse_port
+        || config.worker_threads != running.worker_threads
+        || config.max_blocking_threads != running.max_blocking_threads
+    {
+        warn!("Connection limit or runtime settings changed; restart to apply");
+    }
+    match (&running.tls, &config.tls) {
+        (Some(running_tls), Some(tls)) => {
+            if running_tls.acme.is_none() {
+                running_tls.replace_certificates(tls);
+            }
+            config.tls = Some(running_tls.clone());
+        }
+        (None, None) => {}
+        _ => warn!("TLS enabled or disabled; restart to apply"),
+    }
+    config
+}
+
+/// The sockets the accept loops of `listener` take connections from: one per
+/// loop with `config.reuse_port`, else `listener` shared between them.
+fn shards(listener: TcpListener, config: &Config) -> Vec<Arc<TcpListener>> {
+    let listener = Arc::new(listener);
+    let mut shards = vec![Arc::clone(&listener)];
+    for _ in 1..accept_loop_count(config) {
+        if config.reuse_port {
+            // Binding the same address again only works if the first socket
+            // was bound with SO_REUSEPORT too
+            match listener
+                .local_addr()
+                .and_then(|address| bind_socket(address, config))
+            {
+                Ok(sibling) => {
+                    shards.push(Arc::new(sibling));
+                    continue;
+                }
+                // E.g. a socket passed by systemd without SO_REUSEPORT
+                Err(e) => {
+                    warn!(error = %e, "Cannot bind another SO_REUSEPORT socket, sharing the listener")
+                }
+            }
+        }
+        shards.push(Arc::clone(&listener));
+    }
+    shards
+}
+
+/// `config.accept_loops`, or one per worker thread; a uring runtime has
+/// just the one.
+fn accept_loop_count(config: &Config) -> usize {
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    if crate::uring::active() {
+        return 1;
+    }
+    config
+        .accept_loops
+        .or(config.worker_threads)
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1)
+}
+
+async fn accept_loop(
+    listener: Arc<TcpListener>,
+    protocol: Protocol,
+    configs: watch::Receiver<Arc<Config>>,
+    connection_slots: Arc<Semaphore>,
+) {
+    let overloaded = HttpResponse::new(HttpStatusCode::ServiceUnavailable)
+        .with_header("retry-after", "1")
+        .with_text("Server busy")
+        .close_connection()
+        .to_bytes();
+
+    // Rejections are reported in aggregate so a saturated server does not
+    // also flood its log
+    let mut shed = 0u64;
+    let mut shed_reported = Instant::now() - SHED_REPORT_INTERVAL;
+
+    let mut backoff = Duration::ZERO;
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) if is_connection_error(&e) => {
+                debug!(error = %e, "Connection failed before it was accepted");
+                continue;
+            }
+            Err(e) => {
+                // Usually out of file descriptors: the listener stays ready,
+                // so wait for connections to close rather than spin
+                backoff = (backoff * 2).clamp(ACCEPT_BACKOFF_MIN, ACCEPT_BACKOFF_MAX);
+                error!(error = %e, ?backoff, "Cannot accept connections");
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+        };
+        backoff = Duration::ZERO;
+
+        // Shed load without spawning a task: a fresh socket's send buffer is
+        // empty, so the non-blocking write goes straight through
+        let Ok(permit) = Arc::clone(&connection_slots).try_acquire_owned() else {
+            debug!(?addr, "Connection limit reached, rejecting");
+            shed += 1;
+            if shed_reported.elapsed() >= SHED_REPORT_INTERVAL {
+                warn!(
+                    rejected = shed,
+                    "Connection limit reached, rejecting connections"
+                );
+                shed = 0;
+                shed_reported = Instant::now();
+            }
+            // A plain-text 503 would be garbage to a TLS client
+            if !matches!(protocol, Protocol::Https(_)) {
+                let _ = socket.try_write(&overloaded);
+            }
+            continue;
+        };
+
+        let config = Arc::clone(&configs.borrow());
+        if let Err(e) = configure_socket(&socket, &config) {
+            warn!(?addr, error = %e, "Cannot set TCP options");
+        }
+        let protocol = protocol.clone();
+
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        if crate::uring::active() {
+            match crate::uring::UringStream::from_tokio(socket) {
+                Ok(socket) => {
+                    tokio::task::spawn_local(serve_socket(socket, addr, protocol, config, permit));
+                }
+                Err(e) => warn!(?addr, error = %e, "Cannot hand connection to io_uring"),
+            }
+            continue;
+        }
+        tokio::spawn(serve_socket(socket, addr, protocol, config, permit));
+    }
+}
+
+/// Errors from `accept` that concern only the connection being accepted.
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::ConnectionRefused
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::Interrupted
+    )
+}
+
+/// Serve an accepted connection, holding its connection slot until done.
+async fn serve_socket<S>(
+    socket: S,
+    addr: SocketAddr,
+    protocol: Protocol,
+    config: Arc<Config>,
+    permit: OwnedSemaphorePermit,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let connection = async {
+        match protocol {
+            Protocol::Https(acceptor) => {
+                match timeout(config.header_read_timeout, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => serve_connection(stream, Some(addr), &config).await,
+                    Ok(Err(e)) => {
+                        warn!(?addr, error = %e, "TLS handshake failed");
+                        Ok(())
+                    }
+                    Err(_) => {
+                        warn!(?addr, "TLS handshake timed out");
+                        Ok(())
+                    }
+                }
+            }
+            Protocol::Http => serve_connection(socket, Some(addr), &config).await,
+            Protocol::RedirectToHttps { port } => {
+                serve_https_redirect(socket, Some(addr), port, &config).await
+            }
+        }
+    };
+    match AssertUnwindSafe(connection).catch_unwind().await {
+        Ok(Ok(())) => {}
+        // Errors carry the request they happened in, see
+        // `ServerError::in_request`
+        Ok(Err(e)) => error!(
+            ?addr,
+            kind = ?e.kind(),
+            retryable = e.is_retryable(),
+            error = %e,
+            "Connection error"
+        ),
+        Err(panic) => {
+            error!(
+                ?addr,
+                panic = panic_message(&*panic),
+                "Connection task panicked"
+            )
+        }
+    }
+    drop(permit);
+}
+
+/// Apply `tcp_nodelay` and `tcp_keepalive` to an accepted connection. The
+/// probe interval and count are only set where the system supports them.
+fn configure_socket(socket: &TcpStream, config: &Config) -> std::io::Result<()> {
+    socket.set_nodelay(config.tcp_nodelay)?;
+    if let Some(keepalive) = config.tcp_keepalive {
+        let params = socket2::TcpKeepalive::new().with_time(keepalive.time);
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "freebsd",
+            target_os = "windows"
+        ))]
+        let params = params
+            .with_interval(keepalive.interval)
+            .with_retries(keepalive.retries);
+        socket2::SockRef::from(socket).set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{acme::AcmeConfig, config::TcpKeepalive, tls::TlsConfig};
+    use std::time::Duration;
+
+    #[test]
+    fn test_listen_fds() {
+        assert_eq!(listen_fds(Some("42"), Some("2"), 42), Some(2));
+        assert_eq!(listen_fds(Some("41"), Some("2"), 42), None);
+        assert_eq!(listen_fds(Some("42"), Some("0"), 42), None);
+        assert_eq!(listen_fds(None, Some("2"), 42), None);
+        assert_eq!(listen_fds(Some("42"), None, 42), None);
+    }
+
+    #[test]
+    fn test_inherited_protocols() {
+        let protocols = inherited_protocols(&[8080, 8443], &Config::default()).unwrap();
+        assert!(
+            protocols
+                .iter()
+                .all(|protocol| matches!(protocol, Protocol::Http))
+        );
+
+        let acme = AcmeConfig::new(&["example.com"], "/nonexistent");
+        let tls = TlsConfig::new(&["0.0.0.0:8443"])
+            .with_redirect("0.0.0.0:8081")
+            .with_acme(acme)
+            .unwrap();
+        let config = Config {
+            tls: Some(tls),
+            ..Config::default()
+        };
+        let port = config.addresses[0]
+            .rsplit_once(':')
+            .unwrap()
+            .1
+            .parse()
+            .unwrap();
+        let protocols = inherited_protocols(&[port, 8443, 8081], &config).unwrap();
+        assert!(matches!(protocols[0], Protocol::Http));
+        assert!(matches!(protocols[1], Protocol::Https(_)));
+        assert!(matches!(
+            protocols[2],
+            Protocol::RedirectToHttps { port: 8443 }
+        ));
+
+        assert!(inherited_protocols(&[9999], &config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_socket_options_applied() {
+        let listener = listen("127.0.0.1:0", &Config::default()).await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let config = Config {
+            tcp_keepalive: Some(TcpKeepalive {
+                time: Duration::from_secs(30),
+                ..TcpKeepalive::default()
+            }),
+            ..Config::default()
+        };
+        configure_socket(&socket, &config).unwrap();
+        assert!(socket.nodelay().unwrap());
+        let socket = socket2::SockRef::from(&socket);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(30)
+        );
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack_listener() {
+        let config = Config::default();
+        let Ok(listener) = listen("[::]:0", &config).await else {
+            return; // No IPv6 on this host
+        };
+        let port = listener.local_addr().unwrap().port();
+        let _client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        listener.accept().await.unwrap();
+
+        let config = Config {
+            ipv6_only: true,
+            ..Config::default()
+        };
+        let listener = listen("[::]:0", &config).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+    }
+}
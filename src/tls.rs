@@ -0,0 +1,442 @@
+//! TLS termination, with the certificate chosen by the SNI hostname the
+//! client asks for so several HTTPS sites can share one listener.
+
+use crate::{
+    acme::AcmeConfig,
+    error::{Result, ServerError},
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        RootCertStore, ServerConfig, SupportedProtocolVersion,
+        crypto::{CryptoProvider, ring},
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier},
+        sign::CertifiedKey,
+        version::{TLS12, TLS13},
+    },
+};
+
+/// Oldest TLS version a client may negotiate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TlsVersion {
+    #[default]
+    Tls12,
+    Tls13,
+}
+
+/// Whether clients authenticate with a certificate (mutual TLS). The path
+/// is a PEM file of the CA certificates client certificates must chain to.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ClientAuth {
+    #[default]
+    None,
+    /// Verify a client certificate if one is presented.
+    Optional(PathBuf),
+    /// Refuse handshakes without a valid client certificate.
+    Required(PathBuf),
+}
+
+/// TLS settings: where to listen and the certificates to serve.
+/// Clones share the certificate store, so certificates installed later
+/// (e.g. renewed by ACME) take effect for new handshakes everywhere.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Addresses on which connections are TLS.
+    pub addresses: Vec<String>,
+    /// Plain HTTP addresses that only redirect to HTTPS.
+    pub redirect_addresses: Vec<String>,
+    /// Obtain and renew certificates automatically, if set.
+    pub acme: Option<AcmeConfig>,
+    pub min_version: TlsVersion,
+    /// Cipher suites to offer by IANA name (e.g. `TLS13_AES_256_GCM_SHA384`),
+    /// most preferred first. Empty offers the provider's defaults.
+    pub cipher_suites: Vec<String>,
+    pub client_auth: ClientAuth,
+    resolver: Arc<SniResolver>,
+}
+
+impl TlsConfig {
+    pub fn new(addresses: &[&str]) -> Self {
+        Self {
+            addresses: addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect(),
+            redirect_addresses: Vec::new(),
+            acme: None,
+            min_version: TlsVersion::default(),
+            cipher_suites: Vec::new(),
+            client_auth: ClientAuth::default(),
+            resolver: Arc::new(SniResolver::default()),
+        }
+    }
+
+    /// Also listen on plain HTTP `address`, redirecting every request to
+    /// the same URL on HTTPS (ACME challenges are still answered there).
+    pub fn with_redirect(mut self, address: &str) -> Self {
+        self.redirect_addresses.push(address.to_string());
+        self
+    }
+
+    pub fn with_min_version(mut self, version: TlsVersion) -> Self {
+        self.min_version = version;
+        self
+    }
+
+    /// Offer only `suites`, by IANA name, in this order of preference.
+    pub fn with_cipher_suites(mut self, suites: &[&str]) -> Self {
+        self.cipher_suites = suites.iter().map(|suite| suite.to_string()).collect();
+        self
+    }
+
+    pub fn with_client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = client_auth;
+        self
+    }
+
+    /// Provision certificates for `acme.domains` automatically. A
+    /// previously obtained certificate is served right away if cached.
+    pub fn with_acme(self, acme: AcmeConfig) -> Result<Self> {
+        if let Some((cert_pem, key_pem)) = acme.cached_certificate() {
+            let domains: Vec<&str> = acme.domains.iter().map(String::as_str).collect();
+            self.install_certificate(&domains, &cert_pem, &key_pem)?;
+        }
+        Ok(Self {
+            acme: Some(acme),
+            ..self
+        })
+    }
+
+    /// Serve the certificate chain and key in the PEM files for `hostnames`
+    /// (`*.example.com` matches one extra label). The first certificate
+    /// added is also served to clients that send no or an unknown hostname.
+    pub fn with_certificate(
+        self,
+        hostnames: &[&str],
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|e| {
+                ServerError::InvalidConfig(format!("Cannot read {}: {}", path.display(), e))
+            })
+        };
+        let cert_pem = read(cert_path.as_ref())?;
+        let key_pem = read(key_path.as_ref())?;
+        self.with_certificate_pem(hostnames, &cert_pem, &key_pem)
+    }
+
+    /// Like [`with_certificate`](Self::with_certificate), from PEM data.
+    pub fn with_certificate_pem(
+        self,
+        hostnames: &[&str],
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<Self> {
+        self.install_certificate(hostnames, cert_pem, key_pem)?;
+        Ok(self)
+    }
+
+    /// Serve a certificate for `hostnames` from now on, replacing any
+    /// previous one for those names.
+    pub fn install_certificate(
+        &self,
+        hostnames: &[&str],
+        cert_pem: &[u8],
+        key_pem: &[u8],
+    ) -> Result<()> {
+        let key = certified_key(cert_pem, key_pem)?;
+        let mut sites = self.resolver.sites.write().unwrap();
+
+        if sites.default.is_none() {
+            sites.default = Some(Arc::clone(&key));
+        }
+        for hostname in hostnames {
+            let hostname = hostname.to_lowercase();
+            // Keep the fallback pointing at the current certificate
+            if let Some(previous) = sites.by_name.get(&hostname)
+                && sites
+                    .default
+                    .as_ref()
+                    .is_some_and(|default| Arc::ptr_eq(default, previous))
+            {
+                sites.default = Some(Arc::clone(&key));
+            }
+            sites.by_name.insert(hostname, Arc::clone(&key));
+        }
+        Ok(())
+    }
+
+    /// Serve the certificates of `other` from now on in place of this
+    /// configuration's own, e.g. after they were renewed on disk.
+    /// Handshakes in progress finish with the certificate they picked.
+    pub fn replace_certificates(&self, other: &TlsConfig) {
+        let sites = other.resolver.sites.read().unwrap().clone();
+        *self.resolver.sites.write().unwrap() = sites;
+    }
+
+    /// Hostnames with a certificate of their own, sorted. The default
+    /// certificate is not included.
+    pub fn certificate_hostnames(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .resolver
+            .sites
+            .read()
+            .unwrap()
+            .by_name
+            .keys()
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Build the acceptor that performs handshakes for incoming connections.
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        // With ACME the first certificate may only arrive after startup
+        if self.acme.is_none() && self.resolver.sites.read().unwrap().default.is_none() {
+            return Err(ServerError::InvalidConfig(
+                "TLS is enabled but no certificate is configured".to_string(),
+            ));
+        }
+
+        let mut provider = ring::default_provider();
+        if !self.cipher_suites.is_empty() {
+            provider.cipher_suites = self
+                .cipher_suites
+                .iter()
+                .map(|name| {
+                    ring::ALL_CIPHER_SUITES
+                        .iter()
+                        .find(|suite| suite.suite().as_str() == Some(name.as_str()))
+                        .copied()
+                        .ok_or_else(|| {
+                            ServerError::InvalidConfig(format!("Unknown TLS cipher suite {}", name))
+                        })
+                })
+                .collect::<Result<_>>()?;
+        }
+        let provider = Arc::new(provider);
+        let versions: &[&SupportedProtocolVersion] = match self.min_version {
+            TlsVersion::Tls12 => &[&TLS13, &TLS12],
+            TlsVersion::Tls13 => &[&TLS13],
+        };
+
+        let verifier = match &self.client_auth {
+            ClientAuth::None => WebPkiClientVerifier::no_client_auth(),
+            ClientAuth::Optional(ca_path) | ClientAuth::Required(ca_path) => {
+                let roots = client_roots(ca_path)?;
+                let builder =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+                let builder = match self.client_auth {
+                    ClientAuth::Optional(_) => builder.allow_unauthenticated(),
+                    _ => builder,
+                };
+                builder.build().map_err(|e| {
+                    ServerError::InvalidConfig(format!("TLS client authentication: {}", e))
+                })?
+            }
+        };
+
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(versions)
+            .map_err(|e| ServerError::InvalidConfig(format!("TLS: {}", e)))?
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Load the CA certificates client certificates are verified against.
+fn client_roots(path: &Path) -> Result<RootCertStore> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        ServerError::InvalidConfig(format!("Client CA file {}: {}", path.display(), e))
+    };
+    let pem = std::fs::read(path).map_err(|e| invalid(&e))?;
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(&pem) {
+        roots
+            .add(cert.map_err(|e| invalid(&e))?)
+            .map_err(|e| invalid(&e))?;
+    }
+    if roots.is_empty() {
+        return Err(invalid(&"no certificate found"));
+    }
+    Ok(roots)
+}
+
+/// Parse a PEM certificate chain and private key and check they match.
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<Arc<CertifiedKey>> {
+    let chain = CertificateDer::pem_slice_iter(cert_pem)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| ServerError::InvalidConfig(format!("Invalid certificate: {}", e)))?;
+    if chain.is_empty() {
+        return Err(ServerError::InvalidConfig(
+            "No certificate found in PEM data".to_string(),
+        ));
+    }
+
+    let key = PrivateKeyDer::from_pem_slice(key_pem)
+        .map_err(|e| ServerError::InvalidConfig(format!("Invalid private key: {}", e)))?;
+
+    let provider: CryptoProvider = ring::default_provider();
+    CertifiedKey::from_der(chain, key, &provider)
+        .map(Arc::new)
+        .map_err(|e| ServerError::InvalidConfig(format!("Unusable certificate: {}", e)))
+}
+
+/// Picks the certificate for a handshake by SNI hostname.
+#[derive(Debug, Default)]
+struct SniResolver {
+    sites: RwLock<Sites>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Sites {
+    default: Option<Arc<CertifiedKey>>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn lookup(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let sites = self.sites.read().unwrap();
+        let by_name = server_name.and_then(|name| {
+            let name = name.to_lowercase();
+            sites.by_name.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                sites.by_name.get(&format!("*.{}", parent))
+            })
+        });
+        by_name.or(sites.default.as_ref()).cloned()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.lookup(client_hello.server_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn self_signed(hostname: &str) -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec![hostname.to_string()]).unwrap();
+        (cert.cert.pem(), cert.signing_key.serialize_pem())
+    }
+
+    #[test]
+    fn test_sni_lookup() {
+        let (a_cert, a_key) = self_signed("a.test");
+        let (b_cert, b_key) = self_signed("*.b.test");
+        let config = TlsConfig::new(&["127.0.0.1:0"])
+            .with_certificate_pem(&["a.test"], a_cert.as_bytes(), a_key.as_bytes())
+            .unwrap()
+            .with_certificate_pem(&["*.b.test"], b_cert.as_bytes(), b_key.as_bytes())
+            .unwrap();
+
+        let resolver = &config.resolver;
+        let a = &resolver.sites.read().unwrap().by_name["a.test"].clone();
+        let b = &resolver.sites.read().unwrap().by_name["*.b.test"].clone();
+
+        assert!(Arc::ptr_eq(&resolver.lookup(Some("A.test")).unwrap(), a));
+        assert!(Arc::ptr_eq(
+            &resolver.lookup(Some("www.b.test")).unwrap(),
+            b
+        ));
+        assert!(Arc::ptr_eq(
+            &resolver.lookup(Some("x.www.b.test")).unwrap(),
+            a
+        ));
+        assert!(Arc::ptr_eq(&resolver.lookup(None).unwrap(), a));
+    }
+
+    #[test]
+    fn test_install_replaces_certificate() {
+        let (old_cert, old_key) = self_signed("a.test");
+        let (new_cert, new_key) = self_signed("a.test");
+        let config = TlsConfig::new(&[])
+            .with_certificate_pem(&["a.test"], old_cert.as_bytes(), old_key.as_bytes())
+            .unwrap();
+        let shared = config.clone();
+
+        shared
+            .install_certificate(&["a.test"], new_cert.as_bytes(), new_key.as_bytes())
+            .unwrap();
+
+        let served = config.resolver.lookup(Some("a.test")).unwrap();
+        let new = certified_key(new_cert.as_bytes(), new_key.as_bytes()).unwrap();
+        assert_eq!(served.cert, new.cert);
+        assert_eq!(config.resolver.lookup(None).unwrap().cert, new.cert);
+
+        // A reloaded configuration's certificates replace all of them
+        let (cert, key) = self_signed("b.test");
+        let reloaded = TlsConfig::new(&[])
+            .with_certificate_pem(&[], cert.as_bytes(), key.as_bytes())
+            .unwrap();
+        config.replace_certificates(&reloaded);
+        let reloaded = certified_key(cert.as_bytes(), key.as_bytes()).unwrap();
+        assert_eq!(
+            shared.resolver.lookup(Some("a.test")).unwrap().cert,
+            reloaded.cert
+        );
+    }
+
+    #[test]
+    fn test_mismatched_key_is_rejected() {
+        let (a_cert, _) = self_signed("a.test");
+        let (_, b_key) = self_signed("b.test");
+
+        assert!(
+            TlsConfig::new(&[])
+                .with_certificate_pem(&["a.test"], a_cert.as_bytes(), b_key.as_bytes())
+                .is_err()
+        );
+        assert!(TlsConfig::new(&[]).acceptor().is_err());
+    }
+
+    #[test]
+    fn test_invalid_settings_are_rejected() {
+        let (cert, key) = self_signed("a.test");
+        let config = TlsConfig::new(&[])
+            .with_certificate_pem(&["a.test"], cert.as_bytes(), key.as_bytes())
+            .unwrap();
+        let acceptor = |config: TlsConfig| config.acceptor().map(|_| ());
+
+        assert!(
+            acceptor(
+                config
+                    .clone()
+                    .with_cipher_suites(&["TLS13_AES_256_GCM_SHA384"])
+            )
+            .is_ok()
+        );
+        assert!(matches!(
+            acceptor(config.clone().with_cipher_suites(&["TLS_RSA_WITH_RC4_128_MD5"])),
+            Err(ServerError::InvalidConfig(message)) if message.contains("TLS_RSA_WITH_RC4_128_MD5")
+        ));
+        // No suite left for the only allowed version
+        assert!(
+            acceptor(
+                config
+                    .clone()
+                    .with_min_version(TlsVersion::Tls13)
+                    .with_cipher_suites(&["TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"])
+            )
+            .is_err()
+        );
+        assert!(
+            acceptor(config.with_client_auth(ClientAuth::Required("missing-ca.pem".into())))
+                .is_err()
+        );
+    }
+}
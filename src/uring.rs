@@ -0,0 +1,415 @@
+//! Network and file IO on io_uring, behind the `uring` feature (Linux only).
+//!
+//! Servers run on a [`Runtime`] accept connections as usual, but read and
+//! write them through io_uring instead of readiness polling, and read file
+//! bodies through it rather than on tokio's blocking pool. Handlers see no
+//! difference. The runtime is single-threaded, so `worker_threads` does not
+//! apply; opening files and reading their metadata still go through the
+//! blocking pool.
+
+use std::{
+    cell::Cell,
+    fs::File,
+    future::Future,
+    io,
+    net::Shutdown,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll, ready},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_uring::buf::BoundedBuf;
+
+/// Size of the buffer each read is submitted with.
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+thread_local! {
+    static ACTIVE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A tokio-uring runtime on the current thread. Everything tokio offers is
+/// available inside it.
+pub struct Runtime {
+    runtime: tokio_uring::Runtime,
+}
+
+impl Runtime {
+    /// Set up io_uring, failing where the kernel does not offer it: too old
+    /// a kernel, or io_uring disabled by seccomp or `kernel.io_uring_disabled`.
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            runtime: tokio_uring::Runtime::new(&tokio_uring::builder())?,
+        })
+    }
+
+    /// Run `future` to completion on the runtime.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(async move {
+            ACTIVE.set(true);
+            let output = future.await;
+            ACTIVE.set(false);
+            output
+        })
+    }
+}
+
+/// Whether this thread is running a [`Runtime`], so connections accepted
+/// and files read on it can go through io_uring.
+pub fn active() -> bool {
+    ACTIVE.get()
+}
+
+// io_uring handles are tied to the runtime's thread. File reads therefore
+// run as tasks local to it and hand back only owned buffers, so the
+// futures awaiting them stay `Send` like those of the other runtimes.
+
+/// Read the first `len` bytes of `file`, or up to its end if it is shorter.
+pub async fn read_file(file: &File, len: u64) -> io::Result<Vec<u8>> {
+    let file = file.try_clone()?;
+    let read = tokio::task::spawn_local(async move {
+        let file = tokio_uring::fs::File::from_std(file);
+        let len = len as usize;
+        let mut contents = Vec::with_capacity(len);
+        while contents.len() < len {
+            let pos = contents.len();
+            let (result, slice) = file.read_at(contents.slice(pos..len), pos as u64).await;
+            contents = slice.into_inner();
+            if result? == 0 {
+                break;
+            }
+        }
+        Ok(contents)
+    });
+    AbortOnDrop(read).await
+}
+
+/// Copy the first `len` bytes of `file` to `writer`, reading `chunk_size`
+/// bytes at a time, ahead of the writer.
+pub async fn copy_file<W: AsyncWrite + Unpin>(
+    file: &File,
+    len: u64,
+    chunk_size: usize,
+    writer: &mut W,
+) -> io::Result<()> {
+    let mut chunks = read_chunks(file.try_clone()?, len, chunk_size);
+    while let Some(chunk) = chunks.recv().await {
+        writer.write_all(&chunk?).await?;
+    }
+    Ok(())
+}
+
+/// The first `len` bytes of `file` in chunks of up to `chunk_size` bytes,
+/// read by a task that stays a chunk ahead of the receiver and stops when
+/// it is dropped. A file shorter than `len` ends with an error.
+fn read_chunks(file: File, len: u64, chunk_size: usize) -> mpsc::Receiver<io::Result<Vec<u8>>> {
+    let (chunks, receiver) = mpsc::channel(1);
+    tokio::task::spawn_local(async move {
+        let file = tokio_uring::fs::File::from_std(file);
+        let mut pos = 0;
+        while pos < len {
+            let size = (len - pos).min(chunk_size as u64) as usize;
+            let (result, slice) = file
+                .read_at(Vec::with_capacity(size).slice(..size), pos)
+                .await;
+            let chunk = match result {
+                // The file shrank since its length was taken
+                Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    pos += n as u64;
+                    Ok(slice.into_inner())
+                }
+                Err(e) => Err(e),
+            };
+            let failed = chunk.is_err();
+            if chunks.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// A task that is aborted if its result is no longer awaited.
+struct AbortOnDrop<T>(JoinHandle<io::Result<T>>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = io::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|result| result.map_err(io::Error::other)?)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+type Op = Pin<Box<dyn Future<Output = (io::Result<usize>, Vec<u8>)>>>;
+
+/// A TCP connection driven by io_uring, usable wherever tokio's
+/// `AsyncRead + AsyncWrite` are expected.
+///
+/// Reads go into an owned buffer and are handed out from there. Writes are
+/// copied into an owned buffer, reused from one write to the next, and
+/// reported done at once; the next write, flush or shutdown waits for them,
+/// and reports their errors. File bodies go out through
+/// [`send_file`](Self::send_file) without that copy.
+pub struct UringStream {
+    stream: Rc<tokio_uring::net::TcpStream>,
+    read: Option<Op>,
+    /// Received bytes not handed out yet, from `read_pos` on.
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    write: Option<Op>,
+    /// The buffer of the last completed write, for the next one.
+    write_buf: Vec<u8>,
+}
+
+impl UringStream {
+    /// Take over a connection accepted by tokio.
+    pub fn from_tokio(socket: tokio::net::TcpStream) -> io::Result<Self> {
+        let socket = socket.into_std()?;
+        // io_uring would fail a non-blocking socket's reads with EAGAIN
+        // instead of waiting for data
+        socket.set_nonblocking(false)?;
+        Ok(Self {
+            stream: Rc::new(tokio_uring::net::TcpStream::from_std(socket)),
+            read: None,
+            read_buf: Vec::with_capacity(READ_BUFFER_SIZE),
+            read_pos: 0,
+            write: None,
+            write_buf: Vec::new(),
+        })
+    }
+
+    /// Send the first `len` bytes of `file`, reading `chunk_size` bytes at a
+    /// time. Each chunk is written from the buffer it was read into. The
+    /// transfer runs as a task on this thread, which is aborted if the
+    /// returned future is dropped.
+    pub fn send_file(
+        &mut self,
+        file: &File,
+        len: u64,
+        chunk_size: usize,
+    ) -> impl Future<Output = io::Result<()>> + Send + 'static {
+        let stream = Rc::clone(&self.stream);
+        let pending = self.write.take();
+        let file = file.try_clone();
+        AbortOnDrop(tokio::task::spawn_local(async move {
+            if let Some(op) = pending {
+                finish_write(&stream, op).await?;
+            }
+            let mut chunks = read_chunks(file?, len, chunk_size);
+            while let Some(chunk) = chunks.recv().await {
+                let (result, _) = stream.write_all(chunk?).await;
+                result?;
+            }
+            Ok(())
+        }))
+    }
+
+    /// Drive the write in flight to completion, resubmitting what the
+    /// kernel did not take.
+    fn poll_write_op(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(op) = self.write.as_mut() {
+            let (result, mut buf) = ready!(op.as_mut().poll(cx));
+            self.write = None;
+            let written = result?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            if written < buf.len() {
+                buf.drain(..written);
+                self.write = Some(self.submit_write(buf));
+            } else {
+                self.write_buf = buf;
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn submit_write(&self, buf: Vec<u8>) -> Op {
+        let stream = Rc::clone(&self.stream);
+        Box::pin(async move { stream.write(buf).submit().await })
+    }
+}
+
+impl Drop for UringStream {
+    fn drop(&mut self) {
+        // A write reported as done must still reach the peer; the op keeps
+        // the socket open until it completes
+        if let Some(op) = self.write.take() {
+            let stream = Rc::clone(&self.stream);
+            tokio::task::spawn_local(async move {
+                let _ = finish_write(&stream, op).await;
+            });
+        }
+    }
+}
+
+/// Complete a write in flight, writing out what the kernel did not take.
+async fn finish_write(stream: &tokio_uring::net::TcpStream, op: Op) -> io::Result<()> {
+    let (result, mut buf) = op.await;
+    let written = result?;
+    if written < buf.len() {
+        buf.drain(..written);
+        let (result, _) = stream.write_all(buf).await;
+        result?;
+    }
+    Ok(())
+}
+
+impl AsyncRead for UringStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read_pos == this.read_buf.len() {
+            let op = this.read.get_or_insert_with(|| {
+                let stream = Rc::clone(&this.stream);
+                let mut buf = std::mem::take(&mut this.read_buf);
+                buf.clear();
+                Box::pin(async move { stream.read(buf).await })
+            });
+            let (result, buf) = ready!(op.as_mut().poll(cx));
+            this.read = None;
+            this.read_buf = buf;
+            this.read_pos = 0;
+            result?;
+        }
+        let available = &this.read_buf[this.read_pos..];
+        let n = available.len().min(out.remaining());
+        out.put_slice(&available[..n]);
+        this.read_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UringStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_op(cx))?;
+        if !data.is_empty() {
+            let mut buf = std::mem::take(&mut this.write_buf);
+            buf.clear();
+            buf.extend_from_slice(data);
+            this.write = Some(this.submit_write(buf));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_op(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_op(cx))?;
+        Poll::Ready(this.stream.shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, protocol::serve_connection};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Serve one connection through io_uring and return the response to
+    /// `request`.
+    async fn roundtrip(config: Config, request: &[u8]) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::task::spawn_local(async move {
+            let (socket, addr) = listener.accept().await.unwrap();
+            let socket = UringStream::from_tokio(socket).unwrap();
+            let _ = serve_connection(socket, Some(addr), &config).await;
+        });
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(request).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serves_connections_through_io_uring() {
+        let runtime = Runtime::new().unwrap();
+        runtime.block_on(async {
+            assert!(active());
+            let response = roundtrip(
+                Config::default(),
+                b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        });
+        assert!(!active());
+    }
+
+    #[test]
+    fn test_reads_files_through_io_uring() {
+        let root = std::env::temp_dir().join(format!("http-uring-files-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let large: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        std::fs::write(root.join("large.bin"), &large).unwrap();
+        std::fs::write(root.join("small.txt"), "small file").unwrap();
+        let config = Config {
+            static_dir: root.to_str().unwrap().to_string(),
+            file_chunk_size: 16 * 1024,
+            ..Config::default()
+        };
+
+        Runtime::new().unwrap().block_on(async {
+            // Sent from the buffers the chunks were read into
+            let response = roundtrip(
+                config.clone(),
+                b"GET /large.bin HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+            assert!(response.ends_with(&large));
+
+            let response = roundtrip(
+                config,
+                b"GET /small.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .await;
+            assert!(response.ends_with(b"\r\n\r\nsmall file"));
+
+            let file = File::open(root.join("large.bin")).unwrap();
+            assert_eq!(read_file(&file, 10).await.unwrap(), large[..10]);
+            let mut copied = Vec::new();
+            copy_file(&file, 100_000, 4096, &mut copied).await.unwrap();
+            assert_eq!(copied, large[..100_000]);
+
+            // A file shorter than promised is an error
+            let mut copied = Vec::new();
+            assert!(
+                copy_file(&file, large.len() as u64 + 1, 4096, &mut copied)
+                    .await
+                    .is_err()
+            );
+        });
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+//! A connection handed to the application whole, to read from and write to
+//! at its own pace instead of one handler call per message.
+
+use crate::{
+    error::{Result, ServerError},
+    websocket::{
+        context::WebSocketContext,
+        frame::WebSocketFrame,
+        handler::{Message, Outgoing, WebSocketSender, connection_closed},
+    },
+};
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+
+/// Messages are received in order as they complete; pings and the closing
+/// handshake are still handled by the server. Split it to read and write
+/// from separate tasks.
+#[derive(Debug)]
+pub struct WebSocketConnection {
+    context: WebSocketContext,
+    sink: WebSocketSink,
+    stream: WebSocketStream,
+}
+
+impl WebSocketConnection {
+    pub(crate) fn new(context: WebSocketContext, incoming: mpsc::Receiver<Message>) -> Self {
+        let sender = context.sender().clone();
+        Self {
+            context,
+            sink: WebSocketSink {
+                queue: PollSender::new(sender.outgoing().clone()),
+                sender,
+                closed: false,
+            },
+            stream: WebSocketStream { incoming },
+        }
+    }
+
+    pub fn context(&self) -> &WebSocketContext {
+        &self.context
+    }
+
+    /// The next message, or `None` once the connection is closed.
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.stream.incoming.recv().await
+    }
+
+    pub fn send(&self, message: Message) -> Result<()> {
+        self.sink.sender.send(message)
+    }
+
+    /// A sender for this connection that other tasks can hold on to.
+    pub fn sender(&self) -> &WebSocketSender {
+        &self.sink.sender
+    }
+
+    /// Split into halves; keep a clone of [`context`](Self::context) first
+    /// if it is still needed.
+    pub fn split(self) -> (WebSocketSink, WebSocketStream) {
+        (self.sink, self.stream)
+    }
+}
+
+/// Write half of a [`WebSocketConnection`]. Unlike [`WebSocketSender::send`]
+/// it waits for room in the write queue. Closing it starts the closing
+/// handshake with status 1000.
+#[derive(Debug)]
+pub struct WebSocketSink {
+    sender: WebSocketSender,
+    queue: PollSender<Outgoing>,
+    closed: bool,
+}
+
+impl WebSocketSink {
+    pub fn sender(&self) -> &WebSocketSender {
+        &self.sender
+    }
+}
+
+impl Sink<Message> for WebSocketSink {
+    type Error = ServerError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.queue.poll_reserve(cx).map_err(|_| connection_closed())
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message) -> Result<()> {
+        self.queue
+            .send_item(Outgoing::Frame(message.into()))
+            .map_err(|_| connection_closed())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.closed {
+            return Poll::Ready(Ok(()));
+        }
+        if let Err(e) = std::task::ready!(self.as_mut().poll_ready(cx)) {
+            return Poll::Ready(Err(e));
+        }
+        self.closed = true;
+        Poll::Ready(
+            self.queue
+                .send_item(Outgoing::Frame(WebSocketFrame::close_with_code(1000, "")))
+                .map_err(|_| connection_closed()),
+        )
+    }
+}
+
+/// Read half of a [`WebSocketConnection`]; ends when the connection closes.
+#[derive(Debug)]
+pub struct WebSocketStream {
+    incoming: mpsc::Receiver<Message>,
+}
+
+impl Stream for WebSocketStream {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        self.incoming.poll_recv(cx)
+    }
+}
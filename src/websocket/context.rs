@@ -0,0 +1,120 @@
+//! What the application knows about a WebSocket connection across events.
+
+use crate::{
+    error::Result,
+    protocol::{
+        headers::Headers,
+        request::{Extensions, HttpRequest},
+    },
+    websocket::{
+        handler::{Message, WebSocketSender},
+        metrics::{ConnectionStats, TrafficStats},
+    },
+};
+use std::{
+    any::Any,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Identity and handshake details of one connection, plus a data slot the
+/// application can use to associate state (e.g. the logged-in user) with it.
+/// Clones refer to the same connection and share the data.
+#[derive(Debug, Clone)]
+pub struct WebSocketContext {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    sender: WebSocketSender,
+    peer_addr: Option<SocketAddr>,
+    path: String,
+    headers: Headers,
+    subprotocol: Option<String>,
+    data: Mutex<Extensions>,
+    stats: ConnectionStats,
+}
+
+impl WebSocketContext {
+    /// Context for a connection opened by the upgrade `request`. The data
+    /// slot starts with the request's extensions, e.g. verified JWT claims.
+    pub(crate) fn new(
+        sender: WebSocketSender,
+        peer_addr: Option<SocketAddr>,
+        request: &HttpRequest,
+        subprotocol: Option<&str>,
+        stats: ConnectionStats,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                sender,
+                peer_addr,
+                path: request.path.clone(),
+                headers: request.headers.clone(),
+                subprotocol: subprotocol.map(str::to_string),
+                data: Mutex::new(request.extensions.clone()),
+                stats,
+            }),
+        }
+    }
+
+    /// Stable for the connection's lifetime and unique among open ones.
+    pub fn id(&self) -> u64 {
+        self.inner.sender.id()
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr
+    }
+
+    /// Path of the upgrade request, including any query string.
+    pub fn path(&self) -> &str {
+        &self.inner.path
+    }
+
+    /// A handshake header, by name in any case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.inner.headers.get(name).map(String::as_str)
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.inner.headers
+    }
+
+    pub fn subprotocol(&self) -> Option<&str> {
+        self.inner.subprotocol.as_deref()
+    }
+
+    pub fn sender(&self) -> &WebSocketSender {
+        &self.inner.sender
+    }
+
+    /// Frames and bytes exchanged on this connection so far.
+    pub fn stats(&self) -> TrafficStats {
+        self.inner.stats.get()
+    }
+
+    /// Round trip of the latest server ping, measured when its pong came
+    /// back; `None` until one has.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.inner.stats.round_trip()
+    }
+
+    /// Shorthand for `sender().send(message)`.
+    pub fn send(&self, message: Message) -> Result<()> {
+        self.inner.sender.send(message)
+    }
+
+    /// Store `value` for this connection, replacing any previous value of
+    /// the same type.
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) {
+        self.inner.data.lock().unwrap().insert(value);
+    }
+
+    /// A copy of the stored value of type `T`, if any.
+    pub fn get<T: Any + Send + Sync + Clone>(&self) -> Option<T> {
+        self.inner.data.lock().unwrap().get::<T>().cloned()
+    }
+}
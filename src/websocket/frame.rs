@@ -1,6 +1,19 @@
-use bytes::{Buf, BufMut, BytesMut};
-
-#[derive(Debug, Clone, PartialEq)]
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{
+    fmt,
+    io::{self, IoSlice},
+    ops::Deref,
+};
+use thiserror::Error;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Default limit on a single frame's payload.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default limit on a message's payload once its fragments are joined.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     Continuation = 0x0,
     Text = 0x1,
@@ -10,16 +23,23 @@ pub enum OpCode {
     Pong = 0xa,
 }
 
-impl From<u8> for OpCode {
-    fn from(byte: u8) -> Self {
+/// Longest possible frame header: 2 bytes, an 8 byte extended length and a
+/// 4 byte masking key.
+const MAX_HEADER_LEN: usize = 14;
+
+/// Takes the low four bits of `byte`, as found in a frame's first byte.
+impl TryFrom<u8> for OpCode {
+    type Error = ParseError;
+
+    fn try_from(byte: u8) -> Result<Self, ParseError> {
         match byte & 0x0F {
-            0x0 => OpCode::Continuation,
-            0x1 => OpCode::Text,
-            0x2 => OpCode::Binary,
-            0x8 => OpCode::Close,
-            0x9 => OpCode::Ping,
-            0xa => OpCode::Pong,
-            _ => OpCode::Close,
+            0x0 => Ok(OpCode::Continuation),
+            0x1 => Ok(OpCode::Text),
+            0x2 => Ok(OpCode::Binary),
+            0x8 => Ok(OpCode::Close),
+            0x9 => Ok(OpCode::Ping),
+            0xa => Ok(OpCode::Pong),
+            reserved => Err(ParseError::InvalidOpcode(reserved)),
         }
     }
 }
@@ -30,27 +50,182 @@ impl OpCode {
     }
 }
 
+/// Payloads share the buffer they were read into rather than being copied
+/// out of it, so clones are cheap too.
 #[derive(Debug, Clone)]
 pub enum WebSocketFrame {
-    Text(String),
-    Binary(Vec<u8>),
+    Text(Utf8Bytes),
+    Binary(Bytes),
     Close(Option<(u16, String)>),
-    Ping(Vec<u8>),
-    Pong(Vec<u8>),
+    Ping(Bytes),
+    Pong(Bytes),
+}
+
+/// Text stored as [`Bytes`] known to be valid UTF-8. Dereferences to `str`.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Utf8Bytes(Bytes);
+
+impl Utf8Bytes {
+    pub fn as_str(&self) -> &str {
+        // SAFETY: every constructor checks or guarantees UTF-8
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+}
+
+impl TryFrom<Bytes> for Utf8Bytes {
+    type Error = std::str::Utf8Error;
+
+    fn try_from(bytes: Bytes) -> Result<Self, Self::Error> {
+        std::str::from_utf8(&bytes)?;
+        Ok(Self(bytes))
+    }
+}
+
+impl From<String> for Utf8Bytes {
+    fn from(text: String) -> Self {
+        Self(Bytes::from(text))
+    }
+}
+
+impl From<&str> for Utf8Bytes {
+    fn from(text: &str) -> Self {
+        Self(Bytes::copy_from_slice(text.as_bytes()))
+    }
+}
+
+impl Deref for Utf8Bytes {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq<str> for Utf8Bytes {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
 }
 
-#[derive(Debug)]
+impl PartialEq<&str> for Utf8Bytes {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for Utf8Bytes {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl fmt::Debug for Utf8Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Utf8Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
 pub enum ParseError {
+    #[error("incomplete frame")]
     Incomplete,
+    #[error("invalid UTF-8")]
     InvalidUtf8,
+    #[error("control frame too large")]
     ControlFrameTooLarge,
+    #[error("unmasked client frame")]
     UnmaskedClientFrame,
+    #[error("invalid close code")]
     InvalidCloseCode,
+    /// A frame that is only meaningful as part of a fragmented message, or
+    /// fragments that do not follow the FIN/continuation rules.
+    #[error("invalid fragmentation")]
+    InvalidFragmentation,
+    /// A frame or reassembled message of `size` bytes (so far) above the
+    /// configured `limit`.
+    #[error("message of {size} bytes exceeds the {limit} byte limit")]
+    MessageTooBig { size: u64, limit: usize },
+    /// RSV1-3 set, though no extension defining them was negotiated.
+    #[error("reserved bits set")]
+    ReservedBits,
+    /// One of the opcodes RFC 6455 reserves for future use.
+    #[error("reserved opcode {0:#x}")]
+    InvalidOpcode(u8),
 }
 
-impl WebSocketFrame {
-    /// Parse a WebSocket frame, returning the frame and number of bytes consumed
-    pub fn parse(data: &[u8]) -> Result<(Self, usize), ParseError> {
+impl ParseError {
+    /// Status code to close the connection with (RFC 6455 §7.4.1).
+    pub fn close_code(&self) -> u16 {
+        match self {
+            ParseError::MessageTooBig { .. } => 1009,
+            ParseError::InvalidUtf8 => 1007,
+            _ => 1002,
+        }
+    }
+}
+
+/// A single frame as it appears on the wire, before fragments are joined.
+#[derive(Debug, Clone)]
+pub struct RawFrame {
+    pub fin: bool,
+    pub opcode: OpCode,
+    pub payload: Bytes,
+}
+
+/// What the fixed part of a frame says about it.
+struct FrameHeader {
+    fin: bool,
+    opcode: OpCode,
+    mask: [u8; 4],
+    /// Bytes up to the payload, including the masking key.
+    length: usize,
+    payload_length: usize,
+}
+
+impl RawFrame {
+    /// Parse one frame, returning it unmasked and the number of bytes consumed.
+    /// Frames announcing more than `max_payload` bytes are rejected before
+    /// their payload is buffered.
+    pub fn parse(data: &[u8], max_payload: usize) -> Result<(Self, usize), ParseError> {
+        let header = FrameHeader::parse(data, max_payload)?;
+        let end = header.length + header.payload_length;
+        let mut payload = data[header.length..end].to_vec();
+        apply_mask(&mut payload, header.mask);
+        Ok((header.into_frame(Bytes::from(payload)), end))
+    }
+
+    /// Like [`RawFrame::parse`], but take the frame off the front of
+    /// `buffer`, unmasking its payload in place instead of copying it.
+    pub fn decode(buffer: &mut BytesMut, max_payload: usize) -> Result<(Self, usize), ParseError> {
+        let header = FrameHeader::parse(buffer, max_payload)?;
+        buffer.advance(header.length);
+        let mut payload = buffer.split_to(header.payload_length);
+        apply_mask(&mut payload, header.mask);
+        let consumed = header.length + header.payload_length;
+        Ok((header.into_frame(payload.freeze()), consumed))
+    }
+
+    /// Write the frame, unmasked, to `writer`, e.g. as one fragment of a
+    /// message. Returns the number of bytes written.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<usize> {
+        write_frame(writer, self.fin, self.opcode, None, &self.payload).await
+    }
+}
+
+impl FrameHeader {
+    /// Parse the header of the frame at the start of `data`, which must hold
+    /// the whole frame.
+    fn parse(data: &[u8], max_payload: usize) -> Result<Self, ParseError> {
         if data.len() < 2 {
             return Err(ParseError::Incomplete);
         }
@@ -60,8 +235,16 @@ impl WebSocketFrame {
 
         // First byte: FIN (1 bit) + RSV (3 bits) + OpCode (4 bits)
         let first_byte = buf.get_u8();
-        let _fin = (first_byte & 0x80) != 0;
-        let opcode = OpCode::from(first_byte);
+        let fin = (first_byte & 0x80) != 0;
+        if first_byte & 0x70 != 0 {
+            return Err(ParseError::ReservedBits);
+        }
+        let opcode = OpCode::try_from(first_byte)?;
+
+        // Control frames cannot be fragmented
+        if opcode.is_control() && !fin {
+            return Err(ParseError::InvalidFragmentation);
+        }
 
         // Second byte: MASK (1 bit) + Payload length (7 bits)
         let second_byte = buf.get_u8();
@@ -90,44 +273,132 @@ impl WebSocketFrame {
         if opcode.is_control() && payload_length > 125 {
             return Err(ParseError::ControlFrameTooLarge);
         }
+        if payload_length > max_payload as u64 {
+            return Err(ParseError::MessageTooBig {
+                size: payload_length,
+                limit: max_payload,
+            });
+        }
 
-        // Masking key (if present)
-        let mask = if masked {
-            if buf.remaining() < 4 {
-                return Err(ParseError::Incomplete);
-            }
-            let mut mask_bytes = [0u8; 4];
-            buf.copy_to_slice(&mut mask_bytes);
-            Some(mask_bytes)
-        } else {
-            None
-        };
+        // Masking key
+        if buf.remaining() < 4 {
+            return Err(ParseError::Incomplete);
+        }
+        let mut mask = [0u8; 4];
+        buf.copy_to_slice(&mut mask);
 
         // Payload
         if buf.remaining() < payload_length as usize {
             return Err(ParseError::Incomplete);
         }
 
-        let mut payload = vec![0u8; payload_length as usize];
-        buf.copy_to_slice(&mut payload);
+        Ok(FrameHeader {
+            fin,
+            opcode,
+            mask,
+            length: start_len - buf.remaining(),
+            payload_length: payload_length as usize,
+        })
+    }
 
-        // Unmask payload if needed
-        if let Some(mask_key) = mask {
-            for (i, byte) in payload.iter_mut().enumerate() {
-                *byte ^= mask_key[i % 4];
-            }
+    fn into_frame(self, payload: Bytes) -> RawFrame {
+        RawFrame {
+            fin: self.fin,
+            opcode: self.opcode,
+            payload,
         }
+    }
+}
 
-        let consumed = start_len - buf.remaining();
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
 
-        // Create frame based on opcode
+/// Write a frame header, including the masking key if there is one.
+fn put_header(
+    dst: &mut impl BufMut,
+    fin: bool,
+    opcode: OpCode,
+    payload_len: usize,
+    mask: Option<[u8; 4]>,
+) {
+    // First byte: FIN (1) + RSV (000) + OpCode (4 bits)
+    let fin_bit = if fin { 0x80 } else { 0 };
+    dst.put_u8(fin_bit | (opcode as u8));
+
+    // Second byte: MASK (1 bit) + payload length
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    if payload_len < 126 {
+        dst.put_u8(mask_bit | payload_len as u8);
+    } else if payload_len < 65536 {
+        dst.put_u8(mask_bit | 126);
+        dst.put_u16(payload_len as u16);
+    } else {
+        dst.put_u8(mask_bit | 127);
+        dst.put_u64(payload_len as u64);
+    }
+
+    if let Some(mask) = mask {
+        dst.put_slice(&mask);
+    }
+}
+
+/// Write an unmasked frame with its header from the stack and `payload` from
+/// where it is. A Close status `code` travels with the header.
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    fin: bool,
+    opcode: OpCode,
+    code: Option<[u8; 2]>,
+    payload: &[u8],
+) -> io::Result<usize> {
+    let code_len = code.map_or(0, |code| code.len());
+    let mut head = [0u8; MAX_HEADER_LEN + 2];
+    let mut rest = &mut head[..];
+    put_header(&mut rest, fin, opcode, code_len + payload.len(), None);
+    if let Some(code) = code {
+        rest.put_slice(&code);
+    }
+    let head_len = MAX_HEADER_LEN + 2 - rest.len();
+
+    let mut slices = [IoSlice::new(&head[..head_len]), IoSlice::new(payload)];
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        let written = writer.write_vectored(slices).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut slices, written);
+    }
+    Ok(head_len + payload.len())
+}
+
+impl WebSocketFrame {
+    /// Parse an unfragmented WebSocket frame, returning the frame and number
+    /// of bytes consumed. Fragments must be joined first, see [`RawFrame`].
+    pub fn parse(data: &[u8]) -> Result<(Self, usize), ParseError> {
+        let (raw, consumed) = RawFrame::parse(data, MAX_FRAME_SIZE)?;
+        if !raw.fin {
+            return Err(ParseError::InvalidFragmentation);
+        }
+        Ok((Self::from_message(raw.opcode, raw.payload)?, consumed))
+    }
+
+    /// Build a frame from a complete message payload.
+    pub fn from_message(opcode: OpCode, payload: Bytes) -> Result<Self, ParseError> {
         let frame = match opcode {
             OpCode::Text => {
-                let text = String::from_utf8(payload).map_err(|_| ParseError::InvalidUtf8)?;
+                let text = Utf8Bytes::try_from(payload).map_err(|_| ParseError::InvalidUtf8)?;
                 WebSocketFrame::Text(text)
             }
             OpCode::Binary => WebSocketFrame::Binary(payload),
             OpCode::Close => {
+                // A body, if any, starts with a two-byte status code
+                if payload.len() == 1 {
+                    return Err(ParseError::InvalidCloseCode);
+                }
                 let close_info = if payload.len() >= 2 {
                     let code = u16::from_be_bytes([payload[0], payload[1]]);
 
@@ -136,12 +407,9 @@ impl WebSocketFrame {
                         return Err(ParseError::InvalidCloseCode);
                     }
 
-                    let reason = if payload.len() > 2 {
-                        String::from_utf8_lossy(&payload[2..]).to_string()
-                    } else {
-                        String::new()
-                    };
-                    Some((code, reason))
+                    let reason =
+                        std::str::from_utf8(&payload[2..]).map_err(|_| ParseError::InvalidUtf8)?;
+                    Some((code, reason.to_string()))
                 } else {
                     None
                 };
@@ -149,72 +417,102 @@ impl WebSocketFrame {
             }
             OpCode::Ping => WebSocketFrame::Ping(payload),
             OpCode::Pong => WebSocketFrame::Pong(payload),
-            OpCode::Continuation => {
-                // For now, treat continuation as incomplete
-                // Full fragmentation support would require state management
-                return Err(ParseError::Incomplete);
-            }
+            OpCode::Continuation => return Err(ParseError::InvalidFragmentation),
         };
 
-        Ok((frame, consumed))
+        Ok(frame)
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut frame = BytesMut::new();
-
+    /// Length of the payload as it goes on the wire.
+    pub fn payload_len(&self) -> usize {
         match self {
-            WebSocketFrame::Text(text) => {
-                let payload = text.as_bytes();
-                Self::write_frame(&mut frame, OpCode::Text, payload);
-            }
-            WebSocketFrame::Binary(data) => {
-                Self::write_frame(&mut frame, OpCode::Binary, data);
-            }
-            WebSocketFrame::Close(code_reason) => {
-                let mut payload = Vec::new();
-                if let Some((code, reason)) = code_reason {
-                    payload.extend_from_slice(&code.to_be_bytes());
-                    payload.extend_from_slice(reason.as_bytes());
-                }
-                Self::write_frame(&mut frame, OpCode::Close, &payload);
-            }
-            WebSocketFrame::Ping(data) => {
-                Self::write_frame(&mut frame, OpCode::Ping, data);
-            }
-            WebSocketFrame::Pong(data) => {
-                Self::write_frame(&mut frame, OpCode::Pong, data);
-            }
+            WebSocketFrame::Text(text) => text.len(),
+            WebSocketFrame::Binary(data)
+            | WebSocketFrame::Ping(data)
+            | WebSocketFrame::Pong(data) => data.len(),
+            WebSocketFrame::Close(Some((_, reason))) => 2 + reason.len(),
+            WebSocketFrame::Close(None) => 0,
         }
+    }
+
+    /// Serialize as a server frame, which is never masked.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.serialize(None)
+    }
 
+    /// Serialize as a client frame, masked with a fresh random key as
+    /// RFC 6455 §5.3 requires of clients.
+    pub fn to_masked_bytes(&self) -> Vec<u8> {
+        let mut mask = [0u8; 4];
+        getrandom::fill(&mut mask).expect("OS random number generator failed");
+        self.to_bytes_with_mask(mask)
+    }
+
+    /// Serialize as a client frame masked with `mask`.
+    pub fn to_bytes_with_mask(&self, mask: [u8; 4]) -> Vec<u8> {
+        self.serialize(Some(mask))
+    }
+
+    fn serialize(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut frame = BytesMut::with_capacity(MAX_HEADER_LEN + self.payload_len());
+        self.encode_with(&mut frame, mask);
         frame.to_vec()
     }
 
-    fn write_frame(frame: &mut BytesMut, opcode: OpCode, payload: &[u8]) {
-        // First byte: FIN (1) + RSV (000) + OpCode (4 bits)
-        frame.put_u8(0x80 | (opcode as u8));
-
-        // Second byte and payload length (no masking for server-to-client)
-        let payload_len = payload.len();
-        if payload_len < 126 {
-            frame.put_u8(payload_len as u8);
-        } else if payload_len < 65536 {
-            frame.put_u8(126);
-            frame.put_u16(payload_len as u16);
-        } else {
-            frame.put_u8(127);
-            frame.put_u64(payload_len as u64);
+    /// Append the frame, unmasked, to `dst`.
+    pub fn encode(&self, dst: &mut BytesMut) {
+        self.encode_with(dst, None)
+    }
+
+    fn encode_with(&self, dst: &mut BytesMut, mask: Option<[u8; 4]>) {
+        let (opcode, code, payload) = self.parts();
+        let code = code.as_ref().map_or(&[][..], |code| &code[..]);
+        dst.reserve(MAX_HEADER_LEN + code.len() + payload.len());
+        put_header(dst, true, opcode, code.len() + payload.len(), mask);
+
+        match mask {
+            Some(mask) => dst.extend(
+                code.iter()
+                    .chain(payload)
+                    .enumerate()
+                    .map(|(i, byte)| byte ^ mask[i % 4]),
+            ),
+            None => {
+                dst.extend_from_slice(code);
+                dst.extend_from_slice(payload);
+            }
         }
+    }
+
+    /// Write the frame, unmasked, straight to `writer`: the header is built
+    /// on the stack and the payload is written from where it already is.
+    /// Returns the number of bytes written.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<usize> {
+        let (opcode, code, payload) = self.parts();
+        write_frame(writer, true, opcode, code, payload).await
+    }
 
-        // Payload (no masking for server-to-client frames)
-        frame.extend_from_slice(payload);
+    /// Opcode and payload, with a Close frame's status code kept apart from
+    /// its reason.
+    fn parts(&self) -> (OpCode, Option<[u8; 2]>, &[u8]) {
+        match self {
+            WebSocketFrame::Text(text) => (OpCode::Text, None, text.as_bytes()),
+            WebSocketFrame::Binary(data) => (OpCode::Binary, None, data),
+            WebSocketFrame::Close(Some((code, reason))) => {
+                (OpCode::Close, Some(code.to_be_bytes()), reason.as_bytes())
+            }
+            WebSocketFrame::Close(None) => (OpCode::Close, None, &[]),
+            WebSocketFrame::Ping(data) => (OpCode::Ping, None, data),
+            WebSocketFrame::Pong(data) => (OpCode::Pong, None, data),
+        }
     }
 
     pub fn text(content: &str) -> Self {
-        WebSocketFrame::Text(content.to_string())
+        WebSocketFrame::Text(content.into())
     }
 
-    pub fn binary(data: Vec<u8>) -> Self {
-        WebSocketFrame::Binary(data)
+    pub fn binary(data: impl Into<Bytes>) -> Self {
+        WebSocketFrame::Binary(data.into())
     }
 
     pub fn close() -> Self {
@@ -225,12 +523,12 @@ impl WebSocketFrame {
         WebSocketFrame::Close(Some((code, reason.to_string())))
     }
 
-    pub fn ping(data: Vec<u8>) -> Self {
-        WebSocketFrame::Ping(data)
+    pub fn ping(data: impl Into<Bytes>) -> Self {
+        WebSocketFrame::Ping(data.into())
     }
 
-    pub fn pong(data: Vec<u8>) -> Self {
-        WebSocketFrame::Pong(data)
+    pub fn pong(data: impl Into<Bytes>) -> Self {
+        WebSocketFrame::Pong(data.into())
     }
 }
 
@@ -277,4 +575,119 @@ mod tests {
         // Payload should contain code and reason
         assert!(bytes.len() > 2);
     }
+
+    #[test]
+    fn test_decode_takes_frame_off_buffer() {
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&WebSocketFrame::binary(vec![1, 2, 3]).to_masked_bytes());
+        buffer.extend_from_slice(&WebSocketFrame::text("next").to_masked_bytes());
+
+        let (raw, consumed) = RawFrame::decode(&mut buffer, MAX_FRAME_SIZE).unwrap();
+        assert_eq!(consumed, 9);
+        assert_eq!(raw.payload, [1, 2, 3][..]);
+        let (raw, _) = RawFrame::decode(&mut buffer, MAX_FRAME_SIZE).unwrap();
+        assert_eq!(raw.payload, b"next"[..]);
+        assert!(buffer.is_empty());
+
+        // Nothing is taken until the whole frame is there
+        buffer.extend_from_slice(&WebSocketFrame::text("partial").to_masked_bytes()[..8]);
+        assert!(matches!(
+            RawFrame::decode(&mut buffer, MAX_FRAME_SIZE),
+            Err(ParseError::Incomplete)
+        ));
+        assert_eq!(buffer.len(), 8);
+    }
+
+    #[test]
+    fn test_opcode_from_byte() {
+        assert!(matches!(OpCode::try_from(0x81), Ok(OpCode::Text)));
+        assert!(matches!(OpCode::try_from(0x0a), Ok(OpCode::Pong)));
+        for reserved in (0x3..=0x7).chain(0xb..=0xf) {
+            assert!(matches!(
+                OpCode::try_from(0x80 | reserved),
+                Err(ParseError::InvalidOpcode(opcode)) if opcode == reserved
+            ));
+        }
+        assert_eq!(ParseError::InvalidOpcode(0x3).close_code(), 1002);
+    }
+
+    #[tokio::test]
+    async fn test_write_to_matches_to_bytes() {
+        let frames = [
+            WebSocketFrame::text("Hello"),
+            WebSocketFrame::binary(vec![7; 70_000]),
+            WebSocketFrame::close_with_code(1001, "Going away"),
+            WebSocketFrame::close(),
+        ];
+        for frame in frames {
+            let mut written = Vec::new();
+            let length = frame.write_to(&mut written).await.unwrap();
+            assert_eq!(written, frame.to_bytes());
+            assert_eq!(length, written.len());
+
+            let mut encoded = BytesMut::from(&b"before"[..]);
+            frame.encode(&mut encoded);
+            assert_eq!(encoded[6..], written[..]);
+        }
+
+        let fragment = RawFrame {
+            fin: false,
+            opcode: OpCode::Binary,
+            payload: Bytes::from_static(b"part"),
+        };
+        let mut written = Vec::new();
+        fragment.write_to(&mut written).await.unwrap();
+        assert_eq!(written, b"\x02\x04part");
+    }
+
+    #[test]
+    fn test_masked_frame_roundtrip() {
+        let frame = WebSocketFrame::text("Hello");
+        let bytes = frame.to_bytes_with_mask([1, 2, 3, 4]);
+        assert_eq!(bytes[1], 0x80 | 5);
+        assert_eq!(&bytes[2..6], &[1, 2, 3, 4]);
+        assert_eq!(bytes[6], b'H' ^ 1);
+
+        let (parsed, consumed) = WebSocketFrame::parse(&frame.to_masked_bytes()).unwrap();
+        assert_eq!(consumed, 11);
+        assert!(matches!(parsed, WebSocketFrame::Text(text) if text == "Hello"));
+
+        // 16-bit extended length
+        let long = WebSocketFrame::binary(vec![7; 300]);
+        let (parsed, _) = WebSocketFrame::parse(&long.to_masked_bytes()).unwrap();
+        assert!(matches!(parsed, WebSocketFrame::Binary(data) if data == vec![7; 300]));
+    }
+
+    #[test]
+    fn test_strict_validation() {
+        // Masked, empty payload, mask key 0
+        let frame = |first_byte: u8| [first_byte, 0x80, 0, 0, 0, 0];
+
+        assert!(matches!(
+            RawFrame::parse(&frame(0xc1), MAX_FRAME_SIZE),
+            Err(ParseError::ReservedBits)
+        ));
+        assert!(matches!(
+            RawFrame::parse(&frame(0x83), MAX_FRAME_SIZE),
+            Err(ParseError::InvalidOpcode(0x3))
+        ));
+        assert!(matches!(
+            RawFrame::parse(&frame(0x8b), MAX_FRAME_SIZE),
+            Err(ParseError::InvalidOpcode(0xb))
+        ));
+        assert!(matches!(
+            RawFrame::parse(&frame(0x09), MAX_FRAME_SIZE),
+            Err(ParseError::InvalidFragmentation)
+        ));
+        assert!(RawFrame::parse(&frame(0x89), MAX_FRAME_SIZE).is_ok());
+
+        assert!(matches!(
+            WebSocketFrame::from_message(OpCode::Close, Bytes::from_static(&[0x03])),
+            Err(ParseError::InvalidCloseCode)
+        ));
+        assert!(matches!(
+            WebSocketFrame::from_message(OpCode::Close, Bytes::from_static(&[0x03, 0xe8, 0xff])),
+            Err(ParseError::InvalidUtf8)
+        ));
+    }
 }
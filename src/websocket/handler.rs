@@ -0,0 +1,531 @@
+//! Application handlers for WebSocket messages, registered per path.
+
+use crate::{
+    error::{Result, ServerError},
+    websocket::{
+        connection::WebSocketConnection,
+        context::WebSocketContext,
+        frame::{OpCode, RawFrame, Utf8Bytes, WebSocketFrame},
+        streaming::{MessageStream, MessageWriter, message_incomplete},
+    },
+};
+use bytes::Bytes;
+use std::{fmt, future::Future, pin::Pin, sync::Arc};
+use tokio::{
+    sync::{Notify, mpsc, mpsc::error::TrySendError},
+    task::JoinHandle,
+};
+
+/// A complete data message received from or sent to a client. Payloads
+/// are shared, not copied, when a message is cloned or broadcast.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(Utf8Bytes),
+    Binary(Bytes),
+}
+
+impl Message {
+    pub fn text(text: impl Into<Utf8Bytes>) -> Self {
+        Message::Text(text.into())
+    }
+
+    pub fn binary(data: impl Into<Bytes>) -> Self {
+        Message::Binary(data.into())
+    }
+}
+
+impl From<Message> for WebSocketFrame {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Text(text) => WebSocketFrame::Text(text),
+            Message::Binary(data) => WebSocketFrame::Binary(data),
+        }
+    }
+}
+
+/// An entry in a connection's write queue.
+#[derive(Debug)]
+pub(crate) enum Outgoing {
+    Frame(WebSocketFrame),
+    /// A message written in fragments as its chunks arrive; nothing queued
+    /// after it is written until it ends.
+    Stream(OpCode, mpsc::Receiver<Bytes>),
+}
+
+/// What happens when a message is sent to a connection whose write queue is
+/// full because the client is not reading fast enough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowConsumerPolicy {
+    /// Drop the message; the client misses it but stays connected.
+    DropMessage,
+    /// Close the connection with 1008.
+    #[default]
+    Disconnect,
+}
+
+/// Queues frames for a connection in its bounded write queue. Cheap to
+/// clone, so other tasks (timers, subscribers, HTTP handlers via the
+/// [`Hub`](crate::websocket::Hub)) can push to the client without owning the
+/// socket. Sends fail once the connection is closed or the queue is full.
+#[derive(Debug, Clone)]
+pub struct WebSocketSender {
+    id: u64,
+    outgoing: mpsc::Sender<Outgoing>,
+    policy: SlowConsumerPolicy,
+    /// Signals the connection to disconnect a slow consumer.
+    overflow: Arc<Notify>,
+}
+
+impl WebSocketSender {
+    pub(crate) fn new(
+        id: u64,
+        outgoing: mpsc::Sender<Outgoing>,
+        policy: SlowConsumerPolicy,
+    ) -> Self {
+        Self {
+            id,
+            outgoing,
+            policy,
+            overflow: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Identifies the connection among all open ones.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Queue `message` without waiting. If the queue is full the
+    /// [`SlowConsumerPolicy`] applies and an error is returned.
+    pub fn send(&self, message: Message) -> Result<()> {
+        self.send_frame(message.into())
+    }
+
+    /// Queue `message`, waiting for room if the client is behind.
+    pub async fn send_wait(&self, message: Message) -> Result<()> {
+        self.outgoing
+            .send(Outgoing::Frame(message.into()))
+            .await
+            .map_err(|_| connection_closed())
+    }
+
+    /// A writer sending one message in pieces, for data too large to hold
+    /// in memory at once.
+    pub fn message_writer(&self) -> MessageWriter {
+        MessageWriter::new(self.clone())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.outgoing.is_closed()
+    }
+
+    /// Resolves once the connection has closed, e.g. to stop a task that
+    /// pushes to it.
+    pub async fn closed(&self) {
+        self.outgoing.closed().await
+    }
+
+    /// Start closing the connection with `code` and `reason`.
+    pub fn close(&self, code: u16, reason: &str) -> Result<()> {
+        self.send_frame(WebSocketFrame::close_with_code(code, reason))
+    }
+
+    fn send_frame(&self, frame: WebSocketFrame) -> Result<()> {
+        match self.outgoing.try_send(Outgoing::Frame(frame)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) => {
+                // A close must not be lost, so it always ends the connection
+                if self.policy == SlowConsumerPolicy::Disconnect
+                    || matches!(item, Outgoing::Frame(WebSocketFrame::Close(_)))
+                {
+                    self.overflow.notify_one();
+                }
+                Err(ServerError::WebSocketError("Write queue full".to_string()))
+            }
+            Err(TrySendError::Closed(_)) => Err(connection_closed()),
+        }
+    }
+
+    pub(crate) fn outgoing(&self) -> &mpsc::Sender<Outgoing> {
+        &self.outgoing
+    }
+
+    /// Resolves once a send found the queue full and the connection must
+    /// be dropped.
+    pub(crate) async fn overflowed(&self) {
+        self.overflow.notified().await
+    }
+}
+
+pub(crate) fn connection_closed() -> ServerError {
+    ServerError::WebSocketError("Connection closed".to_string())
+}
+
+/// Messages queued for a connection handler before reading from the
+/// socket pauses.
+const INCOMING_QUEUE: usize = 32;
+
+/// Callbacks for the events of a connection, for applications that would
+/// rather react to events than run a message loop. Only `on_message` is
+/// required; events of one connection are delivered one at a time, in order.
+pub trait WebSocketHandler: Send + Sync + 'static {
+    /// The handshake completed.
+    fn on_open(&self, _context: &WebSocketContext) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn on_message(
+        &self,
+        message: Message,
+        context: &WebSocketContext,
+    ) -> impl Future<Output = ()> + Send;
+
+    /// The connection closed with `code`: the peer's, ours if the server
+    /// closed it, 1005 for a Close without a code and 1006 if the connection
+    /// dropped without one. Always the last event.
+    fn on_close(
+        &self,
+        _code: u16,
+        _reason: &str,
+        _context: &WebSocketContext,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// The client broke the protocol or the connection failed; `on_close`
+    /// follows. Protocol violations arrive as
+    /// [`ServerError::WebSocketFrameError`] with the [`ParseError`] that
+    /// caused them.
+    ///
+    /// [`ParseError`]: crate::websocket::frame::ParseError
+    fn on_error(
+        &self,
+        _error: &ServerError,
+        _context: &WebSocketContext,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type EventFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+type MessageFn = dyn Fn(Message, WebSocketContext) -> HandlerFuture + Send + Sync;
+type ConnectionFn = dyn Fn(WebSocketConnection) -> HandlerFuture + Send + Sync;
+type StreamFn = dyn Fn(MessageStream, WebSocketContext) -> HandlerFuture + Send + Sync;
+
+/// [`WebSocketHandler`] with boxed futures, so routes can store any of them.
+pub(crate) trait EventHandler: Send + Sync {
+    fn open<'a>(&'a self, context: &'a WebSocketContext) -> EventFuture<'a>;
+    fn message<'a>(&'a self, message: Message, context: &'a WebSocketContext) -> EventFuture<'a>;
+    fn close<'a>(
+        &'a self,
+        code: u16,
+        reason: &'a str,
+        context: &'a WebSocketContext,
+    ) -> EventFuture<'a>;
+    fn error<'a>(
+        &'a self,
+        error: &'a ServerError,
+        context: &'a WebSocketContext,
+    ) -> EventFuture<'a>;
+}
+
+impl<H: WebSocketHandler> EventHandler for H {
+    fn open<'a>(&'a self, context: &'a WebSocketContext) -> EventFuture<'a> {
+        Box::pin(self.on_open(context))
+    }
+
+    fn message<'a>(&'a self, message: Message, context: &'a WebSocketContext) -> EventFuture<'a> {
+        Box::pin(self.on_message(message, context))
+    }
+
+    fn close<'a>(
+        &'a self,
+        code: u16,
+        reason: &'a str,
+        context: &'a WebSocketContext,
+    ) -> EventFuture<'a> {
+        Box::pin(self.on_close(code, reason, context))
+    }
+
+    fn error<'a>(
+        &'a self,
+        error: &'a ServerError,
+        context: &'a WebSocketContext,
+    ) -> EventFuture<'a> {
+        Box::pin(self.on_error(error, context))
+    }
+}
+
+#[derive(Clone)]
+enum Handler {
+    Message(Arc<MessageFn>),
+    Connection(Arc<ConnectionFn>),
+    Events(Arc<dyn EventHandler>),
+    Stream(Arc<StreamFn>),
+}
+
+/// The application side of a WebSocket path (query string ignored).
+#[derive(Clone)]
+pub struct WebSocketRoute {
+    pub path: String,
+    handler: Handler,
+}
+
+impl WebSocketRoute {
+    /// Hand every message to `handler`, one at a time, along with the
+    /// connection's context to reply through.
+    pub fn new<F, Fut>(path: &str, handler: F) -> Self
+    where
+        F: Fn(Message, WebSocketContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            path: path.to_string(),
+            handler: Handler::Message(Arc::new(move |message, context| {
+                Box::pin(handler(message, context))
+            })),
+        }
+    }
+
+    /// Run `handler` in its own task for each connection, with the
+    /// connection to read messages from and write replies to.
+    pub fn connection<F, Fut>(path: &str, handler: F) -> Self
+    where
+        F: Fn(WebSocketConnection) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            path: path.to_string(),
+            handler: Handler::Connection(Arc::new(move |connection| Box::pin(handler(connection)))),
+        }
+    }
+
+    /// Hand every message to `handler` as a [`MessageStream`] of its
+    /// fragments, without joining them first. Each call runs in its own
+    /// task, and the next message waits until it returns.
+    pub fn streaming<F, Fut>(path: &str, handler: F) -> Self
+    where
+        F: Fn(MessageStream, WebSocketContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            path: path.to_string(),
+            handler: Handler::Stream(Arc::new(move |stream, context| {
+                Box::pin(handler(stream, context))
+            })),
+        }
+    }
+
+    /// Deliver the connection's events to `handler`.
+    pub fn handler<H: WebSocketHandler>(path: &str, handler: H) -> Self {
+        Self {
+            path: path.to_string(),
+            handler: Handler::Events(Arc::new(handler)),
+        }
+    }
+
+    /// The example route answering every message on `path` with an echo.
+    pub fn echo(path: &str) -> Self {
+        Self::new(path, echo)
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        path.split('?').next() == Some(self.path.as_str())
+    }
+
+    /// Whether messages are handed over in fragments rather than joined.
+    pub(crate) fn is_streaming(&self) -> bool {
+        matches!(self.handler, Handler::Stream(_))
+    }
+
+    /// Start serving a newly opened connection.
+    pub(crate) fn open(&self, context: WebSocketContext) -> Dispatcher {
+        match &self.handler {
+            Handler::Message(handler) => Dispatcher::Message(Arc::clone(handler), context),
+            Handler::Connection(handler) => {
+                let (incoming_tx, incoming) = mpsc::channel(INCOMING_QUEUE);
+                tokio::spawn(handler(WebSocketConnection::new(context, incoming)));
+                Dispatcher::Connection(incoming_tx)
+            }
+            Handler::Events(handler) => Dispatcher::Events(Arc::clone(handler), context),
+            Handler::Stream(handler) => Dispatcher::Stream {
+                handler: Arc::clone(handler),
+                context,
+                chunks: None,
+                task: None,
+            },
+        }
+    }
+}
+
+/// Delivers a connection's messages to its route's handler.
+pub(crate) enum Dispatcher {
+    Message(Arc<MessageFn>, WebSocketContext),
+    Connection(mpsc::Sender<Message>),
+    Events(Arc<dyn EventHandler>, WebSocketContext),
+    Stream {
+        handler: Arc<StreamFn>,
+        context: WebSocketContext,
+        /// Where the chunks of the message being received go, if any.
+        chunks: Option<mpsc::Sender<Result<Bytes>>>,
+        /// The handler task of the latest message.
+        task: Option<JoinHandle<()>>,
+    },
+}
+
+impl Dispatcher {
+    pub(crate) async fn opened(&self) {
+        if let Dispatcher::Events(handler, context) = self {
+            handler.open(context).await;
+        }
+    }
+
+    pub(crate) async fn dispatch(&self, message: Message) {
+        match self {
+            Dispatcher::Message(handler, context) => handler(message, context.clone()).await,
+            // The handler may have stopped reading; the message is dropped
+            Dispatcher::Connection(incoming) => {
+                let _ = incoming.send(message).await;
+            }
+            Dispatcher::Events(handler, context) => handler.message(message, context).await,
+            Dispatcher::Stream { .. } => unreachable!("streaming routes receive chunks"),
+        }
+    }
+
+    /// Deliver a piece of a message to a streaming route. A piece with a
+    /// data opcode starts a new message, `Continuation` continues it.
+    pub(crate) async fn chunk(&mut self, chunk: RawFrame) {
+        let Dispatcher::Stream {
+            handler,
+            context,
+            chunks,
+            task,
+        } = self
+        else {
+            return;
+        };
+        if chunk.opcode != OpCode::Continuation {
+            if let Some(task) = task.take() {
+                let _ = task.await;
+            }
+            let (sender, receiver) = mpsc::channel(INCOMING_QUEUE);
+            let stream = MessageStream::new(chunk.opcode == OpCode::Text, receiver);
+            *task = Some(tokio::spawn(handler(stream, context.clone())));
+            *chunks = Some(sender);
+        }
+        // The handler may have stopped reading; the rest is dropped
+        if let Some(sender) = chunks {
+            let _ = sender.send(Ok(chunk.payload)).await;
+        }
+        if chunk.fin {
+            *chunks = None;
+        }
+    }
+
+    pub(crate) async fn failed(&mut self, error: &ServerError) {
+        match self {
+            Dispatcher::Events(handler, context) => handler.error(error, context).await,
+            Dispatcher::Stream { chunks, .. } => cut_off(chunks).await,
+            _ => {}
+        }
+    }
+
+    pub(crate) async fn closed(&mut self, code: u16, reason: &str) {
+        match self {
+            Dispatcher::Events(handler, context) => handler.close(code, reason, context).await,
+            Dispatcher::Stream { chunks, .. } => cut_off(chunks).await,
+            _ => {}
+        }
+    }
+}
+
+/// End the message being streamed to a handler, if any, with an error.
+async fn cut_off(chunks: &mut Option<mpsc::Sender<Result<Bytes>>>) {
+    if let Some(sender) = chunks.take() {
+        let _ = sender.send(Err(message_incomplete())).await;
+    }
+}
+
+impl fmt::Debug for WebSocketRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocketRoute")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Echo text prefixed with "Echo: " and binary data as-is.
+pub async fn echo(message: Message, context: WebSocketContext) {
+    let reply = match message {
+        Message::Text(text) => Message::text(format!("Echo: {}", text)),
+        binary => binary,
+    };
+    let _ = context.send(reply);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{protocol::request::HttpRequest, websocket::metrics::WebSocketMetrics};
+
+    #[tokio::test]
+    async fn test_route_dispatch() {
+        let route = WebSocketRoute::new("/chat", |message, context: WebSocketContext| async move {
+            // The data slot persists across messages
+            let count = context.get::<u32>().unwrap_or(0) + 1;
+            context.insert(count);
+            if let Message::Text(text) = message {
+                let user = context.header("x-user").unwrap_or("?");
+                let reply = format!("{} #{} from {}", text, count, user);
+                context.send(Message::text(reply)).unwrap();
+            }
+        });
+        assert!(route.matches("/chat"));
+        assert!(route.matches("/chat?room=1"));
+        assert!(!route.matches("/chat/more"));
+
+        let request =
+            HttpRequest::from_buffer_sync(b"GET /chat?room=1 HTTP/1.1\r\nX-User: ada\r\n\r\n")
+                .unwrap();
+        let (tx, mut rx) = mpsc::channel(2);
+        let sender = WebSocketSender::new(7, tx, SlowConsumerPolicy::Disconnect);
+        let metrics = WebSocketMetrics::default().open();
+        let context = WebSocketContext::new(sender, None, &request, None, metrics.stats());
+        assert_eq!(context.id(), 7);
+        assert_eq!(context.path(), "/chat?room=1");
+
+        let dispatcher = route.open(context);
+        for _ in 0..2 {
+            dispatcher.dispatch(Message::text("hi")).await;
+        }
+        assert!(
+            matches!(rx.recv().await, Some(Outgoing::Frame(WebSocketFrame::Text(text))) if text == "hi #1 from ada")
+        );
+        assert!(
+            matches!(rx.recv().await, Some(Outgoing::Frame(WebSocketFrame::Text(text))) if text == "hi #2 from ada")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_applies_policy() {
+        let text = || Message::text("hi");
+
+        let (tx, _rx) = mpsc::channel(1);
+        let sender = WebSocketSender::new(1, tx, SlowConsumerPolicy::DropMessage);
+        sender.send(text()).unwrap();
+        assert!(sender.send(text()).is_err());
+        assert!(
+            tokio::time::timeout(std::time::Duration::ZERO, sender.overflowed())
+                .await
+                .is_err()
+        );
+
+        let (tx, _rx) = mpsc::channel(1);
+        let sender = WebSocketSender::new(1, tx, SlowConsumerPolicy::Disconnect);
+        sender.send(text()).unwrap();
+        assert!(sender.send(text()).is_err());
+        sender.overflowed().await;
+    }
+}
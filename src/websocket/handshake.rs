@@ -1,44 +1,149 @@
 use crate::{
     error::Result,
-    protocol::{request::HttpRequest, response::HttpResponse},
+    protocol::{
+        request::{HttpMethod, HttpRequest},
+        response::{HttpResponse, HttpStatusCode},
+    },
+    websocket::WebSocketConfig,
 };
 use base64::{Engine as _, engine::general_purpose};
 use sha1::{Digest, Sha1};
 
 const WEBSOCKET_MAGIC_STRING: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-pub fn is_websocket_request(request: &HttpRequest) -> Option<&String> {
+/// Why an upgrade request is refused.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandshakeRejection {
+    /// Upgrades must be GET requests.
+    MethodNotAllowed(HttpMethod),
+    /// Upgrades need HTTP/1.1 or later; holds the request's version.
+    HttpVersionTooOld(String),
+    /// A required handshake header is missing.
+    BadRequest(&'static str),
+    /// `Sec-WebSocket-Version` is missing or not 13, the only version
+    /// supported; holds what the client sent.
+    UnsupportedVersion(Option<String>),
+    /// `Origin` is not in `WebSocketConfig::allowed_origins`.
+    OriginNotAllowed(String),
+}
+
+impl HandshakeRejection {
+    /// The response refusing the upgrade.
+    pub fn response(&self) -> HttpResponse {
+        match self {
+            HandshakeRejection::MethodNotAllowed(_) => {
+                HttpResponse::new(HttpStatusCode::MethodNotAllowed)
+                    .with_header("allow", "GET")
+                    .with_text("WebSocket upgrades must use GET")
+            }
+            HandshakeRejection::HttpVersionTooOld(_) => {
+                HttpResponse::bad_request().with_text("WebSocket upgrades require HTTP/1.1")
+            }
+            HandshakeRejection::BadRequest(reason) => HttpResponse::bad_request().with_text(reason),
+            // RFC 6455 §4.4: advertise the versions the server does speak
+            HandshakeRejection::UnsupportedVersion(_) => {
+                HttpResponse::new(HttpStatusCode::UpgradeRequired)
+                    .with_header("sec-websocket-version", "13")
+                    .with_text("Unsupported WebSocket version")
+            }
+            HandshakeRejection::OriginNotAllowed(_) => {
+                HttpResponse::new(HttpStatusCode::Forbidden).with_text("Origin not allowed")
+            }
+        }
+    }
+}
+
+/// The `Sec-WebSocket-Key` of an acceptable upgrade request, `None` if
+/// `request` is not an upgrade, or why the upgrade is refused. Any request
+/// with `Upgrade: websocket` counts as an upgrade attempt, so a malformed one
+/// is refused rather than served as plain HTTP.
+pub fn is_websocket_request<'a>(
+    request: &'a HttpRequest,
+    config: &WebSocketConfig,
+) -> std::result::Result<Option<&'a String>, HandshakeRejection> {
     let is_upgrade = request
         .get_header("upgrade")
         .map(|v| v.to_lowercase() == "websocket")
         .unwrap_or(false);
 
+    if !is_upgrade {
+        return Ok(None);
+    }
+
+    // RFC 6455 §4.1: the opening handshake is an HTTP/1.1 GET
+    if request.method != HttpMethod::Get {
+        return Err(HandshakeRejection::MethodNotAllowed(request.method.clone()));
+    }
+    if !is_http11_or_later(&request.version) {
+        return Err(HandshakeRejection::HttpVersionTooOld(
+            request.version.clone(),
+        ));
+    }
+
     let is_connection_upgrade = request
         .get_header("connection")
         .map(|v| v.to_lowercase().contains("upgrade"))
         .unwrap_or(false);
+    if !is_connection_upgrade {
+        return Err(HandshakeRejection::BadRequest(
+            "Missing Connection: Upgrade",
+        ));
+    }
 
-    let is_version_13 = request
-        .get_header("sec-websocket-version")
-        .map(|v| v == "13")
-        .unwrap_or(false);
+    let version = request.get_header("sec-websocket-version");
+    if version.map(String::as_str) != Some("13") {
+        return Err(HandshakeRejection::UnsupportedVersion(version.cloned()));
+    }
 
-    let websocket_key = request.get_header("sec-websocket-key");
+    let Some(websocket_key) = request.get_header("sec-websocket-key") else {
+        return Err(HandshakeRejection::BadRequest("Missing Sec-WebSocket-Key"));
+    };
 
-    if is_upgrade && is_connection_upgrade && is_version_13 {
-        websocket_key
-    } else {
-        None
+    // Browsers always send Origin, so checking it stops other sites from
+    // opening connections with the user's cookies (cross-site hijacking).
+    // Clients that send none are not browsers and are let through.
+    if !config.allowed_origins.is_empty()
+        && let Some(origin) = request.get_header("origin")
+        && !config
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+    {
+        return Err(HandshakeRejection::OriginNotAllowed(origin.clone()));
     }
+
+    Ok(Some(websocket_key))
+}
+
+fn is_http11_or_later(version: &str) -> bool {
+    let parsed = version.strip_prefix("HTTP/").and_then(|number| {
+        let (major, minor) = number.split_once('.').unwrap_or((number, "0"));
+        Some((major.parse::<u32>().ok()?, minor.parse::<u32>().ok()?))
+    });
+    parsed.is_some_and(|version| version >= (1, 1))
+}
+
+/// The first subprotocol offered in `Sec-WebSocket-Protocol` that the
+/// server supports, if any.
+pub fn select_subprotocol<'a>(request: &HttpRequest, supported: &'a [String]) -> Option<&'a str> {
+    let offered = request.get_header("sec-websocket-protocol")?;
+    offered
+        .split(',')
+        .map(str::trim)
+        .find_map(|protocol| supported.iter().find(|s| *s == protocol))
+        .map(String::as_str)
 }
 
-pub fn generate_accept(websocket_key: &str) -> Result<Vec<u8>> {
+pub fn generate_accept(websocket_key: &str, subprotocol: Option<&str>) -> Result<Vec<u8>> {
     let accept_key = generate_accept_key(websocket_key);
 
-    let response = HttpResponse::switching_protocols()
+    let mut response = HttpResponse::switching_protocols()
         .with_header("upgrade", "websocket")
         .with_header("connection", "Upgrade")
         .with_header("sec-websocket-accept", &accept_key);
+    if let Some(subprotocol) = subprotocol {
+        response = response.with_header("sec-websocket-protocol", subprotocol);
+    }
 
     Ok(response.to_bytes())
 }
@@ -54,8 +159,11 @@ fn generate_accept_key(websocket_key: &str) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::protocol::request::{HttpMethod, HttpRequest};
-    use std::collections::HashMap;
+    use crate::protocol::{
+        headers::Headers,
+        request::{Extensions, HttpMethod, HttpRequest},
+    };
+    use bytes::Bytes;
 
     #[test]
     fn test_websocket_key_generation() {
@@ -67,7 +175,7 @@ mod tests {
 
     #[test]
     fn test_is_websocket_request_valid() {
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
         headers.insert("upgrade".to_string(), "websocket".to_string());
         headers.insert("connection".to_string(), "Upgrade".to_string());
 
@@ -80,15 +188,19 @@ mod tests {
             path: "/".to_string(),
             version: "HTTP/1.1".to_string(),
             headers,
-            body: Vec::new(),
+            body: Bytes::new(),
+            extensions: Extensions::default(),
         };
 
-        assert_eq!(is_websocket_request(&request), Some(&key));
+        assert_eq!(
+            is_websocket_request(&request, &WebSocketConfig::default()),
+            Ok(Some(&key))
+        );
     }
 
     #[test]
     fn test_is_websocket_request_invalid() {
-        let mut headers = HashMap::new();
+        let mut headers = Headers::new();
         headers.insert("upgrade".to_string(), "http/1.1".to_string()); // Invalid
         headers.insert("connection".to_string(), "keep-alive".to_string());
         headers.insert("sec-websocket-key".to_string(), "test-key".to_string());
@@ -99,9 +211,117 @@ mod tests {
             path: "/".to_string(),
             version: "HTTP/1.1".to_string(),
             headers,
-            body: Vec::new(),
+            body: Bytes::new(),
+            extensions: Extensions::default(),
+        };
+
+        assert_eq!(
+            is_websocket_request(&request, &WebSocketConfig::default()),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_malformed_upgrade_is_rejected() {
+        let request = |headers: &[(&str, &str)]| HttpRequest {
+            method: HttpMethod::Get,
+            path: "/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: Bytes::new(),
+            extensions: Extensions::default(),
+        };
+        let config = WebSocketConfig::default();
+        let upgrade = [("upgrade", "websocket"), ("connection", "Upgrade")];
+
+        assert_eq!(
+            is_websocket_request(&request(&upgrade[..1]), &config),
+            Err(HandshakeRejection::BadRequest(
+                "Missing Connection: Upgrade"
+            ))
+        );
+
+        let old_version = [upgrade[0], upgrade[1], ("sec-websocket-version", "8")];
+        let rejection = is_websocket_request(&request(&old_version), &config).unwrap_err();
+        assert_eq!(
+            rejection,
+            HandshakeRejection::UnsupportedVersion(Some("8".to_string()))
+        );
+        let response = rejection.response();
+        assert_eq!(response.status, HttpStatusCode::UpgradeRequired);
+        assert_eq!(
+            response
+                .headers
+                .get("sec-websocket-version")
+                .map(String::as_str),
+            Some("13")
+        );
+
+        let no_key = [upgrade[0], upgrade[1], ("sec-websocket-version", "13")];
+        assert_eq!(
+            is_websocket_request(&request(&no_key), &config),
+            Err(HandshakeRejection::BadRequest("Missing Sec-WebSocket-Key"))
+        );
+    }
+
+    #[test]
+    fn test_upgrade_requires_http11_get() {
+        let request = |method: HttpMethod, version: &str| HttpRequest {
+            method,
+            path: "/".to_string(),
+            version: version.to_string(),
+            headers: Headers::from([
+                ("upgrade".to_string(), "websocket".to_string()),
+                ("connection".to_string(), "Upgrade".to_string()),
+                ("sec-websocket-version".to_string(), "13".to_string()),
+                ("sec-websocket-key".to_string(), "test-key".to_string()),
+            ]),
+            body: Bytes::new(),
+            extensions: Extensions::default(),
+        };
+        let config = WebSocketConfig::default();
+
+        let rejection =
+            is_websocket_request(&request(HttpMethod::Post, "HTTP/1.1"), &config).unwrap_err();
+        assert_eq!(
+            rejection,
+            HandshakeRejection::MethodNotAllowed(HttpMethod::Post)
+        );
+        assert_eq!(
+            rejection.response().status,
+            HttpStatusCode::MethodNotAllowed
+        );
+        assert_eq!(
+            is_websocket_request(&request(HttpMethod::Get, "HTTP/1.0"), &config),
+            Err(HandshakeRejection::HttpVersionTooOld(
+                "HTTP/1.0".to_string()
+            ))
+        );
+        assert!(is_websocket_request(&request(HttpMethod::Get, "HTTP/2"), &config).is_ok());
+    }
+
+    #[test]
+    fn test_select_subprotocol() {
+        let mut headers = Headers::new();
+        headers.insert(
+            "sec-websocket-protocol".to_string(),
+            "v2.chat, v1.chat".to_string(),
+        );
+        let request = HttpRequest {
+            method: HttpMethod::Get,
+            path: "/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers,
+            body: Bytes::new(),
+            extensions: Extensions::default(),
         };
 
-        assert_eq!(is_websocket_request(&request), None);
+        let supported = vec!["v1.chat".to_string(), "v2.chat".to_string()];
+        assert_eq!(select_subprotocol(&request, &supported), Some("v2.chat"));
+        assert_eq!(select_subprotocol(&request, &["mqtt".to_string()]), None);
+        assert_eq!(select_subprotocol(&request, &[]), None);
     }
 }
@@ -0,0 +1,350 @@
+//! Registry of open WebSocket connections for listing them, broadcasting to
+//! all of them or to named rooms, and closing them individually.
+
+use crate::websocket::{
+    handler::{Message, WebSocketSender},
+    metrics::{ConnectionStats, TrafficStats},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{Value, json};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::timeout;
+
+/// What is known about an open connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer_addr: Option<SocketAddr>,
+    /// Path of the upgrade request.
+    pub path: String,
+    pub subprotocol: Option<String>,
+    pub opened_at: DateTime<Utc>,
+    stats: ConnectionStats,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(
+        id: u64,
+        peer_addr: Option<SocketAddr>,
+        path: &str,
+        subprotocol: Option<&str>,
+        stats: ConnectionStats,
+    ) -> Self {
+        Self {
+            id,
+            peer_addr,
+            path: path.to_string(),
+            subprotocol: subprotocol.map(str::to_string),
+            opened_at: Utc::now(),
+            stats,
+        }
+    }
+
+    /// Frames and bytes exchanged so far.
+    pub fn stats(&self) -> TrafficStats {
+        self.stats.get()
+    }
+
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.stats.round_trip()
+    }
+
+    fn to_json(&self) -> Value {
+        let stats = self.stats();
+        json!({
+            "id": self.id,
+            "peer_addr": self.peer_addr.map(|addr| addr.to_string()),
+            "path": self.path,
+            "subprotocol": self.subprotocol,
+            "opened_at": self.opened_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+            "round_trip_ms": self.round_trip_time().map(|time| time.as_secs_f64() * 1000.0),
+            "frames_in": stats.frames_in,
+            "frames_out": stats.frames_out,
+            "bytes_in": stats.bytes_in,
+            "bytes_out": stats.bytes_out,
+        })
+    }
+}
+
+/// Every connection is registered on open and removed on close. Clones share
+/// the registry, so handlers can capture one to join rooms and broadcast.
+#[derive(Debug, Clone, Default)]
+pub struct Hub {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    connections: HashMap<u64, Connection>,
+    rooms: HashMap<String, HashSet<u64>>,
+}
+
+#[derive(Debug)]
+struct Connection {
+    sender: WebSocketSender,
+    info: ConnectionInfo,
+}
+
+impl Hub {
+    pub(crate) fn register(&self, sender: WebSocketSender, info: ConnectionInfo) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .connections
+            .insert(sender.id(), Connection { sender, info });
+    }
+
+    /// Forget a closed connection, including its room memberships.
+    pub(crate) fn unregister(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.connections.remove(&id);
+        state.rooms.retain(|_, members| {
+            members.remove(&id);
+            !members.is_empty()
+        });
+    }
+
+    /// Add the connection behind `sender` to `room`, creating it if needed.
+    pub fn join(&self, room: &str, sender: &WebSocketSender) {
+        let mut state = self.state.lock().unwrap();
+        if state.connections.contains_key(&sender.id()) {
+            state
+                .rooms
+                .entry(room.to_string())
+                .or_default()
+                .insert(sender.id());
+        }
+    }
+
+    pub fn leave(&self, room: &str, sender: &WebSocketSender) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(members) = state.rooms.get_mut(room) {
+            members.remove(&sender.id());
+            if members.is_empty() {
+                state.rooms.remove(room);
+            }
+        }
+    }
+
+    /// The sender for the open connection `id`, to push to it from
+    /// anywhere.
+    pub fn sender(&self, id: u64) -> Option<WebSocketSender> {
+        let state = self.state.lock().unwrap();
+        state
+            .connections
+            .get(&id)
+            .map(|connection| connection.sender.clone())
+    }
+
+    /// Details of the open connection `id`.
+    pub fn info(&self, id: u64) -> Option<ConnectionInfo> {
+        let state = self.state.lock().unwrap();
+        state
+            .connections
+            .get(&id)
+            .map(|connection| connection.info.clone())
+    }
+
+    /// Details of every open connection, oldest first.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let state = self.state.lock().unwrap();
+        let mut connections: Vec<ConnectionInfo> = state
+            .connections
+            .values()
+            .map(|connection| connection.info.clone())
+            .collect();
+        connections.sort_by_key(|info| info.id);
+        connections
+    }
+
+    /// Start closing connection `id` with `code` and `reason`. Returns false
+    /// if it is not open.
+    pub fn close(&self, id: u64, code: u16, reason: &str) -> bool {
+        self.sender(id)
+            .is_some_and(|sender| sender.close(code, reason).is_ok())
+    }
+
+    /// The open connections as a JSON array, for the admin endpoint.
+    pub(crate) fn render(&self) -> String {
+        Value::from_iter(self.connections().iter().map(ConnectionInfo::to_json)).to_string()
+    }
+
+    /// Connection `id` as a JSON object, if it is open.
+    pub(crate) fn render_one(&self, id: u64) -> Option<String> {
+        self.info(id).map(|info| info.to_json().to_string())
+    }
+
+    /// Ids of the open connections.
+    pub fn ids(&self) -> Vec<u64> {
+        self.state
+            .lock()
+            .unwrap()
+            .connections
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    /// Number of open connections.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().connections.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close every open connection with 1001 (Going Away) and wait up to
+    /// `grace` for them to finish their closing handshakes. Returns how many
+    /// were still open when time ran out.
+    pub async fn shutdown(&self, grace: Duration) -> usize {
+        let senders: Vec<WebSocketSender> = {
+            let state = self.state.lock().unwrap();
+            state
+                .connections
+                .values()
+                .map(|connection| connection.sender.clone())
+                .collect()
+        };
+        for sender in &senders {
+            let _ = sender.close(1001, "Going away");
+        }
+
+        let _ = timeout(grace, async {
+            for sender in &senders {
+                sender.closed().await;
+            }
+        })
+        .await;
+        senders.iter().filter(|sender| !sender.is_closed()).count()
+    }
+
+    /// Send `message` to every open connection; returns how many got it.
+    pub fn broadcast(&self, message: Message) -> usize {
+        let state = self.state.lock().unwrap();
+        let senders = state
+            .connections
+            .values()
+            .map(|connection| &connection.sender);
+        send_all(senders, &message)
+    }
+
+    /// Send `message` to the members of `room`; returns how many got it.
+    pub fn broadcast_to(&self, room: &str, message: Message) -> usize {
+        let state = self.state.lock().unwrap();
+        let Some(members) = state.rooms.get(room) else {
+            return 0;
+        };
+        let senders = members
+            .iter()
+            .filter_map(|id| state.connections.get(id))
+            .map(|connection| &connection.sender);
+        send_all(senders, &message)
+    }
+}
+
+fn send_all<'a>(senders: impl Iterator<Item = &'a WebSocketSender>, message: &Message) -> usize {
+    senders
+        .filter(|sender| sender.send(message.clone()).is_ok())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::{
+        frame::WebSocketFrame,
+        handler::{Outgoing, SlowConsumerPolicy},
+        metrics::WebSocketMetrics,
+    };
+    use tokio::sync::mpsc;
+
+    fn connection(hub: &Hub, id: u64) -> (WebSocketSender, mpsc::Receiver<Outgoing>) {
+        let (tx, rx) = mpsc::channel(8);
+        let sender = WebSocketSender::new(id, tx, SlowConsumerPolicy::Disconnect);
+        let stats = WebSocketMetrics::default().open().stats();
+        let info = ConnectionInfo::new(id, None, "/chat", None, stats);
+        hub.register(sender.clone(), info);
+        (sender, rx)
+    }
+
+    #[test]
+    fn test_broadcast_to_rooms() {
+        let hub = Hub::default();
+        let (a, mut a_rx) = connection(&hub, 1);
+        let (b, mut b_rx) = connection(&hub, 2);
+        hub.join("lobby", &a);
+        hub.join("lobby", &b);
+        hub.join("games", &b);
+
+        let text = || Message::text("hi");
+        assert_eq!(hub.broadcast(text()), 2);
+        assert_eq!(hub.broadcast_to("games", text()), 1);
+        hub.leave("lobby", &b);
+        assert_eq!(hub.broadcast_to("lobby", text()), 1);
+        assert_eq!(hub.broadcast_to("nowhere", text()), 0);
+
+        let received =
+            |rx: &mut mpsc::Receiver<Outgoing>| std::iter::from_fn(|| rx.try_recv().ok()).count();
+        assert_eq!(received(&mut a_rx), 2);
+        assert_eq!(received(&mut b_rx), 2);
+
+        assert_eq!(hub.sender(2).map(|sender| sender.id()), Some(2));
+        hub.unregister(b.id());
+        assert!(hub.sender(2).is_none());
+        assert_eq!(hub.len(), 1);
+        assert_eq!(hub.broadcast_to("games", text()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_connections() {
+        let hub = Hub::default();
+        let (_, mut a_rx) = connection(&hub, 1);
+        let (_, mut b_rx) = connection(&hub, 2);
+
+        // One connection completes its close, the other never does
+        let a = tokio::spawn(async move {
+            let frame = a_rx.recv().await;
+            drop(a_rx);
+            frame
+        });
+        let remaining = hub.shutdown(Duration::from_millis(50)).await;
+        assert_eq!(remaining, 1);
+
+        let going_away = |frame| {
+            matches!(
+                frame,
+                Some(Outgoing::Frame(WebSocketFrame::Close(Some((1001, _)))))
+            )
+        };
+        assert!(going_away(a.await.unwrap()));
+        assert!(going_away(b_rx.try_recv().ok()));
+    }
+
+    #[test]
+    fn test_list_and_close_connections() {
+        let hub = Hub::default();
+        let (_, _a_rx) = connection(&hub, 2);
+        let (_, mut b_rx) = connection(&hub, 1);
+
+        let ids: Vec<u64> = hub.connections().iter().map(|info| info.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(hub.info(2).map(|info| info.path), Some("/chat".to_string()));
+
+        assert!(hub.close(1, 1008, "Kicked"));
+        assert!(!hub.close(3, 1008, "Kicked"));
+        assert!(matches!(
+            b_rx.try_recv(),
+            Ok(Outgoing::Frame(WebSocketFrame::Close(Some((1008, _)))))
+        ));
+
+        let listed: Value = serde_json::from_str(&hub.render()).unwrap();
+        assert_eq!(listed[1]["id"], 2);
+        assert_eq!(listed[1]["path"], "/chat");
+        assert_eq!(listed[1]["peer_addr"], Value::Null);
+    }
+}
@@ -0,0 +1,342 @@
+//! Frame, byte and close code counters, per connection and for the server as
+//! a whole, for diagnosing misbehaving clients and networks.
+
+use crate::websocket::frame::WebSocketFrame;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Close code recorded for a Close frame without a status (RFC 6455 §7.4.1).
+const NO_STATUS: u16 = 1005;
+
+/// Traffic counted on one connection, or summed over all of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    /// Frames received, counting each fragment.
+    pub frames_in: u64,
+    pub frames_out: u64,
+    /// Bytes received and sent on the wire, including frame headers.
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub pings_sent: u64,
+    pub pongs_received: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    frames_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    pings_sent: AtomicU64,
+    pongs_received: AtomicU64,
+}
+
+/// Counters kept only per connection.
+#[derive(Debug, Default)]
+struct ConnectionCounters {
+    traffic: Counters,
+    /// Latest ping round trip in microseconds, 0 until one was measured.
+    round_trip: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> TrafficStats {
+        TrafficStats {
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            frames_out: self.frames_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            pings_sent: self.pings_sent.load(Ordering::Relaxed),
+            pongs_received: self.pongs_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counters aggregated over every connection. Clones share them, so the one
+/// in [`WebSocketConfig`](crate::websocket::WebSocketConfig) can be read from
+/// anywhere; it is also served by the metrics endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct WebSocketMetrics {
+    inner: Arc<Totals>,
+}
+
+#[derive(Debug, Default)]
+struct Totals {
+    traffic: Counters,
+    opened: AtomicU64,
+    closed: AtomicU64,
+    close_codes_sent: Mutex<BTreeMap<u16, u64>>,
+    close_codes_received: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl WebSocketMetrics {
+    pub fn traffic(&self) -> TrafficStats {
+        self.inner.traffic.snapshot()
+    }
+
+    /// Connections opened since startup.
+    pub fn connections_opened(&self) -> u64 {
+        self.inner.opened.load(Ordering::Relaxed)
+    }
+
+    /// Connections currently open.
+    pub fn connections_active(&self) -> u64 {
+        self.connections_opened() - self.inner.closed.load(Ordering::Relaxed)
+    }
+
+    /// How often each close code was sent; 1005 stands for no code.
+    pub fn close_codes_sent(&self) -> BTreeMap<u16, u64> {
+        self.inner.close_codes_sent.lock().unwrap().clone()
+    }
+
+    /// How often each close code was received; 1005 stands for no code.
+    pub fn close_codes_received(&self) -> BTreeMap<u16, u64> {
+        self.inner.close_codes_received.lock().unwrap().clone()
+    }
+
+    /// Start counting a newly opened connection.
+    pub(crate) fn open(&self) -> ConnectionMetrics {
+        self.inner.opened.fetch_add(1, Ordering::Relaxed);
+        ConnectionMetrics {
+            own: Arc::default(),
+            totals: Arc::clone(&self.inner),
+        }
+    }
+
+    /// The counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let traffic = self.traffic();
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        counter(
+            "websocket_connections_opened_total",
+            "WebSocket connections opened.",
+            self.connections_opened(),
+        );
+        counter(
+            "websocket_frames_received_total",
+            "WebSocket frames received.",
+            traffic.frames_in,
+        );
+        counter(
+            "websocket_frames_sent_total",
+            "WebSocket frames sent.",
+            traffic.frames_out,
+        );
+        counter(
+            "websocket_bytes_received_total",
+            "WebSocket bytes received.",
+            traffic.bytes_in,
+        );
+        counter(
+            "websocket_bytes_sent_total",
+            "WebSocket bytes sent.",
+            traffic.bytes_out,
+        );
+        counter(
+            "websocket_pings_sent_total",
+            "WebSocket pings sent by the server.",
+            traffic.pings_sent,
+        );
+        counter(
+            "websocket_pongs_received_total",
+            "WebSocket pongs received.",
+            traffic.pongs_received,
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP websocket_connections_active Open WebSocket connections."
+        );
+        let _ = writeln!(out, "# TYPE websocket_connections_active gauge");
+        let _ = writeln!(
+            out,
+            "websocket_connections_active {}",
+            self.connections_active()
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP websocket_close_codes_total WebSocket close frames by direction and code."
+        );
+        let _ = writeln!(out, "# TYPE websocket_close_codes_total counter");
+        for (direction, codes) in [
+            ("sent", self.close_codes_sent()),
+            ("received", self.close_codes_received()),
+        ] {
+            for (code, count) in codes {
+                let _ = writeln!(
+                    out,
+                    "websocket_close_codes_total{{direction=\"{}\",code=\"{}\"}} {}",
+                    direction, code, count
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Records one connection's traffic into both its own counters and the
+/// totals. Counting as closed happens on drop.
+#[derive(Debug)]
+pub(crate) struct ConnectionMetrics {
+    own: Arc<ConnectionCounters>,
+    totals: Arc<Totals>,
+}
+
+impl ConnectionMetrics {
+    /// A live view of this connection's counters.
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        ConnectionStats(Arc::clone(&self.own))
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        for counters in [&self.own.traffic, &self.totals.traffic] {
+            counters.frames_in.fetch_add(1, Ordering::Relaxed);
+            counters.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn sent(&self, frame: &WebSocketFrame, bytes: usize) {
+        self.fragment_sent(bytes);
+        if matches!(frame, WebSocketFrame::Ping(_)) {
+            for counters in [&self.own.traffic, &self.totals.traffic] {
+                counters.pings_sent.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let WebSocketFrame::Close(close) = frame {
+            let code = close.as_ref().map_or(NO_STATUS, |(code, _)| *code);
+            *self
+                .totals
+                .close_codes_sent
+                .lock()
+                .unwrap()
+                .entry(code)
+                .or_default() += 1;
+        }
+    }
+
+    /// Record one frame of a message streamed out in fragments.
+    pub(crate) fn fragment_sent(&self, bytes: usize) {
+        for counters in [&self.own.traffic, &self.totals.traffic] {
+            counters.frames_out.fetch_add(1, Ordering::Relaxed);
+            counters
+                .bytes_out
+                .fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn pong_received(&self) {
+        self.own
+            .traffic
+            .pongs_received
+            .fetch_add(1, Ordering::Relaxed);
+        self.totals
+            .traffic
+            .pongs_received
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the round trip of a ping answered by a matching pong.
+    pub(crate) fn round_trip(&self, time: Duration) {
+        let micros = (time.as_micros() as u64).max(1);
+        self.own.round_trip.store(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn close_received(&self, code: Option<u16>) {
+        let code = code.unwrap_or(NO_STATUS);
+        *self
+            .totals
+            .close_codes_received
+            .lock()
+            .unwrap()
+            .entry(code)
+            .or_default() += 1;
+    }
+}
+
+impl Drop for ConnectionMetrics {
+    fn drop(&mut self) {
+        self.totals.closed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Read access to one connection's counters, which keep updating while it
+/// is open.
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionStats(Arc<ConnectionCounters>);
+
+impl ConnectionStats {
+    pub(crate) fn get(&self) -> TrafficStats {
+        self.0.traffic.snapshot()
+    }
+
+    pub(crate) fn round_trip(&self) -> Option<Duration> {
+        match self.0.round_trip.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_and_total_counters() {
+        let metrics = WebSocketMetrics::default();
+        let first = metrics.open();
+        let second = metrics.open();
+        let stats = first.stats();
+
+        first.received(10);
+        first.sent(&WebSocketFrame::ping(Vec::new()), 2);
+        first.pong_received();
+        assert_eq!(stats.round_trip(), None);
+        first.round_trip(Duration::from_millis(3));
+        assert_eq!(stats.round_trip(), Some(Duration::from_millis(3)));
+        second.sent(
+            &WebSocketFrame::close_with_code(1009, "Message too big"),
+            21,
+        );
+        second.close_received(None);
+        drop(second);
+
+        assert_eq!(
+            stats.get(),
+            TrafficStats {
+                frames_in: 1,
+                frames_out: 1,
+                bytes_in: 10,
+                bytes_out: 2,
+                pings_sent: 1,
+                pongs_received: 1,
+            }
+        );
+        let totals = metrics.traffic();
+        assert_eq!((totals.frames_out, totals.bytes_out), (2, 23));
+        assert_eq!(
+            (metrics.connections_opened(), metrics.connections_active()),
+            (2, 1)
+        );
+        assert_eq!(metrics.close_codes_sent(), BTreeMap::from([(1009, 1)]));
+        assert_eq!(metrics.close_codes_received(), BTreeMap::from([(1005, 1)]));
+
+        let text = metrics.render();
+        assert!(text.contains("websocket_connections_active 1\n"));
+        assert!(text.contains("websocket_bytes_sent_total 23\n"));
+        assert!(text.contains("websocket_close_codes_total{direction=\"sent\",code=\"1009\"} 1\n"));
+    }
+}
@@ -1,143 +1,972 @@
-use crate::{error::Result, websocket::frame::WebSocketFrame};
-use bytes::{Buf, BytesMut};
+use crate::{
+    error::{Result, ServerError},
+    protocol::{request::HttpRequest, response::HttpResponse},
+    websocket::frame::{
+        MAX_FRAME_SIZE, MAX_MESSAGE_SIZE, OpCode, ParseError, RawFrame, WebSocketFrame,
+    },
+};
+use bytes::{Bytes, BytesMut};
+use std::{
+    net::SocketAddr,
+    ops::ControlFlow,
+    pin::pin,
+    sync::atomic::{AtomicU64, Ordering},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    time::{Duration, interval},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    time::{Duration, Instant, Interval, interval, sleep_until, timeout},
 };
 use tracing::{error, info, warn};
 
+use handler::Outgoing;
+use metrics::ConnectionMetrics;
+
+pub mod connection;
+pub mod context;
 pub mod frame;
+pub mod handler;
 pub mod handshake;
+pub mod hub;
+pub mod metrics;
+pub mod streaming;
+
+pub use connection::{WebSocketConnection, WebSocketSink, WebSocketStream};
+pub use context::WebSocketContext;
+pub use handler::{Message, SlowConsumerPolicy, WebSocketHandler, WebSocketRoute, WebSocketSender};
+pub use hub::{ConnectionInfo, Hub};
+pub use metrics::{TrafficStats, WebSocketMetrics};
+pub use streaming::{MessageStream, MessageWriter};
+
+/// How long to wait for the peer's Close reply before dropping the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Queued frames are written together up to this many bytes per write.
+const MAX_WRITE_BATCH: usize = 64 * 1024;
+
+/// Room made in the frame buffer before each read.
+const READ_SIZE: usize = 4096;
+
+/// WebSocket settings shared by all connections.
+#[derive(Debug, Clone)]
+pub struct WebSocketConfig {
+    /// Subprotocols the application speaks, matched against the client's
+    /// `Sec-WebSocket-Protocol` offer. Empty means none is ever selected.
+    pub subprotocols: Vec<String>,
+    /// Largest frame payload accepted; bigger frames close the connection
+    /// with 1009 before their payload is buffered.
+    pub max_frame_size: usize,
+    /// Largest message accepted once its fragments are joined. Streaming
+    /// routes never join them, so only `max_frame_size` applies there.
+    pub max_message_size: usize,
+    /// Handlers by path. Without any, every path gets the echo example;
+    /// otherwise upgrades for unregistered paths are answered with 404.
+    pub routes: Vec<WebSocketRoute>,
+    /// Origins (e.g. `https://example.com`) browsers may open connections
+    /// from; others are refused with 403. Empty allows any origin.
+    pub allowed_origins: Vec<String>,
+    /// Registry of the open connections, for broadcasting.
+    pub hub: Hub,
+    /// How often the server pings the client, or `None` to never ping.
+    pub ping_interval: Option<Duration>,
+    /// Consecutive pings left unanswered before the connection is closed.
+    pub max_missed_pongs: u32,
+    /// Close connections with 1000 after this long without a text or binary
+    /// message in either direction, or `None` to keep them open. Pings do
+    /// not count, so this also catches clients that are alive but idle.
+    pub idle_timeout: Option<Duration>,
+    /// Frames queued per connection before sends find the queue full.
+    pub write_queue_size: usize,
+    /// What a send to a connection with a full write queue does.
+    pub slow_consumer: SlowConsumerPolicy,
+    /// Traffic counters summed over all connections.
+    pub metrics: WebSocketMetrics,
+}
+
+impl WebSocketConfig {
+    /// The route handling upgrades for `path`, if any.
+    pub fn route_for(&self, path: &str) -> Option<WebSocketRoute> {
+        if self.routes.is_empty() {
+            return Some(WebSocketRoute::echo(path));
+        }
+        self.routes
+            .iter()
+            .find(|route| route.matches(path))
+            .cloned()
+    }
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self {
+            subprotocols: Vec::new(),
+            max_frame_size: MAX_FRAME_SIZE,
+            max_message_size: MAX_MESSAGE_SIZE,
+            routes: Vec::new(),
+            allowed_origins: Vec::new(),
+            hub: Hub::default(),
+            ping_interval: Some(Duration::from_secs(30)),
+            max_missed_pongs: 1,
+            idle_timeout: None,
+            write_queue_size: 64,
+            slow_consumer: SlowConsumerPolicy::default(),
+            metrics: WebSocketMetrics::default(),
+        }
+    }
+}
 
-/// Handles the WebSocket connection lifecycle with ping/pong support.
-pub async fn handle_websocket(mut socket: TcpStream, websocket_key: &str) -> Result<()> {
-    let handshake_response = handshake::generate_accept(websocket_key)?;
+/// Completes the upgrade `request` and runs the connection, passing messages
+/// to the route registered for its path, with ping/pong support.
+pub async fn handle_websocket<S>(
+    mut socket: S,
+    peer_addr: Option<SocketAddr>,
+    request: &HttpRequest,
+    config: &WebSocketConfig,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let websocket_key = match handshake::is_websocket_request(request, config) {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            return Err(ServerError::WebSocketHandshakeFailed(
+                "Not a WebSocket upgrade".to_string(),
+            ));
+        }
+        Err(rejection) => {
+            info!(?peer_addr, ?rejection, "WebSocket upgrade refused");
+            let response = rejection.response().close_connection();
+            socket.write_all(&response.to_bytes()).await?;
+            return Ok(());
+        }
+    };
+    let Some(route) = config.route_for(&request.path) else {
+        info!(?peer_addr, path = %request.path, "No WebSocket route for path");
+        let response = HttpResponse::not_found()
+            .with_text("Not Found")
+            .close_connection();
+        socket.write_all(&response.to_bytes()).await?;
+        return Ok(());
+    };
+    let subprotocol = handshake::select_subprotocol(request, &config.subprotocols);
+
+    let handshake_response = handshake::generate_accept(websocket_key, subprotocol)?;
     socket.write_all(&handshake_response).await?;
+    socket.flush().await?;
+    // Each frame is written out in one go when flushed
+    let mut socket = BufWriter::new(socket);
 
-    let peer_addr = socket.peer_addr().ok();
-    info!(?peer_addr, "WebSocket connection established");
+    info!(?peer_addr, path = %route.path, ?subprotocol, "WebSocket connection established");
 
-    // Frame buffering
-    let mut buffer = BytesMut::with_capacity(4096);
-    let mut ping_interval = interval(Duration::from_secs(30));
-    let mut awaiting_pong = false;
+    // Frame buffering and reassembly of fragmented messages
+    let mut buffer = BytesMut::with_capacity(READ_SIZE);
+    let mut reassembler = Reassembler::new(config.max_message_size);
+    reassembler.streaming = route.is_streaming();
+    // Frames queued by the route's handler
+    let (outgoing_tx, outgoing) = mpsc::channel(config.write_queue_size);
+    let sender = WebSocketSender::new(next_connection_id(), outgoing_tx, config.slow_consumer);
+    let metrics = config.metrics.open();
+    let info = ConnectionInfo::new(
+        sender.id(),
+        peer_addr,
+        &request.path,
+        subprotocol,
+        metrics.stats(),
+    );
+    config.hub.register(sender.clone(), info);
+    let context = WebSocketContext::new(
+        sender.clone(),
+        peer_addr,
+        request,
+        subprotocol,
+        metrics.stats(),
+    );
+    let mut dispatcher = route.open(context);
+    let mut outbox = Outbox {
+        outgoing,
+        sending: None,
+        peer_addr,
+    };
+    let mut last_message = Instant::now();
+    let mut ping_interval = config.ping_interval.map(interval);
+    // Payload and send time of the ping awaiting its pong, if any
+    let mut pending_ping: Option<(Bytes, Instant)> = None;
+    let mut pings_sent: u64 = 0;
+    let mut missed_pongs = 0;
+    // Status of the Close the peer sent first, if it did
+    let mut peer_close = None;
 
-    loop {
-        tokio::select! {
-            // Handle ping timer
-            _ = ping_interval.tick() => {
-                if awaiting_pong {
-                    warn!(?peer_addr, "Client did not respond to PING, closing connection");
-                    let _ = socket.write_all(&WebSocketFrame::close_with_code(1002, "Ping timeout").to_bytes()).await;
-                    break;
+    // Handlers are run through `drive`, which keeps writing what they queue
+    // meanwhile
+    macro_rules! drive {
+        ($handler:expr) => {
+            drive(
+                $handler,
+                &mut outbox,
+                &mut socket,
+                &sender,
+                &metrics,
+                &mut last_message,
+            )
+            .await
+        };
+    }
+
+    // The close frame to start the closing handshake with, if the server
+    // ends the connection
+    let close = 'serve: {
+        if let ControlFlow::Break(close) = drive!(dispatcher.opened()) {
+            break 'serve close;
+        }
+        loop {
+            tokio::select! {
+                // Handle ping timer
+                _ = tick(&mut ping_interval) => {
+                    if pending_ping.is_some() {
+                        missed_pongs += 1;
+                        if missed_pongs >= config.max_missed_pongs {
+                            warn!(?peer_addr, missed_pongs, "Client did not respond to PING, closing connection");
+                            break Some(WebSocketFrame::close_with_code(1002, "Ping timeout"));
+                        }
+                    }
+
+                    // A sequence number, so a late pong to an earlier ping is
+                    // not mistaken for the answer to this one
+                    info!(?peer_addr, "Sending PING");
+                    pings_sent += 1;
+                    let payload = Bytes::copy_from_slice(&pings_sent.to_be_bytes());
+                    let ping = WebSocketFrame::Ping(payload.clone());
+                    if let Err(e) = write_frame(&mut socket, &ping, &metrics).await {
+                        error!(?peer_addr, error = ?e, "Failed to send PING");
+                        break None;
+                    }
+
+                    pending_ping = Some((payload, Instant::now()));
                 }
 
-                info!(?peer_addr, "Sending PING");
-                let ping = WebSocketFrame::Ping(Vec::new());
-                if let Err(e) = socket.write_all(&ping.to_bytes()).await {
-                    error!(?peer_addr, error = ?e, "Failed to send PING");
-                    break;
+                _ = idle(last_message, config.idle_timeout) => {
+                    info!(?peer_addr, "Connection idle, closing");
+                    break Some(WebSocketFrame::close_with_code(1000, "Idle timeout"));
                 }
 
-                awaiting_pong = true;
-            }
+                // Send frames queued by the handler
+                _ = sender.overflowed() => {
+                    warn!(?peer_addr, "Write queue full, disconnecting slow client");
+                    break Some(WebSocketFrame::close_with_code(1008, "Too slow"));
+                }
 
-            // Handle incoming data
-            result = read_frame(&mut socket, &mut buffer) => {
-                match result {
-                    Ok(Some(frame)) => {
-                        match frame {
-                            WebSocketFrame::Text(text) => {
-                                info!(?peer_addr, text = %text, "Received text frame");
-                                let response = WebSocketFrame::Text(format!("Echo: {}", text));
-                                if let Err(e) = socket.write_all(&response.to_bytes()).await {
-                                    error!(?peer_addr, error = ?e, "Failed to send response");
-                                    break;
-                                }
+                pending = outbox.next() => {
+                    if let ControlFlow::Break(close) =
+                        outbox.write(pending, &mut socket, &metrics, &mut last_message).await
+                    {
+                        break close;
+                    }
+                }
+
+                // Handle incoming data
+                result = read_frame(&mut socket, &mut buffer, &mut reassembler, config, &metrics) => {
+                    match result {
+                        Ok(Ok(Incoming::Chunk(chunk))) => {
+                            if chunk.opcode != OpCode::Continuation {
+                                info!(?peer_addr, "Receiving streamed message");
                             }
-                            WebSocketFrame::Binary(data) => {
-                                info!(?peer_addr, len = data.len(), "Received binary frame");
-                                let response = WebSocketFrame::Binary(data);
-                                if let Err(e) = socket.write_all(&response.to_bytes()).await {
-                                    error!(?peer_addr, error = ?e, "Failed to send response");
-                                    break;
-                                }
+                            last_message = Instant::now();
+                            if let ControlFlow::Break(close) = drive!(dispatcher.chunk(chunk)) {
+                                break close;
                             }
-                            WebSocketFrame::Ping(data) => {
-                                info!(?peer_addr, "Received PING, sending PONG");
-                                let pong = WebSocketFrame::Pong(data);
-                                if let Err(e) = socket.write_all(&pong.to_bytes()).await {
-                                    error!(?peer_addr, error = ?e, "Failed to send PONG");
-                                    break;
+                        }
+                        Ok(Ok(Incoming::Frame(frame))) => {
+                            match frame {
+                                WebSocketFrame::Text(text) => {
+                                    info!(?peer_addr, len = text.len(), "Received text message");
+                                    last_message = Instant::now();
+                                    if let ControlFlow::Break(close) =
+                                        drive!(dispatcher.dispatch(Message::Text(text)))
+                                    {
+                                        break close;
+                                    }
                                 }
-                            }
-                            WebSocketFrame::Pong(_) => {
-                                info!(?peer_addr, "Received PONG");
-                                awaiting_pong = false;
-                            }
-                            WebSocketFrame::Close(code_reason) => {
-                                if let Some((code, reason)) = code_reason {
-                                    info!(?peer_addr, code = code, reason = %reason, "Received close frame");
-                                } else {
-                                    info!(?peer_addr, "Received close frame");
+                                WebSocketFrame::Binary(data) => {
+                                    info!(?peer_addr, len = data.len(), "Received binary message");
+                                    last_message = Instant::now();
+                                    if let ControlFlow::Break(close) =
+                                        drive!(dispatcher.dispatch(Message::Binary(data)))
+                                    {
+                                        break close;
+                                    }
+                                }
+                                WebSocketFrame::Ping(data) => {
+                                    info!(?peer_addr, "Received PING, sending PONG");
+                                    let pong = WebSocketFrame::Pong(data);
+                                    if let Err(e) = write_frame(&mut socket, &pong, &metrics).await {
+                                        error!(?peer_addr, error = ?e, "Failed to send PONG");
+                                        break None;
+                                    }
+                                }
+                                WebSocketFrame::Pong(data) => {
+                                    metrics.pong_received();
+                                    // Unsolicited pongs are allowed, but only the
+                                    // echo of our ping proves the client is alive
+                                    match pending_ping.take_if(|(payload, _)| *payload == data) {
+                                        Some((_, sent)) => {
+                                            let round_trip = sent.elapsed();
+                                            info!(?peer_addr, ?round_trip, "Received PONG");
+                                            metrics.round_trip(round_trip);
+                                            missed_pongs = 0;
+                                        }
+                                        None => info!(?peer_addr, "Ignoring PONG not matching our PING"),
+                                    }
+                                }
+                                WebSocketFrame::Close(code_reason) => {
+                                    // Echo the status code, then close the TCP
+                                    // connection first as the server should
+                                    metrics.close_received(code_reason.as_ref().map(|(code, _)| *code));
+                                    let reply = if let Some((code, reason)) = &code_reason {
+                                        info!(?peer_addr, code = code, reason = %reason, "Received close frame");
+                                        WebSocketFrame::close_with_code(*code, "")
+                                    } else {
+                                        info!(?peer_addr, "Received close frame");
+                                        WebSocketFrame::Close(None)
+                                    };
+                                    peer_close = Some(code_reason.unwrap_or((1005, String::new())));
+                                    let _ = write_frame(&mut socket, &reply, &metrics).await;
+                                    let _ = socket.shutdown().await;
+                                    break None;
                                 }
-                                let close = WebSocketFrame::Close(None);
-                                let _ = socket.write_all(&close.to_bytes()).await;
-                                break;
                             }
                         }
+                        Ok(Err(ParseError::MessageTooBig { size, limit })) => {
+                            warn!(?peer_addr, size, limit, "Message too big, closing connection");
+                            let _ = drive!(dispatcher.failed(&ParseError::MessageTooBig { size, limit }.into()));
+                            break Some(WebSocketFrame::close_with_code(1009, "Message too big"));
+                        }
+                        Ok(Err(e)) => {
+                            warn!(?peer_addr, error = ?e, "Invalid frame, failing connection");
+                            let reason = match e {
+                                ParseError::InvalidUtf8 => "Invalid UTF-8",
+                                _ => "Protocol error",
+                            };
+                            let _ = drive!(dispatcher.failed(&e.into()));
+                            break Some(WebSocketFrame::close_with_code(e.close_code(), reason));
+                        }
+                        Err(ServerError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            info!(?peer_addr, "Connection dropped without a close frame");
+                            break None;
+                        }
+                        Err(e) => {
+                            error!(?peer_addr, error = ?e, "Error reading frame");
+                            let _ = drive!(dispatcher.failed(&e));
+                            break None;
+                        }
                     }
-                    Ok(None) => {
-                        // Need more data, continue reading
-                        continue;
+                }
+            }
+        }
+    };
+
+    // Leave the hub before waiting on the peer, so nothing more is queued,
+    // and fail sends from here on rather than letting them wait
+    config.hub.unregister(sender.id());
+    outbox.shut();
+    let (code, reason) = match (peer_close, &close) {
+        (Some(peer_close), _) => peer_close,
+        (None, Some(WebSocketFrame::Close(Some((code, reason))))) => (*code, reason.clone()),
+        _ => (1006, String::new()),
+    };
+    if let Some(close) = close
+        && !close_handshake(
+            &mut socket,
+            &mut buffer,
+            &mut reassembler,
+            config,
+            &metrics,
+            close,
+        )
+        .await
+    {
+        info!(?peer_addr, "Peer did not answer the close frame");
+    }
+    dispatcher.closed(code, &reason).await;
+    info!(?peer_addr, "WebSocket connection closed");
+    Ok(())
+}
+
+/// Send `close`, half-close the write side and wait up to `CLOSE_TIMEOUT`
+/// for the peer's Close reply (RFC 6455 §7.1.1), discarding any other frames
+/// still in flight. Returns whether the peer acknowledged.
+async fn close_handshake<S>(
+    socket: &mut S,
+    buffer: &mut BytesMut,
+    reassembler: &mut Reassembler,
+    config: &WebSocketConfig,
+    metrics: &ConnectionMetrics,
+    close: WebSocketFrame,
+) -> bool
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if write_frame(socket, &close, metrics).await.is_err() {
+        return false;
+    }
+    let _ = socket.shutdown().await;
+
+    let reply = timeout(CLOSE_TIMEOUT, async {
+        loop {
+            match read_frame(socket, buffer, reassembler, config, metrics).await {
+                Ok(Ok(Incoming::Frame(WebSocketFrame::Close(close)))) => {
+                    metrics.close_received(close.map(|(code, _)| code));
+                    return true;
+                }
+                Ok(Ok(_)) => continue,
+                _ => return false,
+            }
+        }
+    });
+    reply.await.unwrap_or(false)
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    frame: &WebSocketFrame,
+    metrics: &ConnectionMetrics,
+) -> std::io::Result<()> {
+    write_frames(socket, std::slice::from_ref(frame), metrics).await
+}
+
+/// Write `frames` in one go: a lone frame straight from its payload, several
+/// encoded together into one buffer.
+async fn write_frames<S: AsyncWrite + Unpin>(
+    socket: &mut S,
+    frames: &[WebSocketFrame],
+    metrics: &ConnectionMetrics,
+) -> std::io::Result<()> {
+    if let [frame] = frames {
+        let length = frame.write_to(socket).await?;
+        socket.flush().await?;
+        metrics.sent(frame, length);
+        return Ok(());
+    }
+
+    let mut bytes = BytesMut::new();
+    let mut lengths = Vec::with_capacity(frames.len());
+    for frame in frames {
+        let start = bytes.len();
+        frame.encode(&mut bytes);
+        lengths.push(bytes.len() - start);
+    }
+    socket.write_all(&bytes).await?;
+    socket.flush().await?;
+    for (frame, length) in frames.iter().zip(lengths) {
+        metrics.sent(frame, length);
+    }
+    Ok(())
+}
+
+/// The sending side of a connection: the frames queued by its handler and
+/// the message being streamed out.
+struct Outbox {
+    outgoing: mpsc::Receiver<Outgoing>,
+    /// Opcode for the next fragment of the message being streamed out, and
+    /// where its chunks come from
+    sending: Option<(OpCode, mpsc::Receiver<Bytes>)>,
+    peer_addr: Option<SocketAddr>,
+}
+
+/// Something for the [`Outbox`] to write.
+enum Pending {
+    Queued(Outgoing),
+    /// The next chunk of the message being streamed out, `None` once it is
+    /// complete.
+    Chunk(Option<Bytes>),
+}
+
+impl Outbox {
+    /// Wait for the next thing to write. Nothing queued is taken while a
+    /// message is being streamed out.
+    async fn next(&mut self) -> Pending {
+        match &mut self.sending {
+            Some((_, chunks)) => Pending::Chunk(chunks.recv().await),
+            None => match self.outgoing.recv().await {
+                Some(item) => Pending::Queued(item),
+                None => std::future::pending().await,
+            },
+        }
+    }
+
+    /// Write `pending` to `socket`. Breaks with the close frame to end the
+    /// connection with, or `None` if the socket failed.
+    async fn write<S: AsyncWrite + Unpin>(
+        &mut self,
+        pending: Pending,
+        socket: &mut S,
+        metrics: &ConnectionMetrics,
+        last_message: &mut Instant,
+    ) -> ControlFlow<Option<WebSocketFrame>> {
+        let peer_addr = self.peer_addr;
+        let item = match pending {
+            Pending::Queued(item) => item,
+            Pending::Chunk(chunk) => {
+                // The end of the message is marked by an empty final frame
+                let (opcode, _) = self.sending.as_mut().expect("message being sent");
+                let fragment = RawFrame {
+                    fin: chunk.is_none(),
+                    opcode: std::mem::replace(opcode, OpCode::Continuation),
+                    payload: chunk.unwrap_or_default(),
+                };
+                if fragment.fin {
+                    self.sending = None;
+                }
+                *last_message = Instant::now();
+                let written = match fragment.write_to(socket).await {
+                    Ok(length) => socket.flush().await.map(|()| length),
+                    Err(e) => Err(e),
+                };
+                return match written {
+                    Ok(length) => {
+                        metrics.fragment_sent(length);
+                        ControlFlow::Continue(())
                     }
                     Err(e) => {
-                        error!(?peer_addr, error = ?e, "Error reading frame");
-                        break;
+                        error!(?peer_addr, error = ?e, "Failed to send fragment");
+                        ControlFlow::Break(None)
                     }
+                };
+            }
+        };
+
+        // Write whatever else is already queued along with it, so a burst
+        // of small messages costs one syscall
+        let mut batch = Vec::new();
+        let mut batch_len = 0;
+        let mut close = None;
+        let mut next = Some(item);
+        while let Some(item) = next.take() {
+            let frame = match item {
+                Outgoing::Frame(frame) => frame,
+                Outgoing::Stream(opcode, chunks) => {
+                    self.sending = Some((opcode, chunks));
+                    break;
                 }
+            };
+            match frame {
+                WebSocketFrame::Close(_) => {
+                    close = Some(frame);
+                    break;
+                }
+                WebSocketFrame::Text(_) | WebSocketFrame::Binary(_) => {
+                    *last_message = Instant::now();
+                }
+                _ => {}
+            }
+            batch_len += frame.payload_len();
+            batch.push(frame);
+            if batch_len < MAX_WRITE_BATCH {
+                next = self.outgoing.try_recv().ok();
             }
         }
+        if let Err(e) = write_frames(socket, &batch, metrics).await {
+            error!(?peer_addr, error = ?e, "Failed to send frame");
+            return ControlFlow::Break(None);
+        }
+        match close {
+            Some(close) => ControlFlow::Break(Some(close)),
+            None => ControlFlow::Continue(()),
+        }
     }
 
-    info!(?peer_addr, "WebSocket connection closed");
-    Ok(())
+    /// Stop taking frames: sends fail from now on instead of waiting.
+    fn shut(&mut self) {
+        self.outgoing.close();
+        self.sending = None;
+    }
+}
+
+/// Run `handler` to completion while writing out what it queues, so a
+/// handler waiting for room in the write queue (`send_wait`, the `Sink`, a
+/// `MessageWriter`) is not waiting on itself. Breaks with the close frame to
+/// end the connection with if it must end; the outbox is then shut, so the
+/// handler's sends fail rather than wait.
+async fn drive<S: AsyncWrite + Unpin>(
+    handler: impl Future<Output = ()>,
+    outbox: &mut Outbox,
+    socket: &mut S,
+    sender: &WebSocketSender,
+    metrics: &ConnectionMetrics,
+    last_message: &mut Instant,
+) -> ControlFlow<Option<WebSocketFrame>> {
+    let mut handler = pin!(handler);
+    let mut flow = ControlFlow::Continue(());
+    loop {
+        tokio::select! {
+            () = &mut handler => return flow,
+            _ = sender.overflowed(), if flow.is_continue() => {
+                warn!(peer_addr = ?outbox.peer_addr, "Write queue full, disconnecting slow client");
+                flow = ControlFlow::Break(Some(WebSocketFrame::close_with_code(1008, "Too slow")));
+                outbox.shut();
+            }
+            pending = outbox.next(), if flow.is_continue() => {
+                flow = outbox.write(pending, socket, metrics, last_message).await;
+                if flow.is_break() {
+                    outbox.shut();
+                }
+            }
+        }
+    }
 }
 
-/// Read and parse a WebSocket frame from the socket, buffering incomplete frames
-async fn read_frame(
-    socket: &mut TcpStream,
+/// Wait for the next tick, or forever if pings are disabled.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait until `timeout` has passed since `last_message`, or forever if there
+/// is no idle timeout.
+async fn idle(last_message: Instant, timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => sleep_until(last_message + timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn next_connection_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// What [`read_frame`] returns: a whole frame, or on streaming routes a
+/// piece of a data message as it arrives.
+enum Incoming {
+    Frame(WebSocketFrame),
+    /// The first piece has the message's opcode, later ones `Continuation`.
+    Chunk(RawFrame),
+}
+
+/// Read until a complete message or control frame is available, joining
+/// fragmented messages unless streaming. Progress is kept in `buffer` and `reassembler`, so
+/// the future can be dropped and re-created between frames. Protocol
+/// violations are returned separately from I/O errors, as the connection
+/// must then be failed with a close frame.
+async fn read_frame<S: AsyncRead + Unpin>(
+    socket: &mut S,
     buffer: &mut BytesMut,
-) -> Result<Option<WebSocketFrame>> {
-    let mut temp_buf = [0u8; 4096];
+    reassembler: &mut Reassembler,
+    config: &WebSocketConfig,
+    metrics: &ConnectionMetrics,
+) -> Result<std::result::Result<Incoming, ParseError>> {
+    loop {
+        // Drain every frame already buffered before reading again
+        loop {
+            match RawFrame::decode(buffer, config.max_frame_size) {
+                Ok((raw, consumed)) => {
+                    metrics.received(consumed);
+                    match reassembler.next(raw) {
+                        Ok(Some(frame)) => return Ok(Ok(frame)),
+                        Ok(None) => {}
+                        Err(e) => return Ok(Err(e)),
+                    }
+                }
+                Err(ParseError::Incomplete) => break,
+                Err(e) => return Ok(Err(e)),
+            }
+        }
 
-    match socket.read(&mut temp_buf).await {
-        Ok(0) => {
+        // Read straight into the buffer, behind any partial frame
+        buffer.reserve(READ_SIZE);
+        if socket.read_buf(buffer).await? == 0 {
             return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
         }
-        Ok(n) => {
-            buffer.extend_from_slice(&temp_buf[..n]);
+    }
+}
+
+/// Joins the frames of a fragmented message (RFC 6455 §5.4). Control frames
+/// may be interleaved with fragments and are passed through immediately.
+/// Unfragmented messages keep the payload they were read with; only
+/// fragments are copied to join them.
+#[derive(Debug)]
+struct Reassembler {
+    /// Opcode and payload so far of the message being received, if any.
+    partial: Option<(OpCode, BytesMut)>,
+    /// Length of the partial text message's prefix known to be valid UTF-8.
+    validated: usize,
+    max_message_size: usize,
+    /// Pass data fragments on as they come instead of joining them.
+    streaming: bool,
+    /// Opcode of the message being streamed, if one is in progress.
+    streamed: Option<OpCode>,
+    /// Trailing bytes of a streamed text message that end mid-character.
+    utf8_tail: Vec<u8>,
+}
+
+impl Reassembler {
+    fn new(max_message_size: usize) -> Self {
+        Self {
+            partial: None,
+            validated: 0,
+            max_message_size,
+            streaming: false,
+            streamed: None,
+            utf8_tail: Vec::new(),
+        }
+    }
+
+    /// Feed the next frame, streaming data fragments if enabled and joining
+    /// them with [`push`](Self::push) otherwise.
+    fn next(&mut self, raw: RawFrame) -> std::result::Result<Option<Incoming>, ParseError> {
+        if !self.streaming || raw.opcode.is_control() {
+            return Ok(self.push(raw)?.map(Incoming::Frame));
+        }
+
+        let opcode = match (raw.opcode, self.streamed) {
+            (OpCode::Continuation, Some(opcode)) => opcode,
+            (OpCode::Continuation, None) | (_, Some(_)) => {
+                return Err(ParseError::InvalidFragmentation);
+            }
+            (opcode, None) => {
+                self.utf8_tail.clear();
+                opcode
+            }
+        };
+        if opcode == OpCode::Text {
+            self.check_utf8(&raw.payload)?;
+            if raw.fin && !self.utf8_tail.is_empty() {
+                return Err(ParseError::InvalidUtf8);
+            }
         }
-        Err(e) => {
-            return Err(e.into());
+        self.streamed = (!raw.fin).then_some(opcode);
+        Ok(Some(Incoming::Chunk(raw)))
+    }
+
+    /// Check the next piece of a streamed text message, which may complete
+    /// a character left unfinished by the previous one.
+    fn check_utf8(&mut self, mut data: &[u8]) -> std::result::Result<(), ParseError> {
+        if let Some(&lead) = self.utf8_tail.first() {
+            let width = match lead {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                _ => 4,
+            };
+            let needed = (width - self.utf8_tail.len()).min(data.len());
+            self.utf8_tail.extend_from_slice(&data[..needed]);
+            data = &data[needed..];
+            if valid_utf8_prefix(&self.utf8_tail)? < self.utf8_tail.len() {
+                // Still unfinished, so `data` is used up
+                return Ok(());
+            }
+            self.utf8_tail.clear();
         }
+        let valid = valid_utf8_prefix(data)?;
+        self.utf8_tail.extend_from_slice(&data[valid..]);
+        Ok(())
     }
 
-    // Try to parse a frame from the buffer
-    match WebSocketFrame::parse(buffer) {
-        Ok((frame, consumed)) => {
-            // Remove consumed bytes from buffer
-            buffer.advance(consumed);
-            Ok(Some(frame))
+    /// Feed the next frame. Returns a frame once a message (or a control
+    /// frame) is complete.
+    fn push(&mut self, raw: RawFrame) -> std::result::Result<Option<WebSocketFrame>, ParseError> {
+        if raw.opcode.is_control() {
+            if !raw.fin {
+                return Err(ParseError::InvalidFragmentation);
+            }
+            return WebSocketFrame::from_message(raw.opcode, raw.payload).map(Some);
+        }
+
+        let received = self
+            .partial
+            .as_ref()
+            .map_or(0, |(_, payload)| payload.len());
+        let size = received + raw.payload.len();
+        if size > self.max_message_size {
+            return Err(ParseError::MessageTooBig {
+                size: size as u64,
+                limit: self.max_message_size,
+            });
+        }
+
+        match (raw.opcode, self.partial.as_mut()) {
+            (OpCode::Continuation, Some((_, payload))) => {
+                payload.extend_from_slice(&raw.payload);
+            }
+            // A new message may not start before the previous one finished,
+            // and a continuation needs a message to continue
+            (OpCode::Continuation, None) | (_, Some(_)) => {
+                return Err(ParseError::InvalidFragmentation);
+            }
+            (opcode, None) if raw.fin => {
+                return WebSocketFrame::from_message(opcode, raw.payload).map(Some);
+            }
+            (opcode, None) => {
+                self.partial = Some((opcode, BytesMut::from(raw.payload)));
+                self.validated = 0;
+            }
         }
-        Err(frame::ParseError::Incomplete) => {
-            // Need more data
-            Ok(None)
+
+        if !raw.fin {
+            // Fail invalid text as soon as it arrives rather than once the
+            // whole message is in; the complete message is checked below
+            if let Some((OpCode::Text, payload)) = &self.partial {
+                self.validated += valid_utf8_prefix(&payload[self.validated..])?;
+            }
+            return Ok(None);
         }
-        Err(e) => Err(crate::error::ServerError::WebSocketError(format!(
-            "Parse error: {:?}",
-            e
-        ))),
+        let (opcode, payload) = self.partial.take().expect("message in progress");
+        WebSocketFrame::from_message(opcode, payload.freeze()).map(Some)
+    }
+}
+
+/// Length of the longest prefix of `data` that is valid UTF-8, allowing it to
+/// end in the middle of a character that may be completed later.
+fn valid_utf8_prefix(data: &[u8]) -> std::result::Result<usize, ParseError> {
+    match std::str::from_utf8(data) {
+        Ok(_) => Ok(data.len()),
+        Err(e) if e.error_len().is_none() => Ok(e.valid_up_to()),
+        Err(_) => Err(ParseError::InvalidUtf8),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw(fin: bool, opcode: OpCode, payload: &[u8]) -> RawFrame {
+        RawFrame {
+            fin,
+            opcode,
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    #[test]
+    fn test_reassembles_fragments_around_control_frames() {
+        let mut reassembler = Reassembler::new(MAX_MESSAGE_SIZE);
+
+        assert!(
+            reassembler
+                .push(raw(false, OpCode::Text, b"Hel"))
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            reassembler.push(raw(true, OpCode::Ping, b"p")).unwrap(),
+            Some(WebSocketFrame::Ping(data)) if data == b"p"[..]
+        ));
+        assert!(
+            reassembler
+                .push(raw(false, OpCode::Continuation, b"lo, "))
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            reassembler.push(raw(true, OpCode::Continuation, b"world")).unwrap(),
+            Some(WebSocketFrame::Text(text)) if text == "Hello, world"
+        ));
+    }
+
+    #[test]
+    fn test_rejects_invalid_fragmentation() {
+        let mut reassembler = Reassembler::new(MAX_MESSAGE_SIZE);
+        assert!(
+            reassembler
+                .push(raw(true, OpCode::Continuation, b"x"))
+                .is_err()
+        );
+
+        let mut reassembler = Reassembler::new(MAX_MESSAGE_SIZE);
+        reassembler.push(raw(false, OpCode::Binary, b"x")).unwrap();
+        assert!(reassembler.push(raw(true, OpCode::Text, b"y")).is_err());
+
+        let mut reassembler = Reassembler::new(MAX_MESSAGE_SIZE);
+        assert!(reassembler.push(raw(false, OpCode::Ping, b"")).is_err());
+    }
+
+    #[test]
+    fn test_message_size_limit_spans_fragments() {
+        let mut reassembler = Reassembler::new(8);
+        reassembler
+            .push(raw(false, OpCode::Binary, b"1234"))
+            .unwrap();
+        reassembler
+            .push(raw(false, OpCode::Continuation, b"5678"))
+            .unwrap();
+        assert!(matches!(
+            reassembler.push(raw(true, OpCode::Continuation, b"9")),
+            Err(ParseError::MessageTooBig { size: 9, limit: 8 })
+        ));
+    }
+
+    #[test]
+    fn test_fragmented_text_is_validated_as_a_whole() {
+        // "é" split across two fragments
+        let mut reassembler = Reassembler::new(MAX_MESSAGE_SIZE);
+        reassembler.push(raw(false, OpCode::Text, &[0xc3])).unwrap();
+        assert!(matches!(
+            reassembler.push(raw(true, OpCode::Continuation, &[0xa9])).unwrap(),
+            Some(WebSocketFrame::Text(text)) if text == "é"
+        ));
+    }
+
+    #[test]
+    fn test_streaming_passes_fragments_on() {
+        let mut reassembler = Reassembler::new(4);
+        reassembler.streaming = true;
+        let chunk = |incoming| match incoming {
+            Ok(Some(Incoming::Chunk(raw))) => (raw.fin, raw.opcode, raw.payload),
+            _ => panic!("expected a chunk"),
+        };
+
+        // Not held back by the message size limit; "é" split across chunks
+        assert_eq!(
+            chunk(reassembler.next(raw(false, OpCode::Text, b"caf\xc3"))),
+            (false, OpCode::Text, Bytes::from_static(b"caf\xc3"))
+        );
+        assert!(matches!(
+            reassembler.next(raw(true, OpCode::Ping, b"")),
+            Ok(Some(Incoming::Frame(WebSocketFrame::Ping(_))))
+        ));
+        assert_eq!(
+            chunk(reassembler.next(raw(true, OpCode::Continuation, b"\xa9!"))),
+            (true, OpCode::Continuation, Bytes::from_static(b"\xa9!"))
+        );
+        assert!(
+            reassembler
+                .next(raw(true, OpCode::Continuation, b""))
+                .is_err()
+        );
+
+        // A character cut off by the end of the message
+        assert!(matches!(
+            reassembler.next(raw(true, OpCode::Text, b"\xe2\x82")),
+            Err(ParseError::InvalidUtf8)
+        ));
+        let mut reassembler = Reassembler::new(4);
+        reassembler.streaming = true;
+        reassembler.next(raw(false, OpCode::Text, b"\xe2")).unwrap();
+        assert!(matches!(
+            reassembler.next(raw(false, OpCode::Continuation, b"(")),
+            Err(ParseError::InvalidUtf8)
+        ));
+    }
+
+    #[test]
+    fn test_invalid_utf8_fails_before_the_final_fragment() {
+        let mut reassembler = Reassembler::new(MAX_MESSAGE_SIZE);
+        reassembler
+            .push(raw(false, OpCode::Text, b"ok \xc3"))
+            .unwrap();
+        assert!(matches!(
+            reassembler.push(raw(false, OpCode::Continuation, b"(")),
+            Err(ParseError::InvalidUtf8)
+        ));
+
+        // Binary messages are not checked
+        let mut reassembler = Reassembler::new(MAX_MESSAGE_SIZE);
+        assert!(
+            reassembler
+                .push(raw(false, OpCode::Binary, b"\xff"))
+                .is_ok()
+        );
     }
 }
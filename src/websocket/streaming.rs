@@ -0,0 +1,111 @@
+//! Messages received and sent piece by piece, for transfers too large to
+//! hold in memory whole.
+
+use crate::{
+    error::{Result, ServerError},
+    websocket::{
+        frame::OpCode,
+        handler::{Message, Outgoing, WebSocketSender, connection_closed},
+    },
+};
+use bytes::Bytes;
+use futures_core::Stream;
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc;
+
+/// Chunks of an outgoing message buffered before the writer waits.
+const OUTGOING_CHUNKS: usize = 4;
+
+/// One incoming message, yielded as its fragments arrive. An unfragmented
+/// message is a single chunk. Text may be split in the middle of a
+/// character; it is checked as a whole and the connection is failed if it is
+/// not UTF-8. If the connection ends before the message does, the last item
+/// is an error.
+#[derive(Debug)]
+pub struct MessageStream {
+    text: bool,
+    chunks: mpsc::Receiver<Result<Bytes>>,
+}
+
+impl MessageStream {
+    pub(crate) fn new(text: bool, chunks: mpsc::Receiver<Result<Bytes>>) -> Self {
+        Self { text, chunks }
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.text
+    }
+
+    /// The next chunk, or `None` once the message is complete.
+    pub async fn next_chunk(&mut self) -> Option<Result<Bytes>> {
+        self.chunks.recv().await
+    }
+}
+
+impl Stream for MessageStream {
+    type Item = Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes>>> {
+        self.chunks.poll_recv(cx)
+    }
+}
+
+/// The error a [`MessageStream`] ends with when its message was cut off.
+pub(crate) fn message_incomplete() -> ServerError {
+    ServerError::WebSocketError("Message incomplete".to_string())
+}
+
+/// Sends one message as fragments, each written as soon as the connection
+/// gets to it. Other messages queued meanwhile are sent after this one ends,
+/// which is when the writer is finished or dropped. Nothing is sent if no
+/// chunk was written.
+#[derive(Debug)]
+pub struct MessageWriter {
+    sender: WebSocketSender,
+    /// Whether the message is text, and where its chunks go, once started.
+    started: Option<(bool, mpsc::Sender<Bytes>)>,
+}
+
+impl MessageWriter {
+    pub(crate) fn new(sender: WebSocketSender) -> Self {
+        Self {
+            sender,
+            started: None,
+        }
+    }
+
+    /// Queue `chunk` as the next fragment, waiting for room if the client
+    /// is behind. The first chunk decides whether the message is text or
+    /// binary; later ones must be of the same kind.
+    pub async fn write(&mut self, chunk: Message) -> Result<()> {
+        let (text, payload) = match chunk {
+            Message::Text(text) => (true, text.into_bytes()),
+            Message::Binary(data) => (false, data),
+        };
+        let chunks = match &self.started {
+            Some((started_text, chunks)) if *started_text == text => chunks,
+            Some(_) => {
+                return Err(ServerError::WebSocketError(
+                    "Text and binary chunks in one message".to_string(),
+                ));
+            }
+            None => {
+                let (chunks, receiver) = mpsc::channel(OUTGOING_CHUNKS);
+                let opcode = if text { OpCode::Text } else { OpCode::Binary };
+                self.sender
+                    .outgoing()
+                    .send(Outgoing::Stream(opcode, receiver))
+                    .await
+                    .map_err(|_| connection_closed())?;
+                &self.started.insert((text, chunks)).1
+            }
+        };
+        chunks.send(payload).await.map_err(|_| connection_closed())
+    }
+
+    /// End the message.
+    pub fn finish(self) {}
+}
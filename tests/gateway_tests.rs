@@ -0,0 +1,160 @@
+use bytes::{Bytes, BytesMut};
+use http::{
+    gateway::fastcgi::{self, FastCgiRoute},
+    protocol::{request::HttpRequest, response::HttpStatusCode},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Build a raw FastCGI record without padding.
+fn raw_record(record_type: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![1, record_type, 0, 1];
+    out.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(content);
+    out
+}
+
+#[tokio::test]
+async fn test_fastcgi_forward() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+
+    // Fake application server: echo the request body once STDIN is complete
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buffer = BytesMut::new();
+        let mut params = Vec::new();
+        let mut stdin = Vec::new();
+
+        loop {
+            socket.read_buf(&mut buffer).await.unwrap();
+            while let Some(record) = fastcgi::parse_record(&mut buffer) {
+                match record.record_type {
+                    4 => params.extend_from_slice(&record.content),
+                    5 if record.content.is_empty() => {
+                        let params = String::from_utf8_lossy(&params).to_string();
+                        assert!(params.contains("SCRIPT_FILENAME/srv/www/index.php"));
+
+                        let stdout = format!(
+                            "Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n{}",
+                            String::from_utf8_lossy(&stdin)
+                        );
+                        let mut reply = raw_record(6, stdout.as_bytes());
+                        reply.extend(raw_record(6, &[]));
+                        reply.extend(raw_record(3, &[0; 8]));
+                        socket.write_all(&reply).await.unwrap();
+                        return;
+                    }
+                    5 => stdin.extend_from_slice(&record.content),
+                    _ => {}
+                }
+            }
+        }
+    });
+
+    let route = FastCgiRoute::new("*.php", &address, "/srv/www/");
+    let mut request =
+        HttpRequest::from_buffer_sync(b"POST /index.php HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    request.body = Bytes::from_static(b"name=value");
+
+    assert!(route.matches(&request.path));
+
+    let response = fastcgi::forward(&request, &route).await.unwrap();
+    assert_eq!(response.status, HttpStatusCode::Created);
+    assert_eq!(response.body, &b"name=value"[..]);
+}
+
+#[tokio::test]
+async fn test_fastcgi_unreachable_is_bad_gateway() {
+    let address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+
+    let route = FastCgiRoute::new("*.php", &address, "/srv/www");
+    let request = HttpRequest::from_buffer_sync(b"GET /index.php HTTP/1.1\r\n\r\n").unwrap();
+
+    let response = fastcgi::forward(&request, &route).await.unwrap();
+    assert_eq!(response.status, HttpStatusCode::BadGateway);
+}
+
+#[tokio::test]
+async fn test_fastcgi_refuses_paths_outside_document_root() {
+    // Nothing listens: a request that got as far as the application server
+    // would be answered with 502
+    let address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let route = FastCgiRoute::new("*.php", &address, "/srv/www");
+
+    for path in [
+        "/../../etc/passwd.php",
+        "/app/../../etc/passwd.php",
+        "/%2e%2e/etc/passwd.php",
+    ] {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        let request = HttpRequest::from_buffer_sync(raw.as_bytes()).unwrap();
+        assert!(route.matches(&request.path));
+        let response = fastcgi::forward(&request, &route).await.unwrap();
+        assert_eq!(response.status, HttpStatusCode::BadRequest, "{}", path);
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_cgi_execute() {
+    use http::gateway::cgi::{self, CgiRoute};
+    use std::os::unix::fs::PermissionsExt;
+
+    let directory = std::env::temp_dir().join(format!("http-cgi-test-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let script = directory.join("echo.sh");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\nprintf '%s %s ' \"$REQUEST_METHOD\" \"$PATH_INFO\"\ncat\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let route = CgiRoute::new("/cgi-bin", directory.to_str().unwrap());
+    let mut request =
+        HttpRequest::from_buffer_sync(b"POST /cgi-bin/echo.sh/extra HTTP/1.1\r\n\r\n").unwrap();
+    request.body = Bytes::from_static(b"payload");
+
+    let response = cgi::execute(&request, &route).await.unwrap();
+    assert_eq!(response.status, HttpStatusCode::Ok);
+    assert_eq!(response.body, &b"POST /extra payload"[..]);
+
+    let missing =
+        HttpRequest::from_buffer_sync(b"GET /cgi-bin/missing.sh HTTP/1.1\r\n\r\n").unwrap();
+    let response = cgi::execute(&missing, &route).await.unwrap();
+    assert_eq!(response.status, HttpStatusCode::NotFound);
+
+    // Endless output is cut off at the body limit and the script killed
+    let runaway = directory.join("runaway.sh");
+    std::fs::write(
+        &runaway,
+        "#!/bin/sh
+printf 'Content-Type: text/plain\\n\\n'
+yes
+",
+    )
+    .unwrap();
+    std::fs::set_permissions(&runaway, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let request =
+        HttpRequest::from_buffer_sync(b"GET /cgi-bin/runaway.sh HTTP/1.1\r\n\r\n").unwrap();
+    let started = std::time::Instant::now();
+    let response = cgi::execute(&request, &route).await.unwrap();
+    assert_eq!(response.status, HttpStatusCode::BadGateway);
+    assert!(started.elapsed() < std::time::Duration::from_secs(10));
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
@@ -1,6 +1,21 @@
-use http::protocol::{
-    request::{HttpMethod, HttpRequest},
-    response::{HttpResponse, HttpStatusCode},
+use http::{
+    bench::LoadTest,
+    compression::CompressionConfig,
+    config::{BodyLimit, Builtin, CachePolicy, Config, Limits, Route, RouteTarget, Site},
+    middleware::{basic_auth::BasicAuth, rate_limit::RateLimiter},
+    protocol::{
+        error_handler::{self, ErrorHandler},
+        handle_connection,
+        request::{HttpMethod, HttpRequest},
+        response::{HttpResponse, HttpStatusCode},
+        serve_connection,
+    },
+    server,
+};
+use std::time::{Duration, Instant};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
 };
 
 #[test]
@@ -65,7 +80,17 @@ fn test_http_method_parsing() {
     assert_eq!("POST".parse::<HttpMethod>().unwrap(), HttpMethod::Post);
     assert_eq!("put".parse::<HttpMethod>().unwrap(), HttpMethod::Put);
 
-    assert!("INVALID".parse::<HttpMethod>().is_err());
+    assert_eq!(
+        "PROPFIND".parse::<HttpMethod>().unwrap(),
+        HttpMethod::Other("PROPFIND".to_string())
+    );
+    assert_eq!(
+        HttpMethod::Other("PROPFIND".to_string()).to_string(),
+        "PROPFIND"
+    );
+
+    assert!("IN VALID".parse::<HttpMethod>().is_err());
+    assert!("".parse::<HttpMethod>().is_err());
 }
 
 #[test]
@@ -77,3 +102,928 @@ fn test_status_code_display() {
         "500 Internal Server Error"
     );
 }
+
+#[tokio::test]
+async fn test_response_write_to() {
+    let response = HttpResponse::ok()
+        .with_header("date", "Thu, 01 Jan 2026 00:00:00 GMT")
+        .with_body(vec![b'x'; 64 * 1024]);
+
+    let mut written = Vec::new();
+    response.write_to(&mut written).await.unwrap();
+    assert_eq!(written, response.to_bytes());
+
+    // File bodies are copied when the writer is not a TCP socket
+    let path = std::env::temp_dir().join(format!("http-write-test-{}", std::process::id()));
+    std::fs::write(&path, "from the file, and more").unwrap();
+    let response = HttpResponse::ok().with_file(std::fs::File::open(&path).unwrap(), 13);
+    let mut written = Vec::new();
+    response.write_to(&mut written).await.unwrap();
+    let written = String::from_utf8(written).unwrap();
+    assert!(written.contains("content-length: 13\r\n"));
+    assert!(written.ends_with("\r\n\r\nfrom the file"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Serve a single connection with `config` and return the raw response to `request`.
+async fn roundtrip(config: Config, request: &[u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let _ = handle_connection(socket, &config).await;
+    });
+
+    let mut client = TcpStream::connect(address).await.unwrap();
+    client.write_all(request).await.unwrap();
+
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    String::from_utf8_lossy(&response).to_string()
+}
+
+#[tokio::test]
+async fn test_health_check_ready() {
+    let response = roundtrip(
+        Config::default(),
+        b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains(r#""ready": true"#));
+
+    let response = roundtrip(
+        Config::default(),
+        b"GET /healthz?probe=1 HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+}
+
+#[tokio::test]
+async fn test_malformed_websocket_upgrade_is_refused() {
+    let response = roundtrip(
+        Config::default(),
+        b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required"));
+    assert!(
+        response
+            .to_lowercase()
+            .contains("sec-websocket-version: 13\r\n")
+    );
+
+    let response = roundtrip(
+        Config::default(),
+        b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+          Sec-WebSocket-Version: 13\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+}
+
+#[tokio::test]
+async fn test_upgrades_pass_admission_checks() {
+    let upgrade = b"GET /chat HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+          Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    // Refused upgrades leave an idle connection, closed after this long
+    let header_read_timeout = Duration::from_millis(200);
+
+    let config = Config {
+        basic_auth: Some(BasicAuth::new("chat", &["/chat"]).with_user("carol", "plain")),
+        header_read_timeout,
+        ..Config::default()
+    };
+    let response = roundtrip(config, upgrade).await;
+    assert!(
+        response.starts_with("HTTP/1.1 401 Unauthorized"),
+        "{}",
+        response
+    );
+
+    let config = Config {
+        rate_limiter: Some(RateLimiter::new(0.001, 1)),
+        header_read_timeout,
+        ..Config::default()
+    };
+    let first = roundtrip(
+        config.clone(),
+        b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(first.starts_with("HTTP/1.1 200 OK"));
+    let second = roundtrip(config, upgrade).await;
+    assert!(second.starts_with("HTTP/1.1 429"), "{}", second);
+}
+
+#[tokio::test]
+async fn test_health_check_reports_missing_static_dir() {
+    let config = Config {
+        static_dir: "./does-not-exist".to_string(),
+        ..Config::default()
+    };
+    let response = roundtrip(
+        config,
+        b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+    assert!(response.contains(r#""static_dir": "unavailable""#));
+}
+
+#[tokio::test]
+async fn test_handler_errors_are_answered() {
+    let config = Config {
+        static_dir: "./does-not-exist".to_string(),
+        ..Config::default()
+    };
+    let response = roundtrip(config, b"GET /index.html HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    assert!(response.contains("connection: close\r\n"));
+}
+
+#[tokio::test]
+async fn test_malformed_requests_are_answered() {
+    let response = roundtrip(Config::default(), b"GARBAGE\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(response.contains("connection: close\r\n"));
+
+    let response = roundtrip(
+        Config::default(),
+        b"POST /echo HTTP/1.1\r\nContent-Length: ten\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+}
+
+#[tokio::test]
+async fn test_unknown_methods_are_not_implemented() {
+    let response = roundtrip(Config::default(), b"PROPFIND / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 501 Not Implemented"));
+
+    let response = roundtrip(Config::default(), b"GE(T / HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+}
+
+#[tokio::test]
+async fn test_custom_error_handler() {
+    let config = Config {
+        static_dir: "./does-not-exist".to_string(),
+        error_handler: Some(ErrorHandler::new(|error, context| {
+            let status = error_handler::default_response(error).status;
+            async move {
+                let path = context.request.map(|request| request.path).unwrap();
+                HttpResponse::new(status)
+                    .with_header("x-error-path", &path)
+                    .with_html("<h1>Not here</h1>")
+            }
+        })),
+        ..Config::default()
+    };
+    let response = roundtrip(config, b"GET /index.html HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    assert!(response.contains("x-error-path: /index.html\r\n"));
+    assert!(response.contains("connection: close\r\n"));
+    assert!(response.ends_with("<h1>Not here</h1>"));
+}
+
+#[tokio::test]
+async fn test_panicking_error_handler_falls_back() {
+    let config = Config {
+        static_dir: "./does-not-exist".to_string(),
+        error_handler: Some(ErrorHandler::new(|_, _| async {
+            panic!("error page template missing")
+        })),
+        ..Config::default()
+    };
+    let response = roundtrip(config, b"GET /index.html HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+    assert!(response.ends_with("\r\n\r\nNot Found"));
+}
+
+#[tokio::test]
+async fn test_sites_picked_by_host_header() {
+    let root = std::env::temp_dir().join(format!("http-sites-test-{}", std::process::id()));
+    let blog = root.join("blog");
+    std::fs::create_dir_all(blog.join("posts")).unwrap();
+    std::fs::write(blog.join("home.html"), "blog home").unwrap();
+    std::fs::write(blog.join("posts/index.html"), "all posts").unwrap();
+    std::fs::write(blog.join("missing.html"), "no such post").unwrap();
+
+    let config = Config {
+        sites: vec![
+            Site::new(&["blog.test"], blog.to_str().unwrap())
+                .with_index_files(&["home.html", "index.html"])
+                .with_error_page(404, "/missing.html"),
+        ],
+        ..Config::default()
+    };
+    let get = |host: &str, path: &str| {
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        );
+        let config = config.clone();
+        async move { roundtrip(config, request.as_bytes()).await }
+    };
+
+    assert!(get("blog.test", "/").await.ends_with("blog home"));
+    assert!(
+        get("BLOG.test:8080", "/posts/")
+            .await
+            .ends_with("all posts")
+    );
+    let missing = get("blog.test", "/nope.html").await;
+    assert!(missing.starts_with("HTTP/1.1 404"));
+    assert!(missing.contains("content-type: text/html"));
+    assert!(missing.ends_with("no such post"));
+
+    // Other hosts are served from `static_dir`
+    let other = get("other.test", "/").await;
+    assert!(other.starts_with("HTTP/1.1 200 OK"));
+    assert!(!other.contains("blog home"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_directory_listing_escapes_links() {
+    let root = std::env::temp_dir().join(format!("http-listing-test-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("sub dir")).unwrap();
+    std::fs::write(root.join("a b#c?.txt"), "").unwrap();
+    std::fs::write(root.join("100%.txt"), "").unwrap();
+
+    let config = Config {
+        static_dir: root.to_str().unwrap().to_string(),
+        directory_listing: true,
+        ..Config::default()
+    };
+    let response = roundtrip(config, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+
+    assert!(response.contains(r#"<a href="a%20b%23c%3F.txt">a b#c?.txt</a>"#));
+    assert!(response.contains(r#"<a href="100%25.txt">100%.txt</a>"#));
+    assert!(response.contains(r#"<a href="sub%20dir/">sub dir/</a>"#));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_body_limit_with_route_override() {
+    let config = Config {
+        limits: Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        },
+        body_limits: vec![BodyLimit::new("/upload", 16)],
+        ..Config::default()
+    };
+
+    let response = roundtrip(
+        config.clone(),
+        b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large"));
+
+    let response = roundtrip(
+        config,
+        b"POST /upload HTTP/1.1\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+}
+
+#[tokio::test]
+async fn test_header_limits() {
+    let config = Config {
+        limits: Limits {
+            max_header_size: 64,
+            max_header_count: 2,
+            max_uri_length: 16,
+            ..Limits::default()
+        },
+        ..Config::default()
+    };
+
+    let response = roundtrip(
+        config.clone(),
+        b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+
+    let long_header = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(64));
+    let response = roundtrip(config.clone(), long_header.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 431"));
+
+    let response = roundtrip(config, b"GET /index.html?page=12 HTTP/1.1\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 414 URI Too Long"));
+}
+
+#[tokio::test]
+async fn test_expect_continue() {
+    let config = Config {
+        limits: Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        },
+        ..Config::default()
+    };
+
+    // Refused without the body ever being sent
+    let response = roundtrip(
+        config.clone(),
+        b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large"));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let _ = handle_connection(socket, &config).await;
+    });
+
+    let mut client = TcpStream::connect(address).await.unwrap();
+    client
+        .write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 4\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut interim = [0u8; 25];
+    client.read_exact(&mut interim).await.unwrap();
+    assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+    client.write_all(b"ping").await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 200 OK"));
+}
+
+#[tokio::test]
+async fn test_slow_request_head_is_cut_off() {
+    let config = Config {
+        header_read_timeout: Duration::from_millis(100),
+        ..Config::default()
+    };
+
+    let started = Instant::now();
+    let response = roundtrip(config, b"GET / HTTP/1.1\r\nX-Slow: ").await;
+
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+    assert!(response.contains("connection: close\r\n"));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_slow_request_body_is_cut_off() {
+    let config = Config {
+        header_read_timeout: Duration::from_millis(100),
+        min_body_rate: 1024,
+        ..Config::default()
+    };
+
+    let started = Instant::now();
+    let response = roundtrip(
+        config,
+        b"POST /echo HTTP/1.1\r\nContent-Length: 100000\r\n\r\nsome",
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout"));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn test_serve_on_multiple_listeners() {
+    let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addresses = [first.local_addr().unwrap(), second.local_addr().unwrap()];
+
+    tokio::spawn(server::serve(
+        vec![first.into(), second.into()],
+        Config::default(),
+    ));
+
+    for address in addresses {
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+    }
+}
+
+#[tokio::test]
+async fn test_reload_applies_to_new_connections() {
+    let root = std::env::temp_dir().join(format!("http-reload-test-{}", std::process::id()));
+    for (dir, text) in [("old", "old site"), ("new", "new site")] {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+        std::fs::write(root.join(dir).join("index.html"), text).unwrap();
+    }
+    let config_for = |dir: &str| Config {
+        static_dir: root.join(dir).to_str().unwrap().to_string(),
+        ..Config::default()
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (reload, reloads) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(server::serve_with_reload(
+        vec![listener.into()],
+        config_for("old"),
+        reloads,
+        std::future::pending(),
+    ));
+
+    let mut open = TcpStream::connect(address).await.unwrap();
+    open.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut buf = [0; 1024];
+    let n = open.read(&mut buf).await.unwrap();
+    assert!(buf[..n].ends_with(b"old site"));
+
+    reload.send(config_for("new")).await.unwrap();
+    let get = || async {
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    };
+    let mut response = get().await;
+    for _ in 0..50 {
+        if response.ends_with(b"new site") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        response = get().await;
+    }
+    assert!(response.ends_with(b"new site"));
+
+    // The connection opened before the reload is still served, as before
+    open.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    open.read_to_end(&mut response).await.unwrap();
+    assert!(response.ends_with(b"old site"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_configured_routes() {
+    let docs = std::env::temp_dir().join(format!("http-routes-test-{}", std::process::id()));
+    std::fs::create_dir_all(docs.join("guide")).unwrap();
+    std::fs::write(docs.join("index.html"), "docs home").unwrap();
+    std::fs::write(docs.join("guide/start.txt"), "getting started").unwrap();
+
+    let config = Config {
+        routes: vec![
+            Route::new(
+                "/docs",
+                RouteTarget::Static(docs.to_str().unwrap().to_string()),
+            ),
+            Route::new(
+                "/old",
+                RouteTarget::Redirect {
+                    to: "https://example.com/new/".to_string(),
+                    status: HttpStatusCode::MovedPermanently,
+                },
+            ),
+            Route::new("/ping", RouteTarget::Builtin(Builtin::Echo)),
+        ],
+        ..Config::default()
+    };
+    let send = |request: &'static str| {
+        let config = config.clone();
+        async move { roundtrip(config, request.as_bytes()).await }
+    };
+
+    let response =
+        send("GET /docs/guide/start.txt?v=2 HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("getting started"));
+    assert!(
+        send("GET /docs HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .ends_with("docs home")
+    );
+    let response = send("DELETE /docs/index.html HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 405"));
+
+    let response = send("GET /old/a?b=1 HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert!(response.starts_with("HTTP/1.1 301"));
+    assert!(response.contains("location: https://example.com/new/a?b=1\r\n"));
+
+    let response = send("GET /ping HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+    assert!(response.contains(r#""path": "/ping""#));
+
+    std::fs::remove_dir_all(&docs).unwrap();
+}
+
+#[tokio::test]
+async fn test_compression() {
+    let config = Config {
+        routes: vec![Route::new("/echo", RouteTarget::Builtin(Builtin::Echo))],
+        compression: Some(CompressionConfig::default().with_min_size(64)),
+        ..Config::default()
+    };
+    let send = |request: String| {
+        let config = config.clone();
+        async move { roundtrip(config, request.as_bytes()).await }
+    };
+    let body = "x".repeat(1000);
+    let request = |accept: &str| {
+        format!(
+            "POST /echo HTTP/1.1\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+            accept,
+            body.len(),
+            body
+        )
+    };
+
+    let response = send(request("Accept-Encoding: gzip, deflate\r\n")).await;
+    assert!(response.contains("content-encoding: gzip\r\n"));
+    assert!(response.contains("vary: accept-encoding\r\n"));
+    assert!(!response.contains(&body));
+
+    let response = send(request("")).await;
+    assert!(!response.contains("content-encoding"));
+    assert!(response.contains("vary: accept-encoding\r\n"));
+    assert!(response.contains(&body));
+}
+
+#[tokio::test]
+async fn test_large_static_files() {
+    let root = std::env::temp_dir().join(format!("http-large-test-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let contents: String = (0..300_000)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    std::fs::write(root.join("big.txt"), &contents).unwrap();
+
+    let config = Config {
+        static_dir: root.to_str().unwrap().to_string(),
+        ..Config::default()
+    };
+    // The first response must end exactly where the file does
+    let response = roundtrip(
+        config.clone(),
+        b"GET /big.txt HTTP/1.1\r\n\r\nGET /big.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    let bodies: Vec<&str> = response
+        .split("HTTP/1.1 200 OK\r\n")
+        .skip(1)
+        .map(|part| part.split_once("\r\n\r\n").unwrap().1)
+        .collect();
+    assert_eq!(bodies, [contents.as_str(), contents.as_str()]);
+    assert!(response.contains("content-length: 300000\r\n"));
+
+    // Files of a megabyte or more are sent from the blocking pool
+    let huge = contents.repeat(5);
+    std::fs::write(root.join("huge.txt"), &huge).unwrap();
+    let response = roundtrip(
+        config.clone(),
+        b"GET /huge.txt HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(response.split_once("\r\n\r\n").unwrap().1, huge);
+
+    let config = Config {
+        compression: Some(CompressionConfig::default()),
+        ..config
+    };
+    let response = roundtrip(
+        config,
+        b"GET /big.txt HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.contains("content-encoding: gzip\r\n"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_cache_policies() {
+    let root = std::env::temp_dir().join(format!("http-cache-test-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("immutable")).unwrap();
+    std::fs::write(root.join("site.css"), "body {}").unwrap();
+    std::fs::write(root.join("immutable/app.js"), "run()").unwrap();
+
+    let config = Config {
+        static_dir: root.to_str().unwrap().to_string(),
+        cache_policies: vec![
+            CachePolicy::new("/immutable/*")
+                .with_cache_control("public, max-age=31536000, immutable"),
+            CachePolicy::new("*.css")
+                .with_cache_control("public, max-age=60")
+                .with_expires(Duration::from_secs(60)),
+        ],
+        ..Config::default()
+    };
+    let get = |path: &str| {
+        let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+        let config = config.clone();
+        async move { roundtrip(config, request.as_bytes()).await }
+    };
+
+    let response = get("/site.css").await;
+    assert!(response.contains("cache-control: public, max-age=60\r\n"));
+    assert!(response.contains("expires: "));
+    let response = get("/immutable/app.js").await;
+    assert!(response.contains("cache-control: public, max-age=31536000, immutable\r\n"));
+    assert!(!response.contains("expires: "));
+    let response = get("/missing.css").await;
+    assert!(response.starts_with("HTTP/1.1 404"));
+    assert!(!response.contains("cache-control"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_start_on_ephemeral_port() {
+    let config = Config::builder()
+        .address("127.0.0.1:0")
+        .address("127.0.0.1:0")
+        .build()
+        .unwrap();
+    let server = server::start(config).await.unwrap();
+    let &[first, second] = server.local_addrs() else {
+        panic!("expected two listeners");
+    };
+    assert_ne!(first.port(), 0);
+    assert_ne!(first.port(), second.port());
+
+    let mut client = TcpStream::connect(server.local_addr()).await.unwrap();
+    client
+        .write_all(b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+
+    server.shutdown().await.unwrap();
+    assert!(TcpStream::connect(first).await.is_err());
+}
+
+#[tokio::test]
+async fn test_content_type_overrides() {
+    let root = std::env::temp_dir().join(format!("http-types-test-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("notes.MD"), "# notes").unwrap();
+    std::fs::write(root.join("site.css"), "p {}").unwrap();
+    std::fs::write(root.join("logo.png"), "png").unwrap();
+
+    let config = Config::builder()
+        .static_dir(root.to_str().unwrap())
+        .content_type("md", "text/markdown; charset=utf-8")
+        .content_type(".CSS", "text/x-custom")
+        .build()
+        .unwrap();
+    let get = |path: &str| {
+        let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+        let config = config.clone();
+        async move { roundtrip(config, request.as_bytes()).await }
+    };
+
+    assert!(
+        get("/notes.MD")
+            .await
+            .contains("content-type: text/markdown; charset=utf-8")
+    );
+    // Overrides replace built-in types; others are left as they are
+    assert!(
+        get("/site.css")
+            .await
+            .contains("content-type: text/x-custom")
+    );
+    assert!(get("/logo.png").await.contains("content-type: image/png"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_dev_mode() {
+    let root = std::env::temp_dir().join(format!("http-dev-test-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("assets/img")).unwrap();
+    std::fs::write(root.join("assets/app.js"), "run()").unwrap();
+    std::fs::write(root.join("assets/<b>.txt"), "bold").unwrap();
+
+    let mut config = Config {
+        static_dir: root.to_str().unwrap().to_string(),
+        ..Config::default()
+    };
+    let get = |config: &Config, path: &str| {
+        let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+        let config = config.clone();
+        async move { roundtrip(config, request.as_bytes()).await }
+    };
+    assert!(get(&config, "/assets/").await.starts_with("HTTP/1.1 404"));
+
+    config.enable_dev_mode();
+    let listing = get(&config, "/assets").await;
+    assert!(listing.starts_with("HTTP/1.1 200 OK"));
+    assert!(listing.contains(r#"<a href="assets/app.js">app.js</a>"#));
+    assert!(listing.contains(r#"<a href="assets/img/">img/</a>"#));
+    assert!(listing.contains("&lt;b&gt;.txt"));
+    assert!(listing.contains("access-control-allow-origin: *\r\n"));
+    let listing = get(&config, "/assets/").await;
+    assert!(listing.contains(r#"<a href="app.js">app.js</a>"#));
+
+    let file = get(&config, "/assets/app.js").await;
+    assert!(file.contains("cache-control: no-store\r\n"));
+    assert!(file.contains("access-control-allow-origin: *\r\n"));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn test_pipelined_requests_with_bodies() {
+    // Both requests, bodies included, arrive in one read
+    let response = roundtrip(
+        Config::default(),
+        b"POST /first HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloPOST /second HTTP/1.1\r\n\
+          Content-Length: 5\r\nConnection: close\r\n\r\nworld",
+    )
+    .await;
+
+    assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+    let first = response.find(r#""received": "hello", "path": "/first""#);
+    let second = response.find(r#""received": "world", "path": "/second""#);
+    assert!(first.is_some() && second.is_some() && first < second);
+}
+
+#[tokio::test]
+async fn test_bench_subcommand_load_test() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(server::serve(vec![listener.into()], Config::default()));
+
+    for keep_alive in [true, false] {
+        let report = LoadTest::new(&format!("http://{}/healthz", address))
+            .unwrap()
+            .with_concurrency(2)
+            .with_duration(Duration::from_millis(200))
+            .with_keep_alive(keep_alive)
+            .run()
+            .await
+            .unwrap();
+        assert!(report.requests > 0);
+        assert_eq!(report.errors, 0);
+        assert_eq!(report.statuses.keys().collect::<Vec<_>>(), [&200]);
+    }
+}
+
+/// Replays `input`, then reports end of stream, and counts writes. Like a
+/// TLS stream, it takes one buffer per write.
+struct CountingStream {
+    input: std::io::Cursor<Vec<u8>>,
+    output: std::sync::Arc<std::sync::Mutex<(usize, Vec<u8>)>>,
+}
+
+impl tokio::io::AsyncRead for CountingStream {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.input).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for CountingStream {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+        data: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let mut output = self.output.lock().unwrap();
+        output.0 += 1;
+        output.1.extend_from_slice(data);
+        std::task::Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_pipelined_responses_written_in_one_write() {
+    let output = std::sync::Arc::default();
+    let stream = CountingStream {
+        input: std::io::Cursor::new(
+            b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /healthz HTTP/1.1\r\n\r\n"
+                .to_vec(),
+        ),
+        output: std::sync::Arc::clone(&output),
+    };
+    serve_connection(stream, None, &Config::default())
+        .await
+        .unwrap();
+
+    let (writes, bytes) = &*output.lock().unwrap();
+    let text = String::from_utf8_lossy(bytes);
+    assert_eq!(text.matches("HTTP/1.1 200 OK").count(), 2);
+    // The second request arrived with the first, so both answers go out
+    // together
+    assert_eq!(*writes, 1);
+}
+
+#[tokio::test]
+async fn test_unread_bodies_are_drained() {
+    // The health check ignores the body, which arrives after the answer
+    // and must not be taken for the next request
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let _ = handle_connection(socket, &Config::default()).await;
+    });
+    let mut client = TcpStream::connect(address).await.unwrap();
+    client
+        .write_all(b"GET /healthz HTTP/1.1\r\nContent-Length: 11\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0u8; 1024];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert!(buffer[..n].starts_with(b"HTTP/1.1 200 OK"));
+    client
+        .write_all(b"GET /nope\r\nGET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(response.matches("HTTP/1.1").count(), 1);
+
+    // Bodies over the limit are refused once the response is out
+    let config = Config {
+        limits: Limits {
+            max_body_size: 4,
+            ..Limits::default()
+        },
+        ..Config::default()
+    };
+    let response = roundtrip(
+        config,
+        b"GET /healthz HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789GET /healthz HTTP/1.1\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(response.matches("HTTP/1.1").count(), 1);
+}
+
+#[tokio::test]
+async fn test_accept_loops_per_listener() {
+    for reuse_port in [false, true] {
+        let config = Config::builder()
+            .address("127.0.0.1:0")
+            .accept_loops(4)
+            .reuse_port(reuse_port)
+            .build()
+            .unwrap();
+        let server = server::start(config).await.unwrap();
+
+        for _ in 0..16 {
+            let mut client = TcpStream::connect(server.local_addr()).await.unwrap();
+            client
+                .write_all(b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+        }
+
+        let address = server.local_addr();
+        server.shutdown().await.unwrap();
+        assert!(TcpStream::connect(address).await.is_err());
+    }
+}
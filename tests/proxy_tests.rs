@@ -0,0 +1,288 @@
+use http::{
+    config::Config,
+    protocol::{codec, handle_connection, request::HttpRequest},
+    proxy::{self, Balance, ProxyRoute, UpstreamPool},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Upstream that answers every request on a connection with a fixed body,
+/// reporting how many connections it accepted.
+async fn spawn_upstream(body: &'static str) -> (String, tokio::sync::mpsc::UnboundedReceiver<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (accepted_tx, accepted_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            accepted_tx.send(()).unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                while let Ok(n) = socket.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nX-Upstream: yes\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+        }
+    });
+
+    (address, accepted_rx)
+}
+
+#[tokio::test]
+async fn test_forward_skips_dead_upstream_and_reuses_connection() {
+    let (live, mut accepted) = spawn_upstream("from upstream").await;
+
+    // Grab a free port and close it again so connecting there fails
+    let dead = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+
+    let pool = UpstreamPool::new([dead.clone(), live], Balance::RoundRobin);
+    let request =
+        HttpRequest::from_buffer_sync(b"GET /api/items HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+
+    for _ in 0..2 {
+        let response = proxy::forward(&request, &pool).await.unwrap();
+        assert_eq!(response.status.code(), 200);
+        assert_eq!(response.body, &b"from upstream"[..]);
+        assert_eq!(response.headers.get("x-upstream"), Some(&"yes".to_string()));
+    }
+
+    let dead_upstream = pool.upstreams().iter().find(|u| u.address == dead).unwrap();
+    assert!(!dead_upstream.is_alive());
+
+    // Both requests travelled over a single pooled connection
+    accepted.recv().await.unwrap();
+    assert!(accepted.try_recv().is_err());
+}
+
+/// Upstream that answers the first request on a connection, then closes
+/// it after reading the next one, reporting every request line it read.
+async fn spawn_closing_upstream() -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (seen_tx, seen_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let seen_tx = seen_tx.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 4096];
+                for answered in [true, false] {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        return;
+                    }
+                    let request = String::from_utf8_lossy(&buf[..n]);
+                    let line = request.lines().next().unwrap_or_default().to_string();
+                    seen_tx.send(line).unwrap();
+                    if answered {
+                        socket
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    (address, seen_rx)
+}
+
+#[tokio::test]
+async fn test_forward_retries_only_idempotent_requests_after_upstream_close() {
+    let (address, mut seen) = spawn_closing_upstream().await;
+    let pool = UpstreamPool::new([address], Balance::RoundRobin);
+    let get = HttpRequest::from_buffer_sync(b"GET /a HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    let post = HttpRequest::from_buffer_sync(
+        b"POST /orders HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\n{}",
+    )
+    .unwrap();
+
+    // The POST goes out on the pooled connection, which the upstream then
+    // closes: it may have acted on it, so it is not sent again
+    assert_eq!(
+        proxy::forward(&get, &pool).await.unwrap().status.code(),
+        200
+    );
+    assert_eq!(
+        proxy::forward(&post, &pool).await.unwrap().status.code(),
+        502
+    );
+    assert_eq!(seen.recv().await.unwrap(), "GET /a HTTP/1.1");
+    assert_eq!(seen.recv().await.unwrap(), "POST /orders HTTP/1.1");
+    assert!(seen.try_recv().is_err());
+
+    // A GET in the same spot is repeated on a fresh connection
+    assert_eq!(
+        proxy::forward(&get, &pool).await.unwrap().status.code(),
+        200
+    );
+    assert_eq!(
+        proxy::forward(&get, &pool).await.unwrap().status.code(),
+        200
+    );
+    for _ in 0..3 {
+        assert_eq!(seen.recv().await.unwrap(), "GET /a HTTP/1.1");
+    }
+}
+
+#[tokio::test]
+async fn test_forward_enforces_response_size_limit() {
+    let (address, _accepted) = spawn_upstream("from upstream").await;
+
+    let request = HttpRequest::from_buffer_sync(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let pool = UpstreamPool::new([address.clone()], Balance::RoundRobin).with_max_response_size(8);
+    let response = proxy::forward(&request, &pool).await.unwrap();
+    assert_eq!(response.status.code(), 502);
+
+    // Responses larger than the request body limit are relayed
+    let large = "x".repeat(codec::MAX_BODY_SIZE + 1).leak();
+    let (address, _accepted) = spawn_upstream(large).await;
+    let pool = UpstreamPool::new([address], Balance::RoundRobin);
+    let response = proxy::forward(&request, &pool).await.unwrap();
+    assert_eq!(response.status.code(), 200);
+    assert_eq!(response.body.len(), large.len());
+}
+
+#[tokio::test]
+async fn test_forward_keeps_repeated_response_headers() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4096];
+        let _ = socket.read(&mut buf).await.unwrap();
+        socket
+            .write_all(
+                b"HTTP/1.1 200 OK\r\nSet-Cookie: session=abc\r\n\
+                  Set-Cookie: theme=dark\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+    });
+
+    let pool = UpstreamPool::new([address], Balance::RoundRobin);
+    let request = HttpRequest::from_buffer_sync(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let response = proxy::forward(&request, &pool).await.unwrap();
+
+    assert_eq!(
+        response.headers.get_all("set-cookie").collect::<Vec<_>>(),
+        ["session=abc", "theme=dark"]
+    );
+    let bytes = response.to_bytes();
+    let head = String::from_utf8_lossy(&bytes);
+    assert!(head.contains("set-cookie: session=abc\r\nset-cookie: theme=dark\r\n"));
+}
+
+#[tokio::test]
+async fn test_forward_without_live_upstreams_is_bad_gateway() {
+    let dead = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+
+    let pool = UpstreamPool::new([dead], Balance::LeastConnections);
+    let request = HttpRequest::from_buffer_sync(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+    let response = proxy::forward(&request, &pool).await.unwrap();
+    assert_eq!(response.status.code(), 502);
+}
+
+#[tokio::test]
+async fn test_upgrade_is_tunnelled_to_upstream() {
+    // Upstream that switches protocols, greets right after the 101 and then
+    // echoes whatever arrives
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            socket.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap().to_lowercase();
+        assert!(head.contains("upgrade: websocket\r\n"));
+        assert!(head.contains("sec-websocket-key: abc\r\n"));
+        socket
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                  Connection: Upgrade\r\nSec-WebSocket-Accept: xyz\r\n\r\nhello",
+            )
+            .await
+            .unwrap();
+        let mut buf = [0u8; 64];
+        loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            socket.write_all(&buf[..n]).await.unwrap();
+        }
+    });
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let config = Config {
+        proxy_routes: vec![ProxyRoute::new(
+            "/ws",
+            UpstreamPool::new([upstream], Balance::RoundRobin),
+        )],
+        ..Config::default()
+    };
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let _ = handle_connection(socket, &config).await;
+    });
+
+    // Data sent right behind the request reaches the upstream too
+    let mut client = TcpStream::connect(address).await.unwrap();
+    client
+        .write_all(
+            b"GET /ws HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: abc\r\nSec-WebSocket-Version: 13\r\n\r\nearly",
+        )
+        .await
+        .unwrap();
+
+    let expected_head = "HTTP/1.1 101 Switching Protocols\r\n";
+    let mut received = Vec::new();
+    while !String::from_utf8_lossy(&received).ends_with("helloearly") {
+        let mut buf = [0u8; 256];
+        let n = client.read(&mut buf).await.unwrap();
+        assert!(n > 0, "tunnel closed early");
+        received.extend_from_slice(&buf[..n]);
+    }
+    let received = String::from_utf8(received).unwrap();
+    assert!(received.starts_with(expected_head));
+    assert!(
+        received
+            .to_lowercase()
+            .contains("sec-websocket-accept: xyz\r\n")
+    );
+
+    client.write_all(b"later").await.unwrap();
+    let mut buf = [0u8; 5];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"later");
+}
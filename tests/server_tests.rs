@@ -0,0 +1,60 @@
+// Runs as its own test binary: exhausting file descriptors would break any
+// test running alongside it
+#![cfg(target_os = "linux")]
+
+use http::{config::Config, server};
+use std::{fs::File, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Lower the soft descriptor limit and open files until it is reached.
+fn exhaust_file_descriptors() -> (libc::rlimit, Vec<File>) {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    assert_eq!(
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+        0
+    );
+    let lowered = libc::rlimit {
+        rlim_cur: limit.rlim_cur.min(1024),
+        ..limit
+    };
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &lowered) }, 0);
+
+    let mut files = Vec::new();
+    while let Ok(file) = File::open("/dev/null") {
+        files.push(file);
+    }
+    (limit, files)
+}
+
+#[tokio::test]
+async fn test_accept_errors_do_not_stop_the_server() {
+    let config = Config::builder()
+        .address("127.0.0.1:0")
+        .accept_loops(1)
+        .build()
+        .unwrap();
+    let server = server::start(config).await.unwrap();
+
+    // Queued in the backlog, but accepting it fails for lack of descriptors
+    let mut client = TcpStream::connect(server.local_addr()).await.unwrap();
+    let (limit, files) = exhaust_file_descriptors();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    drop(files);
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+
+    client
+        .write_all(b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+
+    server.shutdown().await.unwrap();
+}
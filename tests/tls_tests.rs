@@ -0,0 +1,229 @@
+use http::{
+    config::Config,
+    server,
+    tls::{ClientAuth, TlsConfig, TlsVersion},
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{
+    TlsConnector,
+    rustls::{
+        ClientConfig, RootCertStore,
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+        version::TLS12,
+    },
+};
+
+struct Site {
+    hostname: &'static str,
+    cert_pem: String,
+    key_pem: String,
+    cert_der: CertificateDer<'static>,
+}
+
+fn site(hostname: &'static str) -> Site {
+    let cert = rcgen::generate_simple_self_signed(vec![hostname.to_string()]).unwrap();
+    Site {
+        hostname,
+        cert_pem: cert.cert.pem(),
+        key_pem: cert.signing_key.serialize_pem(),
+        cert_der: cert.cert.der().clone(),
+    }
+}
+
+/// TLS settings serving the certificates of `sites`.
+fn tls_for(sites: &[Site]) -> TlsConfig {
+    let mut tls = TlsConfig::new(&[]);
+    for site in sites {
+        tls = tls
+            .with_certificate_pem(
+                &[site.hostname],
+                site.cert_pem.as_bytes(),
+                site.key_pem.as_bytes(),
+            )
+            .unwrap();
+    }
+    tls
+}
+
+/// Start an HTTPS-only server for `sites` and return its address.
+async fn start_tls_server(sites: &[Site]) -> std::net::SocketAddr {
+    serve_tls(tls_for(sites)).await
+}
+
+async fn serve_tls(tls: TlsConfig) -> std::net::SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let listener = server::Listener {
+        listener,
+        protocol: server::Protocol::Https(tls.acceptor().unwrap()),
+    };
+
+    tokio::spawn(server::serve(vec![listener], Config::default()));
+    address
+}
+
+fn roots(sites: &[Site]) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    for site in sites {
+        roots.add(site.cert_der.clone()).unwrap();
+    }
+    roots
+}
+
+fn connector(sites: &[Site]) -> TlsConnector {
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots(sites))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Whether a health check over a TLS connection made by `connector` to
+/// `hostname` at `address` succeeds.
+async fn health_check(connector: &TlsConnector, address: SocketAddr, hostname: &str) -> bool {
+    let tcp = TcpStream::connect(address).await.unwrap();
+    let server_name = ServerName::try_from(hostname.to_string()).unwrap();
+    let Ok(mut stream) = connector.connect(server_name, tcp).await else {
+        return false;
+    };
+    // With TLS 1.3 a refused client certificate only shows once data flows
+    let request = b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n";
+    if stream.write_all(request).await.is_err() {
+        return false;
+    }
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response).await;
+    response.starts_with(b"HTTP/1.1 200 OK")
+}
+
+#[tokio::test]
+async fn test_certificate_selected_by_sni() {
+    let sites = [site("a.test"), site("b.test")];
+    let address = start_tls_server(&sites).await;
+    let connector = connector(&sites);
+
+    for site in &sites {
+        let tcp = TcpStream::connect(address).await.unwrap();
+        let server_name = ServerName::try_from(site.hostname).unwrap();
+        let mut stream = connector.connect(server_name, tcp).await.unwrap();
+
+        let presented = stream.get_ref().1.peer_certificates().unwrap();
+        assert_eq!(presented[0], site.cert_der);
+
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        assert!(response.starts_with(b"HTTP/1.1 200 OK"));
+    }
+}
+
+#[tokio::test]
+async fn test_http_listener_redirects_to_https() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let listener = server::Listener {
+        listener,
+        protocol: server::Protocol::RedirectToHttps { port: 8443 },
+    };
+    tokio::spawn(server::serve(vec![listener], Config::default()));
+
+    let mut client = TcpStream::connect(address).await.unwrap();
+    client
+        .write_all(
+            b"GET /docs?page=2 HTTP/1.1\r\nHost: example.com:8080\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8_lossy(&response);
+
+    assert!(response.starts_with("HTTP/1.1 301 Moved Permanently"));
+    assert!(response.contains("location: https://example.com:8443/docs?page=2\r\n"));
+}
+
+#[tokio::test]
+async fn test_websocket_over_tls() {
+    let sites = [site("ws.test")];
+    let address = start_tls_server(&sites).await;
+    let tcp = TcpStream::connect(address).await.unwrap();
+    let server_name = ServerName::try_from("ws.test").unwrap();
+    let mut stream = connector(&sites).connect(server_name, tcp).await.unwrap();
+
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: ws.test\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101"));
+
+    // Masked "hi" with an all-zero mask
+    stream
+        .write_all(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i'])
+        .await
+        .unwrap();
+    loop {
+        let opcode = stream.read_u8().await.unwrap() & 0x0f;
+        let mut payload = vec![0u8; (stream.read_u8().await.unwrap() & 0x7f) as usize];
+        stream.read_exact(&mut payload).await.unwrap();
+        if opcode == 0x1 {
+            assert_eq!(payload, b"Echo: hi");
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_minimum_version_is_enforced() {
+    let sites = [site("v13.test")];
+    let address = serve_tls(tls_for(&sites).with_min_version(TlsVersion::Tls13)).await;
+    assert!(health_check(&connector(&sites), address, "v13.test").await);
+
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&TLS12])
+        .unwrap()
+        .with_root_certificates(roots(&sites))
+        .with_no_client_auth();
+    let tls12_only = TlsConnector::from(Arc::new(config));
+    assert!(!health_check(&tls12_only, address, "v13.test").await);
+}
+
+#[tokio::test]
+async fn test_client_certificate_required() {
+    let sites = [site("mtls.test")];
+    let client = site("client.test");
+    let ca_path = std::env::temp_dir().join(format!("http-client-ca-{}.pem", std::process::id()));
+    std::fs::write(&ca_path, &client.cert_pem).unwrap();
+    let address =
+        serve_tls(tls_for(&sites).with_client_auth(ClientAuth::Required(ca_path.clone()))).await;
+    std::fs::remove_file(&ca_path).unwrap();
+
+    assert!(!health_check(&connector(&sites), address, "mtls.test").await);
+
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots(&sites))
+        .with_client_auth_cert(
+            vec![client.cert_der.clone()],
+            PrivateKeyDer::from_pem_slice(client.key_pem.as_bytes()).unwrap(),
+        )
+        .unwrap();
+    let with_certificate = TlsConnector::from(Arc::new(config));
+    assert!(health_check(&with_certificate, address, "mtls.test").await);
+}
@@ -1,12 +1,36 @@
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
 use http::{
-    protocol::request::{HttpMethod, HttpRequest},
-    websocket::{frame::WebSocketFrame, handshake::is_websocket_request},
+    config::Config,
+    error::ServerError,
+    protocol::{
+        handle_connection,
+        headers::Headers,
+        request::{Extensions, HttpMethod, HttpRequest},
+    },
+    server,
+    websocket::{
+        Message, MessageStream, SlowConsumerPolicy, WebSocketConfig, WebSocketContext,
+        WebSocketHandler, WebSocketRoute, frame::WebSocketFrame, handshake::is_websocket_request,
+    },
+};
+use std::{
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    net::{TcpListener, TcpStream},
 };
-use std::collections::HashMap;
 
 #[test]
 fn test_websocket_detection() {
-    let mut headers = HashMap::new();
+    let mut headers = Headers::new();
     headers.insert("upgrade".to_string(), "websocket".to_string());
     headers.insert("connection".to_string(), "Upgrade".to_string());
     headers.insert("sec-websocket-key".to_string(), "test-key".to_string());
@@ -17,15 +41,19 @@ fn test_websocket_detection() {
         path: "/".to_string(),
         version: "HTTP/1.1".to_string(),
         headers,
-        body: Vec::new(),
+        body: Bytes::new(),
+        extensions: Extensions::default(),
     };
 
-    assert!(is_websocket_request(&request).is_some());
+    assert!(matches!(
+        is_websocket_request(&request, &WebSocketConfig::default()),
+        Ok(Some(_))
+    ));
 }
 
 #[test]
 fn test_websocket_frame_text_serialization() {
-    let text_frame = WebSocketFrame::Text("Hello, WebSocket!".to_string());
+    let text_frame = WebSocketFrame::text("Hello, WebSocket!");
     let bytes = text_frame.to_bytes();
 
     // Should start with 0x81 (FIN + TEXT opcode)
@@ -88,7 +116,7 @@ fn test_websocket_frame_close_with_code() {
 #[test]
 fn test_websocket_frame_ping_pong() {
     let ping_data = b"ping data".to_vec();
-    let ping_frame = WebSocketFrame::Ping(ping_data.clone());
+    let ping_frame = WebSocketFrame::ping(ping_data.clone());
     let bytes = ping_frame.to_bytes();
 
     // Should start with 0x89 (FIN + PING opcode)
@@ -116,3 +144,977 @@ fn test_websocket_frame_ping_pong() {
         panic!("Expected ping frame");
     }
 }
+
+/// Serve a single connection with `config`, send an upgrade request for `path` with
+/// `extra_headers` and return the stream with the handshake response head.
+async fn upgrade(config: Config, path: &str, extra_headers: &str) -> (TcpStream, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let _ = handle_connection(socket, &config).await;
+    });
+
+    let mut client = TcpStream::connect(address).await.unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+        path, extra_headers
+    );
+    client.write_all(request.as_bytes()).await.unwrap();
+    let head = read_head(&mut client).await;
+    (client, head)
+}
+
+/// Read a response head byte by byte, so no frame data past it is consumed.
+async fn read_head<S: AsyncRead + Unpin>(client: &mut S) -> String {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0u8; 1];
+        if client.read(&mut byte).await.unwrap() == 0 {
+            break;
+        }
+        head.push(byte[0]);
+    }
+    String::from_utf8_lossy(&head).to_string()
+}
+
+/// Opcodes and payloads of unmasked server `frames`. Server frames in
+/// these tests are small enough for a 7-bit length.
+fn frames(mut data: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut frames = Vec::new();
+    while data.len() >= 2 {
+        let length = (data[1] & 0x7f) as usize;
+        let Some(payload) = data.get(2..2 + length) else {
+            break;
+        };
+        frames.push((data[0] & 0x0f, payload));
+        data = &data[2 + length..];
+    }
+    frames
+}
+
+/// Status code of the close frame among unmasked server `data`, if any.
+fn close_code(data: &[u8]) -> Option<u16> {
+    frames(data)
+        .into_iter()
+        .find(|(opcode, payload)| *opcode == 0x8 && payload.len() >= 2)
+        .map(|(_, payload)| u16::from_be_bytes([payload[0], payload[1]]))
+}
+
+#[tokio::test]
+async fn test_subprotocol_negotiation() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            subprotocols: vec!["graphql-ws".to_string()],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let (_, head) = upgrade(
+        config.clone(),
+        "/",
+        "Sec-WebSocket-Protocol: chat, graphql-ws\r\n",
+    )
+    .await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+    assert!(head.contains("sec-websocket-protocol: graphql-ws\r\n"));
+
+    let (_, head) = upgrade(config, "/", "Sec-WebSocket-Protocol: chat\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+    assert!(!head.contains("sec-websocket-protocol"));
+}
+
+#[tokio::test]
+async fn test_oversized_frame_closes_with_1009() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            max_frame_size: 1024,
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, head) = upgrade(config, "/", "").await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    // Only the header of a 1 MiB binary frame: the server must not wait for
+    // the payload
+    let mut header = vec![0x82, 0x80 | 127];
+    header.extend_from_slice(&(1024u64 * 1024).to_be_bytes());
+    header.extend_from_slice(&[1, 2, 3, 4]);
+    client.write_all(&header).await.unwrap();
+
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(1009));
+    let close = frames(&received)
+        .into_iter()
+        .find(|(opcode, _)| *opcode == 0x8);
+    assert_eq!(close.unwrap().1[2..], *b"Message too big");
+}
+
+/// A masked client frame carrying `payload` with `opcode`.
+fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![
+        if fin { 0x80 } else { 0 } | opcode,
+        0x80 | payload.len() as u8,
+    ];
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]),
+    );
+    frame
+}
+
+/// Read the next server frame's opcode and payload.
+async fn next_frame<S: AsyncRead + Unpin>(client: &mut S) -> (u8, Vec<u8>) {
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await.unwrap();
+    let mut payload = vec![0u8; (header[1] & 0x7f) as usize];
+    client.read_exact(&mut payload).await.unwrap();
+    (header[0] & 0x0f, payload)
+}
+
+/// Read server frames until a text frame arrives and return its payload.
+async fn next_text<S: AsyncRead + Unpin>(client: &mut S) -> String {
+    loop {
+        if let (0x1, payload) = next_frame(client).await {
+            return String::from_utf8(payload).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_reserved_opcode_closes_with_1002() {
+    let (mut client, head) = upgrade(Config::default(), "/", "").await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    client
+        .write_all(&client_frame(true, 0x3, b"?"))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(1002));
+}
+
+#[tokio::test]
+async fn test_messages_dispatched_by_path() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            routes: vec![WebSocketRoute::new(
+                "/shout",
+                |message, context: WebSocketContext| async move {
+                    if let Message::Text(text) = message {
+                        let _ = context.send(Message::text(text.to_uppercase()));
+                    }
+                },
+            )],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let (mut client, head) = upgrade(config.clone(), "/shout?v=1", "").await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+    client
+        .write_all(&client_frame(false, 0x1, b"hel"))
+        .await
+        .unwrap();
+    client
+        .write_all(&client_frame(true, 0x0, b"lo"))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await, "HELLO");
+
+    let (_, head) = upgrade(config, "/other", "").await;
+    assert!(head.starts_with("HTTP/1.1 404"));
+}
+
+#[tokio::test]
+async fn test_closing_handshake() {
+    // Server-initiated: the close frame is followed by a half-close, before
+    // the client has replied
+    let config = Config {
+        websocket: WebSocketConfig {
+            routes: vec![WebSocketRoute::new(
+                "/",
+                |_, context: WebSocketContext| async move {
+                    let _ = context.sender().close(4000, "Bye");
+                },
+            )],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, _) = upgrade(config, "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x1, b"hi"))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(4000));
+    client
+        .write_all(&client_frame(true, 0x8, &4000u16.to_be_bytes()))
+        .await
+        .unwrap();
+
+    // Client-initiated: the status code is echoed
+    let (mut client, _) = upgrade(Config::default(), "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x8, &1000u16.to_be_bytes()))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(1000));
+}
+
+#[tokio::test]
+async fn test_connection_halves() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            routes: vec![WebSocketRoute::connection("/", |connection| async move {
+                let (mut sink, mut stream) = connection.split();
+                while let Some(message) = stream.next().await {
+                    if message == Message::text("bye") {
+                        let _ = sink.close().await;
+                        break;
+                    }
+                    let _ = sink.send(message).await;
+                }
+            })],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let (mut client, _) = upgrade(config, "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x1, b"one"))
+        .await
+        .unwrap();
+    client
+        .write_all(&client_frame(true, 0x1, b"two"))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await, "one");
+    assert_eq!(next_text(&mut client).await, "two");
+
+    client
+        .write_all(&client_frame(true, 0x1, b"bye"))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(1000));
+}
+
+#[tokio::test]
+async fn test_slow_consumer_is_disconnected() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            // The handler runs before the queue is drained, so the second
+            // send finds it full
+            routes: vec![WebSocketRoute::new(
+                "/",
+                |message, context: WebSocketContext| async move {
+                    let _ = context.send(message.clone());
+                    let _ = context.send(message);
+                },
+            )],
+            write_queue_size: 1,
+            slow_consumer: SlowConsumerPolicy::Disconnect,
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let (mut client, _) = upgrade(config, "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x1, b"hi"))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(1008));
+}
+
+#[tokio::test]
+async fn test_handler_can_wait_for_room_in_the_write_queue() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            routes: vec![WebSocketRoute::new(
+                "/",
+                |_, context: WebSocketContext| async move {
+                    for i in 0..200 {
+                        context
+                            .sender()
+                            .send_wait(Message::text(i.to_string()))
+                            .await
+                            .unwrap();
+                    }
+                },
+            )],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    assert!(200 > config.websocket.write_queue_size);
+
+    let (mut client, _) = upgrade(config, "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x1, b"go"))
+        .await
+        .unwrap();
+    let received = tokio::time::timeout(Duration::from_secs(10), async {
+        let mut received = Vec::new();
+        for _ in 0..200 {
+            received.push(next_text(&mut client).await);
+        }
+        received
+    })
+    .await
+    .expect("handler stuck waiting on its own write queue");
+    assert_eq!(received[0], "0");
+    assert_eq!(received[199], "199");
+}
+
+#[tokio::test]
+async fn test_cross_origin_upgrade_is_refused() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let (_, head) = upgrade(config.clone(), "/", "Origin: https://evil.example\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 403"));
+
+    let (_, head) = upgrade(config.clone(), "/", "Origin: https://APP.example.com\r\n").await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    // Non-browser clients send no Origin
+    let (_, head) = upgrade(config, "/", "").await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+}
+
+#[tokio::test]
+async fn test_missed_pongs_are_tolerated_up_to_limit() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            ping_interval: Some(Duration::from_millis(50)),
+            max_missed_pongs: 2,
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, _) = upgrade(config, "/", "").await;
+
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    let opcodes: Vec<u8> = frames(&received)
+        .iter()
+        .map(|(opcode, _)| *opcode)
+        .collect();
+    assert_eq!(opcodes, [0x9, 0x9, 0x8]);
+    assert_eq!(close_code(&received), Some(1002));
+}
+
+#[tokio::test]
+async fn test_pong_must_echo_ping_payload() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            ping_interval: Some(Duration::from_millis(100)),
+            routes: vec![WebSocketRoute::new(
+                "/",
+                |_, context: WebSocketContext| async move {
+                    let measured = context.round_trip_time().is_some();
+                    let _ = context.send(Message::text(measured.to_string()));
+                },
+            )],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, _) = upgrade(config, "/", "").await;
+
+    let (opcode, payload) = next_frame(&mut client).await;
+    assert_eq!((opcode, payload.len()), (0x9, 8));
+    client
+        .write_all(&client_frame(true, 0xA, b"stale"))
+        .await
+        .unwrap();
+    client
+        .write_all(&client_frame(true, 0xA, &payload))
+        .await
+        .unwrap();
+    client
+        .write_all(&client_frame(true, 0x1, b"rtt?"))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await, "true");
+
+    // A pong with the wrong payload leaves the ping unanswered
+    let (opcode, next_payload) = next_frame(&mut client).await;
+    assert_eq!(opcode, 0x9);
+    assert_ne!(next_payload, payload);
+    client
+        .write_all(&client_frame(true, 0xA, &payload))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(1002));
+}
+
+#[tokio::test]
+async fn test_pings_can_be_disabled() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            ping_interval: None,
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, _) = upgrade(config, "/", "").await;
+
+    let mut byte = [0u8; 1];
+    let read = tokio::time::timeout(Duration::from_millis(100), client.read(&mut byte)).await;
+    assert!(read.is_err(), "no frame expected");
+}
+
+#[tokio::test]
+async fn test_idle_connection_is_closed() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            ping_interval: None,
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, _) = upgrade(config, "/", "").await;
+
+    // A message restarts the countdown; a ping does not
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    client
+        .write_all(&client_frame(true, 0x1, b"hi"))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await, "Echo: hi");
+    let last_message = std::time::Instant::now();
+    client
+        .write_all(&client_frame(true, 0x9, b""))
+        .await
+        .unwrap();
+
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(1000));
+    assert!(last_message.elapsed() >= Duration::from_millis(250));
+}
+
+#[tokio::test]
+async fn test_push_from_another_task() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            routes: vec![WebSocketRoute::new(
+                "/",
+                |_, context: WebSocketContext| async move {
+                    // A timer pushing until the client goes away
+                    let sender = context.sender().clone();
+                    tokio::spawn(async move {
+                        let mut ticks = tokio::time::interval(Duration::from_millis(10));
+                        for n in 0.. {
+                            tokio::select! {
+                                _ = ticks.tick() => {
+                                    let _ = sender.send(Message::text(format!("tick {}", n)));
+                                }
+                                _ = sender.closed() => break,
+                            }
+                        }
+                    });
+                },
+            )],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let hub = config.websocket.hub.clone();
+
+    let (mut client, _) = upgrade(config, "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x1, b"subscribe"))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await, "tick 0");
+    assert_eq!(next_text(&mut client).await, "tick 1");
+
+    // Pushing through the hub, as e.g. an HTTP handler would
+    let ids = hub.ids();
+    assert_eq!(ids.len(), 1);
+    let sender = hub.sender(ids[0]).unwrap();
+    sender.close(1000, "").unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    sender.closed().await;
+    assert!(sender.is_closed());
+}
+
+#[tokio::test]
+async fn test_traffic_metrics() {
+    let config = Config {
+        metrics_path: Some("/metrics".to_string()),
+        websocket: WebSocketConfig {
+            ping_interval: None,
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let metrics = config.websocket.metrics.clone();
+
+    let (mut client, _) = upgrade(config.clone(), "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x1, b"hi"))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await, "Echo: hi");
+    client
+        .write_all(&client_frame(true, 0x8, &1000u16.to_be_bytes()))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+
+    let traffic = metrics.traffic();
+    assert_eq!((traffic.frames_in, traffic.bytes_in), (2, 16));
+    assert_eq!((traffic.frames_out, traffic.bytes_out), (2, 14));
+    assert_eq!(metrics.connections_opened(), 1);
+    assert_eq!(metrics.close_codes_received().get(&1000), Some(&1));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let _ = handle_connection(socket, &config).await;
+    });
+    let mut client = TcpStream::connect(address).await.unwrap();
+    client
+        .write_all(b"GET /metrics?scrape=1 HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("websocket_frames_received_total 2\n"));
+    assert!(response.contains("websocket_close_codes_total{direction=\"sent\",code=\"1000\"} 1\n"));
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_with_going_away() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(server::serve_with_shutdown(
+        vec![listener.into()],
+        Config::default(),
+        async {
+            let _ = stopped.await;
+        },
+    ));
+
+    let mut client = TcpStream::connect(address).await.unwrap();
+    client
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    assert!(read_head(&mut client).await.starts_with("HTTP/1.1 101"));
+    client
+        .write_all(&client_frame(true, 0x1, b"hi"))
+        .await
+        .unwrap();
+    // Once the echo arrives the connection is registered
+    assert_eq!(next_text(&mut client).await, "Echo: hi");
+
+    stop.send(()).unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(1001));
+    client
+        .write_all(&client_frame(true, 0x8, &1001u16.to_be_bytes()))
+        .await
+        .unwrap();
+
+    // The server finishes well before the shutdown timeout
+    tokio::time::timeout(Duration::from_secs(2), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_control_frames_between_fragments() {
+    let config = Config {
+        websocket: WebSocketConfig {
+            ping_interval: None,
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, _) = upgrade(config.clone(), "/", "").await;
+
+    // The PONG is sent while the message is still incomplete
+    client
+        .write_all(&client_frame(false, 0x1, b"Hel"))
+        .await
+        .unwrap();
+    client
+        .write_all(&client_frame(true, 0x9, b"p"))
+        .await
+        .unwrap();
+    assert_eq!(next_frame(&mut client).await, (0xA, b"p".to_vec()));
+    client
+        .write_all(&client_frame(true, 0x0, b"lo"))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await, "Echo: Hello");
+
+    // A Close abandons the message in progress
+    let (mut client, _) = upgrade(config, "/", "").await;
+    client
+        .write_all(&client_frame(false, 0x1, b"Hel"))
+        .await
+        .unwrap();
+    client
+        .write_all(&client_frame(true, 0x8, &1000u16.to_be_bytes()))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    assert_eq!(close_code(&received), Some(1000));
+    assert!(frames(&received).iter().all(|(opcode, _)| *opcode == 0x8));
+}
+
+/// Reports every event it sees.
+struct Recorder(tokio::sync::mpsc::UnboundedSender<String>);
+
+impl WebSocketHandler for Recorder {
+    async fn on_open(&self, _context: &WebSocketContext) {
+        let _ = self.0.send("open".to_string());
+    }
+
+    async fn on_message(&self, message: Message, context: &WebSocketContext) {
+        if let Message::Text(text) = &message {
+            let _ = self.0.send(format!("message {}", text));
+        }
+        let _ = context.send(message);
+    }
+
+    async fn on_close(&self, code: u16, reason: &str, _context: &WebSocketContext) {
+        let _ = self.0.send(format!("close {} {}", code, reason));
+    }
+
+    async fn on_error(&self, error: &ServerError, _context: &WebSocketContext) {
+        let _ = self.0.send(format!("error {}", error));
+    }
+}
+
+#[tokio::test]
+async fn test_event_handler() {
+    let (events_tx, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let config = Config {
+        websocket: WebSocketConfig {
+            ping_interval: None,
+            routes: vec![WebSocketRoute::handler("/", Recorder(events_tx))],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+
+    let (mut client, _) = upgrade(config.clone(), "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x1, b"hi"))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await, "hi");
+    let mut close = 4001u16.to_be_bytes().to_vec();
+    close.extend_from_slice(b"bye");
+    client
+        .write_all(&client_frame(true, 0x8, &close))
+        .await
+        .unwrap();
+    for expected in ["open", "message hi", "close 4001 bye"] {
+        assert_eq!(events.recv().await.unwrap(), expected);
+    }
+
+    // Protocol errors are reported, then the close we sent
+    let (mut client, _) = upgrade(config, "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x1, &[0xff]))
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    client
+        .write_all(&client_frame(true, 0x8, &1007u16.to_be_bytes()))
+        .await
+        .unwrap();
+    assert_eq!(events.recv().await.unwrap(), "open");
+    assert!(events.recv().await.unwrap().starts_with("error"));
+    assert_eq!(events.recv().await.unwrap(), "close 1007 Invalid UTF-8");
+}
+
+#[tokio::test]
+async fn test_streamed_messages() {
+    // Every chunk received is sent straight back as a fragment
+    let route = WebSocketRoute::streaming(
+        "/",
+        |mut stream: MessageStream, context: WebSocketContext| async move {
+            let mut writer = context.sender().message_writer();
+            while let Some(Ok(chunk)) = stream.next_chunk().await {
+                let chunk = if stream.is_text() {
+                    Message::text(String::from_utf8(chunk.to_vec()).unwrap())
+                } else {
+                    Message::binary(chunk)
+                };
+                writer.write(chunk).await.unwrap();
+            }
+            writer.finish();
+        },
+    );
+    let config = Config {
+        websocket: WebSocketConfig {
+            ping_interval: None,
+            max_message_size: 4,
+            routes: vec![route],
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, head) = upgrade(config, "/", "").await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    for frame in [
+        client_frame(false, 0x2, b"abc"),
+        client_frame(true, 0x9, b"p"),
+        client_frame(false, 0x0, b"def"),
+        client_frame(true, 0x0, b"gh"),
+        client_frame(true, 0x1, b"done"),
+    ] {
+        client.write_all(&frame).await.unwrap();
+    }
+
+    let mut received = Vec::new();
+    while received.len() < 6 {
+        match next_frame(&mut client).await {
+            (0xa, _) => {}
+            (opcode, payload) => received.push((opcode, payload)),
+        }
+    }
+    let expected: [(u8, &[u8]); 6] = [
+        (0x2, b"abc"),
+        (0x0, b"def"),
+        (0x0, b"gh"),
+        (0x0, b""),
+        (0x1, b"done"),
+        (0x0, b""),
+    ];
+    assert_eq!(
+        received,
+        expected.map(|(opcode, payload)| (opcode, payload.to_vec()))
+    );
+}
+
+/// Send one plain HTTP request to a server running `config`.
+async fn http_request(config: &Config, request: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let config = config.clone();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let _ = handle_connection(socket, &config).await;
+    });
+    let mut client = TcpStream::connect(address).await.unwrap();
+    client.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_admin_requires_token_when_configured() {
+    let config = Config {
+        websocket_admin_path: Some("/admin/websockets".to_string()),
+        websocket_admin_token: Some("s3cret".to_string()),
+        ..Config::default()
+    };
+
+    let anonymous = http_request(
+        &config,
+        "GET /admin/websockets HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(anonymous.starts_with("HTTP/1.1 401 Unauthorized"));
+
+    let wrong = http_request(
+        &config,
+        "GET /admin/websockets HTTP/1.1\r\nAuthorization: Bearer guess\r\n\
+         Connection: close\r\n\r\n",
+    )
+    .await;
+    assert!(wrong.starts_with("HTTP/1.1 401 Unauthorized"));
+
+    let authorized = http_request(
+        &config,
+        "GET /admin/websockets HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\
+         Connection: close\r\n\r\n",
+    )
+    .await;
+    assert!(authorized.starts_with("HTTP/1.1 200 OK"), "{}", authorized);
+}
+
+#[tokio::test]
+async fn test_admin_lists_and_closes_connections() {
+    let config = Config {
+        websocket_admin_path: Some("/admin/websockets".to_string()),
+        websocket: WebSocketConfig {
+            ping_interval: None,
+            ..WebSocketConfig::default()
+        },
+        ..Config::default()
+    };
+    let (mut client, _) = upgrade(config.clone(), "/", "").await;
+    client
+        .write_all(&client_frame(true, 0x1, b"hi"))
+        .await
+        .unwrap();
+    assert_eq!(next_text(&mut client).await, "Echo: hi");
+    let id = config.websocket.hub.ids()[0];
+
+    let listing = http_request(
+        &config,
+        "GET /admin/websockets HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(listing.starts_with("HTTP/1.1 200 OK"));
+    assert!(listing.contains(&format!(r#""id":{}"#, id)));
+    assert!(listing.contains(r#""frames_in":1"#));
+
+    let broadcast = http_request(
+        &config,
+        "POST /admin/websockets/broadcast HTTP/1.1\r\nContent-Length: 5\r\n\
+         Connection: close\r\n\r\nhello",
+    )
+    .await;
+    assert!(broadcast.ends_with(r#"{"sent": 1}"#));
+    assert_eq!(next_text(&mut client).await, "hello");
+
+    let closed = http_request(
+        &config,
+        &format!(
+            "DELETE /admin/websockets/{} HTTP/1.1\r\nConnection: close\r\n\r\n",
+            id
+        ),
+    )
+    .await;
+    assert!(closed.starts_with("HTTP/1.1 202"));
+    let (opcode, payload) = next_frame(&mut client).await;
+    assert_eq!((opcode, &payload[..2]), (0x8, &1008u16.to_be_bytes()[..]));
+
+    let missing = http_request(
+        &config,
+        "DELETE /admin/websockets/999 HTTP/1.1\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(missing.starts_with("HTTP/1.1 404"));
+}
+
+/// Counts the writes made to the stream it wraps.
+struct CountingStream {
+    inner: DuplexStream,
+    writes: Arc<AtomicUsize>,
+}
+
+impl AsyncRead for CountingStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CountingStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_queued_frames_are_written_together() {
+    let config = WebSocketConfig {
+        ping_interval: None,
+        routes: vec![WebSocketRoute::new(
+            "/",
+            |_, context: WebSocketContext| async move {
+                for n in 0..20 {
+                    context.send(Message::text(n.to_string())).unwrap();
+                }
+            },
+        )],
+        ..WebSocketConfig::default()
+    };
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let writes = Arc::new(AtomicUsize::new(0));
+    let socket = CountingStream {
+        inner: server,
+        writes: Arc::clone(&writes),
+    };
+    tokio::spawn(async move {
+        let request = HttpRequest::from_buffer_sync(
+            b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+        let _ = http::websocket::handle_websocket(socket, None, &request, &config).await;
+    });
+
+    assert!(read_head(&mut client).await.starts_with("HTTP/1.1 101"));
+    let before = writes.load(Ordering::Relaxed);
+    client
+        .write_all(&client_frame(true, 0x1, b"go"))
+        .await
+        .unwrap();
+    for n in 0..20 {
+        assert_eq!(next_text(&mut client).await, n.to_string());
+    }
+    assert_eq!(writes.load(Ordering::Relaxed) - before, 1);
+}